@@ -0,0 +1,259 @@
+use crate::config::AuthorityConfig;
+use crate::consensus;
+use crate::registry::{self, Registry};
+use penum_protocol::cell::unix_time;
+use penum_protocol::directory::{
+    parse_authority_key, Directory, SignedDescriptor, SignedDirectory, SigningKey, VerifyingKey,
+};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinSet;
+
+// How long a request to a peer authority may take
+const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Peer {
+    url: String,
+    key: VerifyingKey,
+}
+
+// One directory authority. Each consensus period it votes with its own view
+// of the network, combines the votes of all authorities, signs the result and
+// publishes it once enough authorities signed the same document.
+pub struct Authority {
+    config: AuthorityConfig,
+    key: SigningKey,
+    peers: Vec<Peer>,
+    http: reqwest::Client,
+    registry: Mutex<Registry>,
+    vote: Mutex<Option<SignedDirectory>>,  // Our vote for the current period
+    pending: Mutex<Option<SignedDirectory>>,  // The consensus we computed, with our signature only
+    published: Mutex<Option<SignedDirectory>>,  // The consensus clients fetch
+}
+
+impl Authority {
+    pub fn new(config: AuthorityConfig, key: SigningKey) -> anyhow::Result<Self> {
+        config.validate()?;
+        let peers = config
+            .peers
+            .iter()
+            .map(|peer| {
+                Ok(Peer {
+                    url: peer.url.trim_end_matches('/').to_string(),
+                    key: parse_authority_key(&peer.key)
+                        .map_err(|e| e.context(format!("Invalid key for peer {}", peer.url)))?,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Self {
+            http: reqwest::Client::builder().timeout(PEER_TIMEOUT).build()?,
+            registry: Mutex::new(Registry::default()),
+            vote: Mutex::new(None),
+            pending: Mutex::new(None),
+            published: Mutex::new(None),
+            config,
+            key,
+            peers,
+        })
+    }
+
+    pub fn accept_descriptor(&self, descriptor: &SignedDescriptor, source: IpAddr) -> anyhow::Result<()> {
+        self.registry
+            .lock()
+            .expect("registry poisoned")
+            .accept(descriptor, source, unix_time(), &self.config)
+    }
+
+    pub fn vote(&self) -> Option<SignedDirectory> {
+        self.vote.lock().expect("vote poisoned").clone()
+    }
+
+    pub fn pending(&self) -> Option<SignedDirectory> {
+        self.pending.lock().expect("pending consensus poisoned").clone()
+    }
+
+    pub fn published(&self) -> Option<SignedDirectory> {
+        self.published.lock().expect("consensus poisoned").clone()
+    }
+
+    // Test every known node at the address it listed, so only nodes that
+    // actually answer with their identity key are voted for
+    pub async fn run_reachability_tests(self: Arc<Self>) {
+        loop {
+            let now = unix_time();
+            let targets = {
+                let mut registry = self.registry.lock().expect("registry poisoned");
+                registry.expire(now, self.config.heartbeat_timeout_secs);
+                registry.test_targets()
+            };
+
+            let mut tests = JoinSet::new();
+            for (key, address, identity) in targets {
+                tests.spawn(async move { (key, address, registry::test_reachability(address, identity).await) });
+            }
+            while let Some(result) = tests.join_next().await {
+                if let Ok((key, address, passed)) = result {
+                    self.registry
+                        .lock()
+                        .expect("registry poisoned")
+                        .record_test(&key, address, passed, unix_time());
+                }
+            }
+
+            tokio::time::sleep(Duration::from_secs(self.config.reachability_interval_secs)).await;
+        }
+    }
+
+    // Periods start at multiples of consensus_interval_secs in Unix time, so
+    // authorities agree on them without talking to each other:
+    // vote at the start, combine votes after one vote delay, collect
+    // signatures and publish after two.
+    pub async fn run_consensus(self: Arc<Self>) {
+        let interval = self.config.consensus_interval_secs;
+        let delay = self.config.vote_delay_secs;
+        loop {
+            let period = (unix_time() / interval + 1) * interval;
+
+            sleep_until_unix(period).await;
+            self.make_vote(period);
+
+            sleep_until_unix(period + delay).await;
+            let votes = self.collect_votes(period).await;
+            if let Err(e) = self.make_pending(&votes, period) {
+                eprintln!("⚠️  Could not compute the consensus: {:#}", e);
+                continue;
+            }
+
+            sleep_until_unix(period + 2 * delay).await;
+            self.publish(period).await;
+        }
+    }
+
+    fn make_vote(&self, period: u64) {
+        let nodes = self
+            .registry
+            .lock()
+            .expect("registry poisoned")
+            .running_nodes(period, &self.config);
+        let directory = Directory {
+            published: period,
+            valid_until: period + self.config.consensus_valid_secs,
+            nodes,
+        };
+        let Ok(mut vote) = SignedDirectory::new(&directory) else {
+            return;
+        };
+        vote.sign(&self.key);
+        *self.vote.lock().expect("vote poisoned") = Some(vote);
+    }
+
+    // Our vote plus every peer vote for this period that carries the peer's signature
+    async fn collect_votes(&self, period: u64) -> Vec<Directory> {
+        let mut votes = Vec::new();
+        if let Some(directory) = self
+            .vote()
+            .and_then(|vote| serde_json::from_str::<Directory>(&vote.directory).ok())
+        {
+            votes.push(directory);
+        }
+
+        for peer in &self.peers {
+            let vote = match self.fetch(&peer.url, "vote").await {
+                Ok(vote) => vote,
+                Err(e) => {
+                    eprintln!("⚠️  No vote from {}: {:#}", peer.url, e);
+                    continue;
+                }
+            };
+            match vote.verify(std::slice::from_ref(&peer.key), 1, unix_time()) {
+                Ok(directory) if directory.published == period => votes.push(directory),
+                Ok(_) => eprintln!("⚠️  Vote from {} is for another period", peer.url),
+                Err(e) => eprintln!("⚠️  Invalid vote from {}: {:#}", peer.url, e),
+            }
+        }
+        votes
+    }
+
+    fn make_pending(&self, votes: &[Directory], period: u64) -> anyhow::Result<()> {
+        let directory = consensus::combine(
+            votes,
+            self.config.authority_count(),
+            period,
+            period + self.config.consensus_valid_secs,
+        );
+        let mut pending = SignedDirectory::new(&directory)?;
+        pending.sign(&self.key);
+        *self.pending.lock().expect("pending consensus poisoned") = Some(pending);
+        Ok(())
+    }
+
+    // Add the signatures of peers that computed the same consensus, and
+    // publish it once the threshold is reached. Otherwise the previous
+    // consensus stays published until it expires.
+    async fn publish(&self, period: u64) {
+        let Some(mut consensus) = self.pending() else {
+            return;
+        };
+
+        for peer in &self.peers {
+            let Ok(theirs) = self.fetch(&peer.url, "pending").await else {
+                continue; // Counted as missing below
+            };
+            if theirs.directory != consensus.directory {
+                eprintln!("⚠️  {} computed a different consensus", peer.url);
+                continue;
+            }
+            let signature = theirs.signatures.into_iter().find(|signature| {
+                let single = SignedDirectory {
+                    directory: consensus.directory.clone(),
+                    signatures: vec![signature.clone()],
+                };
+                single.verify(std::slice::from_ref(&peer.key), 1, unix_time()).is_ok()
+            });
+            consensus.signatures.extend(signature);
+        }
+
+        let signed = consensus.signatures.len();
+        let nodes = serde_json::from_str::<Directory>(&consensus.directory)
+            .map(|directory| directory.nodes.len())
+            .unwrap_or(0);
+        if signed < self.config.threshold() {
+            eprintln!(
+                "⚠️  Consensus for {} not published: {} of {} required signatures",
+                period,
+                signed,
+                self.config.threshold()
+            );
+            return;
+        }
+
+        println!(
+            "📜 Published consensus for {}: {} nodes, {} of {} authorities signed",
+            period,
+            nodes,
+            signed,
+            self.config.authority_count()
+        );
+        *self.published.lock().expect("consensus poisoned") = Some(consensus);
+    }
+
+    async fn fetch(&self, base_url: &str, document: &str) -> anyhow::Result<SignedDirectory> {
+        let response = self
+            .http
+            .get(format!("{}/{}", base_url, document))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(response.json().await?)
+    }
+}
+
+async fn sleep_until_unix(target: u64) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let target = Duration::from_secs(target);
+    if target > now {
+        tokio::time::sleep(target - now).await;
+    }
+}
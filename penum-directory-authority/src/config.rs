@@ -0,0 +1,138 @@
+use serde::{Deserialize, Serialize};
+
+// Another authority taking part in the consensus
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct PeerAuthority {
+    pub url: String,  // Base URL, e.g. http://203.0.113.5:7000
+    pub key: String,  // Hex-encoded Ed25519 key the peer prints on start
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct AuthorityConfig {
+    pub listen_addr: String,
+    pub listen_port: u16,
+    #[serde(default = "default_signing_key_path")]
+    pub signing_key_path: String,  // Ed25519 key the consensus is signed with, created on first start
+    #[serde(default)]
+    pub peers: Vec<PeerAuthority>,
+    #[serde(default)]
+    pub threshold: Option<usize>,  // Signatures needed to publish; default is a majority of all authorities
+    #[serde(default = "default_consensus_interval_secs")]
+    pub consensus_interval_secs: u64,  // A new consensus is made at every multiple of this, in Unix time
+    #[serde(default = "default_vote_delay_secs")]
+    pub vote_delay_secs: u64,  // Time allowed for votes, and then signatures, to reach every peer
+    #[serde(default = "default_consensus_valid_secs")]
+    pub consensus_valid_secs: u64,  // How long clients may use a consensus
+    #[serde(default = "default_heartbeat_timeout_secs")]
+    pub heartbeat_timeout_secs: u64,  // Nodes not heard from for this long are left out
+    #[serde(default = "default_reachability_interval_secs")]
+    pub reachability_interval_secs: u64,
+    #[serde(default = "default_stable_after_secs")]
+    pub stable_after_secs: u64,  // Continuous reachability needed for the stable flag
+    #[serde(default = "default_max_bandwidth_kbps")]
+    pub max_bandwidth_kbps: u64,  // Cap on the bandwidth a node may declare in our vote
+    #[serde(default = "default_fast_bandwidth_kbps")]
+    pub fast_bandwidth_kbps: u64,  // Declared bandwidth needed for the fast flag
+    #[serde(default = "default_max_new_nodes_per_source")]
+    pub max_new_nodes_per_source: usize,  // New identity keys one address (IPv6: /64) may register per hour
+    #[serde(default)]
+    pub allow_private_addresses: bool,  // List nodes at private addresses, for test networks
+}
+
+fn default_signing_key_path() -> String {
+    "authority.key".to_string()
+}
+
+fn default_consensus_interval_secs() -> u64 {
+    600
+}
+
+fn default_vote_delay_secs() -> u64 {
+    20
+}
+
+fn default_consensus_valid_secs() -> u64 {
+    3 * 3600
+}
+
+fn default_heartbeat_timeout_secs() -> u64 {
+    300
+}
+
+fn default_reachability_interval_secs() -> u64 {
+    60
+}
+
+fn default_stable_after_secs() -> u64 {
+    24 * 3600
+}
+
+fn default_max_bandwidth_kbps() -> u64 {
+    100 * 1024
+}
+
+fn default_fast_bandwidth_kbps() -> u64 {
+    800
+}
+
+fn default_max_new_nodes_per_source() -> usize {
+    4
+}
+
+impl Default for AuthorityConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: "127.0.0.1".to_string(),
+            listen_port: 7000,
+            signing_key_path: default_signing_key_path(),
+            peers: Vec::new(),
+            threshold: None,
+            consensus_interval_secs: default_consensus_interval_secs(),
+            vote_delay_secs: default_vote_delay_secs(),
+            consensus_valid_secs: default_consensus_valid_secs(),
+            heartbeat_timeout_secs: default_heartbeat_timeout_secs(),
+            reachability_interval_secs: default_reachability_interval_secs(),
+            stable_after_secs: default_stable_after_secs(),
+            max_bandwidth_kbps: default_max_bandwidth_kbps(),
+            fast_bandwidth_kbps: default_fast_bandwidth_kbps(),
+            max_new_nodes_per_source: default_max_new_nodes_per_source(),
+            allow_private_addresses: false,
+        }
+    }
+}
+
+impl AuthorityConfig {
+    pub fn from_json(json_str: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json_str)
+    }
+
+    pub fn authority_count(&self) -> usize {
+        self.peers.len() + 1
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold.unwrap_or(self.authority_count() / 2 + 1)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.threshold() == 0 || self.threshold() > self.authority_count() {
+            return Err(anyhow::anyhow!(
+                "threshold must be between 1 and the number of authorities ({})",
+                self.authority_count()
+            ));
+        }
+        if self.consensus_interval_secs == 0 || 2 * self.vote_delay_secs >= self.consensus_interval_secs {
+            return Err(anyhow::anyhow!("consensus_interval_secs must be longer than two vote delays"));
+        }
+        if self.consensus_valid_secs < self.consensus_interval_secs {
+            return Err(anyhow::anyhow!("consensus_valid_secs must be at least consensus_interval_secs"));
+        }
+        if self.reachability_interval_secs == 0 {
+            return Err(anyhow::anyhow!("reachability_interval_secs must be at least 1"));
+        }
+        if self.max_new_nodes_per_source == 0 {
+            return Err(anyhow::anyhow!("max_new_nodes_per_source must be at least 1"));
+        }
+        Ok(())
+    }
+}
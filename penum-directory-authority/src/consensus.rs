@@ -0,0 +1,156 @@
+use penum_protocol::directory::{Directory, NodeDescriptor, NodeFlag};
+use std::collections::{BTreeMap, HashMap};
+
+// Flags an authority may vote on, in the order they appear in the consensus
+const VOTED_FLAGS: [NodeFlag; 5] = [
+    NodeFlag::Running,
+    NodeFlag::Valid,
+    NodeFlag::Stable,
+    NodeFlag::Fast,
+    NodeFlag::Guard,
+];
+
+// Combine the votes of one period into the consensus. Every authority that
+// received the same votes computes byte-for-byte the same directory, which
+// is what lets their signatures add up.
+//
+// A node is listed if a majority of all authorities (not only of those whose
+// vote arrived) listed it, so a minority of authorities cannot add nodes. It
+// gets the description most of those votes agree on, every flag a majority
+// of them assigned, and the low median of their bandwidths, so a minority
+// cannot inflate its weight.
+pub fn combine(votes: &[Directory], authority_count: usize, published: u64, valid_until: u64) -> Directory {
+    let mut listings: BTreeMap<String, Vec<&NodeDescriptor>> = BTreeMap::new();
+    for vote in votes {
+        let mut seen = Vec::new();
+        for node in &vote.nodes {
+            // One listing per node and vote
+            if seen.contains(&&node.identity_key) {
+                continue;
+            }
+            seen.push(&node.identity_key);
+            listings.entry(node.identity_key.clone()).or_default().push(node);
+        }
+    }
+
+    let mut nodes = Vec::new();
+    for listed in listings.into_values() {
+        if listed.len() * 2 <= authority_count {
+            continue;
+        }
+
+        // Ties go to the smallest encoding so every authority picks the same
+        let mut descriptions: HashMap<String, (usize, &NodeDescriptor)> = HashMap::new();
+        for node in &listed {
+            let mut description = (*node).clone();
+            description.flags.clear();
+            description.bandwidth = 0;
+            let key = serde_json::to_string(&description).unwrap_or_default();
+            descriptions.entry(key).or_insert((0, node)).0 += 1;
+        }
+        let Some((_, (_, chosen))) = descriptions
+            .into_iter()
+            .max_by(|(a_key, (a_count, _)), (b_key, (b_count, _))| a_count.cmp(b_count).then(b_key.cmp(a_key)))
+        else {
+            continue;
+        };
+
+        let mut bandwidths: Vec<u64> = listed.iter().map(|listing| listing.bandwidth).collect();
+        bandwidths.sort_unstable();
+
+        let mut node = chosen.clone();
+        node.bandwidth = bandwidths[(bandwidths.len() - 1) / 2];
+        node.flags = VOTED_FLAGS
+            .into_iter()
+            .filter(|flag| listed.iter().filter(|listing| listing.has_flag(*flag)).count() * 2 > listed.len())
+            .collect();
+        nodes.push(node);
+    }
+
+    Directory {
+        published,
+        valid_until,
+        nodes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use penum_protocol::directory::NodeRole;
+
+    fn node(seed: u8, bandwidth: u64, flags: &[NodeFlag]) -> NodeDescriptor {
+        NodeDescriptor {
+            nickname: format!("node{}", seed),
+            role: NodeRole::Relay,
+            address: ([51, 15, 0, seed], 9001).into(),
+            mix_address: None,
+            identity_key: hex::encode([seed; 32]),
+            bandwidth,
+            family: Vec::new(),
+            flags: flags.to_vec(),
+        }
+    }
+
+    fn vote(nodes: Vec<NodeDescriptor>) -> Directory {
+        Directory { published: 0, valid_until: 0, nodes }
+    }
+
+    fn listed(consensus: &Directory) -> Vec<String> {
+        consensus.nodes.iter().map(|node| node.nickname.clone()).collect()
+    }
+
+    #[test]
+    fn needs_a_majority_of_all_authorities() {
+        let votes = [vote(vec![node(1, 100, &[]), node(2, 100, &[])]), vote(vec![node(1, 100, &[])])];
+        assert_eq!(listed(&combine(&votes, 3, 0, 0)), ["node1"]);
+        // Two of four is no majority, even if only those two votes arrived
+        assert!(combine(&votes, 4, 0, 0).nodes.is_empty());
+        assert!(combine(&votes[1..], 3, 0, 0).nodes.is_empty());
+    }
+
+    #[test]
+    fn duplicate_listings_count_once() {
+        let votes = [vote(vec![node(1, 100, &[]), node(1, 100, &[]), node(1, 100, &[])])];
+        assert!(combine(&votes, 3, 0, 0).nodes.is_empty());
+        let votes = [votes[0].clone(), vote(vec![node(1, 900, &[])])];
+        let consensus = combine(&votes, 3, 0, 0);
+        assert_eq!(consensus.nodes.len(), 1);
+        assert_eq!(consensus.nodes[0].bandwidth, 100);
+    }
+
+    #[test]
+    fn description_ties_are_broken_the_same_everywhere() {
+        let mut renamed = node(1, 100, &[]);
+        renamed.nickname = "another".to_string();
+        let votes = [vote(vec![node(1, 100, &[])]), vote(vec![renamed.clone()])];
+        let reversed = [votes[1].clone(), votes[0].clone()];
+        let chosen = combine(&votes, 2, 0, 0);
+        assert_eq!(listed(&chosen), ["another"]);
+        assert_eq!(serde_json::to_string(&chosen).unwrap(), serde_json::to_string(&combine(&reversed, 2, 0, 0)).unwrap());
+
+        // The description most votes agree on wins over the smaller one
+        let votes = [votes[0].clone(), votes[0].clone(), vote(vec![renamed])];
+        assert_eq!(listed(&combine(&votes, 3, 0, 0)), ["node1"]);
+    }
+
+    #[test]
+    fn bandwidth_is_the_low_median() {
+        let votes: Vec<Directory> = [100, 300, 1_000_000, 200].into_iter().map(|bandwidth| vote(vec![node(1, bandwidth, &[])])).collect();
+        assert_eq!(combine(&votes, 4, 0, 0).nodes[0].bandwidth, 200);
+        assert_eq!(combine(&votes[..3], 3, 0, 0).nodes[0].bandwidth, 300);
+    }
+
+    #[test]
+    fn flags_need_a_majority_of_the_listing_votes() {
+        use NodeFlag::*;
+        let votes = [
+            vote(vec![node(1, 100, &[Running, Valid, Fast, Guard, Unknown])]),
+            vote(vec![node(1, 100, &[Guard, Valid, Running, Unknown])]),
+            vote(vec![node(1, 100, &[Running, Fast, Stable])]),
+            vote(Vec::new()),
+        ];
+        // Fast and Guard have two of three listings; Stable one; Unknown is never voted
+        assert_eq!(combine(&votes, 4, 0, 0).nodes[0].flags, [Running, Valid, Fast, Guard]);
+    }
+}
@@ -0,0 +1,42 @@
+use penum_protocol::directory::SigningKey;
+use rand::{thread_rng, RngCore};
+use std::fs;
+use std::path::Path;
+
+// Load the authority's signing key from `path`, creating a new one on first start.
+// Clients and peer authorities pin the public half.
+pub fn load_or_generate(path: &str) -> anyhow::Result<SigningKey> {
+    if Path::new(path).exists() {
+        let key_hex = fs::read_to_string(path)?;
+        let key_bytes: [u8; 32] = hex::decode(key_hex.trim())
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| anyhow::anyhow!("Invalid signing key file: {}", path))?;
+        return Ok(SigningKey::from_bytes(&key_bytes));
+    }
+
+    let mut seed = [0u8; 32];
+    thread_rng().fill_bytes(&mut seed);
+    write_secret_file(path, &hex::encode(seed))?;
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+#[cfg(unix)]
+fn write_secret_file(path: &str, contents: &str) -> anyhow::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn write_secret_file(path: &str, contents: &str) -> anyhow::Result<()> {
+    fs::write(path, contents)?;
+    Ok(())
+}
@@ -0,0 +1,44 @@
+mod authority;
+mod config;
+mod consensus;
+mod keys;
+mod registry;
+mod server;
+
+use authority::Authority;
+use config::AuthorityConfig;
+use std::fs;
+use std::sync::Arc;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    // Try to load from config.json, fall back to default if not found
+    let config = match fs::read_to_string("config.json") {
+        Ok(config_str) => AuthorityConfig::from_json(&config_str)?,
+        Err(_) => {
+            println!("⚠️  config.json not found, using default configuration");
+            AuthorityConfig::default()
+        }
+    };
+
+    let key = keys::load_or_generate(&config.signing_key_path)?;
+
+    println!("🚀 Starting Penum Directory Authority");
+    println!("   Listen:       {}:{}", config.listen_addr, config.listen_port);
+    println!("   Signing Key:  {}", hex::encode(key.verifying_key().as_bytes()));
+    println!(
+        "   Consensus:    every {}s, {} of {} authority signatures required",
+        config.consensus_interval_secs,
+        config.threshold(),
+        config.authority_count()
+    );
+    println!();
+
+    let listen_addr = config.listen_addr.clone();
+    let listen_port = config.listen_port;
+    let authority = Arc::new(Authority::new(config, key)?);
+    tokio::spawn(authority.clone().run_reachability_tests());
+    tokio::spawn(authority.clone().run_consensus());
+
+    server::start_server(&listen_addr, listen_port, authority).await
+}
@@ -0,0 +1,327 @@
+use crate::config::AuthorityConfig;
+use penum_protocol::cell::{Packet, PACKET_SIZE};
+use penum_protocol::directory::{
+    is_public_address, NodeDescriptor, NodeFlag, NodeRole, SignedDescriptor, MAX_CLOCK_SKEW_SECS,
+};
+use penum_protocol::handshake::{
+    ClientHandshake, CAP_KEY_CONFIRMATION, HANDSHAKE_REPLY_LEN, KEY_CONFIRMATION_LEN, MAX_PROTOCOL_VERSION,
+};
+use penum_protocol::PublicKey;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+// Most nodes one authority keeps track of
+const MAX_NODES: usize = 10_000;
+
+// Window for `max_new_nodes_per_source`. Every node registered is tested, so
+// the limit also bounds the connections anyone can make the authority open.
+const NEW_NODE_WINDOW_SECS: u64 = 3600;
+
+// How long a reachability test may take
+const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct NodeRecord {
+    node: NodeDescriptor,
+    published: u64,  // Of the newest descriptor, which doubles as the last heartbeat
+    reachable_since: Option<u64>,  // Start of the current run of passed tests
+}
+
+// The nodes that described themselves to this authority, keyed by identity key
+#[derive(Default)]
+pub struct Registry {
+    nodes: HashMap<String, NodeRecord>,
+    registrations: HashMap<IpAddr, Vec<u64>>,  // When each source registered its recent new nodes
+}
+
+impl Registry {
+    // Accept a descriptor if it is signed with the identity key it lists and
+    // newer than the one we have for that key. `source` is where it came from.
+    pub fn accept(
+        &mut self,
+        signed: &SignedDescriptor,
+        source: IpAddr,
+        now: u64,
+        config: &AuthorityConfig,
+    ) -> anyhow::Result<()> {
+        let descriptor = signed.verify()?;
+        if descriptor.published > now + MAX_CLOCK_SKEW_SECS || descriptor.published + MAX_CLOCK_SKEW_SECS < now {
+            return Err(anyhow::anyhow!("Descriptor timestamp too far from the authority's clock"));
+        }
+
+        // Clients and relays connect wherever a listed node says it is, so a
+        // private address would point them into someone's LAN or at
+        // themselves, and testing it would do the same to the authority
+        if !config.allow_private_addresses {
+            for address in std::iter::once(descriptor.node.address).chain(descriptor.node.mix_address) {
+                if !is_public_address(address.ip()) {
+                    return Err(anyhow::anyhow!("Node address {} is not a public address", address));
+                }
+            }
+        }
+
+        let mut node = descriptor.node;
+        node.flags.clear();
+        node.identity_key = node.identity_key.to_lowercase();
+        let key = node.identity_key.clone();
+        match self.nodes.get_mut(&key) {
+            Some(record) => {
+                if descriptor.published <= record.published {
+                    return Err(anyhow::anyhow!("Descriptor is not newer than the one we have"));
+                }
+                // A node that moved has to pass the test at its new address
+                if record.node.address != node.address {
+                    record.reachable_since = None;
+                }
+                record.node = node;
+                record.published = descriptor.published;
+            }
+            None => {
+                if self.nodes.len() >= MAX_NODES {
+                    return Err(anyhow::anyhow!("Too many nodes"));
+                }
+                let registered = self.registrations.entry(source_prefix(source)).or_default();
+                registered.retain(|&at| at + NEW_NODE_WINDOW_SECS > now);
+                if registered.len() >= config.max_new_nodes_per_source {
+                    return Err(anyhow::anyhow!("Too many new nodes from {}, try again later", source));
+                }
+                registered.push(now);
+                self.nodes.insert(
+                    key,
+                    NodeRecord {
+                        node,
+                        published: descriptor.published,
+                        reachable_since: None,
+                    },
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // Nodes to test, with the address and identity key they claim
+    pub fn test_targets(&self) -> Vec<(String, SocketAddr, PublicKey)> {
+        self.nodes
+            .iter()
+            .filter_map(|(key, record)| Some((key.clone(), record.node.address, record.node.identity().ok()?)))
+            .collect()
+    }
+
+    pub fn record_test(&mut self, key: &str, address: SocketAddr, passed: bool, now: u64) {
+        let Some(record) = self.nodes.get_mut(key) else {
+            return;
+        };
+        if record.node.address != address {
+            return; // Moved while the test ran
+        }
+        record.reachable_since = match (passed, record.reachable_since) {
+            (true, Some(since)) => Some(since),
+            (true, None) => Some(now),
+            (false, _) => None,
+        };
+    }
+
+    // Forget nodes that stopped sending heartbeats
+    pub fn expire(&mut self, now: u64, heartbeat_timeout_secs: u64) {
+        self.nodes
+            .retain(|_, record| record.published + heartbeat_timeout_secs >= now);
+        self.registrations.retain(|_, registered| {
+            registered.retain(|&at| at + NEW_NODE_WINDOW_SECS > now);
+            !registered.is_empty()
+        });
+    }
+
+    // This authority's view: every node with a recent heartbeat that passed
+    // its last reachability test
+    pub fn running_nodes(&self, now: u64, config: &AuthorityConfig) -> Vec<NodeDescriptor> {
+        let mut nodes: Vec<NodeDescriptor> = self
+            .nodes
+            .values()
+            .filter(|record| record.published + config.heartbeat_timeout_secs >= now)
+            .filter_map(|record| {
+                let since = record.reachable_since?;
+                let mut node = record.node.clone();
+                // Declared capacity is unverified, so one node cannot claim
+                // more than the cap and attract most circuits
+                node.bandwidth = node.bandwidth.min(config.max_bandwidth_kbps);
+                node.flags = vec![NodeFlag::Running, NodeFlag::Valid];
+                if node.bandwidth >= config.fast_bandwidth_kbps {
+                    node.flags.push(NodeFlag::Fast);
+                }
+                if since + config.stable_after_secs <= now {
+                    node.flags.push(NodeFlag::Stable);
+                    // Clients keep their entry relays for months, so only
+                    // relays with a record of staying up are offered as guards
+                    if node.role == NodeRole::Relay {
+                        node.flags.push(NodeFlag::Guard);
+                    }
+                }
+                Some(node)
+            })
+            .collect();
+        nodes.sort_by(|a, b| a.identity_key.cmp(&b.identity_key));
+        nodes
+    }
+}
+
+// IPv6 sources count per /64, which is what one host usually gets
+fn source_prefix(source: IpAddr) -> IpAddr {
+    match source {
+        IpAddr::V4(_) => source,
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(mapped) => IpAddr::V4(mapped),
+            None => IpAddr::V6(Ipv6Addr::from(ip.to_bits() & !0u128 << 64)),
+        },
+    }
+}
+
+// Connect to a node and check that it proves, in the handshake, that it holds
+// the identity key it listed. Naming the key in its hello is not enough: any
+// server at the claimed address could do that.
+pub async fn test_reachability(address: SocketAddr, identity: PublicKey) -> bool {
+    tokio::time::timeout(TEST_TIMEOUT, handshake_identity(address, identity))
+        .await
+        .ok()
+        .and_then(Result::ok)
+        .is_some_and(|answered| answered == identity)
+}
+
+async fn handshake_identity(address: SocketAddr, identity: PublicKey) -> anyhow::Result<PublicKey> {
+    let mut stream = TcpStream::connect(address).await?;
+
+    // Plain X25519 is enough here: no ML-KEM cells follow the node hello
+    let mut handshake = ClientHandshake::new(MAX_PROTOCOL_VERSION, CAP_KEY_CONFIRMATION, Some(&identity));
+    stream.write_all(&Packet::padded(handshake.hello())).await?;
+
+    let mut reply_cell = [0u8; PACKET_SIZE];
+    stream.read_exact(&mut reply_cell).await?;
+    let mut reply = [0u8; HANDSHAKE_REPLY_LEN];
+    reply.copy_from_slice(&reply_cell[..HANDSHAKE_REPLY_LEN]);
+    handshake.read_reply(&reply)?;
+    let mut confirmation = [0u8; KEY_CONFIRMATION_LEN];
+    confirmation.copy_from_slice(&reply_cell[HANDSHAKE_REPLY_LEN..HANDSHAKE_REPLY_LEN + KEY_CONFIRMATION_LEN]);
+    handshake.confirm_identity(&confirmation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use penum_protocol::directory::Descriptor;
+    use penum_protocol::StaticSecret;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn config() -> AuthorityConfig {
+        AuthorityConfig::default()
+    }
+
+    fn descriptor(seed: u8, published: u64) -> SignedDescriptor {
+        let identity = StaticSecret::from([seed; 32]);
+        let node = NodeDescriptor {
+            nickname: format!("node{}", seed),
+            role: NodeRole::Relay,
+            address: SocketAddr::from(([51, 15, 0, seed], 9001)),
+            mix_address: None,
+            identity_key: hex::encode(PublicKey::from(&identity).as_bytes()),
+            bandwidth: 100,
+            family: Vec::new(),
+            flags: Vec::new(),
+        };
+        SignedDescriptor::sign(&Descriptor { published, node }, &identity).unwrap()
+    }
+
+    fn modified(seed: u8, published: u64, modify: impl FnOnce(&mut NodeDescriptor)) -> SignedDescriptor {
+        let mut node = descriptor(seed, published).verify().unwrap().node;
+        modify(&mut node);
+        SignedDescriptor::sign(&Descriptor { published, node }, &StaticSecret::from([seed; 32])).unwrap()
+    }
+
+    #[test]
+    fn limits_new_nodes_per_source() {
+        let mut registry = Registry::default();
+        let source: IpAddr = "203.0.113.9".parse().unwrap();
+        for seed in 1..=config().max_new_nodes_per_source as u8 {
+            registry.accept(&descriptor(seed, NOW), source, NOW, &config()).unwrap();
+        }
+        assert!(registry.accept(&descriptor(100, NOW), source, NOW, &config()).is_err());
+
+        // Known nodes may still send heartbeats, and other sources register
+        registry.accept(&descriptor(1, NOW + 60), source, NOW + 60, &config()).unwrap();
+        registry.accept(&descriptor(100, NOW), "203.0.113.10".parse().unwrap(), NOW, &config()).unwrap();
+
+        // Until the window has passed
+        let later = NOW + NEW_NODE_WINDOW_SECS;
+        registry.expire(later, NEW_NODE_WINDOW_SECS);
+        registry.accept(&descriptor(101, later), source, later, &config()).unwrap();
+    }
+
+    #[test]
+    fn ipv6_sources_count_per_64() {
+        let mut registry = Registry::default();
+        for seed in 1..=config().max_new_nodes_per_source as u8 {
+            let source = format!("2001:db8:1:2::{}", seed).parse().unwrap();
+            registry.accept(&descriptor(seed, NOW), source, NOW, &config()).unwrap();
+        }
+        assert!(registry.accept(&descriptor(100, NOW), "2001:db8:1:2:ffff::1".parse().unwrap(), NOW, &config()).is_err());
+        registry.accept(&descriptor(100, NOW), "2001:db8:1:3::1".parse().unwrap(), NOW, &config()).unwrap();
+    }
+
+    #[test]
+    fn refuses_descriptors_not_signed_by_their_identity() {
+        let mut registry = Registry::default();
+        let mut forged = descriptor(1, NOW);
+        forged.signing_key = descriptor(2, NOW).signing_key;
+        assert!(registry.accept(&forged, "203.0.113.9".parse().unwrap(), NOW, &config()).is_err());
+    }
+
+    #[test]
+    fn refuses_private_addresses_unless_allowed() {
+        let source: IpAddr = "203.0.113.9".parse().unwrap();
+        let at = |address: &str, mix_address: Option<&str>| {
+            modified(1, NOW, |node| {
+                node.address = address.parse().unwrap();
+                node.mix_address = mix_address.map(|address| address.parse().unwrap());
+            })
+        };
+        let mut registry = Registry::default();
+        for private in ["127.0.0.1:9001", "10.1.2.3:9001", "192.168.1.1:9001", "[fd00::1]:9001", "[::ffff:127.0.0.1]:9001"] {
+            assert!(registry.accept(&at(private, None), source, NOW, &config()).is_err(), "{}", private);
+        }
+        assert!(registry.accept(&at("51.15.0.1:9001", Some("127.0.0.1:9101")), source, NOW, &config()).is_err());
+        registry.accept(&at("51.15.0.1:9001", Some("51.15.0.1:9101")), source, NOW, &config()).unwrap();
+
+        let test_network = AuthorityConfig { allow_private_addresses: true, ..config() };
+        let mut registry = Registry::default();
+        registry.accept(&at("127.0.0.1:9001", None), source, NOW, &test_network).unwrap();
+    }
+
+    #[test]
+    fn flags_follow_bandwidth_and_uptime() {
+        use NodeFlag::*;
+        let config = AuthorityConfig { fast_bandwidth_kbps: 100, ..config() };
+        let source: IpAddr = "203.0.113.9".parse().unwrap();
+        let mut registry = Registry::default();
+        let announce = |registry: &mut Registry, now: u64| {
+            registry.accept(&descriptor(1, now), source, now, &config).unwrap();
+            registry.accept(&modified(2, now, |node| node.bandwidth = 99), source, now, &config).unwrap();
+        };
+        let flags = |registry: &Registry, now: u64, nickname: &str| {
+            registry.running_nodes(now, &config).into_iter().find(|node| node.nickname == nickname).unwrap().flags
+        };
+
+        announce(&mut registry, NOW);
+        assert!(registry.running_nodes(NOW, &config).is_empty());
+        for (key, address, _) in registry.test_targets() {
+            registry.record_test(&key, address, true, NOW);
+        }
+        assert_eq!(flags(&registry, NOW, "node1"), [Running, Valid, Fast]);
+        assert_eq!(flags(&registry, NOW, "node2"), [Running, Valid]);
+
+        let later = NOW + config.stable_after_secs;
+        announce(&mut registry, later);
+        assert_eq!(flags(&registry, later, "node1"), [Running, Valid, Fast, Stable, Guard]);
+        assert_eq!(flags(&registry, later, "node2"), [Running, Valid, Stable, Guard]);
+    }
+}
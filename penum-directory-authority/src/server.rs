@@ -0,0 +1,67 @@
+use crate::authority::Authority;
+use penum_protocol::directory::{SignedDescriptor, SignedDirectory};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{Filter, Reply};
+
+// Largest descriptor accepted from a node
+const MAX_DESCRIPTOR_LEN: u64 = 16 * 1024;
+
+// Nodes POST their signed descriptors to /descriptor. Clients and mirrors GET
+// the consensus from /directory, peer authorities GET /vote and /pending.
+pub async fn start_server(listen_addr: &str, listen_port: u16, authority: Arc<Authority>) -> anyhow::Result<()> {
+    let ip: IpAddr = listen_addr.parse()?;
+    let authority = warp::any().map(move || authority.clone());
+
+    let descriptor = warp::post()
+        .and(warp::path("descriptor"))
+        .and(warp::path::end())
+        .and(warp::body::content_length_limit(MAX_DESCRIPTOR_LEN))
+        .and(warp::body::json())
+        .and(warp::addr::remote())
+        .and(authority.clone())
+        .map(|descriptor: SignedDescriptor, source: Option<SocketAddr>, authority: Arc<Authority>| {
+            let Some(source) = source else {
+                return StatusCode::BAD_REQUEST.into_response();
+            };
+            match authority.accept_descriptor(&descriptor, source.ip()) {
+                Ok(()) => StatusCode::NO_CONTENT.into_response(),
+                Err(e) => warp::reply::with_status(format!("{:#}", e), StatusCode::BAD_REQUEST).into_response(),
+            }
+        });
+
+    let directory = warp::get()
+        .and(warp::path("directory"))
+        .and(warp::path::end())
+        .and(authority.clone())
+        .map(|authority: Arc<Authority>| document(authority.published()));
+
+    let vote = warp::get()
+        .and(warp::path("vote"))
+        .and(warp::path::end())
+        .and(authority.clone())
+        .map(|authority: Arc<Authority>| document(authority.vote()));
+
+    let pending = warp::get()
+        .and(warp::path("pending"))
+        .and(warp::path::end())
+        .and(authority)
+        .map(|authority: Arc<Authority>| document(authority.pending()));
+
+    println!("🏛️  Directory authority listening on http://{}:{}", listen_addr, listen_port);
+
+    warp::serve(descriptor.or(directory).or(vote).or(pending))
+        .run((ip, listen_port))
+        .await;
+
+    Ok(())
+}
+
+fn document(document: Option<SignedDirectory>) -> Response {
+    match document {
+        Some(document) => warp::reply::json(&document).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
@@ -0,0 +1,36 @@
+use chacha20poly1305::{AeadInPlace, ChaCha20Poly1305, KeyInit, Nonce};
+
+// Each key is used in one direction only, so the nonce is just the number of
+// cells already sealed under it: 4 zero bytes || counter (8, big-endian)
+fn counter_nonce(counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce.into()
+}
+
+pub(crate) fn encrypt_in_place(
+    key: &[u8; 32],
+    counter: u64,
+    aad: &[u8],
+    buffer: &mut [u8],
+) -> anyhow::Result<[u8; 16]> {
+    let cipher = ChaCha20Poly1305::new(key.into());
+    let tag = cipher
+        .encrypt_in_place_detached(&counter_nonce(counter), aad, buffer)
+        .map_err(|_| anyhow::anyhow!("Encryption failed"))?;
+    Ok(tag.into())
+}
+
+pub(crate) fn decrypt_in_place(
+    key: &[u8; 32],
+    counter: u64,
+    aad: &[u8],
+    buffer: &mut [u8],
+    tag: &[u8; 16],
+) -> anyhow::Result<()> {
+    let cipher = ChaCha20Poly1305::new(key.into());
+    cipher
+        .decrypt_in_place_detached(&counter_nonce(counter), aad, buffer, tag.into())
+        .map_err(|_| anyhow::anyhow!("Decryption failed"))?;
+    Ok(())
+}
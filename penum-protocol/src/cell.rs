@@ -0,0 +1,461 @@
+//! Fixed-size cells.
+//!
+//! Every cell on every link is exactly [`PACKET_SIZE`] bytes:
+//!
+//! ```text
+//! header (32, authenticated as AAD) | payload (976) | AEAD tag (16)
+//! ```
+//!
+//! Header layout (big-endian):
+//!
+//! ```text
+//! version (1) | cell type (1) | stream id (2) | sequence (4) | payload length (2) | flags (1) | timestamp (8) | reserved (13)
+//! ```
+//!
+//! The timestamp is the sender's clock (seconds since the Unix epoch) when the
+//! cell was built. Gateways use it to reject stale and replayed handshakes and
+//! requests.
+//!
+//! Unused payload bytes are random. End-to-end cells between client and
+//! gateway are sealed with ChaCha20-Poly1305 by a [`CellSealer`] and opened
+//! by a [`CellOpener`]; handshake cells exchanged with relays are only encoded
+//! and rely on the onion layer for confidentiality.
+
+use crate::aead::{decrypt_in_place, encrypt_in_place};
+use rand::RngCore;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Size of every cell on the wire.
+pub const PACKET_SIZE: usize = 1024;
+/// Size of the cell header.
+pub const HEADER_LEN: usize = 32;
+/// Size of the AEAD tag at the end of the cell.
+pub const AEAD_TAG_LEN: usize = 16;
+/// Payload capacity of one cell.
+pub const PAYLOAD_LEN: usize = PACKET_SIZE - HEADER_LEN - AEAD_TAG_LEN;
+
+/// Cell header format version.
+pub const CELL_VERSION: u8 = 1;
+
+/// Set on the last cell of a message.
+pub const FLAG_END: u8 = 0x01;
+
+/// Largest request or response that may be split across cells.
+pub const MAX_MESSAGE_LEN: usize = 1024 * 1024;
+
+/// Per-stream flow control: a sender may have at most `STREAM_WINDOW` data
+/// cells unacknowledged, and the receiver returns a SENDME every
+/// [`SENDME_INCREMENT`] cells.
+pub const STREAM_WINDOW: usize = 64;
+/// Data cells acknowledged by one SENDME.
+pub const SENDME_INCREMENT: usize = 32;
+
+const TYPE_OFFSET: usize = 1;
+const STREAM_ID_OFFSET: usize = 2;
+const SEQ_OFFSET: usize = 4;
+const PAYLOAD_LEN_OFFSET: usize = 8;
+const FLAGS_OFFSET: usize = 10;
+const TIMESTAMP_OFFSET: usize = 11;
+const RESERVED_OFFSET: usize = 19;
+
+/// Current time in seconds since the Unix epoch, as used in cell timestamps.
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+/// Kind of cell, carried in the header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CellType {
+    /// Circuit extension request or reply.
+    Handshake = 1,
+    /// Part of a request or response.
+    Data = 2,
+    /// The gateway could not answer the request on this stream.
+    Error = 3,
+    /// Ignored by the receiver.
+    Padding = 4,
+    /// Flow control acknowledgement for a stream.
+    Sendme = 5,
+}
+
+impl CellType {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(CellType::Handshake),
+            2 => Some(CellType::Data),
+            3 => Some(CellType::Error),
+            4 => Some(CellType::Padding),
+            5 => Some(CellType::Sendme),
+            _ => None,
+        }
+    }
+}
+
+/// Decoded cell header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CellHeader {
+    /// Always [`CELL_VERSION`].
+    pub version: u8,
+    /// Kind of cell.
+    pub cell_type: CellType,
+    /// Stream the cell belongs to. Never reused within a tunnel.
+    pub stream_id: u16,
+    /// Position of the cell within its message, starting at 0.
+    pub seq: u32,
+    /// Number of meaningful payload bytes.
+    pub payload_len: u16,
+    /// Bit flags such as [`FLAG_END`].
+    pub flags: u8,
+    /// Seconds since the Unix epoch when the cell was built.
+    pub timestamp: u64,
+}
+
+impl CellHeader {
+    /// Header with no flags set, stamped with the current time.
+    pub fn new(cell_type: CellType, stream_id: u16, seq: u32, payload_len: usize) -> Self {
+        Self {
+            version: CELL_VERSION,
+            cell_type,
+            stream_id,
+            seq,
+            payload_len: payload_len as u16,
+            flags: 0,
+            timestamp: unix_time(),
+        }
+    }
+
+    /// Replace the flags.
+    pub fn with_flags(mut self, flags: u8) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Whether this is the last cell of a message.
+    pub fn is_end(&self) -> bool {
+        self.flags & FLAG_END != 0
+    }
+
+    /// Encode for the wire.
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut header = [0u8; HEADER_LEN];
+        header[0] = self.version;
+        header[TYPE_OFFSET] = self.cell_type as u8;
+        header[STREAM_ID_OFFSET..SEQ_OFFSET].copy_from_slice(&self.stream_id.to_be_bytes());
+        header[SEQ_OFFSET..PAYLOAD_LEN_OFFSET].copy_from_slice(&self.seq.to_be_bytes());
+        header[PAYLOAD_LEN_OFFSET..FLAGS_OFFSET].copy_from_slice(&self.payload_len.to_be_bytes());
+        header[FLAGS_OFFSET] = self.flags;
+        header[TIMESTAMP_OFFSET..RESERVED_OFFSET].copy_from_slice(&self.timestamp.to_be_bytes());
+        header
+    }
+
+    /// Decode and validate version, type and payload length.
+    pub fn decode(header: &[u8]) -> anyhow::Result<Self> {
+        if header.len() < HEADER_LEN {
+            return Err(anyhow::anyhow!("Truncated cell header"));
+        }
+        if header[0] != CELL_VERSION {
+            return Err(anyhow::anyhow!("Unsupported cell version: {}", header[0]));
+        }
+        let cell_type = CellType::from_u8(header[TYPE_OFFSET])
+            .ok_or_else(|| anyhow::anyhow!("Unknown cell type: {}", header[TYPE_OFFSET]))?;
+        let stream_id = u16::from_be_bytes([header[STREAM_ID_OFFSET], header[STREAM_ID_OFFSET + 1]]);
+        let seq = u32::from_be_bytes(header[SEQ_OFFSET..PAYLOAD_LEN_OFFSET].try_into()?);
+        let payload_len = u16::from_be_bytes([header[PAYLOAD_LEN_OFFSET], header[PAYLOAD_LEN_OFFSET + 1]]);
+        let timestamp = u64::from_be_bytes(header[TIMESTAMP_OFFSET..RESERVED_OFFSET].try_into()?);
+        if payload_len as usize > PAYLOAD_LEN {
+            return Err(anyhow::anyhow!("Invalid payload length: {}", payload_len));
+        }
+
+        Ok(Self {
+            version: header[0],
+            cell_type,
+            stream_id,
+            seq,
+            payload_len,
+            flags: header[FLAGS_OFFSET],
+            timestamp,
+        })
+    }
+}
+
+/// Cell codec.
+pub struct Packet;
+
+impl Packet {
+    /// A cell of random bytes.
+    pub fn new_random() -> [u8; PACKET_SIZE] {
+        let mut data = [0u8; PACKET_SIZE];
+        rand::thread_rng().fill_bytes(&mut data);
+        data
+    }
+
+    /// A cell starting with `prefix`, padded with random bytes.
+    pub fn padded(prefix: &[u8]) -> [u8; PACKET_SIZE] {
+        let mut cell = Self::new_random();
+        cell[..prefix.len()].copy_from_slice(prefix);
+        cell
+    }
+
+    /// Lay out a plaintext cell: header | payload | random padding | tag space.
+    pub fn encode(header: &CellHeader, payload: &[u8]) -> anyhow::Result<[u8; PACKET_SIZE]> {
+        if payload.len() > PAYLOAD_LEN || payload.len() != header.payload_len as usize {
+            return Err(anyhow::anyhow!("Invalid payload length: {}", payload.len()));
+        }
+
+        // Cryptographically secure random padding fills everything we don't use
+        let mut cell = Self::new_random();
+        cell[..HEADER_LEN].copy_from_slice(&header.encode());
+        cell[HEADER_LEN..HEADER_LEN + payload.len()].copy_from_slice(payload);
+        Ok(cell)
+    }
+
+    /// Read the header and the exact payload back out of a plaintext cell.
+    pub fn decode(cell: &[u8; PACKET_SIZE]) -> anyhow::Result<(CellHeader, &[u8])> {
+        let header = CellHeader::decode(&cell[..HEADER_LEN])?;
+        let payload = &cell[HEADER_LEN..HEADER_LEN + header.payload_len as usize];
+        Ok((header, payload))
+    }
+
+    /// Split a message into plaintext cells with consecutive sequence numbers.
+    /// Only the last cell carries [`FLAG_END`]. The cells are sealed one by one
+    /// with a [`CellSealer`] in the order they are sent.
+    pub fn split_message(
+        cell_type: CellType,
+        stream_id: u16,
+        message: &[u8],
+    ) -> anyhow::Result<Vec<(CellHeader, Vec<u8>)>> {
+        if message.len() > MAX_MESSAGE_LEN {
+            return Err(anyhow::anyhow!("Message too large: {} bytes (max {})", message.len(), MAX_MESSAGE_LEN));
+        }
+
+        // An empty message still needs one cell to carry FLAG_END
+        let chunk_count = message.len().div_ceil(PAYLOAD_LEN).max(1);
+        let mut cells = Vec::with_capacity(chunk_count);
+        for seq in 0..chunk_count {
+            let chunk = &message[seq * PAYLOAD_LEN..message.len().min((seq + 1) * PAYLOAD_LEN)];
+            let mut header = CellHeader::new(cell_type, stream_id, seq as u32, chunk.len());
+            if seq + 1 == chunk_count {
+                header = header.with_flags(FLAG_END);
+            }
+            cells.push((header, chunk.to_vec()));
+        }
+        Ok(cells)
+    }
+}
+
+/// Seals end-to-end cells in one direction of a session.
+///
+/// The nonce is a counter of cells sealed so far, so cells must be sent in the
+/// order they are sealed and the receiving [`CellOpener`] must see every one.
+pub struct CellSealer {
+    key: [u8; 32],
+    counter: u64,
+}
+
+impl CellSealer {
+    /// Sealer starting at counter 0. `key` must only ever be used by this sealer.
+    pub fn new(key: [u8; 32]) -> Self {
+        Self { key, counter: 0 }
+    }
+
+    /// Encode and encrypt the next cell; the header is authenticated as AAD.
+    pub fn seal(&mut self, header: &CellHeader, payload: &[u8]) -> anyhow::Result<[u8; PACKET_SIZE]> {
+        if self.counter == u64::MAX {
+            return Err(anyhow::anyhow!("Session cell counter exhausted"));
+        }
+
+        let mut cell = Packet::encode(header, payload)?;
+        let (header_bytes, payload_and_tag) = cell.split_at_mut(HEADER_LEN);
+        let (body, tag_space) = payload_and_tag.split_at_mut(PAYLOAD_LEN);
+        let tag = encrypt_in_place(&self.key, self.counter, header_bytes, body)?;
+        tag_space.copy_from_slice(&tag);
+        self.counter += 1;
+        Ok(cell)
+    }
+}
+
+/// Opens end-to-end cells in one direction of a session, in the order they
+/// were sealed. A dropped, replayed or reordered cell fails to open, after
+/// which the session should be closed.
+pub struct CellOpener {
+    key: [u8; 32],
+    counter: u64,
+}
+
+impl CellOpener {
+    /// Opener starting at counter 0, matching a fresh [`CellSealer`] on the other side.
+    pub fn new(key: [u8; 32]) -> Self {
+        Self { key, counter: 0 }
+    }
+
+    /// Decrypt and decode the next cell.
+    pub fn open(&mut self, mut cell: [u8; PACKET_SIZE]) -> anyhow::Result<(CellHeader, Vec<u8>)> {
+        let (header_bytes, payload_and_tag) = cell.split_at_mut(HEADER_LEN);
+        let (body, tag) = payload_and_tag.split_at_mut(PAYLOAD_LEN);
+        let tag: [u8; AEAD_TAG_LEN] = (&*tag).try_into()?;
+        decrypt_in_place(&self.key, self.counter, header_bytes, body, &tag)?;
+        self.counter += 1;
+
+        let (header, payload) = Packet::decode(&cell)?;
+        Ok((header, payload.to_vec()))
+    }
+}
+
+/// Reassembles a message from the cells of one stream. Cells must arrive in
+/// order with no gaps; since the sequence number and end flag are part of the
+/// authenticated header, a dropped, reordered or truncated message is rejected.
+pub struct Reassembler {
+    stream_id: u16,
+    next_seq: u32,
+    message: Vec<u8>,
+}
+
+impl Reassembler {
+    /// Reassembler for `stream_id`.
+    pub fn new(stream_id: u16) -> Self {
+        Self {
+            stream_id,
+            next_seq: 0,
+            message: Vec::new(),
+        }
+    }
+
+    /// Add one cell; returns the full message once the final cell arrives.
+    pub fn push(&mut self, header: &CellHeader, payload: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        if header.stream_id != self.stream_id {
+            return Err(anyhow::anyhow!("Unexpected stream id: {}", header.stream_id));
+        }
+        if header.seq != self.next_seq {
+            return Err(anyhow::anyhow!("Out of order cell: expected {}, got {}", self.next_seq, header.seq));
+        }
+        if self.message.len() + payload.len() > MAX_MESSAGE_LEN {
+            return Err(anyhow::anyhow!("Message exceeds {} bytes", MAX_MESSAGE_LEN));
+        }
+
+        self.message.extend_from_slice(payload);
+        self.next_seq += 1;
+
+        if header.is_end() {
+            self.next_seq = 0;
+            return Ok(Some(std::mem::take(&mut self.message)));
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair() -> (CellSealer, CellOpener) {
+        (CellSealer::new([7u8; 32]), CellOpener::new([7u8; 32]))
+    }
+
+    #[test]
+    fn seal_open_round_trip() {
+        let (mut sealer, mut opener) = pair();
+        for (seq, payload) in [&b"first"[..], &[], &[0xab; PAYLOAD_LEN]].into_iter().enumerate() {
+            let header = CellHeader::new(CellType::Data, 9, seq as u32, payload.len()).with_flags(FLAG_END);
+            let cell = sealer.seal(&header, payload).unwrap();
+            assert_eq!(cell.len(), PACKET_SIZE);
+            let (opened, opened_payload) = opener.open(cell).unwrap();
+            assert_eq!(opened, header);
+            assert_eq!(opened_payload, payload);
+        }
+    }
+
+    #[test]
+    fn payload_is_encrypted() {
+        let (mut sealer, _) = pair();
+        let payload = [0x42u8; 64];
+        let header = CellHeader::new(CellType::Data, 1, 0, payload.len());
+        let cell = sealer.seal(&header, &payload).unwrap();
+        assert_eq!(&cell[..HEADER_LEN], &header.encode()[..]);
+        assert_ne!(&cell[HEADER_LEN..HEADER_LEN + payload.len()], &payload[..]);
+    }
+
+    #[test]
+    fn tampered_cell_is_rejected() {
+        let header = CellHeader::new(CellType::Data, 1, 0, 5);
+        // A byte of the header, the payload, the padding and the tag
+        for offset in [STREAM_ID_OFFSET, HEADER_LEN, HEADER_LEN + 500, PACKET_SIZE - 1] {
+            let (mut sealer, mut opener) = pair();
+            let mut cell = sealer.seal(&header, b"hello").unwrap();
+            cell[offset] ^= 0x01;
+            assert!(opener.open(cell).is_err(), "offset {}", offset);
+        }
+    }
+
+    #[test]
+    fn wrong_key_is_rejected() {
+        let mut sealer = CellSealer::new([1u8; 32]);
+        let mut opener = CellOpener::new([2u8; 32]);
+        let cell = sealer.seal(&CellHeader::new(CellType::Data, 1, 0, 2), b"hi").unwrap();
+        assert!(opener.open(cell).is_err());
+    }
+
+    #[test]
+    fn replayed_and_reordered_cells_are_rejected() {
+        let header = CellHeader::new(CellType::Data, 1, 0, 2);
+        let (mut sealer, mut opener) = pair();
+        let first = sealer.seal(&header, b"hi").unwrap();
+        let second = sealer.seal(&header, b"hi").unwrap();
+        assert!(opener.open(second).is_err());
+
+        let (_, mut opener) = pair();
+        opener.open(first).unwrap();
+        assert!(opener.open(first).is_err());
+    }
+
+    #[test]
+    fn split_and_reassemble() {
+        let message: Vec<u8> = (0..3 * PAYLOAD_LEN + 17).map(|i| i as u8).collect();
+        let cells = Packet::split_message(CellType::Data, 4, &message).unwrap();
+        assert_eq!(cells.len(), 4);
+        assert!(cells.iter().take(3).all(|(header, _)| !header.is_end()));
+
+        let (mut sealer, mut opener) = pair();
+        let mut reassembler = Reassembler::new(4);
+        let mut result = None;
+        for (header, payload) in &cells {
+            let (header, payload) = opener.open(sealer.seal(header, payload).unwrap()).unwrap();
+            result = reassembler.push(&header, &payload).unwrap();
+        }
+        assert_eq!(result.unwrap(), message);
+
+        let empty = Packet::split_message(CellType::Data, 4, &[]).unwrap();
+        assert_eq!(empty.len(), 1);
+        assert!(empty[0].0.is_end());
+        assert!(Packet::split_message(CellType::Data, 4, &vec![0; MAX_MESSAGE_LEN + 1]).is_err());
+    }
+
+    #[test]
+    fn reassembler_rejects_gaps_and_other_streams() {
+        let cells = Packet::split_message(CellType::Data, 4, &[0; 2 * PAYLOAD_LEN]).unwrap();
+        let mut reassembler = Reassembler::new(4);
+        assert!(reassembler.push(&cells[1].0, &cells[1].1).is_err());
+        let mut reassembler = Reassembler::new(5);
+        assert!(reassembler.push(&cells[0].0, &cells[0].1).is_err());
+    }
+
+    #[test]
+    fn header_decode_validates() {
+        let header = CellHeader::new(CellType::Sendme, 3, 1, 0);
+        assert_eq!(CellHeader::decode(&header.encode()).unwrap(), header);
+
+        let mut bad_version = header.encode();
+        bad_version[0] = CELL_VERSION + 1;
+        assert!(CellHeader::decode(&bad_version).is_err());
+        let mut bad_type = header.encode();
+        bad_type[TYPE_OFFSET] = 0xff;
+        assert!(CellHeader::decode(&bad_type).is_err());
+        let mut bad_len = header.encode();
+        bad_len[PAYLOAD_LEN_OFFSET..FLAGS_OFFSET].copy_from_slice(&(PAYLOAD_LEN as u16 + 1).to_be_bytes());
+        assert!(CellHeader::decode(&bad_len).is_err());
+        assert!(CellHeader::decode(&header.encode()[..HEADER_LEN - 1]).is_err());
+    }
+}
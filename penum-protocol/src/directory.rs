@@ -0,0 +1,506 @@
+//! The signed network directory.
+//!
+//! Directory authorities publish the relays and gateways of the network as a
+//! JSON document, signed with their Ed25519 keys:
+//!
+//! ```text
+//! { "directory": "<directory JSON>", "signatures": [{ "authority": "<hex>", "signature": "<hex>" }] }
+//! ```
+//!
+//! The directory is kept as the exact text that was signed, so verifying never
+//! depends on how a JSON library orders or formats fields. A client accepts a
+//! document once a threshold of the authorities it trusts have signed it and
+//! the document has not expired. Fields and flags this version does not know
+//! are ignored, so authorities can add them without breaking older clients.
+//!
+//! Gateways mirror the document. A client that already has a usable directory
+//! asks the gateway at the end of a circuit for the current one with a
+//! [`DIRECTORY_METHOD`] request, so mirrors only see clients without one.
+//!
+//! Relays and gateways describe themselves to the authorities with a
+//! [`SignedDescriptor`]. It is signed with the node's X25519 identity key,
+//! used as an Ed25519 key the way XEdDSA does, so the signature proves the
+//! node holds the identity secret it advertises and nobody else can publish
+//! a descriptor for it.
+
+use crate::{PublicKey, StaticSecret};
+use curve25519_dalek::scalar::{clamp_integer, Scalar};
+use ed25519_dalek::hazmat::{raw_sign, ExpandedSecretKey};
+use ed25519_dalek::{Signature, Signer, Verifier};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha512};
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+
+// Domain separation, so a directory signature is never valid for anything else
+const SIGNATURE_CONTEXT: &[u8] = b"penum-directory-v1";
+const DESCRIPTOR_CONTEXT: &[u8] = b"penum-descriptor-v1";
+const DESCRIPTOR_NONCE_CONTEXT: &[u8] = b"penum-descriptor-nonce-v1";
+
+/// Accepted difference between an authority's clock and ours.
+pub const MAX_CLOCK_SKEW_SECS: u64 = 300;
+
+/// JSON-RPC method asking a gateway, through a circuit, for the signed
+/// directory it mirrors. The result is the [`SignedDirectory`] JSON as a string.
+pub const DIRECTORY_METHOD: &str = "penum_getDirectory";
+
+/// What a node does in a circuit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeRole {
+    /// Forwards cells between the client and the next hop.
+    Relay,
+    /// Terminates circuits and forwards requests to an RPC provider.
+    Gateway,
+}
+
+/// What the authorities know about a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum NodeFlag {
+    /// The node answered the authorities recently.
+    Running,
+    /// The node's descriptor is well formed and its operator is not excluded.
+    Valid,
+    /// The node has been up long enough to carry long-lived circuits.
+    Stable,
+    /// The node has more capacity than most.
+    Fast,
+    /// The node is suitable as an entry relay.
+    Guard,
+    /// A flag this version does not know.
+    #[serde(other)]
+    Unknown,
+}
+
+/// One relay or gateway listed in the directory.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeDescriptor {
+    /// Human readable name chosen by the operator.
+    pub nickname: String,
+    /// Relay or gateway.
+    pub role: NodeRole,
+    /// Address of the circuit listener.
+    pub address: SocketAddr,
+    /// Address of the mixnet listener, if the node runs one.
+    #[serde(default)]
+    pub mix_address: Option<SocketAddr>,
+    /// Hex-encoded X25519 identity key, answered in every handshake.
+    pub identity_key: String,
+    /// Capacity in KB/s. Clients choose nodes in proportion to it. Nodes
+    /// declare it, the consensus lists the low median of the votes.
+    #[serde(default)]
+    pub bandwidth: u64,
+    /// Identity keys of other nodes run by the same operator. Two nodes are
+    /// one family only if each lists the other.
+    #[serde(default)]
+    pub family: Vec<String>,
+    /// Flags assigned by the authorities.
+    #[serde(default)]
+    pub flags: Vec<NodeFlag>,
+}
+
+impl NodeDescriptor {
+    /// The node's identity key.
+    pub fn identity(&self) -> anyhow::Result<PublicKey> {
+        Ok(PublicKey::from(decode_key(&self.identity_key)?))
+    }
+
+    /// Whether the authorities assigned `flag` to the node.
+    pub fn has_flag(&self, flag: NodeFlag) -> bool {
+        self.flags.contains(&flag)
+    }
+
+    /// Whether clients should build circuits through the node.
+    pub fn is_usable(&self) -> bool {
+        self.has_flag(NodeFlag::Running) && self.has_flag(NodeFlag::Valid)
+    }
+
+    /// Whether both nodes declared each other as family.
+    pub fn same_family(&self, other: &NodeDescriptor) -> bool {
+        let lists = |node: &NodeDescriptor, key: &str| node.family.iter().any(|member| member.eq_ignore_ascii_case(key));
+        lists(self, &other.identity_key) && lists(other, &self.identity_key)
+    }
+}
+
+/// The network view published by the authorities.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Directory {
+    /// When the directory was published, in seconds since the Unix epoch.
+    pub published: u64,
+    /// When clients must stop using it, in seconds since the Unix epoch.
+    pub valid_until: u64,
+    /// Every listed relay and gateway.
+    pub nodes: Vec<NodeDescriptor>,
+}
+
+impl Directory {
+    /// The node with `role` listening on `address`, if it is listed.
+    pub fn find(&self, role: NodeRole, address: SocketAddr) -> Option<&NodeDescriptor> {
+        self.nodes
+            .iter()
+            .find(|node| node.role == role && node.address == address)
+    }
+}
+
+/// One authority's signature over a directory.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DirectorySignature {
+    /// Hex-encoded Ed25519 key of the authority.
+    pub authority: String,
+    /// Hex-encoded Ed25519 signature.
+    pub signature: String,
+}
+
+/// A directory as published: its exact JSON text and the signatures over it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedDirectory {
+    /// The directory JSON, exactly as it was signed.
+    pub directory: String,
+    /// Signatures of the authorities that agreed on it.
+    pub signatures: Vec<DirectorySignature>,
+}
+
+impl SignedDirectory {
+    /// Serialize `directory` for signing. No signatures yet.
+    pub fn new(directory: &Directory) -> anyhow::Result<Self> {
+        Ok(Self {
+            directory: serde_json::to_string(directory)?,
+            signatures: Vec::new(),
+        })
+    }
+
+    /// Add the signature of the authority holding `key`.
+    pub fn sign(&mut self, key: &SigningKey) {
+        let signature = key.sign(&signed_message(&self.directory));
+        self.signatures.push(DirectorySignature {
+            authority: hex::encode(key.verifying_key().as_bytes()),
+            signature: hex::encode(signature.to_bytes()),
+        });
+    }
+
+    /// Check that at least `threshold` of `authorities` signed the directory
+    /// and that it is valid at `now` (seconds since the Unix epoch), then
+    /// parse it. Signatures of unknown authorities are ignored and each
+    /// authority counts once.
+    pub fn verify(&self, authorities: &[VerifyingKey], threshold: usize, now: u64) -> anyhow::Result<Directory> {
+        if threshold == 0 {
+            return Err(anyhow::anyhow!("Directory signature threshold must be at least 1"));
+        }
+
+        let message = signed_message(&self.directory);
+        let mut signed_by = HashSet::new();
+        for entry in &self.signatures {
+            let Ok(authority) = decode_key(&entry.authority) else {
+                continue;
+            };
+            let Some(key) = authorities.iter().find(|key| key.as_bytes() == &authority) else {
+                continue;
+            };
+            let Some(signature) = hex::decode(&entry.signature)
+                .ok()
+                .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            else {
+                continue;
+            };
+            if key.verify(&message, &signature).is_ok() {
+                signed_by.insert(authority);
+            }
+        }
+        if signed_by.len() < threshold {
+            return Err(anyhow::anyhow!(
+                "Directory signed by {} trusted authorities, {} required",
+                signed_by.len(),
+                threshold
+            ));
+        }
+
+        let directory: Directory = serde_json::from_str(&self.directory)?;
+        if directory.published > now + MAX_CLOCK_SKEW_SECS {
+            return Err(anyhow::anyhow!("Directory published in the future"));
+        }
+        if directory.valid_until < now {
+            return Err(anyhow::anyhow!("Directory expired"));
+        }
+        Ok(directory)
+    }
+}
+
+/// A node's description of itself, as sent to the authorities.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Descriptor {
+    /// When the node signed it, in seconds since the Unix epoch. Authorities
+    /// keep the newest descriptor of each node and treat it as a heartbeat.
+    pub published: u64,
+    /// The node as it should appear in the directory. Flags are ignored:
+    /// only the authorities assign them.
+    pub node: NodeDescriptor,
+}
+
+/// A descriptor signed with the identity key it lists.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedDescriptor {
+    /// The descriptor JSON, exactly as it was signed.
+    pub descriptor: String,
+    /// Hex-encoded Ed25519 form of the node's identity key.
+    pub signing_key: String,
+    /// Hex-encoded Ed25519 signature.
+    pub signature: String,
+}
+
+impl SignedDescriptor {
+    /// Sign `descriptor` with the node's identity secret. The descriptor must
+    /// list the public half of `identity`.
+    pub fn sign(descriptor: &Descriptor, identity: &StaticSecret) -> anyhow::Result<Self> {
+        if descriptor.node.identity()? != PublicKey::from(identity) {
+            return Err(anyhow::anyhow!("Descriptor lists a different identity key"));
+        }
+
+        // The Ed25519 key with the same scalar as the X25519 secret. Its
+        // Montgomery form is the identity key, which is what verifiers check.
+        let secret = identity.to_bytes();
+        let nonce_key: [u8; 64] = Sha512::new()
+            .chain_update(DESCRIPTOR_NONCE_CONTEXT)
+            .chain_update(secret)
+            .finalize()
+            .into();
+        let mut hash_prefix = [0u8; 32];
+        hash_prefix.copy_from_slice(&nonce_key[..32]);
+        let expanded = ExpandedSecretKey {
+            scalar: Scalar::from_bytes_mod_order(clamp_integer(secret)),
+            hash_prefix,
+        };
+        let signing_key = VerifyingKey::from(&expanded);
+
+        let descriptor = serde_json::to_string(descriptor)?;
+        let signature = raw_sign::<Sha512>(&expanded, &descriptor_message(&descriptor), &signing_key);
+        Ok(Self {
+            descriptor,
+            signing_key: hex::encode(signing_key.as_bytes()),
+            signature: hex::encode(signature.to_bytes()),
+        })
+    }
+
+    /// Check the signature and that it was made with the listed identity
+    /// key, then parse the descriptor.
+    pub fn verify(&self) -> anyhow::Result<Descriptor> {
+        let signing_key = VerifyingKey::from_bytes(&decode_key(&self.signing_key)?)?;
+        let signature = hex::decode(&self.signature)
+            .ok()
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .ok_or_else(|| anyhow::anyhow!("Invalid descriptor signature encoding"))?;
+        signing_key
+            .verify(&descriptor_message(&self.descriptor), &signature)
+            .map_err(|_| anyhow::anyhow!("Invalid descriptor signature"))?;
+
+        let descriptor: Descriptor = serde_json::from_str(&self.descriptor)?;
+        if signing_key.to_montgomery().to_bytes() != *descriptor.node.identity()?.as_bytes() {
+            return Err(anyhow::anyhow!("Descriptor not signed with the identity key it lists"));
+        }
+        Ok(descriptor)
+    }
+}
+
+/// Parse a hex-encoded Ed25519 authority key.
+pub fn parse_authority_key(key_hex: &str) -> anyhow::Result<VerifyingKey> {
+    Ok(VerifyingKey::from_bytes(&decode_key(key_hex)?)?)
+}
+
+/// Whether `ip` is a globally routable unicast address. Nodes only connect
+/// to such next hops, and authorities only list nodes at one, unless they run
+/// a test network.
+pub fn is_public_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(mapped) => is_public_v4(mapped),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0  // "This network"
+        || (a == 100 && (b & 0xc0) == 64)  // Shared address space (carrier-grade NAT)
+        || (a == 192 && b == 0 && c == 0)  // IETF protocol assignments
+        || (a == 198 && (b & 0xfe) == 18)  // Benchmarking
+        || a >= 240)  // Reserved
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let [first, second, ..] = ip.segments();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        || ip.is_unique_local()
+        || ip.is_unicast_link_local()
+        || (first == 0x2001 && second == 0x0db8)  // Documentation
+        || (first == 0x0064 && second == 0xff9b)  // NAT64, would reach IPv4 behind it
+        || (first & 0xe000) != 0x2000)  // Outside global unicast
+}
+
+fn signed_message(directory: &str) -> Vec<u8> {
+    let mut message = Vec::with_capacity(SIGNATURE_CONTEXT.len() + directory.len());
+    message.extend_from_slice(SIGNATURE_CONTEXT);
+    message.extend_from_slice(directory.as_bytes());
+    message
+}
+
+fn descriptor_message(descriptor: &str) -> Vec<u8> {
+    let mut message = Vec::with_capacity(DESCRIPTOR_CONTEXT.len() + descriptor.len());
+    message.extend_from_slice(DESCRIPTOR_CONTEXT);
+    message.extend_from_slice(descriptor.as_bytes());
+    message
+}
+
+fn decode_key(key_hex: &str) -> anyhow::Result<[u8; 32]> {
+    hex::decode(key_hex.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| anyhow::anyhow!("Invalid key: expected 32 hex-encoded bytes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn authority(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    fn node(seed: u8) -> NodeDescriptor {
+        NodeDescriptor {
+            nickname: format!("node{}", seed),
+            role: NodeRole::Relay,
+            address: SocketAddr::from(([198, 51, 100, seed], 9001)),
+            mix_address: None,
+            identity_key: hex::encode(PublicKey::from(&StaticSecret::from([seed; 32])).as_bytes()),
+            bandwidth: 100,
+            family: Vec::new(),
+            flags: vec![NodeFlag::Running, NodeFlag::Valid],
+        }
+    }
+
+    fn directory() -> Directory {
+        Directory {
+            published: NOW - 60,
+            valid_until: NOW + 3600,
+            nodes: vec![node(1), node(2)],
+        }
+    }
+
+    fn signed_by(seeds: &[u8]) -> SignedDirectory {
+        let mut signed = SignedDirectory::new(&directory()).unwrap();
+        for seed in seeds {
+            signed.sign(&authority(*seed));
+        }
+        signed
+    }
+
+    fn trusted() -> Vec<VerifyingKey> {
+        [1, 2, 3].iter().map(|seed| authority(*seed).verifying_key()).collect()
+    }
+
+    #[test]
+    fn threshold_of_trusted_signatures() {
+        assert_eq!(signed_by(&[1, 3]).verify(&trusted(), 2, NOW).unwrap(), directory());
+        assert!(signed_by(&[1, 2, 3]).verify(&trusted(), 3, NOW).is_ok());
+        assert!(signed_by(&[2]).verify(&trusted(), 2, NOW).is_err());
+        assert!(signed_by(&[]).verify(&trusted(), 1, NOW).is_err());
+        assert!(signed_by(&[1, 2, 3]).verify(&trusted(), 0, NOW).is_err());
+    }
+
+    #[test]
+    fn each_authority_counts_once() {
+        assert!(signed_by(&[1, 1, 1]).verify(&trusted(), 2, NOW).is_err());
+    }
+
+    #[test]
+    fn untrusted_signatures_do_not_count() {
+        assert!(signed_by(&[1, 7, 8]).verify(&trusted(), 2, NOW).is_err());
+    }
+
+    #[test]
+    fn invalid_signatures_do_not_count() {
+        let mut signed = signed_by(&[1, 2]);
+        signed.signatures[1].signature = signed.signatures[0].signature.clone();
+        assert!(signed.verify(&trusted(), 2, NOW).is_err());
+
+        let mut signed = signed_by(&[1, 2]);
+        signed.signatures[1].signature = "not hex".to_string();
+        assert!(signed.verify(&trusted(), 1, NOW).is_ok());
+        assert!(signed.verify(&trusted(), 2, NOW).is_err());
+    }
+
+    #[test]
+    fn modified_directory_is_rejected() {
+        let mut signed = signed_by(&[1, 2]);
+        signed.directory = signed.directory.replace("node2", "evil2");
+        assert!(signed.verify(&trusted(), 1, NOW).is_err());
+    }
+
+    #[test]
+    fn expired_and_future_directories_are_rejected() {
+        let signed = signed_by(&[1, 2]);
+        assert!(signed.verify(&trusted(), 2, NOW + 3601).is_err());
+        assert!(signed.verify(&trusted(), 2, NOW - 60 - MAX_CLOCK_SKEW_SECS - 1).is_err());
+        assert!(signed.verify(&trusted(), 2, NOW - 60 - MAX_CLOCK_SKEW_SECS).is_ok());
+    }
+
+    #[test]
+    fn unknown_fields_and_flags_are_ignored() {
+        let mut signed = SignedDirectory {
+            directory: serde_json::to_string(&directory())
+                .unwrap()
+                .replace("\"valid\"", "\"valid\",\"exit\"")
+                .replace("{\"published\"", "{\"future\":1,\"published\""),
+            signatures: Vec::new(),
+        };
+        signed.sign(&authority(1));
+        let parsed = signed.verify(&trusted(), 1, NOW).unwrap();
+        assert!(parsed.nodes[0].has_flag(NodeFlag::Valid));
+        assert!(parsed.nodes[0].has_flag(NodeFlag::Unknown));
+    }
+
+    #[test]
+    fn descriptor_signed_with_identity_key() {
+        let identity = StaticSecret::from([1u8; 32]);
+        let descriptor = Descriptor { published: NOW, node: node(1) };
+        let signed = SignedDescriptor::sign(&descriptor, &identity).unwrap();
+        assert_eq!(signed.verify().unwrap(), descriptor);
+
+        // Another node cannot sign for it, and the text cannot change
+        assert!(SignedDescriptor::sign(&descriptor, &StaticSecret::from([2u8; 32])).is_err());
+        let mut tampered = signed.clone();
+        tampered.descriptor = tampered.descriptor.replace("node1", "evil1");
+        assert!(tampered.verify().is_err());
+
+        // A valid signature by a different key listing this node's identity
+        let other = SignedDescriptor::sign(&Descriptor { published: NOW, node: node(2) }, &StaticSecret::from([2u8; 32])).unwrap();
+        let mut forged = signed;
+        forged.signing_key = other.signing_key;
+        assert!(forged.verify().is_err());
+    }
+
+    #[test]
+    fn family_must_be_mutual() {
+        let (mut a, mut b) = (node(1), node(2));
+        a.family = vec![b.identity_key.to_uppercase()];
+        assert!(!a.same_family(&b));
+        b.family = vec![a.identity_key.clone()];
+        assert!(a.same_family(&b) && b.same_family(&a));
+    }
+}
@@ -0,0 +1,121 @@
+//! Circuit extension.
+//!
+//! The first cell a relay receives on a circuit is a handshake cell under the
+//! relay's onion layer. Its payload names the next hop and carries the client
+//! hello for it:
+//!
+//! ```text
+//! address type (1: 4 or 6) | address (16) | port (2) | client hello (52) | identity key (32)
+//! ```
+//!
+//! The identity key is the one the client expects the next hop to have, or
+//! all zeros if it does not know it. The relay uses it to open a link to the
+//! next hop, which it may share with other circuits (see
+//! [`crate::handshake::CAP_LINK`]); without it the relay opens a connection
+//! for this circuit alone. Either way it sends the hello padded to a full
+//! cell and returns the prefix of the node hello cell in a handshake cell
+//! under its backward layer.
+//!
+//! On a circuit built by a version 2 client the payload carries a
+//! [`LEGACY_CLIENT_HELLO_LEN`]-byte hello, which the relay sends and answers
+//! without padding.
+
+use crate::handshake::{CLIENT_HELLO_LEN, LEGACY_CLIENT_HELLO_LEN};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use x25519_dalek::PublicKey;
+
+pub(crate) const ADDR_LEN: usize = 19;
+
+/// Length of an extend payload.
+pub const EXTEND_LEN: usize = ADDR_LEN + CLIENT_HELLO_LEN + 32;
+
+/// Build the payload asking a relay to extend the circuit to `next_hop`,
+/// whose identity key is `identity` if known.
+pub fn encode_extend(
+    next_hop: SocketAddr,
+    hello: &[u8; CLIENT_HELLO_LEN],
+    identity: Option<&PublicKey>,
+) -> [u8; EXTEND_LEN] {
+    let mut payload = [0u8; EXTEND_LEN];
+    payload[..ADDR_LEN].copy_from_slice(&encode_addr(next_hop));
+    payload[ADDR_LEN..ADDR_LEN + CLIENT_HELLO_LEN].copy_from_slice(hello);
+    if let Some(identity) = identity {
+        payload[ADDR_LEN + CLIENT_HELLO_LEN..].copy_from_slice(identity.as_bytes());
+    }
+    payload
+}
+
+/// Parse an extend payload into the next hop, the client hello to forward and
+/// the next hop's identity key if the client named one.
+pub fn parse_extend(payload: &[u8]) -> Option<(SocketAddr, [u8; CLIENT_HELLO_LEN], Option<PublicKey>)> {
+    if payload.len() != EXTEND_LEN {
+        return None;
+    }
+
+    let next_hop = parse_addr(&payload[..ADDR_LEN])?;
+    let mut hello = [0u8; CLIENT_HELLO_LEN];
+    hello.copy_from_slice(&payload[ADDR_LEN..ADDR_LEN + CLIENT_HELLO_LEN]);
+    let mut identity = [0u8; 32];
+    identity.copy_from_slice(&payload[ADDR_LEN + CLIENT_HELLO_LEN..]);
+    let identity = (identity != [0u8; 32]).then(|| PublicKey::from(identity));
+    Some((next_hop, hello, identity))
+}
+
+/// Parse the extend payload of a version 2 circuit.
+pub fn parse_legacy_extend(payload: &[u8]) -> Option<(SocketAddr, [u8; LEGACY_CLIENT_HELLO_LEN])> {
+    if payload.len() != ADDR_LEN + LEGACY_CLIENT_HELLO_LEN {
+        return None;
+    }
+
+    let next_hop = parse_addr(&payload[..ADDR_LEN])?;
+    let mut hello = [0u8; LEGACY_CLIENT_HELLO_LEN];
+    hello.copy_from_slice(&payload[ADDR_LEN..]);
+    Some((next_hop, hello))
+}
+
+// Address type (4 or 6), address padded to 16 bytes, port
+pub(crate) fn encode_addr(addr: SocketAddr) -> [u8; ADDR_LEN] {
+    let mut encoded = [0u8; ADDR_LEN];
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            encoded[0] = 4;
+            encoded[1..5].copy_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            encoded[0] = 6;
+            encoded[1..17].copy_from_slice(&ip.octets());
+        }
+    }
+    encoded[17..19].copy_from_slice(&addr.port().to_be_bytes());
+    encoded
+}
+
+pub(crate) fn parse_addr(encoded: &[u8]) -> Option<SocketAddr> {
+    let ip = match encoded[0] {
+        4 => IpAddr::V4(Ipv4Addr::new(encoded[1], encoded[2], encoded[3], encoded[4])),
+        6 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&encoded[1..17]).ok()?)),
+        _ => return None,
+    };
+    let port = u16::from_be_bytes([encoded[17], encoded[18]]);
+    Some(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extend_round_trips_with_and_without_identity() {
+        let next_hop: SocketAddr = "[2001:db8::7]:9002".parse().unwrap();
+        let hello = [0x5au8; CLIENT_HELLO_LEN];
+        let identity = PublicKey::from([9u8; 32]);
+
+        let (parsed_hop, parsed_hello, parsed_identity) = parse_extend(&encode_extend(next_hop, &hello, Some(&identity))).unwrap();
+        assert_eq!((parsed_hop, parsed_hello), (next_hop, hello));
+        assert_eq!(parsed_identity.map(|key| key.to_bytes()), Some([9u8; 32]));
+
+        let (_, _, parsed_identity) = parse_extend(&encode_extend(next_hop, &hello, None)).unwrap();
+        assert!(parsed_identity.is_none());
+        assert!(parse_extend(&[0u8; EXTEND_LEN - 1]).is_none());
+    }
+}
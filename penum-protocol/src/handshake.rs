@@ -0,0 +1,1110 @@
+//! The handshake every relay and gateway answers.
+//!
+//! Both hellos travel as full [`PACKET_SIZE`] cells. Only a prefix of each cell
+//! is meaningful and the rest is random padding:
+//!
+//! ```text
+//! Client hello: representative (32) | masked: min version (1) | max version (1) | capabilities (2) | MAC (16)
+//! Node hello:   representative (32) | encrypted: selected version (1) | max version (1) | capabilities (2) | identity key (32)
+//! ```
+//!
+//! Ephemeral keys are sent as Elligator2 representatives, which are uniformly
+//! random 32-byte strings. Everything the node sends after its representative
+//! is encrypted under a key derived from the ephemeral DH. A handshake cell
+//! therefore looks like any other cell.
+//!
+//! A client that knows the node's identity key, as it does for hops taken
+//! from the directory, a pinned gateway or configured relay keys, masks its
+//! version fields with a keystream derived from `DH(e, node identity)` and
+//! follows them with a MAC under a key derived the same way, as obfs4 does.
+//! Only the node can remove the mask, so an observer cannot even tell which
+//! versions were offered. A client that does not know the key masks the
+//! fields with a keystream derived from its representative alone and sends
+//! random bytes in place of the MAC. That keeps fixed bytes out of the cell,
+//! but anyone implementing the protocol can remove the mask. The node checks
+//! the MAC first and falls back to the unkeyed mask when it does not match;
+//! nodes can be told to refuse unkeyed hellos so that a prober that does not
+//! know the identity key gets no answer at all.
+//!
+//! The node selects the highest version both sides support and the capability
+//! bits both sides set. A selected version of `0` is a rejection: the node
+//! supports none of the offered versions and closes the connection.
+//!
+//! Both DH outputs `DH(e, node ephemeral)` and `DH(e, node identity)` feed the
+//! key derivation, so only the holder of the identity secret ends up with the
+//! right keys. Both hellos are hashed into a transcript that is mixed into
+//! every derived key, so tampering with the offer changes the keys.
+//!
+//! If [`CAP_HYBRID_ML_KEM_768`] is selected, the hellos are followed by the
+//! ML-KEM exchange described in [`crate::kem`], whose messages are part of the
+//! transcript and whose shared secret is part of the key derivation. Its cells
+//! are encrypted under the same ephemeral DH key as the node hello. A peer
+//! that does not know the bit never selects it and the classic handshake is
+//! used unchanged.
+//!
+//! If [`CAP_KEY_CONFIRMATION`] is selected, the node follows its hello with a
+//! [`KEY_CONFIRMATION_LEN`]-byte value derived from both DH outputs and the
+//! hellos, in the same cell. The node hello merely names the identity key;
+//! the confirmation proves the node holds its secret before any further
+//! cell is exchanged. Directory authorities require it in their reachability
+//! tests. Clients do not ask for it: a node that cannot compute the keys of
+//! a circuit hop breaks the circuit anyway, and a relay extending a circuit
+//! forwards the node hello alone.
+//!
+//! A relay that offers [`CAP_LINK`] opens a link rather than a circuit: the
+//! connection then carries the cells of many circuits to the next hop, each
+//! tagged with a circuit ID, under keys derived from this handshake. Nodes
+//! that do not know the bit leave it unselected and the relay opens one
+//! connection per circuit as before.
+//!
+//! Version 2 sent a bare 36-byte hello and got a bare 68-byte reply, both in
+//! the clear:
+//!
+//! ```text
+//! Client hello: min version (1) | max version (1) | capabilities (2) | ephemeral key (32)
+//! Node hello:   selected version (1) | max version (1) | capabilities (2) | ephemeral key (32) | identity key (32)
+//! ```
+//!
+//! While clients move to version 3, nodes keep answering such hellos with
+//! [`respond_legacy`]. The first 36 bytes of a connection tell the two apart
+//! ([`is_legacy_hello`]): a version 2 hello starts with small version numbers
+//! and known capability bits, which the random-looking start of a version 3
+//! hello matches about once in 10^9 handshakes. The ML-KEM cells of a legacy
+//! hybrid handshake are sent in the clear, and a version 2 gateway adds no
+//! onion layer. Answering old hellos lets anyone who sends one recognize the
+//! node, so nodes can stop once their clients have upgraded. Clients only
+//! speak version 3.
+
+use crate::cell::PACKET_SIZE;
+use crate::cell::Packet;
+use crate::kdf::{
+    derive_handshake_obfuscation, derive_identity_confirmation, derive_onion_layer, hello_mask, keyed_hello_keys,
+    HandshakeObfuscation, OnionLayer,
+};
+use crate::kem::{self, KemKeyPair, CIPHERTEXT_LEN, ENCAPSULATION_KEY_LEN};
+use crate::session::Session;
+use chacha20::cipher::StreamCipher;
+use hmac::{Hmac, Mac};
+use curve25519_elligator2::{MapToPointVariant, MontgomeryPoint, Randomized};
+use rand::{thread_rng, RngCore};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use subtle::ConstantTimeEq;
+use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
+
+/// Lowest wire protocol version this crate speaks. Version 3 changed the hello
+/// framing; nodes still answer [`LEGACY_PROTOCOL_VERSION`] hellos in the old
+/// framing (see the module docs).
+pub const MIN_PROTOCOL_VERSION: u8 = 3;
+
+/// Version answered in the pre-version 3 framing by [`respond_legacy`].
+pub const LEGACY_PROTOCOL_VERSION: u8 = 2;
+
+/// Highest wire protocol version this crate speaks.
+pub const MAX_PROTOCOL_VERSION: u8 = 3;
+
+/// Hybrid X25519 + ML-KEM-768 key exchange.
+pub const CAP_HYBRID_ML_KEM_768: u16 = 0x0001;
+
+/// Node proves it holds its identity secret right after its hello.
+pub const CAP_KEY_CONFIRMATION: u16 = 0x0002;
+
+/// The connection is a link between two relays carrying many circuits
+/// rather than a single circuit. Only relays offer it.
+pub const CAP_LINK: u16 = 0x0004;
+
+/// Optional protocol features this crate supports, as bit flags.
+pub const SUPPORTED_CAPABILITIES: u16 = CAP_HYBRID_ML_KEM_768 | CAP_KEY_CONFIRMATION | CAP_LINK;
+
+/// Meaningful prefix of a client hello cell.
+pub const CLIENT_HELLO_LEN: usize = 52;
+
+/// Meaningful prefix of the node hello cell sent back by a relay or gateway.
+/// A legacy reply has the same length and is sent without padding.
+pub const HANDSHAKE_REPLY_LEN: usize = 68;
+
+/// Length of a version 2 client hello, which is sent without padding.
+pub const LEGACY_CLIENT_HELLO_LEN: usize = 36;
+
+/// Key confirmation following the node hello when [`CAP_KEY_CONFIRMATION`]
+/// is selected.
+pub const KEY_CONFIRMATION_LEN: usize = 32;
+
+const REPRESENTATIVE_LEN: usize = 32;
+
+// Version and capability fields of a client hello
+const HELLO_FIELDS_LEN: usize = 4;
+
+const HELLO_MAC_LEN: usize = 16;
+
+/// Ephemeral keys for a single handshake. They are used for two DH operations
+/// (against the node's ephemeral and identity keys), then dropped.
+pub struct EphemeralKeys {
+    secret: StaticSecret,
+    /// Elligator2 representative of the public half, sent in place of the key.
+    pub representative: [u8; 32],
+}
+
+impl EphemeralKeys {
+    /// Generate a fresh key pair from the OS RNG. Only about half of all public
+    /// keys have a representative, so this retries until it finds one.
+    pub fn generate() -> Self {
+        let mut rng = thread_rng();
+        loop {
+            let mut secret = [0u8; 32];
+            rng.fill_bytes(&mut secret);
+            // The tweak randomizes the two high bits, which the map leaves unused
+            let tweak = rng.next_u32() as u8;
+            if let Some(representative) = Randomized::to_representative(&secret, tweak).into() {
+                return Self {
+                    secret: StaticSecret::from(secret),
+                    representative,
+                };
+            }
+        }
+    }
+
+    /// X25519 with a peer public key.
+    pub fn diffie_hellman(&self, remote_public: &PublicKey) -> SharedSecret {
+        self.secret.diffie_hellman(remote_public)
+    }
+
+    /// The public half, for a legacy reply that sends it as it is.
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey::from(&self.secret)
+    }
+}
+
+/// Public key behind an Elligator2 representative. Every 32-byte string maps
+/// to a key, so there is nothing to validate.
+pub fn public_key_from_representative(representative: &[u8; 32]) -> PublicKey {
+    let point = MontgomeryPoint::from_representative::<Randomized>(representative)
+        .expect("every representative maps to a curve point");
+    PublicKey::from(point.to_bytes())
+}
+
+/// Decoded client hello.
+pub struct ClientHello {
+    /// Lowest protocol version the client accepts.
+    pub min_version: u8,
+    /// Highest protocol version the client accepts.
+    pub max_version: u8,
+    /// Capability bits the client supports.
+    pub capabilities: u16,
+    /// Representative of the client's ephemeral public key.
+    pub representative: [u8; 32],
+}
+
+impl ClientHello {
+    /// Encode the meaningful prefix of the hello cell. `identity_secret` is
+    /// `DH(e, node identity)` when the client knows the node's identity key;
+    /// the fields are then masked and authenticated under it. Without it they
+    /// are masked with a keystream derived from the representative and the
+    /// MAC is random.
+    pub fn encode(&self, identity_secret: Option<&SharedSecret>) -> [u8; CLIENT_HELLO_LEN] {
+        let mut hello = [0u8; CLIENT_HELLO_LEN];
+        hello[..REPRESENTATIVE_LEN].copy_from_slice(&self.representative);
+        let fields = &mut hello[REPRESENTATIVE_LEN..REPRESENTATIVE_LEN + HELLO_FIELDS_LEN];
+        fields[0] = self.min_version;
+        fields[1] = self.max_version;
+        fields[2..].copy_from_slice(&self.capabilities.to_be_bytes());
+        match identity_secret {
+            Some(identity_secret) => {
+                let (mut mask, mac_key) = keyed_hello_keys(identity_secret, &self.representative);
+                mask.apply_keystream(fields);
+                let mac = hello_mac(&mac_key, &hello);
+                hello[REPRESENTATIVE_LEN + HELLO_FIELDS_LEN..].copy_from_slice(&mac);
+            }
+            None => {
+                hello_mask(&self.representative).apply_keystream(fields);
+                thread_rng().fill_bytes(&mut hello[REPRESENTATIVE_LEN + HELLO_FIELDS_LEN..]);
+            }
+        }
+        hello
+    }
+
+    /// Decode from the wire with `DH(node identity, e)`. Returns the hello and
+    /// whether its MAC matched, i.e. whether the client masked it under our
+    /// identity key. Every byte string of the right length decodes to a hello.
+    pub fn decode(hello: &[u8; CLIENT_HELLO_LEN], identity_secret: &SharedSecret) -> (Self, bool) {
+        let mut representative = [0u8; 32];
+        representative.copy_from_slice(&hello[..REPRESENTATIVE_LEN]);
+        let (keyed_mask, mac_key) = keyed_hello_keys(identity_secret, &representative);
+        let keyed = bool::from(hello_mac(&mac_key, hello).ct_eq(&hello[REPRESENTATIVE_LEN + HELLO_FIELDS_LEN..]));
+        let mut mask = if keyed { keyed_mask } else { hello_mask(&representative) };
+
+        let mut fields = [0u8; HELLO_FIELDS_LEN];
+        fields.copy_from_slice(&hello[REPRESENTATIVE_LEN..REPRESENTATIVE_LEN + HELLO_FIELDS_LEN]);
+        mask.apply_keystream(&mut fields);
+        let hello = Self {
+            min_version: fields[0],
+            max_version: fields[1],
+            capabilities: u16::from_be_bytes([fields[2], fields[3]]),
+            representative,
+        };
+        (hello, keyed)
+    }
+
+    /// The client's ephemeral public key.
+    pub fn public_key(&self) -> PublicKey {
+        public_key_from_representative(&self.representative)
+    }
+}
+
+/// The client's ephemeral public key, read from an encoded hello. The
+/// representative is never masked, so this needs no key.
+pub fn client_hello_public_key(hello: &[u8; CLIENT_HELLO_LEN]) -> PublicKey {
+    let mut representative = [0u8; 32];
+    representative.copy_from_slice(&hello[..REPRESENTATIVE_LEN]);
+    public_key_from_representative(&representative)
+}
+
+// MAC over the representative and the masked fields of a client hello
+fn hello_mac(mac_key: &[u8; 32], hello: &[u8; CLIENT_HELLO_LEN]) -> [u8; HELLO_MAC_LEN] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(mac_key).expect("HMAC accepts any key length");
+    mac.update(&hello[..REPRESENTATIVE_LEN + HELLO_FIELDS_LEN]);
+    let mut tag = [0u8; HELLO_MAC_LEN];
+    tag.copy_from_slice(&mac.finalize().into_bytes()[..HELLO_MAC_LEN]);
+    tag
+}
+
+/// Decoded node hello, without the node's representative that precedes it on
+/// the wire.
+pub struct NodeHello {
+    /// Selected protocol version, or `0` for a rejection.
+    pub version: u8,
+    /// Highest protocol version the node supports.
+    pub max_version: u8,
+    /// Capability bits in use for this circuit hop.
+    pub capabilities: u16,
+    /// Node long-term identity key.
+    pub identity: PublicKey,
+}
+
+impl NodeHello {
+    /// Encode the fields before encryption.
+    pub fn encode(&self) -> [u8; HANDSHAKE_REPLY_LEN - REPRESENTATIVE_LEN] {
+        let mut fields = [0u8; HANDSHAKE_REPLY_LEN - REPRESENTATIVE_LEN];
+        fields[0] = self.version;
+        fields[1] = self.max_version;
+        fields[2..4].copy_from_slice(&self.capabilities.to_be_bytes());
+        fields[4..].copy_from_slice(self.identity.as_bytes());
+        fields
+    }
+
+    /// Decode the fields after decryption.
+    pub fn decode(fields: &[u8; HANDSHAKE_REPLY_LEN - REPRESENTATIVE_LEN]) -> Self {
+        let mut identity = [0u8; 32];
+        identity.copy_from_slice(&fields[4..]);
+        Self {
+            version: fields[0],
+            max_version: fields[1],
+            capabilities: u16::from_be_bytes([fields[2], fields[3]]),
+            identity: PublicKey::from(identity),
+        }
+    }
+
+    /// Whether the node rejected every offered version.
+    pub fn is_rejection(&self) -> bool {
+        self.version == 0
+    }
+}
+
+/// Highest version in both the offered range and ours, if the ranges overlap.
+pub fn negotiate_version(min_version: u8, max_version: u8) -> Option<u8> {
+    let version = max_version.min(MAX_PROTOCOL_VERSION);
+    if version >= min_version.max(MIN_PROTOCOL_VERSION) {
+        Some(version)
+    } else {
+        None
+    }
+}
+
+/// Hash of both hello prefixes exactly as they were sent, followed by the
+/// ML-KEM encapsulation key and ciphertext for a hybrid handshake.
+pub fn handshake_transcript(
+    client_hello: &[u8],
+    reply: &[u8; HANDSHAKE_REPLY_LEN],
+    kem_messages: Option<(&[u8], &[u8])>,
+) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"penum-handshake");
+    hasher.update(client_hello);
+    hasher.update(reply);
+    if let Some((encapsulation_key, ciphertext)) = kem_messages {
+        hasher.update(encapsulation_key);
+        hasher.update(ciphertext);
+    }
+    hasher.finalize().into()
+}
+
+/// Secrets both sides hold after a successful handshake. Turn them into a
+/// [`Session`] (client and gateway) and an [`OnionLayer`] (client and every node).
+pub struct HandshakeSecrets {
+    /// Negotiated protocol version.
+    pub version: u8,
+    /// DH between the client's and the node's ephemeral keys.
+    pub ephemeral_secret: SharedSecret,
+    /// DH between the client's ephemeral key and the node's identity key.
+    pub identity_secret: SharedSecret,
+    /// ML-KEM shared secret, for a hybrid handshake.
+    pub kem_secret: Option<[u8; 32]>,
+    /// [`handshake_transcript`] of this handshake.
+    pub transcript: [u8; 32],
+    /// Capability bits selected by the node.
+    pub capabilities: u16,
+}
+
+impl HandshakeSecrets {
+    /// Client end of the end-to-end session with the gateway.
+    pub fn client_session(&self) -> Session {
+        Session::client(self)
+    }
+
+    /// Gateway end of the end-to-end session with the client.
+    pub fn gateway_session(&self) -> Session {
+        Session::gateway(self)
+    }
+
+    /// Onion layer between the client and this hop.
+    pub fn onion_layer(&self) -> OnionLayer {
+        derive_onion_layer(self)
+    }
+}
+
+/// Node side of a handshake.
+pub struct HandshakeResponse {
+    /// Prefix of the reply cell to send back, whether the handshake was
+    /// accepted or not.
+    pub reply: [u8; HANDSHAKE_REPLY_LEN],
+    /// `None` when the client offered no version we support. The reply is then
+    /// a rejection and the connection should be closed after sending it.
+    pub handshake: Option<NodeHandshake>,
+    /// Whether the client masked its hello under our identity key. Nodes that
+    /// refuse unkeyed hellos close the connection without sending the reply.
+    pub keyed: bool,
+    identity_confirmation: Option<[u8; KEY_CONFIRMATION_LEN]>,
+}
+
+impl HandshakeResponse {
+    /// The reply cell to send on a direct connection: the reply, followed by
+    /// the key confirmation if it was selected, and random padding.
+    pub fn reply_cell(&self) -> [u8; PACKET_SIZE] {
+        let mut prefix = self.reply.to_vec();
+        if let Some(confirmation) = &self.identity_confirmation {
+            prefix.extend_from_slice(confirmation);
+        }
+        Packet::padded(&prefix)
+    }
+}
+
+/// Accepted handshake on the node side, waiting for the ML-KEM exchange if
+/// the hybrid capability was selected.
+pub struct NodeHandshake {
+    client_hello: Vec<u8>,
+    reply: [u8; HANDSHAKE_REPLY_LEN],
+    ephemeral_secret: SharedSecret,
+    identity_secret: SharedSecret,
+    obfuscation: Option<HandshakeObfuscation>,  // None for a legacy handshake
+    version: u8,
+    capabilities: u16,
+}
+
+impl NodeHandshake {
+    /// Number of encapsulation key cells to read from the client before
+    /// calling [`finish`](Self::finish); `0` for a classic handshake.
+    pub fn expected_cells(&self) -> usize {
+        if self.hybrid() {
+            kem::ENCAPSULATION_KEY_CELLS
+        } else {
+            0
+        }
+    }
+
+    /// Complete the handshake. For a hybrid handshake, `encapsulation_key_cells`
+    /// are the client's cells and the returned cells carry the ciphertext to
+    /// send back; for a classic handshake both are empty.
+    pub fn finish(
+        mut self,
+        encapsulation_key_cells: &[[u8; PACKET_SIZE]],
+    ) -> anyhow::Result<(Vec<[u8; PACKET_SIZE]>, HandshakeSecrets)> {
+        if !self.hybrid() {
+            let secrets = HandshakeSecrets {
+                version: self.version,
+                ephemeral_secret: self.ephemeral_secret,
+                identity_secret: self.identity_secret,
+                kem_secret: None,
+                transcript: handshake_transcript(&self.client_hello, &self.reply, None),
+                capabilities: self.capabilities,
+            };
+            return Ok((Vec::new(), secrets));
+        }
+
+        let mut cells = encapsulation_key_cells.to_vec();
+        if let Some(obfuscation) = &mut self.obfuscation {
+            for cell in cells.iter_mut() {
+                obfuscation.to_node.apply_keystream(cell);
+            }
+        }
+        let encapsulation_key = kem::decode_cells(&cells, ENCAPSULATION_KEY_LEN)?;
+        let (ciphertext, kem_secret) = kem::encapsulate(&encapsulation_key)?;
+
+        let mut ciphertext_cells = kem::encode_cells(&ciphertext)?;
+        if let Some(obfuscation) = &mut self.obfuscation {
+            for cell in ciphertext_cells.iter_mut() {
+                obfuscation.to_client.apply_keystream(cell);
+            }
+        }
+        let secrets = HandshakeSecrets {
+            version: self.version,
+            ephemeral_secret: self.ephemeral_secret,
+            identity_secret: self.identity_secret,
+            kem_secret: Some(kem_secret),
+            transcript: handshake_transcript(&self.client_hello, &self.reply, Some((&encapsulation_key, &ciphertext))),
+            capabilities: self.capabilities,
+        };
+        Ok((ciphertext_cells, secrets))
+    }
+
+    fn hybrid(&self) -> bool {
+        self.capabilities & CAP_HYBRID_ML_KEM_768 != 0
+    }
+}
+
+/// Answer a client hello with a fresh ephemeral key and our identity key.
+pub fn respond(identity: &StaticSecret, client_hello: &[u8; CLIENT_HELLO_LEN]) -> HandshakeResponse {
+    let client_public = client_hello_public_key(client_hello);
+    let identity_secret = identity.diffie_hellman(&client_public);
+    let (hello, keyed) = ClientHello::decode(client_hello, &identity_secret);
+
+    // Even a rejection is encrypted, so it looks like any other reply
+    let keys = EphemeralKeys::generate();
+    let ephemeral_secret = keys.diffie_hellman(&client_public);
+    let mut obfuscation = derive_handshake_obfuscation(&ephemeral_secret, &hello.representative, &keys.representative);
+
+    let version = negotiate_version(hello.min_version, hello.max_version);
+    let capabilities = match version {
+        Some(_) => hello.capabilities & SUPPORTED_CAPABILITIES,
+        None => 0,
+    };
+    let node_hello = NodeHello {
+        version: version.unwrap_or(0),
+        max_version: MAX_PROTOCOL_VERSION,
+        capabilities,
+        identity: PublicKey::from(identity),
+    };
+
+    let mut reply = [0u8; HANDSHAKE_REPLY_LEN];
+    reply[..REPRESENTATIVE_LEN].copy_from_slice(&keys.representative);
+    reply[REPRESENTATIVE_LEN..].copy_from_slice(&node_hello.encode());
+    obfuscation.to_client.apply_keystream(&mut reply[REPRESENTATIVE_LEN..]);
+
+    let Some(version) = version else {
+        return HandshakeResponse {
+            reply,
+            handshake: None,
+            keyed,
+            identity_confirmation: None,
+        };
+    };
+
+    let identity_confirmation = (capabilities & CAP_KEY_CONFIRMATION != 0).then(|| {
+        derive_identity_confirmation(
+            &ephemeral_secret,
+            &identity_secret,
+            &handshake_transcript(client_hello, &reply, None),
+        )
+    });
+    HandshakeResponse {
+        reply,
+        handshake: Some(NodeHandshake {
+            client_hello: client_hello.to_vec(),
+            reply,
+            ephemeral_secret,
+            identity_secret,
+            obfuscation: Some(obfuscation),
+            version,
+            capabilities,
+        }),
+        keyed,
+        identity_confirmation,
+    }
+}
+
+/// Whether the first [`LEGACY_CLIENT_HELLO_LEN`] bytes of a connection are a
+/// version 1 or 2 hello rather than the start of a hello cell.
+pub fn is_legacy_hello(prefix: &[u8; LEGACY_CLIENT_HELLO_LEN]) -> bool {
+    let (min_version, max_version) = (prefix[0], prefix[1]);
+    let capabilities = u16::from_be_bytes([prefix[2], prefix[3]]);
+    min_version >= 1
+        && min_version <= max_version
+        && max_version <= LEGACY_PROTOCOL_VERSION
+        && capabilities & !CAP_HYBRID_ML_KEM_768 == 0
+}
+
+/// Answer a version 1 or 2 hello in the old framing. The reply goes back
+/// without padding, and the ML-KEM cells of a hybrid handshake are not
+/// obfuscated. Version 1 is rejected.
+pub fn respond_legacy(identity: &StaticSecret, client_hello: &[u8; LEGACY_CLIENT_HELLO_LEN]) -> HandshakeResponse {
+    let (min_version, max_version) = (client_hello[0], client_hello[1]);
+    let capabilities = u16::from_be_bytes([client_hello[2], client_hello[3]]) & CAP_HYBRID_ML_KEM_768;
+    let mut client_public = [0u8; 32];
+    client_public.copy_from_slice(&client_hello[4..]);
+    let client_public = PublicKey::from(client_public);
+
+    // A rejection carries an all-zero ephemeral key
+    let mut reply = [0u8; HANDSHAKE_REPLY_LEN];
+    reply[1] = MAX_PROTOCOL_VERSION;
+    reply[36..].copy_from_slice(PublicKey::from(identity).as_bytes());
+    if !(min_version..=max_version).contains(&LEGACY_PROTOCOL_VERSION) {
+        return HandshakeResponse {
+            reply,
+            handshake: None,
+            keyed: false,
+            identity_confirmation: None,
+        };
+    }
+
+    let keys = EphemeralKeys::generate();
+    reply[0] = LEGACY_PROTOCOL_VERSION;
+    reply[2..4].copy_from_slice(&capabilities.to_be_bytes());
+    reply[4..36].copy_from_slice(keys.public_key().as_bytes());
+    HandshakeResponse {
+        reply,
+        handshake: Some(NodeHandshake {
+            client_hello: client_hello.to_vec(),
+            reply,
+            ephemeral_secret: keys.diffie_hellman(&client_public),
+            identity_secret: identity.diffie_hellman(&client_public),
+            obfuscation: None,
+            version: LEGACY_PROTOCOL_VERSION,
+            capabilities,
+        }),
+        keyed: false,
+        identity_confirmation: None,
+    }
+}
+
+/// Returned when a node supports none of the protocol versions we offered.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct ProtocolVersionRejected {
+    /// Lowest version offered.
+    pub offered_min: u8,
+    /// Highest version offered.
+    pub offered_max: u8,
+    /// Highest version the node supports.
+    pub node_max: u8,
+}
+
+impl fmt::Display for ProtocolVersionRejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Protocol version rejected: offered versions {}-{}, node supports up to {}",
+            self.offered_min, self.offered_max, self.node_max
+        )
+    }
+}
+
+impl std::error::Error for ProtocolVersionRejected {}
+
+// What the client learned from the node hello
+struct AcceptedReply {
+    reply: [u8; HANDSHAKE_REPLY_LEN],
+    node_hello: NodeHello,
+    ephemeral_secret: SharedSecret,
+    obfuscation: HandshakeObfuscation,
+}
+
+/// Client side of a handshake with one hop.
+pub struct ClientHandshake {
+    keys: EphemeralKeys,
+    hello: [u8; CLIENT_HELLO_LEN],
+    max_version: u8,
+    capabilities: u16,
+    accepted: Option<AcceptedReply>,
+    kem_keys: Option<KemKeyPair>,
+}
+
+impl ClientHandshake {
+    /// Start a handshake offering versions `MIN_PROTOCOL_VERSION..=max_version`
+    /// and the given capability bits. Pass the node's identity key when it is
+    /// known in advance so the hello is masked under it (see the module docs).
+    /// It is not checked here: compare it with the key [`finish`](Self::finish)
+    /// returns.
+    pub fn new(max_version: u8, capabilities: u16, node_identity: Option<&PublicKey>) -> Self {
+        let keys = EphemeralKeys::generate();
+        let capabilities = capabilities & SUPPORTED_CAPABILITIES;
+        let identity_secret = node_identity.map(|identity| keys.diffie_hellman(identity));
+        let hello = ClientHello {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version,
+            capabilities,
+            representative: keys.representative,
+        }
+        .encode(identity_secret.as_ref());
+        Self {
+            keys,
+            hello,
+            max_version,
+            capabilities,
+            accepted: None,
+            kem_keys: None,
+        }
+    }
+
+    /// Prefix of the hello cell to send to the node.
+    pub fn hello(&self) -> &[u8; CLIENT_HELLO_LEN] {
+        &self.hello
+    }
+
+    /// Check the node's reply. For a hybrid handshake, returns the cells
+    /// carrying our ML-KEM encapsulation key; send them, then read
+    /// [`kem::CIPHERTEXT_CELLS`] cells and pass them to [`finish`](Self::finish).
+    ///
+    /// Fails with [`ProtocolVersionRejected`] if the node rejected every
+    /// offered version, and with a plain error if it selected anything other
+    /// than the highest common version or unrequested capabilities.
+    pub fn read_reply(&mut self, reply: &[u8; HANDSHAKE_REPLY_LEN]) -> anyhow::Result<Option<Vec<[u8; PACKET_SIZE]>>> {
+        let mut node_representative = [0u8; 32];
+        node_representative.copy_from_slice(&reply[..REPRESENTATIVE_LEN]);
+        let ephemeral_secret = self
+            .keys
+            .diffie_hellman(&public_key_from_representative(&node_representative));
+        let mut obfuscation =
+            derive_handshake_obfuscation(&ephemeral_secret, &self.keys.representative, &node_representative);
+
+        let mut fields = [0u8; HANDSHAKE_REPLY_LEN - REPRESENTATIVE_LEN];
+        fields.copy_from_slice(&reply[REPRESENTATIVE_LEN..]);
+        obfuscation.to_client.apply_keystream(&mut fields);
+        let node_hello = NodeHello::decode(&fields);
+
+        if node_hello.is_rejection() {
+            return Err(ProtocolVersionRejected {
+                offered_min: MIN_PROTOCOL_VERSION,
+                offered_max: self.max_version,
+                node_max: node_hello.max_version,
+            }
+            .into());
+        }
+
+        // The node must pick the highest version we both support. Anything lower
+        // means the offer was tampered with or the node is misbehaving.
+        if node_hello.version != self.max_version.min(node_hello.max_version) || node_hello.version < MIN_PROTOCOL_VERSION {
+            return Err(anyhow::anyhow!("Protocol downgrade detected"));
+        }
+        if node_hello.capabilities & !self.capabilities != 0 {
+            return Err(anyhow::anyhow!("Node selected unrequested capabilities"));
+        }
+
+        let hybrid = node_hello.capabilities & CAP_HYBRID_ML_KEM_768 != 0;
+        let mut accepted = AcceptedReply {
+            reply: *reply,
+            node_hello,
+            ephemeral_secret,
+            obfuscation,
+        };
+        if !hybrid {
+            self.accepted = Some(accepted);
+            return Ok(None);
+        }
+
+        let kem_keys = KemKeyPair::generate();
+        let mut cells = kem::encode_cells(kem_keys.encapsulation_key())?;
+        for cell in cells.iter_mut() {
+            accepted.obfuscation.to_node.apply_keystream(cell);
+        }
+        self.accepted = Some(accepted);
+        self.kem_keys = Some(kem_keys);
+        Ok(Some(cells))
+    }
+
+    /// Check the key confirmation that followed the node hello, once the reply
+    /// has been read, and return the identity key it proves. Fails if the node
+    /// did not select [`CAP_KEY_CONFIRMATION`] or does not hold the secret of
+    /// the identity key it named.
+    pub fn confirm_identity(&self, confirmation: &[u8; KEY_CONFIRMATION_LEN]) -> anyhow::Result<PublicKey> {
+        let accepted = self
+            .accepted
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Handshake reply not read"))?;
+        if accepted.node_hello.capabilities & CAP_KEY_CONFIRMATION == 0 {
+            return Err(anyhow::anyhow!("Node did not confirm its identity key"));
+        }
+        let identity = accepted.node_hello.identity;
+        let expected = derive_identity_confirmation(
+            &accepted.ephemeral_secret,
+            &self.keys.diffie_hellman(&identity),
+            &handshake_transcript(&self.hello, &accepted.reply, None),
+        );
+        if !bool::from(expected.ct_eq(confirmation)) {
+            return Err(anyhow::anyhow!("Node does not hold the identity key it named"));
+        }
+        Ok(identity)
+    }
+
+    /// Compute the shared secrets once the reply has been read. `ciphertext_cells`
+    /// are the node's ML-KEM cells for a hybrid handshake and empty otherwise.
+    /// Returns the node's identity key so the caller can compare it with a pinned key.
+    pub fn finish(self, ciphertext_cells: &[[u8; PACKET_SIZE]]) -> anyhow::Result<(PublicKey, HandshakeSecrets)> {
+        let mut accepted = self
+            .accepted
+            .ok_or_else(|| anyhow::anyhow!("Handshake reply not read"))?;
+
+        let (kem_secret, transcript) = match &self.kem_keys {
+            Some(kem_keys) => {
+                let mut cells = ciphertext_cells.to_vec();
+                for cell in cells.iter_mut() {
+                    accepted.obfuscation.to_client.apply_keystream(cell);
+                }
+                let ciphertext = kem::decode_cells(&cells, CIPHERTEXT_LEN)?;
+                let kem_secret = kem_keys.decapsulate(&ciphertext)?;
+                let transcript = handshake_transcript(
+                    &self.hello,
+                    &accepted.reply,
+                    Some((kem_keys.encapsulation_key(), &ciphertext)),
+                );
+                (Some(kem_secret), transcript)
+            }
+            None => (None, handshake_transcript(&self.hello, &accepted.reply, None)),
+        };
+
+        let identity = accepted.node_hello.identity;
+        let secrets = HandshakeSecrets {
+            version: accepted.node_hello.version,
+            ephemeral_secret: accepted.ephemeral_secret,
+            identity_secret: self.keys.diffie_hellman(&identity),
+            kem_secret,
+            transcript,
+            capabilities: accepted.node_hello.capabilities,
+        };
+        Ok((identity, secrets))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kem::{CIPHERTEXT_CELLS, ENCAPSULATION_KEY_CELLS};
+
+    fn node_identity() -> StaticSecret {
+        StaticSecret::from([0x11u8; 32])
+    }
+
+    // Run both sides of a handshake, passing each message along unchanged
+    fn handshake(capabilities: u16) -> (PublicKey, HandshakeSecrets, HandshakeSecrets) {
+        let identity = node_identity();
+        let mut client = ClientHandshake::new(MAX_PROTOCOL_VERSION, capabilities, Some(&PublicKey::from(&identity)));
+        let response = respond(&identity, client.hello());
+        assert!(response.keyed);
+        let node = response.handshake.expect("handshake accepted");
+
+        let key_cells = client.read_reply(&response.reply).unwrap().unwrap_or_default();
+        assert_eq!(key_cells.len(), node.expected_cells());
+        let (ciphertext_cells, node_secrets) = node.finish(&key_cells).unwrap();
+        let (node_key, client_secrets) = client.finish(&ciphertext_cells).unwrap();
+        (node_key, client_secrets, node_secrets)
+    }
+
+    fn assert_agree(client: &HandshakeSecrets, node: &HandshakeSecrets) {
+        assert_eq!(client.version, node.version);
+        assert_eq!(client.transcript, node.transcript);
+        assert_eq!(client.ephemeral_secret.as_bytes(), node.ephemeral_secret.as_bytes());
+        assert_eq!(client.identity_secret.as_bytes(), node.identity_secret.as_bytes());
+        assert_eq!(client.kem_secret, node.kem_secret);
+        assert_eq!(client.capabilities, node.capabilities);
+
+        // Each side's confirmation opens on the other
+        let mut client_session = client.client_session();
+        let mut gateway_session = node.gateway_session();
+        gateway_session.check_confirmation(client_session.confirmation_cell().unwrap()).unwrap();
+        client_session.check_confirmation(gateway_session.confirmation_cell().unwrap()).unwrap();
+
+        // The client adds the layer the node removes, in both directions
+        let (mut client_layer, mut node_layer) = (client.onion_layer(), node.onion_layer());
+        let mut cell = [0x5au8; PACKET_SIZE];
+        client_layer.apply_forward(&mut cell);
+        assert_ne!(cell, [0x5au8; PACKET_SIZE]);
+        node_layer.apply_forward(&mut cell);
+        assert_eq!(cell, [0x5au8; PACKET_SIZE]);
+        node_layer.apply_backward(&mut cell);
+        client_layer.apply_backward(&mut cell);
+        assert_eq!(cell, [0x5au8; PACKET_SIZE]);
+    }
+
+    #[test]
+    fn classic_handshake_agrees() {
+        let (node_key, client, node) = handshake(0);
+        assert_eq!(node_key.as_bytes(), PublicKey::from(&node_identity()).as_bytes());
+        assert_eq!(client.version, MAX_PROTOCOL_VERSION);
+        assert!(client.kem_secret.is_none());
+        assert_agree(&client, &node);
+    }
+
+    #[test]
+    fn hybrid_handshake_agrees() {
+        let (_, client, node) = handshake(CAP_HYBRID_ML_KEM_768);
+        assert!(client.kem_secret.is_some());
+        assert_agree(&client, &node);
+    }
+
+    #[test]
+    fn hybrid_exchange_sizes() {
+        let identity = node_identity();
+        let mut client = ClientHandshake::new(MAX_PROTOCOL_VERSION, CAP_HYBRID_ML_KEM_768, None);
+        let response = respond(&identity, client.hello());
+        let key_cells = client.read_reply(&response.reply).unwrap().unwrap();
+        assert_eq!(key_cells.len(), ENCAPSULATION_KEY_CELLS);
+        let node = response.handshake.unwrap();
+        assert_eq!(node.expected_cells(), ENCAPSULATION_KEY_CELLS);
+        let (ciphertext_cells, _) = node.finish(&key_cells).unwrap();
+        assert_eq!(ciphertext_cells.len(), CIPHERTEXT_CELLS);
+    }
+
+    #[test]
+    fn tampered_kem_ciphertext_breaks_agreement() {
+        let identity = node_identity();
+        let mut client = ClientHandshake::new(MAX_PROTOCOL_VERSION, CAP_HYBRID_ML_KEM_768, None);
+        let response = respond(&identity, client.hello());
+        let key_cells = client.read_reply(&response.reply).unwrap().unwrap();
+        let (mut ciphertext_cells, node) = response.handshake.unwrap().finish(&key_cells).unwrap();
+        ciphertext_cells[0][40] ^= 0x01;
+        // ML-KEM decapsulation never fails outright; the keys just differ
+        let (_, client) = client.finish(&ciphertext_cells).unwrap();
+        assert_ne!(client.kem_secret, node.kem_secret);
+        assert!(node
+            .gateway_session()
+            .check_confirmation(client.client_session().confirmation_cell().unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn stripped_capability_breaks_agreement() {
+        // Someone on the path clears the hybrid bit so the node falls back to
+        // the classic handshake. The transcripts differ and confirmation fails.
+        let identity = node_identity();
+        let mut client = ClientHandshake::new(MAX_PROTOCOL_VERSION, CAP_HYBRID_ML_KEM_768, None);
+        let identity_secret = identity.diffie_hellman(&client_hello_public_key(client.hello()));
+        let (mut hello, _) = ClientHello::decode(client.hello(), &identity_secret);
+        hello.capabilities = 0;
+        let response = respond(&identity, &hello.encode(None));
+        assert_eq!(response.handshake.as_ref().unwrap().expected_cells(), 0);
+
+        assert!(client.read_reply(&response.reply).unwrap().is_none());
+        let (_, node) = response.handshake.unwrap().finish(&[]).unwrap();
+        let (_, client) = client.finish(&[]).unwrap();
+        assert_ne!(client.transcript, node.transcript);
+        assert!(node
+            .gateway_session()
+            .check_confirmation(client.client_session().confirmation_cell().unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn key_confirmation_proves_the_identity() {
+        let identity = node_identity();
+        let reply_confirmation = |cell: &[u8; PACKET_SIZE]| {
+            let mut confirmation = [0u8; KEY_CONFIRMATION_LEN];
+            confirmation.copy_from_slice(&cell[HANDSHAKE_REPLY_LEN..HANDSHAKE_REPLY_LEN + KEY_CONFIRMATION_LEN]);
+            confirmation
+        };
+
+        let mut client = ClientHandshake::new(MAX_PROTOCOL_VERSION, CAP_KEY_CONFIRMATION, None);
+        let response = respond(&identity, client.hello());
+        let cell = response.reply_cell();
+        assert_eq!(cell[..HANDSHAKE_REPLY_LEN], response.reply);
+        client.read_reply(&response.reply).unwrap();
+        let confirmed = client.confirm_identity(&reply_confirmation(&cell)).unwrap();
+        assert_eq!(confirmed.as_bytes(), PublicKey::from(&identity).as_bytes());
+
+        // A node that names a key it does not hold cannot compute it: this one
+        // answers with another secret and rewrites the key in its hello
+        let mut client = ClientHandshake::new(MAX_PROTOCOL_VERSION, CAP_KEY_CONFIRMATION, None);
+        let mut response = respond(&StaticSecret::from([0x22u8; 32]), client.hello());
+        let ephemeral_secret = &response.handshake.as_ref().unwrap().ephemeral_secret;
+        let client_representative: [u8; 32] = client.hello()[..REPRESENTATIVE_LEN].try_into().unwrap();
+        let node_representative: [u8; 32] = response.reply[..REPRESENTATIVE_LEN].try_into().unwrap();
+        let keystream = || derive_handshake_obfuscation(ephemeral_secret, &client_representative, &node_representative).to_client;
+        let mut fields: [u8; HANDSHAKE_REPLY_LEN - REPRESENTATIVE_LEN] = response.reply[REPRESENTATIVE_LEN..].try_into().unwrap();
+        keystream().apply_keystream(&mut fields);
+        let mut node_hello = NodeHello::decode(&fields);
+        node_hello.identity = PublicKey::from(&identity);
+        fields = node_hello.encode();
+        keystream().apply_keystream(&mut fields);
+        response.reply[REPRESENTATIVE_LEN..].copy_from_slice(&fields);
+        client.read_reply(&response.reply).unwrap();
+        assert!(client.confirm_identity(&reply_confirmation(&response.reply_cell())).is_err());
+
+        // Nothing to check unless the node selected the capability
+        let mut client = ClientHandshake::new(MAX_PROTOCOL_VERSION, 0, None);
+        let response = respond(&identity, client.hello());
+        client.read_reply(&response.reply).unwrap();
+        assert!(client.confirm_identity(&reply_confirmation(&response.reply_cell())).is_err());
+    }
+
+    #[test]
+    fn unsupported_versions_are_rejected() {
+        let identity = node_identity();
+        let hello = ClientHello {
+            min_version: MAX_PROTOCOL_VERSION + 1,
+            max_version: MAX_PROTOCOL_VERSION + 2,
+            capabilities: 0,
+            representative: EphemeralKeys::generate().representative,
+        };
+        assert!(respond(&identity, &hello.encode(None)).handshake.is_none());
+
+        let mut client = ClientHandshake::new(MIN_PROTOCOL_VERSION - 1, 0, None);
+        let response = respond(&identity, client.hello());
+        assert!(response.handshake.is_none());
+        let error = client.read_reply(&response.reply).unwrap_err();
+        let rejected = error.downcast_ref::<ProtocolVersionRejected>().unwrap();
+        assert_eq!(rejected.node_max, MAX_PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn keyed_hellos_open_only_for_the_named_node() {
+        let identity = node_identity();
+        let other = StaticSecret::from([0x22u8; 32]);
+        let client = ClientHandshake::new(MAX_PROTOCOL_VERSION, 0, Some(&PublicKey::from(&identity)));
+        let client_public = client_hello_public_key(client.hello());
+
+        let (hello, keyed) = ClientHello::decode(client.hello(), &identity.diffie_hellman(&client_public));
+        assert!(keyed);
+        assert_eq!((hello.min_version, hello.max_version), (MIN_PROTOCOL_VERSION, MAX_PROTOCOL_VERSION));
+
+        // Without the identity secret the MAC does not match and the unkeyed
+        // mask does not reveal the fields
+        let (hello, keyed) = ClientHello::decode(client.hello(), &other.diffie_hellman(&client_public));
+        assert!(!keyed);
+        assert_ne!(
+            (hello.min_version, hello.max_version, hello.capabilities),
+            (MIN_PROTOCOL_VERSION, MAX_PROTOCOL_VERSION, 0)
+        );
+
+        // Any change to the masked fields breaks the MAC
+        let mut tampered = *client.hello();
+        tampered[REPRESENTATIVE_LEN] ^= 0x01;
+        assert!(!respond(&identity, &tampered).keyed);
+
+        // A client that does not know the key still gets an answer
+        let client = ClientHandshake::new(MAX_PROTOCOL_VERSION, 0, None);
+        let response = respond(&identity, client.hello());
+        assert!(!response.keyed);
+        assert!(response.handshake.is_some());
+    }
+
+    // A version 2 client's hello: versions in the clear and a plain key
+    fn legacy_hello(keys: &EphemeralKeys, min_version: u8, capabilities: u16) -> [u8; LEGACY_CLIENT_HELLO_LEN] {
+        let mut hello = [0u8; LEGACY_CLIENT_HELLO_LEN];
+        hello[0] = min_version;
+        hello[1] = LEGACY_PROTOCOL_VERSION;
+        hello[2..4].copy_from_slice(&capabilities.to_be_bytes());
+        hello[4..].copy_from_slice(keys.public_key().as_bytes());
+        hello
+    }
+
+    #[test]
+    fn legacy_hellos_are_answered_in_the_old_framing() {
+        let identity = node_identity();
+        let keys = EphemeralKeys::generate();
+        let hello = legacy_hello(&keys, 1, CAP_HYBRID_ML_KEM_768 | CAP_KEY_CONFIRMATION);
+        let response = respond_legacy(&identity, &hello);
+        let reply = response.reply;
+        assert_eq!(reply[..4], [LEGACY_PROTOCOL_VERSION, MAX_PROTOCOL_VERSION, 0, 1]);
+        assert_eq!(reply[36..], *PublicKey::from(&identity).as_bytes());
+
+        // The ML-KEM cells travel in the clear
+        let kem_keys = KemKeyPair::generate();
+        let key_cells = kem::encode_cells(kem_keys.encapsulation_key()).unwrap();
+        let (ciphertext_cells, node) = response.handshake.unwrap().finish(&key_cells).unwrap();
+        let ciphertext = kem::decode_cells(&ciphertext_cells, CIPHERTEXT_LEN).unwrap();
+
+        // What a version 2 client derives from the reply
+        let node_ephemeral: [u8; 32] = reply[4..36].try_into().unwrap();
+        let client = HandshakeSecrets {
+            version: LEGACY_PROTOCOL_VERSION,
+            ephemeral_secret: keys.diffie_hellman(&PublicKey::from(node_ephemeral)),
+            identity_secret: keys.diffie_hellman(&PublicKey::from(&identity)),
+            kem_secret: Some(kem_keys.decapsulate(&ciphertext).unwrap()),
+            transcript: handshake_transcript(&hello, &reply, Some((kem_keys.encapsulation_key(), &ciphertext))),
+            capabilities: CAP_HYBRID_ML_KEM_768,
+        };
+        assert_agree(&client, &node);
+    }
+
+    #[test]
+    fn legacy_hellos_are_told_apart() {
+        let keys = EphemeralKeys::generate();
+        assert!(is_legacy_hello(&legacy_hello(&keys, 1, 0)));
+        assert!(is_legacy_hello(&legacy_hello(&keys, LEGACY_PROTOCOL_VERSION, CAP_HYBRID_ML_KEM_768)));
+        assert!(!is_legacy_hello(&legacy_hello(&keys, 0, 0)));
+        assert!(!is_legacy_hello(&legacy_hello(&keys, 1, 0x8000)));
+
+        // The start of a padded hello is a random representative
+        for _ in 0..64 {
+            let client = ClientHandshake::new(MAX_PROTOCOL_VERSION, SUPPORTED_CAPABILITIES, None);
+            assert!(!is_legacy_hello(client.hello()[..LEGACY_CLIENT_HELLO_LEN].try_into().unwrap()));
+        }
+
+        // A version 1 client is rejected with an all-zero ephemeral key
+        let mut hello = legacy_hello(&keys, 1, 0);
+        hello[1] = 1;
+        assert!(is_legacy_hello(&hello));
+        let response = respond_legacy(&node_identity(), &hello);
+        assert!(response.handshake.is_none());
+        assert_eq!(response.reply[0], 0);
+        assert_eq!(response.reply[4..36], [0u8; 32]);
+    }
+
+    #[test]
+    fn version_negotiation() {
+        assert_eq!(negotiate_version(MIN_PROTOCOL_VERSION, u8::MAX), Some(MAX_PROTOCOL_VERSION));
+        assert_eq!(negotiate_version(0, MIN_PROTOCOL_VERSION), Some(MIN_PROTOCOL_VERSION));
+        assert_eq!(negotiate_version(0, MIN_PROTOCOL_VERSION - 1), None);
+        assert_eq!(negotiate_version(MAX_PROTOCOL_VERSION + 1, u8::MAX), None);
+    }
+
+    #[test]
+    fn hellos_round_trip() {
+        let hello = ClientHello {
+            min_version: 2,
+            max_version: 9,
+            capabilities: 0xbeef,
+            representative: [3u8; 32],
+        };
+        let identity_secret = node_identity().diffie_hellman(&hello.public_key());
+        for keyed in [false, true] {
+            let encoded = hello.encode(keyed.then_some(&identity_secret));
+            assert_ne!(encoded[32..36], [2, 9, 0xbe, 0xef]);
+            let (decoded, decoded_keyed) = ClientHello::decode(&encoded, &identity_secret);
+            assert_eq!(decoded_keyed, keyed);
+            assert_eq!(
+                (decoded.min_version, decoded.max_version, decoded.capabilities, decoded.representative),
+                (2, 9, 0xbeef, [3u8; 32])
+            );
+        }
+
+        let node_hello = NodeHello {
+            version: 3,
+            max_version: 4,
+            capabilities: CAP_HYBRID_ML_KEM_768,
+            identity: PublicKey::from(&node_identity()),
+        };
+        let decoded = NodeHello::decode(&node_hello.encode());
+        assert_eq!((decoded.version, decoded.max_version, decoded.capabilities), (3, 4, CAP_HYBRID_ML_KEM_768));
+        assert_eq!(decoded.identity.as_bytes(), node_hello.identity.as_bytes());
+        assert!(!decoded.is_rejection());
+    }
+
+    #[test]
+    fn representatives_map_back_to_keys() {
+        let keys = EphemeralKeys::generate();
+        let peer = StaticSecret::from([0x22u8; 32]);
+        let public = public_key_from_representative(&keys.representative);
+        assert_eq!(
+            peer.diffie_hellman(&public).as_bytes(),
+            keys.diffie_hellman(&PublicKey::from(&peer)).as_bytes()
+        );
+    }
+}
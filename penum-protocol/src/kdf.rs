@@ -0,0 +1,183 @@
+//! Key derivation from a completed handshake.
+//!
+//! Both DH outputs of the handshake, followed by the ML-KEM secret of a hybrid
+//! handshake, are fed to HKDF-SHA256 with salt `penum-v1`. The info string is a label followed by the handshake
+//! transcript, so keys depend on the exact hellos both sides exchanged.
+
+use crate::handshake::HandshakeSecrets;
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::ChaCha20;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::SharedSecret;
+
+const SALT: &[u8] = b"penum-v1";
+
+// Combine the ephemeral-ephemeral and ephemeral-identity DH outputs.
+// Only the holder of the identity secret can compute the second one, so an
+// impostor that substitutes its own keys ends up with different session keys.
+// The ML-KEM secret is appended, so a hybrid key stays secret as long as
+// either X25519 or ML-KEM holds.
+fn handshake_hkdf(secrets: &HandshakeSecrets) -> Hkdf<Sha256> {
+    let mut ikm = Vec::with_capacity(96);
+    ikm.extend_from_slice(secrets.ephemeral_secret.as_bytes());
+    ikm.extend_from_slice(secrets.identity_secret.as_bytes());
+    if let Some(kem_secret) = &secrets.kem_secret {
+        ikm.extend_from_slice(kem_secret);
+    }
+    Hkdf::<Sha256>::new(Some(SALT), &ikm)
+}
+
+// HKDF info: label || handshake transcript
+fn expand_info(label: &[u8], transcript: &[u8; 32]) -> Vec<u8> {
+    [label, transcript.as_slice()].concat()
+}
+
+/// End-to-end keys shared by the client and the gateway.
+///
+/// Each direction has its own AEAD key, so a nonce counter never repeats under
+/// the same key even though both sides start counting at zero. The
+/// confirmation values prove to the other side that the same keys were derived.
+pub struct SessionKeys {
+    /// AEAD key for cells sent by the client.
+    pub client_to_server: [u8; 32],
+    /// AEAD key for cells sent by the gateway.
+    pub server_to_client: [u8; 32],
+    /// Sent by the client in its first cell.
+    pub client_confirmation: [u8; 32],
+    /// Sent by the gateway in its first cell.
+    pub server_confirmation: [u8; 32],
+}
+
+/// Derive the end-to-end session keys.
+pub fn derive_session_keys(secrets: &HandshakeSecrets) -> SessionKeys {
+    let hk = handshake_hkdf(secrets);
+    let expand = |label: &[u8]| {
+        let mut okm = [0u8; 32];
+        hk.expand(&expand_info(label, &secrets.transcript), &mut okm)
+            .expect("HKDF expand failed");
+        okm
+    };
+
+    SessionKeys {
+        client_to_server: expand(b"penum-session-c2s"),
+        server_to_client: expand(b"penum-session-s2c"),
+        client_confirmation: expand(b"penum-confirm-client"),
+        server_confirmation: expand(b"penum-confirm-server"),
+    }
+}
+
+/// One onion layer shared between the client and a single relay hop.
+///
+/// Each direction has its own keystream which advances with every cell, so
+/// the same key is never applied twice to different data. Layers are
+/// length-preserving and carry no authentication; integrity is provided by
+/// the end-to-end AEAD between client and gateway.
+pub struct OnionLayer {
+    /// Client → relay direction.
+    pub forward: ChaCha20,
+    /// Relay → client direction.
+    pub backward: ChaCha20,
+}
+
+impl OnionLayer {
+    /// Add (client) or remove (relay) this layer on a cell heading towards the gateway.
+    pub fn apply_forward(&mut self, cell: &mut [u8]) {
+        self.forward.apply_keystream(cell);
+    }
+
+    /// Add (relay) or remove (client) this layer on a cell heading towards the client.
+    pub fn apply_backward(&mut self, cell: &mut [u8]) {
+        self.backward.apply_keystream(cell);
+    }
+}
+
+/// Derive the onion layer for one relay hop.
+pub fn derive_onion_layer(secrets: &HandshakeSecrets) -> OnionLayer {
+    let hk = handshake_hkdf(secrets);
+    let mut forward_key = [0u8; 32];
+    let mut backward_key = [0u8; 32];
+    hk.expand(&expand_info(b"penum-hop-forward", &secrets.transcript), &mut forward_key)
+        .expect("HKDF expand failed");
+    hk.expand(&expand_info(b"penum-hop-backward", &secrets.transcript), &mut backward_key)
+        .expect("HKDF expand failed");
+
+    // Keys are unique per hop handshake, so a fixed IV is safe here
+    OnionLayer {
+        forward: keystream(forward_key),
+        backward: keystream(backward_key),
+    }
+}
+
+// Key confirmation sent after the node hello (see
+// `CAP_KEY_CONFIRMATION`). It only needs the two DH outputs and the hellos,
+// so the node can send it before any ML-KEM exchange.
+pub(crate) fn derive_identity_confirmation(
+    ephemeral_secret: &SharedSecret,
+    identity_secret: &SharedSecret,
+    transcript: &[u8; 32],
+) -> [u8; 32] {
+    let ikm = [ephemeral_secret.as_bytes().as_slice(), identity_secret.as_bytes()].concat();
+    let hk = Hkdf::<Sha256>::new(Some(SALT), &ikm);
+    let mut okm = [0u8; 32];
+    hk.expand(&expand_info(b"penum-confirm-identity", transcript), &mut okm)
+        .expect("HKDF expand failed");
+    okm
+}
+
+pub(crate) fn keystream(key: [u8; 32]) -> ChaCha20 {
+    ChaCha20::new(&key.into(), &[0u8; 12].into())
+}
+
+// Keystreams that hide the node hello and the ML-KEM cells on one link. They
+// only need the ephemeral DH, which both sides have as soon as they have seen
+// each other's representative.
+pub(crate) struct HandshakeObfuscation {
+    pub(crate) to_node: ChaCha20,
+    pub(crate) to_client: ChaCha20,
+}
+
+pub(crate) fn derive_handshake_obfuscation(
+    ephemeral_secret: &SharedSecret,
+    client_representative: &[u8; 32],
+    node_representative: &[u8; 32],
+) -> HandshakeObfuscation {
+    let hk = Hkdf::<Sha256>::new(Some(SALT), ephemeral_secret.as_bytes());
+    let representatives = [client_representative.as_slice(), node_representative].concat();
+    let expand = |label: &[u8]| {
+        let mut okm = [0u8; 32];
+        hk.expand(&[label, &representatives].concat(), &mut okm)
+            .expect("HKDF expand failed");
+        okm
+    };
+
+    HandshakeObfuscation {
+        to_node: keystream(expand(b"penum-obfs-to-node")),
+        to_client: keystream(expand(b"penum-obfs-to-client")),
+    }
+}
+
+// Keystream masking the version and capability bytes of a client hello for a
+// node whose identity key the client does not know. It is derived from the
+// representative alone, so it only keeps fixed bytes out of the cell; an
+// observer that implements this protocol can remove it.
+pub(crate) fn hello_mask(representative: &[u8; 32]) -> ChaCha20 {
+    let hk = Hkdf::<Sha256>::new(Some(SALT), representative);
+    let mut key = [0u8; 32];
+    hk.expand(b"penum-hello-mask", &mut key).expect("HKDF expand failed");
+    keystream(key)
+}
+
+// Mask and MAC key for a client hello to a node whose identity key the client
+// knows. Both come from `DH(e, node identity)`, so only the node can remove
+// the mask or check the MAC; to anyone else the hello stays random bytes.
+pub(crate) fn keyed_hello_keys(identity_secret: &SharedSecret, representative: &[u8; 32]) -> (ChaCha20, [u8; 32]) {
+    let hk = Hkdf::<Sha256>::new(Some(SALT), identity_secret.as_bytes());
+    let expand = |label: &[u8]| {
+        let mut okm = [0u8; 32];
+        hk.expand(&[label, representative.as_slice()].concat(), &mut okm)
+            .expect("HKDF expand failed");
+        okm
+    };
+    (keystream(expand(b"penum-hello-keyed-mask")), expand(b"penum-hello-mac"))
+}
@@ -0,0 +1,101 @@
+//! ML-KEM-768 exchange for the hybrid post-quantum handshake.
+//!
+//! When both sides set [`CAP_HYBRID_ML_KEM_768`](crate::handshake::CAP_HYBRID_ML_KEM_768)
+//! in their hellos, two more messages follow:
+//!
+//! ```text
+//! client → node: ML-KEM-768 encapsulation key (1184 bytes, 2 cells)
+//! node → client: ML-KEM-768 ciphertext (1088 bytes, 2 cells)
+//! ```
+//!
+//! Both are sent as plaintext handshake cells on stream 0, split like any
+//! other multi-cell message, so every link still only carries full cells.
+//! The shared secret is added to the X25519 outputs in the key derivation:
+//! recorded traffic stays secret unless both X25519 and ML-KEM are broken.
+
+use crate::cell::{CellType, Packet, Reassembler, PACKET_SIZE, PAYLOAD_LEN};
+use ml_kem::kem::{Decapsulate, Encapsulate};
+use ml_kem::{Ciphertext, Encoded, EncodedSizeUser, KemCore, MlKem768};
+use rand::thread_rng;
+
+type DecapsulationKey = <MlKem768 as KemCore>::DecapsulationKey;
+type EncapsulationKey = <MlKem768 as KemCore>::EncapsulationKey;
+
+/// Length of an ML-KEM-768 encapsulation key.
+pub const ENCAPSULATION_KEY_LEN: usize = 1184;
+/// Length of an ML-KEM-768 ciphertext.
+pub const CIPHERTEXT_LEN: usize = 1088;
+/// Cells carrying the encapsulation key.
+pub const ENCAPSULATION_KEY_CELLS: usize = ENCAPSULATION_KEY_LEN.div_ceil(PAYLOAD_LEN);
+/// Cells carrying the ciphertext.
+pub const CIPHERTEXT_CELLS: usize = CIPHERTEXT_LEN.div_ceil(PAYLOAD_LEN);
+
+/// Client key pair for a single hybrid handshake.
+pub struct KemKeyPair {
+    decapsulation_key: DecapsulationKey,
+    encapsulation_key: Vec<u8>,
+}
+
+impl KemKeyPair {
+    /// Generate a fresh key pair from the OS RNG.
+    pub fn generate() -> Self {
+        let (decapsulation_key, encapsulation_key) = MlKem768::generate(&mut thread_rng());
+        Self {
+            decapsulation_key,
+            encapsulation_key: encapsulation_key.as_bytes().to_vec(),
+        }
+    }
+
+    /// Encoded encapsulation key, sent to the node.
+    pub fn encapsulation_key(&self) -> &[u8] {
+        &self.encapsulation_key
+    }
+
+    /// Recover the shared secret from the node's ciphertext.
+    pub fn decapsulate(&self, ciphertext: &[u8]) -> anyhow::Result<[u8; 32]> {
+        let ciphertext = Ciphertext::<MlKem768>::try_from(ciphertext)
+            .map_err(|_| anyhow::anyhow!("Invalid ML-KEM ciphertext"))?;
+        let shared = self
+            .decapsulation_key
+            .decapsulate(&ciphertext)
+            .map_err(|_| anyhow::anyhow!("ML-KEM decapsulation failed"))?;
+        Ok(shared.into())
+    }
+}
+
+/// Node side: encapsulate a fresh secret to the client's key.
+/// Returns the ciphertext to send back and the shared secret.
+pub fn encapsulate(encapsulation_key: &[u8]) -> anyhow::Result<(Vec<u8>, [u8; 32])> {
+    let encoded = Encoded::<EncapsulationKey>::try_from(encapsulation_key)
+        .map_err(|_| anyhow::anyhow!("Invalid ML-KEM encapsulation key"))?;
+    let (ciphertext, shared) = EncapsulationKey::from_bytes(&encoded)
+        .encapsulate(&mut thread_rng())
+        .map_err(|_| anyhow::anyhow!("ML-KEM encapsulation failed"))?;
+    Ok((ciphertext.to_vec(), shared.into()))
+}
+
+/// Split a handshake message into plaintext handshake cells on stream 0.
+pub fn encode_cells(message: &[u8]) -> anyhow::Result<Vec<[u8; PACKET_SIZE]>> {
+    Packet::split_message(CellType::Handshake, 0, message)?
+        .iter()
+        .map(|(header, payload)| Packet::encode(header, payload))
+        .collect()
+}
+
+/// Reassemble a handshake message of exactly `expected_len` bytes.
+pub fn decode_cells(cells: &[[u8; PACKET_SIZE]], expected_len: usize) -> anyhow::Result<Vec<u8>> {
+    let mut reassembler = Reassembler::new(0);
+    let mut message = None;
+    for cell in cells {
+        let (header, payload) = Packet::decode(cell)?;
+        if header.cell_type != CellType::Handshake || message.is_some() {
+            return Err(anyhow::anyhow!("Invalid handshake cell"));
+        }
+        message = reassembler.push(&header, payload)?;
+    }
+
+    match message {
+        Some(message) if message.len() == expected_len => Ok(message),
+        _ => Err(anyhow::anyhow!("Invalid handshake message")),
+    }
+}
@@ -0,0 +1,45 @@
+//! Wire protocol spoken by Penum clients, relays and gateways.
+//!
+//! A client reaches a gateway through a telescoping onion circuit
+//! (`client → entry relay → middle relay → gateway`). This crate contains
+//! everything needed to take part in such a circuit:
+//!
+//! - [`handshake`]: the versioned X25519 handshake every node answers, for both
+//!   the client side ([`handshake::ClientHandshake`]) and the node side
+//!   ([`handshake::respond`]).
+//! - [`kdf`]: derivation of the end-to-end session keys and of the per-hop
+//!   onion layers, bound to the handshake transcript.
+//! - [`kem`]: the optional ML-KEM-768 exchange that makes the handshake a
+//!   hybrid post-quantum key exchange.
+//! - [`session`]: the end-to-end session between client and gateway, with
+//!   directional keys, counter nonces and key confirmation.
+//! - [`cell`]: the fixed 1024-byte cell layout, AEAD sealing and opening of
+//!   end-to-end cells, multi-cell messages and stream flow control constants.
+//! - [`extend`]: the payload of the handshake cell that asks a relay to extend
+//!   a circuit to its next hop.
+//! - [`sphinx`]: self-contained Sphinx packets and single-use reply blocks for
+//!   the mixnet mode, an alternative to circuits.
+//! - [`directory`]: the signed list of relays and gateways that clients
+//!   build their paths from.
+//!
+//! # Stability
+//!
+//! The wire format is versioned separately from this crate: a node announces
+//! the protocol versions it speaks ([`handshake::MIN_PROTOCOL_VERSION`] to
+//! [`handshake::MAX_PROTOCOL_VERSION`]) and the handshake selects one. The Rust
+//! API follows semver. Enums that will grow with the protocol are marked
+//! `#[non_exhaustive]`, so adding a cell type is not a breaking change.
+
+#![warn(missing_docs)]
+
+mod aead;
+pub mod cell;
+pub mod directory;
+pub mod extend;
+pub mod handshake;
+pub mod kdf;
+pub mod kem;
+pub mod session;
+pub mod sphinx;
+
+pub use x25519_dalek::{PublicKey, StaticSecret};
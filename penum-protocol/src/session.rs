@@ -0,0 +1,73 @@
+//! End-to-end session between a client and a gateway.
+//!
+//! After the handshake each side builds a [`Session`] from the
+//! [`HandshakeSecrets`]. Cells are sealed with the sender's directional key
+//! and a counter nonce, so both directions can carry any number of cells
+//! without a nonce ever repeating.
+//!
+//! Before any data, each side sends one confirmation cell: a handshake cell
+//! on stream 0 whose payload is its confirmation value. The gateway sends its
+//! confirmation right after the handshake and the client checks it before
+//! sending a request. A mismatch means the two sides did not derive the same
+//! keys, for example because someone on the path altered the handshake.
+
+use crate::cell::{CellHeader, CellOpener, CellSealer, CellType, FLAG_END, PACKET_SIZE};
+use crate::handshake::HandshakeSecrets;
+use crate::kdf::derive_session_keys;
+use subtle::ConstantTimeEq;
+
+/// One end of an end-to-end session.
+pub struct Session {
+    sealer: CellSealer,
+    opener: CellOpener,
+    our_confirmation: [u8; 32],
+    their_confirmation: [u8; 32],
+}
+
+impl Session {
+    /// Client end: seals with the client-to-server key.
+    pub fn client(secrets: &HandshakeSecrets) -> Self {
+        let keys = derive_session_keys(secrets);
+        Self {
+            sealer: CellSealer::new(keys.client_to_server),
+            opener: CellOpener::new(keys.server_to_client),
+            our_confirmation: keys.client_confirmation,
+            their_confirmation: keys.server_confirmation,
+        }
+    }
+
+    /// Gateway end: seals with the server-to-client key.
+    pub fn gateway(secrets: &HandshakeSecrets) -> Self {
+        let keys = derive_session_keys(secrets);
+        Self {
+            sealer: CellSealer::new(keys.server_to_client),
+            opener: CellOpener::new(keys.client_to_server),
+            our_confirmation: keys.server_confirmation,
+            their_confirmation: keys.client_confirmation,
+        }
+    }
+
+    /// Seal our confirmation cell. It must be the first cell we send.
+    pub fn confirmation_cell(&mut self) -> anyhow::Result<[u8; PACKET_SIZE]> {
+        let header = CellHeader::new(CellType::Handshake, 0, 0, self.our_confirmation.len()).with_flags(FLAG_END);
+        self.sealer.seal(&header, &self.our_confirmation)
+    }
+
+    /// Open the peer's first cell and check that it carries their confirmation.
+    /// Returns the cell's header, whose timestamp the gateway checks for replays.
+    pub fn check_confirmation(&mut self, cell: [u8; PACKET_SIZE]) -> anyhow::Result<CellHeader> {
+        let (header, payload) = self
+            .opener
+            .open(cell)
+            .map_err(|_| anyhow::anyhow!("Key confirmation failed"))?;
+        if header.cell_type != CellType::Handshake || !bool::from(payload.ct_eq(&self.their_confirmation)) {
+            return Err(anyhow::anyhow!("Key confirmation failed"));
+        }
+        Ok(header)
+    }
+
+    /// Split into the sealer and opener for the rest of the session.
+    pub fn into_parts(self) -> (CellSealer, CellOpener) {
+        (self.sealer, self.opener)
+    }
+}
@@ -0,0 +1,834 @@
+//! Sphinx packets with single-use reply blocks, for the mixnet mode.
+//!
+//! Instead of building a circuit, the client can send each request as one
+//! self-contained packet through `entry → middle → gateway`:
+//!
+//! ```text
+//! alpha (32) | routing info (240) | MAC (32) | payload (7888)
+//! ```
+//!
+//! Every hop computes a shared secret from `alpha` and its identity key,
+//! checks the MAC, decrypts its 80-byte slot of routing info and shifts the
+//! rest along, blinds `alpha` and removes one layer of payload encryption. The
+//! header stays the same size and the payload is a wide-block cipher
+//! (LIONESS), so a packet looks unrelated before and after each hop, even to a
+//! relay that records everything. A modified payload turns into noise and is
+//! rejected by the gateway.
+//!
+//! The gateway does not answer over the incoming connection. The request
+//! carries single-use reply blocks ([`Surb`]): a header prepared by the client
+//! for the route `middle → entry`, the address of its first hop and a key for
+//! the reply payload. Each reply packet ends at the entry relay, which hands
+//! it to the client connection that registered the mailbox named in the
+//! block.
+//!
+//! Each hop's routing info carries its own expiry time, rounded up to
+//! [`EXPIRY_GRANULARITY_SECS`] after a random extra lifetime drawn for that
+//! hop alone. Many packets share each value and the hops of one packet
+//! usually see different ones, so the expiry neither tells packets apart nor
+//! links a packet's hops. Nodes drop expired packets and remember the replay
+//! tag of every other one until it expires, so a recorded packet cannot be
+//! sent again.
+
+use crate::cell::unix_time;
+use crate::extend::{encode_addr, parse_addr, ADDR_LEN};
+use crate::kdf::keystream;
+use chacha20::cipher::StreamCipher;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::{thread_rng, RngCore};
+use sha2::Sha256;
+use std::net::SocketAddr;
+use subtle::ConstantTimeEq;
+use x25519_dalek::{x25519, PublicKey, StaticSecret, X25519_BASEPOINT_BYTES};
+
+/// Size of every packet on a mixnet link.
+pub const SPHINX_PACKET_SIZE: usize = 8192;
+/// Longest route a header can describe.
+pub const MAX_HOPS: usize = 3;
+
+const ROUTING_LEN: usize = 48;
+const MAC_LEN: usize = 32;
+const HOP_LEN: usize = ROUTING_LEN + MAC_LEN;
+const BETA_LEN: usize = MAX_HOPS * HOP_LEN;
+
+/// Length of a packet header.
+pub const HEADER_LEN: usize = 32 + BETA_LEN + MAC_LEN;
+/// Length of the encrypted payload.
+pub const SPHINX_PAYLOAD_LEN: usize = SPHINX_PACKET_SIZE - HEADER_LEN;
+
+// Zero bytes at the start of a payload, intact only if no hop modified it
+const TAG_LEN: usize = 16;
+
+/// Longest message that fits into one payload.
+pub const MAX_BODY_LEN: usize = SPHINX_PAYLOAD_LEN - TAG_LEN - 2;
+/// Length of an encoded reply block.
+pub const SURB_LEN: usize = ADDR_LEN + HEADER_LEN + 32;
+/// Response bytes carried by one reply packet.
+pub const REPLY_PART_LEN: usize = MAX_BODY_LEN - 2;
+
+const SALT: &[u8] = b"penum-sphinx-v1";
+
+/// Hop expiry times are multiples of this many seconds.
+pub const EXPIRY_GRANULARITY_SECS: u64 = 60;
+/// Shortest time a packet or reply block stays valid at each hop.
+pub const MIN_LIFETIME_SECS: u64 = 120;
+/// Up to this much extra lifetime, drawn separately for every hop.
+pub const EXPIRY_JITTER_SECS: u64 = 180;
+/// Longest time a packet can be valid at a hop. Nodes refuse expiry times
+/// further ahead, which would have to be remembered longer.
+pub const MAX_LIFETIME_SECS: u64 = MIN_LIFETIME_SECS + EXPIRY_JITTER_SECS + EXPIRY_GRANULARITY_SECS;
+
+/// A full Sphinx packet.
+pub type SphinxPacket = [u8; SPHINX_PACKET_SIZE];
+
+/// Identifies a client connection at its entry relay, for reply delivery.
+pub type Mailbox = [u8; 16];
+
+const COMMAND_FORWARD: u8 = 1;
+const COMMAND_DELIVER: u8 = 2;
+const COMMAND_EXIT: u8 = 3;
+
+/// What a hop does with a packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Send the processed packet on to `next_hop`. With `reply_mailbox` set,
+    /// replies addressed to that mailbox are delivered over the connection the
+    /// packet arrived on; the client sets it for its entry relay.
+    Forward {
+        /// Mix listener of the next hop.
+        next_hop: SocketAddr,
+        /// Mailbox to register for the incoming connection.
+        reply_mailbox: Option<Mailbox>,
+    },
+    /// Last hop of a reply: hand the payload to the client holding `mailbox`.
+    Deliver {
+        /// Mailbox registered by the client.
+        mailbox: Mailbox,
+        /// Which of the client's reply blocks this packet used.
+        index: u8,
+    },
+    /// Last hop of a request: the gateway reads the payload.
+    Exit,
+}
+
+// command (1) | expiry (8) | address (19) | mailbox (16) | index (1) | zero (3)
+fn encode_routing(command: &Command, expiry: u64) -> [u8; ROUTING_LEN] {
+    let mut routing = [0u8; ROUTING_LEN];
+    routing[1..9].copy_from_slice(&expiry.to_be_bytes());
+    match command {
+        Command::Forward { next_hop, reply_mailbox } => {
+            routing[0] = COMMAND_FORWARD;
+            routing[9..28].copy_from_slice(&encode_addr(*next_hop));
+            if let Some(mailbox) = reply_mailbox {
+                routing[28..44].copy_from_slice(mailbox);
+            }
+        }
+        Command::Deliver { mailbox, index } => {
+            routing[0] = COMMAND_DELIVER;
+            routing[28..44].copy_from_slice(mailbox);
+            routing[44] = *index;
+        }
+        Command::Exit => routing[0] = COMMAND_EXIT,
+    }
+    routing
+}
+
+fn decode_routing(routing: &[u8]) -> Option<(Command, u64)> {
+    let expiry = u64::from_be_bytes(routing[1..9].try_into().ok()?);
+    let mailbox: Mailbox = routing[28..44].try_into().ok()?;
+    let command = match routing[0] {
+        COMMAND_FORWARD => Command::Forward {
+            next_hop: parse_addr(&routing[9..28])?,
+            reply_mailbox: (mailbox != [0u8; 16]).then_some(mailbox),
+        },
+        COMMAND_DELIVER => Command::Deliver {
+            mailbox,
+            index: routing[44],
+        },
+        COMMAND_EXIT => Command::Exit,
+        _ => return None,
+    };
+    Some((command, expiry))
+}
+
+// Expiry for one hop of a packet created at `now`
+fn hop_expiry(now: u64) -> u64 {
+    let jitter = thread_rng().next_u64() % (EXPIRY_JITTER_SECS + 1);
+    (now + MIN_LIFETIME_SECS + jitter).div_ceil(EXPIRY_GRANULARITY_SECS) * EXPIRY_GRANULARITY_SECS
+}
+
+// Keys one hop derives from its shared secret
+struct HopKeys {
+    routing: [u8; 32],
+    mac: [u8; 32],
+    payload: LionessKeys,
+    blinding: [u8; 32],
+    replay_tag: [u8; 32],
+}
+
+fn hop_keys(shared: &[u8; 32], alpha: &[u8; 32]) -> HopKeys {
+    let hk = Hkdf::<Sha256>::new(Some(SALT), shared);
+    let expand = |label: &[u8]| {
+        let mut okm = [0u8; 32];
+        hk.expand(&[label, alpha].concat(), &mut okm)
+            .expect("HKDF expand failed");
+        okm
+    };
+
+    HopKeys {
+        routing: expand(b"penum-sphinx-routing"),
+        mac: expand(b"penum-sphinx-mac"),
+        payload: lioness_keys(&hk, alpha),
+        blinding: expand(b"penum-sphinx-blind"),
+        replay_tag: expand(b"penum-sphinx-replay"),
+    }
+}
+
+fn mac(key: &[u8; 32], data: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+fn prg(key: [u8; 32], len: usize) -> Vec<u8> {
+    let mut stream = vec![0u8; len];
+    keystream(key).apply_keystream(&mut stream);
+    stream
+}
+
+fn xor(data: &mut [u8], stream: &[u8]) {
+    data.iter_mut().zip(stream).for_each(|(byte, key)| *byte ^= key);
+}
+
+// LIONESS over the whole payload: any change to the ciphertext scrambles all
+// of the plaintext, so the zero tag catches tampering at the final hop
+type LionessKeys = [[u8; 32]; 4];
+
+fn lioness_keys(hk: &Hkdf<Sha256>, context: &[u8]) -> LionessKeys {
+    let mut keys = [[0u8; 32]; 4];
+    for (i, key) in keys.iter_mut().enumerate() {
+        hk.expand(&[b"penum-sphinx-payload".as_slice(), context, &[i as u8]].concat(), key)
+            .expect("HKDF expand failed");
+    }
+    keys
+}
+
+fn lioness_round_stream(block: &mut [u8], key: &[u8; 32]) {
+    let (left, right) = block.split_at_mut(32);
+    let mut round_key: [u8; 32] = left.try_into().expect("32-byte left half");
+    xor(&mut round_key, key);
+    keystream(round_key).apply_keystream(right);
+}
+
+fn lioness_round_hash(block: &mut [u8], key: &[u8; 32]) {
+    let (left, right) = block.split_at_mut(32);
+    xor(left, &mac(key, right));
+}
+
+fn lioness_encrypt(keys: &LionessKeys, block: &mut [u8]) {
+    lioness_round_stream(block, &keys[0]);
+    lioness_round_hash(block, &keys[1]);
+    lioness_round_stream(block, &keys[2]);
+    lioness_round_hash(block, &keys[3]);
+}
+
+fn lioness_decrypt(keys: &LionessKeys, block: &mut [u8]) {
+    lioness_round_hash(block, &keys[3]);
+    lioness_round_stream(block, &keys[2]);
+    lioness_round_hash(block, &keys[1]);
+    lioness_round_stream(block, &keys[0]);
+}
+
+// Tag, length and message, padded to a full payload with random bytes
+fn frame_body(body: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if body.len() > MAX_BODY_LEN {
+        return Err(anyhow::anyhow!("Message too large for a Sphinx packet"));
+    }
+    let mut payload = vec![0u8; SPHINX_PAYLOAD_LEN];
+    payload[TAG_LEN..TAG_LEN + 2].copy_from_slice(&(body.len() as u16).to_be_bytes());
+    payload[TAG_LEN + 2..TAG_LEN + 2 + body.len()].copy_from_slice(body);
+    thread_rng().fill_bytes(&mut payload[TAG_LEN + 2 + body.len()..]);
+    Ok(payload)
+}
+
+fn unframe_body(payload: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if !bool::from(payload[..TAG_LEN].ct_eq(&[0u8; TAG_LEN])) {
+        return Err(anyhow::anyhow!("Sphinx payload was modified"));
+    }
+    let len = u16::from_be_bytes([payload[TAG_LEN], payload[TAG_LEN + 1]]) as usize;
+    if len > MAX_BODY_LEN {
+        return Err(anyhow::anyhow!("Invalid Sphinx payload length"));
+    }
+    Ok(payload[TAG_LEN + 2..TAG_LEN + 2 + len].to_vec())
+}
+
+// Build a header for `route`, returning it with every hop's keys
+fn create_header(route: &[(PublicKey, Command)]) -> anyhow::Result<([u8; HEADER_LEN], Vec<HopKeys>)> {
+    if route.is_empty() || route.len() > MAX_HOPS {
+        return Err(anyhow::anyhow!("Sphinx routes have 1 to {} hops", MAX_HOPS));
+    }
+
+    // Hop i sees alpha = b_{i-1} ... b_0 x G and shares b_{i-1} ... b_0 x Y_i
+    // with the client. The blinding factors b_i hash the previous alpha and
+    // shared secret, so consecutive alphas cannot be linked.
+    let mut secret = [0u8; 32];
+    thread_rng().fill_bytes(&mut secret);
+    let mut alpha = x25519(secret, X25519_BASEPOINT_BYTES);
+    let first_alpha = alpha;
+    let mut blindings: Vec<[u8; 32]> = Vec::with_capacity(route.len());
+    let mut keys = Vec::with_capacity(route.len());
+    for (public_key, _) in route {
+        let shared = blindings
+            .iter()
+            .fold(x25519(secret, public_key.to_bytes()), |shared, blinding| x25519(*blinding, shared));
+        let hop = hop_keys(&shared, &alpha);
+        alpha = x25519(hop.blinding, alpha);
+        blindings.push(hop.blinding);
+        keys.push(hop);
+    }
+
+    // The filler is what the hops before the last shift into the end of the
+    // routing info, computed ahead so the last hop's MAC covers it
+    let mut filler = Vec::with_capacity((route.len() - 1) * HOP_LEN);
+    for hop in &keys[..route.len() - 1] {
+        filler.extend_from_slice(&[0u8; HOP_LEN]);
+        let stream = prg(hop.routing, BETA_LEN + HOP_LEN);
+        let offset = BETA_LEN + HOP_LEN - filler.len();
+        xor(&mut filler, &stream[offset..]);
+    }
+
+    let now = unix_time();
+    let last = route.len() - 1;
+    let mut beta = vec![0u8; BETA_LEN - filler.len()];
+    thread_rng().fill_bytes(&mut beta[ROUTING_LEN..]);
+    beta[..ROUTING_LEN].copy_from_slice(&encode_routing(&route[last].1, hop_expiry(now)));
+    xor(&mut beta, &prg(keys[last].routing, BETA_LEN));
+    beta.extend_from_slice(&filler);
+    let mut gamma = mac(&keys[last].mac, &beta);
+
+    for i in (0..last).rev() {
+        let mut wrapped = Vec::with_capacity(BETA_LEN);
+        wrapped.extend_from_slice(&encode_routing(&route[i].1, hop_expiry(now)));
+        wrapped.extend_from_slice(&gamma);
+        wrapped.extend_from_slice(&beta[..BETA_LEN - HOP_LEN]);
+        xor(&mut wrapped, &prg(keys[i].routing, BETA_LEN));
+        beta = wrapped;
+        gamma = mac(&keys[i].mac, &beta);
+    }
+
+    let mut header = [0u8; HEADER_LEN];
+    header[..32].copy_from_slice(&first_alpha);
+    header[32..32 + BETA_LEN].copy_from_slice(&beta);
+    header[32 + BETA_LEN..].copy_from_slice(&gamma);
+    Ok((header, keys))
+}
+
+/// Client side: wrap `body` for `route`, each hop given by its identity key and
+/// the command it should carry out.
+pub fn create_packet(route: &[(PublicKey, Command)], body: &[u8]) -> anyhow::Result<Box<SphinxPacket>> {
+    let (header, keys) = create_header(route)?;
+    let mut payload = frame_body(body)?;
+    for hop in keys.iter().rev() {
+        lioness_encrypt(&hop.payload, &mut payload);
+    }
+
+    let mut packet = Box::new([0u8; SPHINX_PACKET_SIZE]);
+    packet[..HEADER_LEN].copy_from_slice(&header);
+    packet[HEADER_LEN..].copy_from_slice(&payload);
+    Ok(packet)
+}
+
+/// A single-use reply block: everything the gateway needs to send one reply
+/// packet without learning where it goes.
+pub struct Surb {
+    /// Mix listener of the first hop on the reply route.
+    pub first_hop: SocketAddr,
+    header: [u8; HEADER_LEN],
+    key: [u8; 32],
+}
+
+/// The client's half of a reply block, needed to read the reply.
+pub struct SurbKeys {
+    hops: Vec<LionessKeys>,
+    reply: LionessKeys,
+}
+
+fn reply_keys(key: &[u8; 32]) -> LionessKeys {
+    lioness_keys(&Hkdf::<Sha256>::new(Some(SALT), key), b"reply")
+}
+
+/// Client side: create a reply block for `route`, which starts at `first_hop`
+/// and should end with a [`Command::Deliver`] at the client's entry relay.
+pub fn create_surb(route: &[(PublicKey, Command)], first_hop: SocketAddr) -> anyhow::Result<(Surb, SurbKeys)> {
+    let (header, keys) = create_header(route)?;
+    let mut key = [0u8; 32];
+    thread_rng().fill_bytes(&mut key);
+    let surb_keys = SurbKeys {
+        hops: keys.into_iter().map(|hop| hop.payload).collect(),
+        reply: reply_keys(&key),
+    };
+    Ok((Surb { first_hop, header, key }, surb_keys))
+}
+
+impl Surb {
+    /// Encode for inclusion in a request.
+    pub fn encode(&self) -> [u8; SURB_LEN] {
+        let mut encoded = [0u8; SURB_LEN];
+        encoded[..ADDR_LEN].copy_from_slice(&encode_addr(self.first_hop));
+        encoded[ADDR_LEN..ADDR_LEN + HEADER_LEN].copy_from_slice(&self.header);
+        encoded[ADDR_LEN + HEADER_LEN..].copy_from_slice(&self.key);
+        encoded
+    }
+
+    /// Decode a reply block taken from a request.
+    pub fn decode(encoded: &[u8]) -> Option<Self> {
+        if encoded.len() != SURB_LEN {
+            return None;
+        }
+        Some(Self {
+            first_hop: parse_addr(&encoded[..ADDR_LEN])?,
+            header: encoded[ADDR_LEN..ADDR_LEN + HEADER_LEN].try_into().ok()?,
+            key: encoded[ADDR_LEN + HEADER_LEN..].try_into().ok()?,
+        })
+    }
+
+    /// Gateway side: build the reply packet carrying `body`.
+    pub fn reply_packet(&self, body: &[u8]) -> anyhow::Result<Box<SphinxPacket>> {
+        let mut payload = frame_body(body)?;
+        lioness_encrypt(&reply_keys(&self.key), &mut payload);
+
+        let mut packet = Box::new([0u8; SPHINX_PACKET_SIZE]);
+        packet[..HEADER_LEN].copy_from_slice(&self.header);
+        packet[HEADER_LEN..].copy_from_slice(&payload);
+        Ok(packet)
+    }
+}
+
+impl SurbKeys {
+    /// Client side: recover the body of a reply delivered by the entry relay.
+    pub fn open(&self, payload: &[u8]) -> anyhow::Result<Vec<u8>> {
+        if payload.len() != SPHINX_PAYLOAD_LEN {
+            return Err(anyhow::anyhow!("Invalid reply payload length"));
+        }
+        let mut payload = payload.to_vec();
+        for hop in self.hops.iter().rev() {
+            lioness_encrypt(hop, &mut payload);
+        }
+        lioness_decrypt(&self.reply, &mut payload);
+        unframe_body(&payload)
+    }
+}
+
+/// What a node should do with a packet after processing it.
+pub enum Action {
+    /// Send `packet` on to `next_hop`.
+    Forward {
+        /// Mix listener of the next hop.
+        next_hop: SocketAddr,
+        /// Mailbox to register for the incoming connection.
+        reply_mailbox: Option<Mailbox>,
+        /// The packet as the next hop must receive it.
+        packet: Box<SphinxPacket>,
+    },
+    /// Hand `payload` to the client connection holding `mailbox`.
+    Deliver {
+        /// Mailbox registered by the client.
+        mailbox: Mailbox,
+        /// Which of the client's reply blocks the packet used.
+        index: u8,
+        /// Reply payload, still encrypted for the client.
+        payload: Vec<u8>,
+    },
+    /// The packet is addressed to this node.
+    Exit {
+        /// Message sent by the client.
+        body: Vec<u8>,
+    },
+}
+
+/// A packet processed by one hop.
+pub struct Processed {
+    /// Seconds since the Unix epoch after which this hop must drop the packet.
+    pub expiry: u64,
+    /// Unique per packet and hop; seeing it twice means the packet was replayed.
+    pub replay_tag: [u8; 32],
+    /// What to do next.
+    pub action: Action,
+}
+
+/// Node side: remove this hop's layer from a packet.
+pub fn process_packet(identity: &StaticSecret, packet: &SphinxPacket) -> anyhow::Result<Processed> {
+    let alpha: [u8; 32] = packet[..32].try_into().expect("32-byte alpha");
+    let beta = &packet[32..32 + BETA_LEN];
+    let gamma = &packet[32 + BETA_LEN..HEADER_LEN];
+
+    let shared = identity.diffie_hellman(&PublicKey::from(alpha));
+    if !shared.was_contributory() {
+        return Err(anyhow::anyhow!("Invalid Sphinx packet"));
+    }
+    let keys = hop_keys(shared.as_bytes(), &alpha);
+    if !bool::from(mac(&keys.mac, beta).ct_eq(gamma)) {
+        return Err(anyhow::anyhow!("Invalid Sphinx packet"));
+    }
+
+    // Decrypt our slot and shift the rest of the routing info forward
+    let mut routing = [beta, &[0u8; HOP_LEN]].concat();
+    xor(&mut routing, &prg(keys.routing, BETA_LEN + HOP_LEN));
+    let (command, expiry) = decode_routing(&routing[..ROUTING_LEN])
+        .ok_or_else(|| anyhow::anyhow!("Invalid Sphinx routing info"))?;
+
+    let mut payload = packet[HEADER_LEN..].to_vec();
+    lioness_decrypt(&keys.payload, &mut payload);
+
+    let action = match command {
+        Command::Forward { next_hop, reply_mailbox } => {
+            let mut next = Box::new([0u8; SPHINX_PACKET_SIZE]);
+            next[..32].copy_from_slice(&x25519(keys.blinding, alpha));
+            next[32..32 + BETA_LEN].copy_from_slice(&routing[HOP_LEN..]);
+            next[32 + BETA_LEN..HEADER_LEN].copy_from_slice(&routing[ROUTING_LEN..HOP_LEN]);
+            next[HEADER_LEN..].copy_from_slice(&payload);
+            Action::Forward {
+                next_hop,
+                reply_mailbox,
+                packet: next,
+            }
+        }
+        Command::Deliver { mailbox, index } => Action::Deliver {
+            mailbox,
+            index,
+            payload,
+        },
+        Command::Exit => Action::Exit {
+            body: unframe_body(&payload)?,
+        },
+    };
+
+    Ok(Processed {
+        expiry,
+        replay_tag: keys.replay_tag,
+        action,
+    })
+}
+
+/// Frame sent by the entry relay to the client for a delivered reply: the
+/// reply block index, random filler up to the header length, then the payload.
+pub fn encode_delivery(index: u8, payload: &[u8]) -> Box<SphinxPacket> {
+    let mut frame = Box::new([0u8; SPHINX_PACKET_SIZE]);
+    thread_rng().fill_bytes(&mut frame[1..HEADER_LEN]);
+    frame[0] = index;
+    frame[HEADER_LEN..].copy_from_slice(payload);
+    frame
+}
+
+/// Split a delivery frame into the reply block index and the payload.
+pub fn decode_delivery(frame: &SphinxPacket) -> (u8, &[u8]) {
+    (frame[0], &frame[HEADER_LEN..])
+}
+
+/// Body of a mixnet request: the reply blocks, then the JSON-RPC request.
+///
+/// ```text
+/// reply blocks (1) | reply blocks (355 each) | request
+/// ```
+pub fn encode_request(surbs: &[Surb], request: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let len = 1 + surbs.len() * SURB_LEN + request.len();
+    if surbs.len() > u8::MAX as usize || len > MAX_BODY_LEN {
+        return Err(anyhow::anyhow!(
+            "Request too large for mixnet mode: {} bytes with {} reply blocks (max {})",
+            request.len(),
+            surbs.len(),
+            MAX_BODY_LEN
+        ));
+    }
+    let mut body = Vec::with_capacity(len);
+    body.push(surbs.len() as u8);
+    for surb in surbs {
+        body.extend_from_slice(&surb.encode());
+    }
+    body.extend_from_slice(request);
+    Ok(body)
+}
+
+/// Split a mixnet request body into its reply blocks and the request.
+pub fn decode_request(body: &[u8]) -> Option<(Vec<Surb>, &[u8])> {
+    let count = *body.first()? as usize;
+    let request_start = 1 + count * SURB_LEN;
+    if body.len() < request_start {
+        return None;
+    }
+    let surbs = body[1..request_start]
+        .chunks(SURB_LEN)
+        .map(Surb::decode)
+        .collect::<Option<Vec<_>>>()?;
+    Some((surbs, &body[request_start..]))
+}
+
+/// Split a response into one reply body per reply block.
+///
+/// Each body is `index (1) | count (1) | data`. A response that does not fit
+/// into `surb_count` parts yields `None`; [`error_reply`] reports it instead.
+pub fn encode_reply_parts(response: &[u8], surb_count: usize) -> Option<Vec<Vec<u8>>> {
+    let count = response.len().div_ceil(REPLY_PART_LEN).max(1);
+    if count > surb_count || count > u8::MAX as usize {
+        return None;
+    }
+    let parts = (0..count)
+        .map(|index| {
+            let start = index * REPLY_PART_LEN;
+            let end = (start + REPLY_PART_LEN).min(response.len());
+            [&[index as u8, count as u8], &response[start..end]].concat()
+        })
+        .collect();
+    Some(parts)
+}
+
+/// Reply body telling the client the request could not be answered.
+pub fn error_reply() -> Vec<u8> {
+    vec![0, 0]
+}
+
+/// Parse a reply body into its part index, part count and data. A count of
+/// zero is an [`error_reply`].
+pub fn decode_reply_part(body: &[u8]) -> Option<(u8, u8, &[u8])> {
+    match body {
+        [index, count, data @ ..] => Some((*index, *count, data)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(seed: u8) -> (StaticSecret, PublicKey) {
+        let secret = StaticSecret::from([seed; 32]);
+        let public = PublicKey::from(&secret);
+        (secret, public)
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([198, 51, 100, 1], port))
+    }
+
+    fn forward(port: u16) -> Command {
+        Command::Forward {
+            next_hop: addr(port),
+            reply_mailbox: None,
+        }
+    }
+
+    // Pass a packet along its route, returning what each hop saw
+    fn process_route(secrets: &[StaticSecret], mut packet: Box<SphinxPacket>) -> Vec<Processed> {
+        let mut processed = Vec::new();
+        for secret in secrets {
+            let hop = process_packet(secret, &packet).unwrap();
+            if let Action::Forward { packet: next, .. } = &hop.action {
+                packet = next.clone();
+            }
+            processed.push(hop);
+        }
+        processed
+    }
+
+    #[test]
+    fn packet_follows_its_route() {
+        let (entry, middle, gateway) = (node(1), node(2), node(3));
+        let mailbox = [9u8; 16];
+        let route = [
+            (entry.1, Command::Forward { next_hop: addr(2), reply_mailbox: Some(mailbox) }),
+            (middle.1, forward(3)),
+            (gateway.1, Command::Exit),
+        ];
+        let packet = create_packet(&route, b"eth_blockNumber").unwrap();
+        let hops = process_route(&[entry.0, middle.0, gateway.0], packet);
+
+        assert!(matches!(hops[0].action, Action::Forward { next_hop, reply_mailbox: Some(m), .. } if next_hop == addr(2) && m == mailbox));
+        assert!(matches!(hops[1].action, Action::Forward { next_hop, reply_mailbox: None, .. } if next_hop == addr(3)));
+        assert!(matches!(&hops[2].action, Action::Exit { body } if body == b"eth_blockNumber"));
+        assert_ne!(hops[0].replay_tag, hops[1].replay_tag);
+        assert_ne!(hops[1].replay_tag, hops[2].replay_tag);
+    }
+
+    #[test]
+    fn routes_of_every_length() {
+        let nodes: Vec<_> = (1..=MAX_HOPS as u8).map(node).collect();
+        for len in 1..=MAX_HOPS {
+            let mut route: Vec<_> = nodes[..len - 1].iter().enumerate().map(|(i, n)| (n.1, forward(i as u16 + 2))).collect();
+            route.push((nodes[len - 1].1, Command::Exit));
+            let secrets: Vec<_> = nodes[..len].iter().map(|n| n.0.clone()).collect();
+            let hops = process_route(&secrets, create_packet(&route, b"hi").unwrap());
+            assert!(matches!(&hops[len - 1].action, Action::Exit { body } if body == b"hi"), "{} hops", len);
+        }
+        assert!(create_packet(&[], b"hi").is_err());
+        let too_long: Vec<_> = (0..=MAX_HOPS).map(|_| (node(1).1, Command::Exit)).collect();
+        assert!(create_packet(&too_long, b"hi").is_err());
+    }
+
+    #[test]
+    fn headers_look_unrelated_between_hops() {
+        let (entry, middle, gateway) = (node(1), node(2), node(3));
+        let route = [(entry.1, forward(2)), (middle.1, forward(3)), (gateway.1, Command::Exit)];
+        let packet = create_packet(&route, b"hi").unwrap();
+        let hops = process_route(&[entry.0, middle.0], packet.clone());
+        let Action::Forward { packet: next, .. } = &hops[0].action else {
+            panic!("entry forwards");
+        };
+        assert_ne!(packet[..32], next[..32]);
+        assert_ne!(packet[32..HEADER_LEN], next[32..HEADER_LEN]);
+        assert_ne!(packet[HEADER_LEN..], next[HEADER_LEN..]);
+    }
+
+    #[test]
+    fn filler_keeps_the_last_mac_valid() {
+        // The routing info the earlier hops shift in must be exactly what the
+        // client computed as filler, or the last hop's MAC fails
+        let (entry, middle, gateway) = (node(1), node(2), node(3));
+        let route = [(entry.1, forward(2)), (middle.1, forward(3)), (gateway.1, Command::Exit)];
+        let (header, keys) = create_header(&route).unwrap();
+        let mut packet = Box::new([0u8; SPHINX_PACKET_SIZE]);
+        packet[..HEADER_LEN].copy_from_slice(&header);
+        let mut payload = frame_body(b"x").unwrap();
+        for hop in keys.iter().rev() {
+            lioness_encrypt(&hop.payload, &mut payload);
+        }
+        packet[HEADER_LEN..].copy_from_slice(&payload);
+        let hops = process_route(&[entry.0, middle.0], packet);
+        let Action::Forward { packet: last, .. } = &hops[1].action else {
+            panic!("middle forwards");
+        };
+        let gamma = &last[32 + BETA_LEN..HEADER_LEN];
+        assert_eq!(mac(&keys[2].mac, &last[32..32 + BETA_LEN]), gamma);
+        assert!(process_packet(&gateway.0, last).is_ok());
+    }
+
+    #[test]
+    fn tampered_or_misrouted_packets_are_rejected() {
+        let (entry, gateway) = (node(1), node(3));
+        let route = [(entry.1, forward(3)), (gateway.1, Command::Exit)];
+        let packet = create_packet(&route, b"hi").unwrap();
+
+        // Header changes break the MAC
+        for offset in [0, 40, HEADER_LEN - 1] {
+            let mut tampered = packet.clone();
+            tampered[offset] ^= 1;
+            assert!(process_packet(&entry.0, &tampered).is_err(), "offset {}", offset);
+        }
+        assert!(process_packet(&gateway.0, &packet).is_err());
+
+        // Payload changes pass the relays but are caught at the exit
+        let mut tampered = packet.clone();
+        tampered[HEADER_LEN + 100] ^= 1;
+        let hops = process_route(std::slice::from_ref(&entry.0), tampered);
+        let Action::Forward { packet: next, .. } = &hops[0].action else {
+            panic!("entry forwards");
+        };
+        assert!(process_packet(&gateway.0, next).is_err());
+    }
+
+    #[test]
+    fn lioness_round_trip() {
+        let keys: LionessKeys = [[1; 32], [2; 32], [3; 32], [4; 32]];
+        let plaintext = frame_body(b"some request").unwrap();
+        let mut block = plaintext.clone();
+        lioness_encrypt(&keys, &mut block);
+        assert_ne!(block, plaintext);
+        lioness_decrypt(&keys, &mut block);
+        assert_eq!(block, plaintext);
+
+        // One flipped bit anywhere scrambles the whole block, tag included
+        for offset in [0, 40, SPHINX_PAYLOAD_LEN - 1] {
+            let mut block = plaintext.clone();
+            lioness_encrypt(&keys, &mut block);
+            block[offset] ^= 1;
+            lioness_decrypt(&keys, &mut block);
+            assert!(unframe_body(&block).is_err(), "offset {}", offset);
+            let changed = block.iter().zip(&plaintext).filter(|(a, b)| a != b).count();
+            assert!(changed > SPHINX_PAYLOAD_LEN / 2, "offset {}", offset);
+        }
+    }
+
+    #[test]
+    fn body_framing() {
+        assert_eq!(unframe_body(&frame_body(b"").unwrap()).unwrap(), b"");
+        let largest = vec![7u8; MAX_BODY_LEN];
+        assert_eq!(unframe_body(&frame_body(&largest).unwrap()).unwrap(), largest);
+        assert!(frame_body(&vec![0u8; MAX_BODY_LEN + 1]).is_err());
+    }
+
+    #[test]
+    fn surb_round_trip() {
+        let (entry, middle) = (node(1), node(2));
+        let mailbox = [5u8; 16];
+        let reply_route = [
+            (middle.1, Command::Forward { next_hop: addr(1), reply_mailbox: None }),
+            (entry.1, Command::Deliver { mailbox, index: 4 }),
+        ];
+        let (surb, keys) = create_surb(&reply_route, addr(2)).unwrap();
+
+        // Through the request body to the gateway
+        let body = encode_request(&[surb], b"request").unwrap();
+        let (surbs, request) = decode_request(&body).unwrap();
+        assert_eq!(request, b"request");
+        assert_eq!(surbs[0].first_hop, addr(2));
+
+        let reply = surbs[0].reply_packet(b"response").unwrap();
+        let hops = process_route(&[middle.0, entry.0], reply);
+        let Action::Deliver { mailbox: delivered_to, index, payload } = &hops[1].action else {
+            panic!("entry delivers");
+        };
+        assert_eq!((*delivered_to, *index), (mailbox, 4));
+
+        let frame = encode_delivery(*index, payload);
+        let (index, payload) = decode_delivery(&frame);
+        assert_eq!(index, 4);
+        assert_eq!(keys.open(payload).unwrap(), b"response");
+
+        let mut tampered = payload.to_vec();
+        tampered[10] ^= 1;
+        assert!(keys.open(&tampered).is_err());
+    }
+
+    #[test]
+    fn surb_encoding() {
+        let (surb, _) = create_surb(&[(node(1).1, Command::Exit)], addr(7)).unwrap();
+        let decoded = Surb::decode(&surb.encode()).unwrap();
+        assert_eq!(decoded.encode(), surb.encode());
+        assert!(Surb::decode(&surb.encode()[1..]).is_none());
+    }
+
+    #[test]
+    fn hop_expiries_are_coarse_and_independent() {
+        let nodes: Vec<_> = (1..=3).map(node).collect();
+        let route = [(nodes[0].1, forward(2)), (nodes[1].1, forward(3)), (nodes[2].1, Command::Exit)];
+        let secrets: Vec<_> = nodes.iter().map(|n| n.0.clone()).collect();
+        let mut differ = false;
+        for _ in 0..20 {
+            let before = unix_time();
+            let hops = process_route(&secrets, create_packet(&route, b"hi").unwrap());
+            for hop in &hops {
+                assert_eq!(hop.expiry % EXPIRY_GRANULARITY_SECS, 0);
+                assert!(hop.expiry >= before + MIN_LIFETIME_SECS);
+                assert!(hop.expiry <= unix_time() + MAX_LIFETIME_SECS);
+            }
+            differ |= hops.iter().any(|hop| hop.expiry != hops[0].expiry);
+        }
+        assert!(differ);
+    }
+
+    #[test]
+    fn reply_parts() {
+        let response = vec![1u8; 2 * REPLY_PART_LEN + 1];
+        let parts = encode_reply_parts(&response, 3).unwrap();
+        assert_eq!(parts.len(), 3);
+        let mut reassembled = Vec::new();
+        for (i, part) in parts.iter().enumerate() {
+            let (index, count, data) = decode_reply_part(part).unwrap();
+            assert_eq!((index as usize, count), (i, 3));
+            reassembled.extend_from_slice(data);
+        }
+        assert_eq!(reassembled, response);
+        assert!(encode_reply_parts(&response, 2).is_none());
+        assert_eq!(decode_reply_part(&error_reply()), Some((0, 0, &[][..])));
+    }
+}
@@ -0,0 +1,574 @@
+use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+use serde_json::Value;
+use sha3::{Digest, Keccak256};
+
+pub type Address = [u8; 20];
+
+// The accounts a request is about. `account` is the one the request is
+// pinned to; `mentioned` are other addresses in it (a call target, an
+// address argument in the calldata) that may belong to the same wallet.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequestAccounts {
+    pub account: Option<Address>,
+    pub mentioned: Vec<Address>,
+}
+
+impl RequestAccounts {
+    // The account of `eth_getBalance`, the `from` of `eth_call` and the
+    // sender of `eth_sendRawTransaction`. A transaction whose sender cannot be
+    // recovered is still sent, just without an account.
+    pub fn of_request(method: &str, params: &Value) -> Self {
+        match method {
+            "eth_getBalance" => Self {
+                account: params.get(0).and_then(parse_address),
+                mentioned: Vec::new(),
+            },
+            "eth_call" => {
+                let call = params.get(0);
+                let data = call
+                    .and_then(|call| call.get("input").or_else(|| call.get("data")))
+                    .and_then(Value::as_str)
+                    .and_then(|data| decode_hex(data).ok())
+                    .unwrap_or_default();
+                Self {
+                    account: call.and_then(|call| call.get("from")).and_then(parse_address),
+                    mentioned: call
+                        .and_then(|call| call.get("to"))
+                        .and_then(parse_address)
+                        .into_iter()
+                        .chain(calldata_addresses(&data))
+                        .collect(),
+                }
+            }
+            "eth_sendRawTransaction" => {
+                let transaction = params
+                    .get(0)
+                    .and_then(Value::as_str)
+                    .and_then(|raw| decode_hex(raw).ok())
+                    .and_then(|raw| decode_transaction(&raw).ok());
+                match transaction {
+                    Some(transaction) => Self {
+                        account: Some(transaction.sender),
+                        mentioned: transaction.to.into_iter().chain(calldata_addresses(&transaction.data)).collect(),
+                    },
+                    None => Self::default(),
+                }
+            }
+            _ => Self::default(),
+        }
+    }
+}
+
+pub fn format_address(address: &Address) -> String {
+    format!("0x{}", hex::encode(address))
+}
+
+fn parse_address(value: &Value) -> Option<Address> {
+    decode_hex(value.as_str()?).ok()?.try_into().ok()
+}
+
+fn decode_hex(value: &str) -> Result<Vec<u8>, hex::FromHexError> {
+    hex::decode(value.strip_prefix("0x").unwrap_or(value))
+}
+
+// ABI words that hold an address: twelve zero bytes, then twenty that are not
+fn calldata_addresses(data: &[u8]) -> impl Iterator<Item = Address> + '_ {
+    data.get(4..)
+        .unwrap_or_default()
+        .chunks_exact(32)
+        .filter(|word| word[..12].iter().all(|byte| *byte == 0) && word[12..].iter().any(|byte| *byte != 0))
+        .filter_map(|word| word[12..].try_into().ok())
+}
+
+struct Transaction {
+    sender: Address,
+    to: Option<Address>,
+    data: Vec<u8>,
+}
+
+// Legacy (with or without EIP-155 replay protection) and typed EIP-2718
+// transactions. The sender is recovered from the signature over the
+// signing hash, like a node does before accepting the transaction.
+fn decode_transaction(raw: &[u8]) -> anyhow::Result<Transaction> {
+    let first = *raw.first().ok_or_else(|| anyhow::anyhow!("empty transaction"))?;
+    if first >= 0xc0 {
+        return decode_legacy(raw);
+    }
+
+    let kind = first;
+    let mut fields = rlp::list(&rlp::parse_all(&raw[1..])?)?;
+    // Blob transactions are sent with their blobs: [transaction, blobs, commitments, proofs]
+    if kind == 3 && matches!(fields.first().map(|field| &field.value), Some(rlp::Value::List(_))) {
+        fields = rlp::list(&fields[0])?;
+    }
+    let to_index = match kind {
+        1 => 4,
+        2..=4 => 5,
+        _ => return Err(anyhow::anyhow!("unknown transaction type {}", kind)),
+    };
+    if fields.len() < to_index + 6 {
+        return Err(anyhow::anyhow!("truncated transaction"));
+    }
+
+    let (unsigned, signature) = fields.split_at(fields.len() - 3);
+    let mut preimage = vec![kind];
+    preimage.extend(rlp::encode_list(unsigned));
+    let y_parity = rlp::integer(&signature[0])?;
+    Ok(Transaction {
+        sender: recover(&preimage, y_parity, &signature[1], &signature[2])?,
+        to: rlp::bytes(&fields[to_index])?.try_into().ok(),
+        data: rlp::bytes(&fields[to_index + 2])?.to_vec(),
+    })
+}
+
+fn decode_legacy(raw: &[u8]) -> anyhow::Result<Transaction> {
+    // [nonce, gasPrice, gas, to, value, data, v, r, s]
+    let fields = rlp::list(&rlp::parse_all(raw)?)?;
+    if fields.len() != 9 {
+        return Err(anyhow::anyhow!("legacy transaction has {} fields", fields.len()));
+    }
+
+    let v = rlp::integer(&fields[6])?;
+    let (preimage, parity) = match v {
+        27 | 28 => (rlp::encode_list(&fields[..6]), v - 27),
+        v if v >= 35 => {
+            let chain_id = (v - 35) / 2;
+            let mut encoded: Vec<u8> = fields[..6].iter().flat_map(|field| field.raw.to_vec()).collect();
+            encoded.extend(rlp::encode_bytes(&trim_leading_zeros(&chain_id.to_be_bytes())));
+            encoded.extend(rlp::encode_bytes(&[]));
+            encoded.extend(rlp::encode_bytes(&[]));
+            (rlp::list_header(encoded.len()).into_iter().chain(encoded).collect(), (v - 35) % 2)
+        }
+        _ => return Err(anyhow::anyhow!("invalid v {}", v)),
+    };
+    Ok(Transaction {
+        sender: recover(&preimage, parity, &fields[7], &fields[8])?,
+        to: rlp::bytes(&fields[3])?.try_into().ok(),
+        data: rlp::bytes(&fields[5])?.to_vec(),
+    })
+}
+
+fn recover(preimage: &[u8], parity: u64, r: &rlp::Item, s: &rlp::Item) -> anyhow::Result<Address> {
+    let hash = Keccak256::digest(preimage);
+    let mut signature = Signature::from_scalars(scalar_bytes(rlp::bytes(r)?)?, scalar_bytes(rlp::bytes(s)?)?)?;
+    let mut parity = u8::try_from(parity).ok().filter(|parity| *parity <= 1).ok_or_else(|| anyhow::anyhow!("invalid y parity"))?;
+    // Nodes reject high-s signatures, but flipping s and the parity gives
+    // the same key
+    if let Some(normalized) = signature.normalize_s() {
+        signature = normalized;
+        parity ^= 1;
+    }
+    let recovery_id = RecoveryId::from_byte(parity).ok_or_else(|| anyhow::anyhow!("invalid y parity"))?;
+    let key = VerifyingKey::recover_from_prehash(&hash, &signature, recovery_id)?;
+    let point = key.to_encoded_point(false);
+    let digest = Keccak256::digest(&point.as_bytes()[1..]);
+    Ok(digest[12..].try_into()?)
+}
+
+fn scalar_bytes(value: &[u8]) -> anyhow::Result<[u8; 32]> {
+    if value.len() > 32 {
+        return Err(anyhow::anyhow!("signature value longer than 32 bytes"));
+    }
+    let mut bytes = [0u8; 32];
+    bytes[32 - value.len()..].copy_from_slice(value);
+    Ok(bytes)
+}
+
+fn trim_leading_zeros(bytes: &[u8]) -> Vec<u8> {
+    let start = bytes.iter().position(|byte| *byte != 0).unwrap_or(bytes.len());
+    bytes[start..].to_vec()
+}
+
+// Just enough RLP to take a transaction apart and hash the unsigned part
+mod rlp {
+    // Lists nested inside the outermost one. The deepest real case is a
+    // blob transaction in network form: wrapper, transaction, access list,
+    // entry, storage keys. Anything deeper is refused before it can exhaust
+    // the stack.
+    const MAX_DEPTH: usize = 4;
+
+    #[derive(Clone)]
+    pub enum Value<'a> {
+        Bytes(&'a [u8]),
+        List(Vec<Item<'a>>),
+    }
+
+    #[derive(Clone)]
+    pub struct Item<'a> {
+        pub raw: &'a [u8],  // The item's complete encoding
+        pub value: Value<'a>,
+    }
+
+    // Exactly one item, with nothing after it
+    pub fn parse_all(input: &[u8]) -> anyhow::Result<Item<'_>> {
+        let (item, rest) = parse(input, 0)?;
+        if !rest.is_empty() {
+            return Err(anyhow::anyhow!("trailing bytes after RLP item"));
+        }
+        Ok(item)
+    }
+
+    fn parse(input: &[u8], depth: usize) -> anyhow::Result<(Item<'_>, &[u8])> {
+        let prefix = *input.first().ok_or_else(|| anyhow::anyhow!("truncated RLP"))?;
+        let (is_list, offset, len) = match prefix {
+            0x00..=0x7f => (false, 0, 1),
+            0x80..=0xb7 => (false, 1, (prefix - 0x80) as usize),
+            0xb8..=0xbf => {
+                let size = (prefix - 0xb7) as usize;
+                (false, 1 + size, long_length(input, size)?)
+            }
+            0xc0..=0xf7 => (true, 1, (prefix - 0xc0) as usize),
+            0xf8..=0xff => {
+                let size = (prefix - 0xf7) as usize;
+                (true, 1 + size, long_length(input, size)?)
+            }
+        };
+        let end = offset
+            .checked_add(len)
+            .filter(|end| *end <= input.len())
+            .ok_or_else(|| anyhow::anyhow!("truncated RLP"))?;
+        let payload = &input[offset..end];
+
+        let value = if is_list {
+            if depth > MAX_DEPTH {
+                return Err(anyhow::anyhow!("RLP nested too deeply"));
+            }
+            let mut items = Vec::new();
+            let mut rest = payload;
+            while !rest.is_empty() {
+                let (item, remaining) = parse(rest, depth + 1)?;
+                items.push(item);
+                rest = remaining;
+            }
+            Value::List(items)
+        } else if offset == 0 {
+            Value::Bytes(&input[..1])
+        } else {
+            Value::Bytes(payload)
+        };
+        Ok((Item { raw: &input[..end], value }, &input[end..]))
+    }
+
+    fn long_length(input: &[u8], size: usize) -> anyhow::Result<usize> {
+        let bytes = input.get(1..1 + size).ok_or_else(|| anyhow::anyhow!("truncated RLP"))?;
+        if size > 8 {
+            return Err(anyhow::anyhow!("RLP length too large"));
+        }
+        Ok(bytes.iter().fold(0usize, |len, byte| (len << 8) | *byte as usize))
+    }
+
+    pub fn list<'a>(item: &Item<'a>) -> anyhow::Result<Vec<Item<'a>>> {
+        match &item.value {
+            Value::List(items) => Ok(items.clone()),
+            Value::Bytes(_) => Err(anyhow::anyhow!("expected an RLP list")),
+        }
+    }
+
+    pub fn bytes<'a>(item: &Item<'a>) -> anyhow::Result<&'a [u8]> {
+        match item.value {
+            Value::Bytes(bytes) => Ok(bytes),
+            Value::List(_) => Err(anyhow::anyhow!("expected RLP bytes")),
+        }
+    }
+
+    pub fn integer(item: &Item) -> anyhow::Result<u64> {
+        let bytes = bytes(item)?;
+        if bytes.len() > 8 {
+            return Err(anyhow::anyhow!("integer too large"));
+        }
+        Ok(bytes.iter().fold(0u64, |value, byte| (value << 8) | *byte as u64))
+    }
+
+    pub fn encode_bytes(bytes: &[u8]) -> Vec<u8> {
+        match bytes {
+            [byte] if *byte < 0x80 => vec![*byte],
+            _ => {
+                let mut encoded = length_prefix(0x80, bytes.len());
+                encoded.extend_from_slice(bytes);
+                encoded
+            }
+        }
+    }
+
+    // The items' own encodings, wrapped in a list
+    pub fn encode_list(items: &[Item]) -> Vec<u8> {
+        let payload: Vec<u8> = items.iter().flat_map(|item| item.raw.iter().copied()).collect();
+        let mut encoded = list_header(payload.len());
+        encoded.extend(payload);
+        encoded
+    }
+
+    pub fn list_header(len: usize) -> Vec<u8> {
+        length_prefix(0xc0, len)
+    }
+
+    fn length_prefix(base: u8, len: usize) -> Vec<u8> {
+        if len <= 55 {
+            return vec![base + len as u8];
+        }
+        let len_bytes: Vec<u8> = len.to_be_bytes().into_iter().skip_while(|byte| *byte == 0).collect();
+        let mut prefix = vec![base + 55 + len_bytes.len() as u8];
+        prefix.extend(len_bytes);
+        prefix
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k256::ecdsa::SigningKey;
+    use serde_json::json;
+
+    const CONTRACT: Address = [0x35; 20];
+    const OTHER: Address = [0x11; 20];
+
+    fn key() -> SigningKey {
+        SigningKey::from_slice(&[0x46; 32]).unwrap()
+    }
+
+    fn key_address() -> Address {
+        let point = key().verifying_key().to_encoded_point(false);
+        Keccak256::digest(&point.as_bytes()[1..])[12..].try_into().unwrap()
+    }
+
+    fn bytes(value: &[u8]) -> Vec<u8> {
+        rlp::encode_bytes(value)
+    }
+
+    fn int(value: u64) -> Vec<u8> {
+        bytes(&trim_leading_zeros(&value.to_be_bytes()))
+    }
+
+    fn list(items: &[Vec<u8>]) -> Vec<u8> {
+        let payload: Vec<u8> = items.concat();
+        [rlp::list_header(payload.len()), payload].concat()
+    }
+
+    // (y parity, r, s) over the Keccak hash of `preimage`
+    fn sign(preimage: &[u8]) -> (u64, Vec<u8>, Vec<u8>) {
+        let (signature, recovery_id) = key().sign_prehash_recoverable(&Keccak256::digest(preimage)).unwrap();
+        let (r, s) = signature.split_bytes();
+        (
+            u64::from(recovery_id.is_y_odd()),
+            trim_leading_zeros(&r),
+            trim_leading_zeros(&s),
+        )
+    }
+
+    // balanceOf(OTHER), so the calldata mentions an address
+    fn calldata() -> Vec<u8> {
+        let mut data = vec![0x70, 0xa0, 0x82, 0x31];
+        data.extend([0u8; 12]);
+        data.extend(OTHER);
+        data
+    }
+
+    // One entry with one storage key: three lists deep inside the transaction
+    fn access_list() -> Vec<u8> {
+        list(&[list(&[bytes(&CONTRACT), list(&[bytes(&[1; 32])])])])
+    }
+
+    fn typed(kind: u8, unsigned: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        let preimage = [vec![kind], list(&unsigned)].concat();
+        let (parity, r, s) = sign(&preimage);
+        [unsigned, vec![int(parity), bytes(&r), bytes(&s)]].concat()
+    }
+
+    fn eip1559_fields() -> Vec<Vec<u8>> {
+        vec![
+            int(1),
+            int(7),
+            int(1_000_000_000),
+            int(30_000_000_000),
+            int(60_000),
+            bytes(&CONTRACT),
+            int(0),
+            bytes(&calldata()),
+            access_list(),
+        ]
+    }
+
+    fn send_raw(raw: &[u8]) -> RequestAccounts {
+        RequestAccounts::of_request("eth_sendRawTransaction", &json!([format!("0x{}", hex::encode(raw))]))
+    }
+
+    #[test]
+    fn recovers_sender_of_eip155_reference_transaction() {
+        // The example from EIP-155, signed with the key 0x4646..46
+        let raw = hex::decode(
+            "f86c098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a7640000\
+             8025a028ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276a067cbe9d8997f76\
+             1aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83",
+        )
+        .unwrap();
+        let transaction = decode_transaction(&raw).unwrap();
+        assert_eq!(format_address(&transaction.sender), "0x9d8a62f656a8d1615c1294fd71e9cfb3e4855a4f");
+        assert_eq!(transaction.sender, key_address());
+        assert_eq!(transaction.to, Some(CONTRACT));
+        assert!(transaction.data.is_empty());
+    }
+
+    #[test]
+    fn decodes_legacy_transaction_without_chain_id() {
+        let unsigned = vec![int(3), int(20_000_000_000), int(21_000), bytes(&CONTRACT), int(5), bytes(&calldata())];
+        let (parity, r, s) = sign(&list(&unsigned));
+        let raw = list(&[unsigned, vec![int(27 + parity), bytes(&r), bytes(&s)]].concat());
+
+        let accounts = send_raw(&raw);
+        assert_eq!(accounts.account, Some(key_address()));
+        assert_eq!(accounts.mentioned, vec![CONTRACT, OTHER]);
+    }
+
+    #[test]
+    fn decodes_eip155_transaction_on_other_chain() {
+        let chain_id = 137;
+        let unsigned = vec![int(0), int(1), int(21_000), bytes(&CONTRACT), int(0), bytes(&[])];
+        let preimage = list(&[unsigned.clone(), vec![int(chain_id), bytes(&[]), bytes(&[])]].concat());
+        let (parity, r, s) = sign(&preimage);
+        let raw = list(&[unsigned, vec![int(chain_id * 2 + 35 + parity), bytes(&r), bytes(&s)]].concat());
+
+        assert_eq!(decode_transaction(&raw).unwrap().sender, key_address());
+    }
+
+    #[test]
+    fn decodes_eip2930_transaction() {
+        let fields = typed(
+            1,
+            vec![int(1), int(7), int(20_000_000_000), int(60_000), bytes(&CONTRACT), int(0), bytes(&calldata()), access_list()],
+        );
+        let raw = [vec![1], list(&fields)].concat();
+
+        let accounts = send_raw(&raw);
+        assert_eq!(accounts.account, Some(key_address()));
+        assert_eq!(accounts.mentioned, vec![CONTRACT, OTHER]);
+    }
+
+    #[test]
+    fn decodes_eip1559_transaction() {
+        let raw = [vec![2], list(&typed(2, eip1559_fields()))].concat();
+
+        let accounts = send_raw(&raw);
+        assert_eq!(accounts.account, Some(key_address()));
+        assert_eq!(accounts.mentioned, vec![CONTRACT, OTHER]);
+    }
+
+    #[test]
+    fn decodes_eip1559_contract_creation() {
+        let mut fields = eip1559_fields();
+        fields[5] = bytes(&[]);
+        let raw = [vec![2], list(&typed(2, fields))].concat();
+
+        let transaction = decode_transaction(&raw).unwrap();
+        assert_eq!(transaction.sender, key_address());
+        assert_eq!(transaction.to, None);
+    }
+
+    #[test]
+    fn decodes_eip4844_transaction_with_and_without_blobs() {
+        let blob_fields = [eip1559_fields(), vec![int(1), list(&[bytes(&[0x01; 32])])]].concat();
+        let transaction = list(&typed(3, blob_fields));
+
+        let canonical = [vec![3], transaction.clone()].concat();
+        assert_eq!(decode_transaction(&canonical).unwrap().sender, key_address());
+
+        // Network form: [transaction, blobs, commitments, proofs]. With the
+        // access list its storage keys are the deepest lists accepted.
+        let wrapped = list(&[
+            transaction,
+            list(&[bytes(&[0; 64])]),
+            list(&[bytes(&[0; 48])]),
+            list(&[bytes(&[0; 48])]),
+        ]);
+        let network = [vec![3], wrapped].concat();
+        let accounts = send_raw(&network);
+        assert_eq!(accounts.account, Some(key_address()));
+        assert_eq!(accounts.mentioned, vec![CONTRACT, OTHER]);
+    }
+
+    #[test]
+    fn recovers_sender_from_high_s_signature() {
+        // n - s with the parity flipped is the same signature to ecrecover
+        let order = hex::decode("fffffffffffffffffffffffffffffffebaaedce6af48a03bbfd25e8cd0364141").unwrap();
+        let unsigned = vec![int(3), int(1), int(21_000), bytes(&CONTRACT), int(0), bytes(&[])];
+        let (parity, r, s) = sign(&list(&unsigned));
+        let mut padded = [0u8; 32];
+        padded[32 - s.len()..].copy_from_slice(&s);
+        let mut high_s = [0u8; 32];
+        let mut borrow = 0i16;
+        for i in (0..32).rev() {
+            let difference = order[i] as i16 - padded[i] as i16 - borrow;
+            high_s[i] = difference.rem_euclid(256) as u8;
+            borrow = i16::from(difference < 0);
+        }
+        let raw = list(&[unsigned, vec![int(28 - parity), bytes(&r), bytes(&high_s)]].concat());
+
+        assert_eq!(decode_transaction(&raw).unwrap().sender, key_address());
+    }
+
+    #[test]
+    fn rejects_malformed_transactions() {
+        let valid = [vec![2], list(&typed(2, eip1559_fields()))].concat();
+
+        assert!(decode_transaction(&[]).is_err());
+        assert!(decode_transaction(&valid[..valid.len() - 1]).is_err(), "truncated");
+        assert!(decode_transaction(&[valid.clone(), vec![0]].concat()).is_err(), "trailing bytes");
+        assert!(decode_transaction(&[vec![5], valid[1..].to_vec()].concat()).is_err(), "unknown type");
+        assert!(decode_transaction(&list(&[int(1), int(2)])).is_err(), "too few legacy fields");
+        assert!(decode_transaction(&[vec![2], list(&[int(1), int(2)])].concat()).is_err(), "too few typed fields");
+
+        let mut tampered = valid.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert_ne!(decode_transaction(&tampered).ok().map(|transaction| transaction.sender), Some(key_address()));
+
+        // Still sent, just without an account
+        assert_eq!(send_raw(&valid[..10]), RequestAccounts::default());
+        assert_eq!(
+            RequestAccounts::of_request("eth_sendRawTransaction", &json!(["0xnothex"])),
+            RequestAccounts::default()
+        );
+    }
+
+    #[test]
+    fn rejects_deeply_nested_input_without_overflowing() {
+        // 100000 lists, each holding only the next one
+        let depth = 100_000;
+        let mut headers = Vec::with_capacity(depth);
+        let mut len = 1;  // The innermost empty list
+        for _ in 0..depth {
+            let header = rlp::list_header(len);
+            len += header.len();
+            headers.push(header);
+        }
+        let raw: Vec<u8> = headers.into_iter().rev().flatten().chain([0xc0]).collect();
+
+        assert!(rlp::parse_all(&raw).is_err());
+        assert_eq!(send_raw(&raw), RequestAccounts::default());
+        assert_eq!(send_raw(&[vec![2], raw].concat()), RequestAccounts::default());
+    }
+
+    #[test]
+    fn accepts_nesting_up_to_the_limit() {
+        let mut nested = list(&[]);
+        for _ in 0..4 {
+            nested = list(&[nested]);
+        }
+        assert!(rlp::parse_all(&nested).is_ok());
+        assert!(rlp::parse_all(&list(&[nested])).is_err());
+    }
+
+    #[test]
+    fn finds_accounts_of_calls_and_balances() {
+        let from = format_address(&key_address());
+        let call = RequestAccounts::of_request(
+            "eth_call",
+            &json!([{ "from": from, "to": format_address(&CONTRACT), "data": format!("0x{}", hex::encode(calldata())) }, "latest"]),
+        );
+        assert_eq!(call.account, Some(key_address()));
+        assert_eq!(call.mentioned, vec![CONTRACT, OTHER]);
+
+        let balance = RequestAccounts::of_request("eth_getBalance", &json!([from, "latest"]));
+        assert_eq!(balance.account, Some(key_address()));
+        assert_eq!(RequestAccounts::of_request("eth_blockNumber", &json!([])), RequestAccounts::default());
+    }
+}
@@ -0,0 +1,212 @@
+use chacha20::cipher::StreamCipher;
+use chacha20::ChaCha20;
+use penum_protocol::cell::{CellHeader, CellType, Packet, PACKET_SIZE};
+use penum_protocol::extend::encode_extend;
+use penum_protocol::handshake::{ClientHandshake, HandshakeSecrets, CLIENT_HELLO_LEN, HANDSHAKE_REPLY_LEN};
+use penum_protocol::kdf::OnionLayer;
+use penum_protocol::kem::CIPHERTEXT_CELLS;
+use penum_protocol::session::Session;
+use penum_protocol::PublicKey;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+// A telescoping onion circuit: one TCP connection to the entry relay and one
+// onion layer per relay hop. Each relay only ever learns its neighbours.
+pub struct Circuit {
+    stream: TcpStream,
+    layers: Vec<OnionLayer>,
+    max_version: u8,
+    capabilities: u16,
+}
+
+impl Circuit {
+    // Connect to the entry relay and negotiate the first onion layer.
+    // Every hop is offered protocol versions MIN_PROTOCOL_VERSION..=max_version
+    // and the given capability bits. A hop's expected identity key, when
+    // known, masks the hello to it. Returns the entry relay's identity key.
+    pub async fn connect(
+        entry_relay: SocketAddr,
+        expected_identity: Option<PublicKey>,
+        max_version: u8,
+        capabilities: u16,
+    ) -> anyhow::Result<(Self, PublicKey)> {
+        let mut stream = TcpStream::connect(entry_relay).await?;
+
+        // Both hellos are padded to full cells, so the handshake looks like data
+        let handshake = ClientHandshake::new(max_version, capabilities, expected_identity.as_ref());
+        stream.write_all(&Packet::padded(handshake.hello())).await?;
+
+        let mut reply_cell = [0u8; PACKET_SIZE];
+        stream.read_exact(&mut reply_cell).await?;
+        let mut reply = [0u8; HANDSHAKE_REPLY_LEN];
+        reply.copy_from_slice(&reply_cell[..HANDSHAKE_REPLY_LEN]);
+
+        // With no layers yet, the ML-KEM cells go to the entry relay as they are
+        let mut circuit = Self {
+            stream,
+            layers: Vec::new(),
+            max_version,
+            capabilities,
+        };
+        let (identity, secrets) = circuit
+            .complete_handshake(handshake, &reply)
+            .await
+            .map_err(|e| e.context(format!("entry relay {}", entry_relay)))?;
+        circuit.layers.push(secrets.onion_layer());
+        Ok((circuit, identity))
+    }
+
+    // Extend the circuit through the current last hop to another relay.
+    // Returns the relay's identity key.
+    pub async fn extend(&mut self, next_hop: SocketAddr, expected_identity: Option<PublicKey>) -> anyhow::Result<PublicKey> {
+        let handshake = ClientHandshake::new(self.max_version, self.capabilities, expected_identity.as_ref());
+        let reply = self.extend_handshake(next_hop, handshake.hello(), expected_identity.as_ref()).await?;
+        let (identity, secrets) = self
+            .complete_handshake(handshake, &reply)
+            .await
+            .map_err(|e| e.context(format!("relay {}", next_hop)))?;
+        self.layers.push(secrets.onion_layer());
+        Ok(identity)
+    }
+
+    // Extend the circuit to the gateway and derive the end-to-end session.
+    // The gateway terminates the circuit but still adds an onion layer, so
+    // sealed cells never show their headers on the last link.
+    // Returns the gateway's identity key so the caller can check it against a pin
+    // before calling `confirm_session`.
+    pub async fn open_gateway(
+        &mut self,
+        gateway: SocketAddr,
+        expected_identity: Option<PublicKey>,
+    ) -> anyhow::Result<(PublicKey, Session)> {
+        let handshake = ClientHandshake::new(self.max_version, self.capabilities, expected_identity.as_ref());
+        let reply = self.extend_handshake(gateway, handshake.hello(), expected_identity.as_ref()).await?;
+        let (gateway_identity, secrets) = self
+            .complete_handshake(handshake, &reply)
+            .await
+            .map_err(|e| e.context(format!("gateway {}", gateway)))?;
+        self.layers.push(secrets.onion_layer());
+        Ok((gateway_identity, secrets.client_session()))
+    }
+
+    // Check a hop's reply and, if it selected the hybrid handshake, exchange
+    // the ML-KEM cells with it through the layers of the hops before it
+    async fn complete_handshake(
+        &mut self,
+        mut handshake: ClientHandshake,
+        reply: &[u8; HANDSHAKE_REPLY_LEN],
+    ) -> anyhow::Result<(PublicKey, HandshakeSecrets)> {
+        let mut ciphertext_cells = Vec::with_capacity(CIPHERTEXT_CELLS);
+        if let Some(encapsulation_key_cells) = handshake.read_reply(reply)? {
+            for cell in encapsulation_key_cells {
+                self.send_cell(cell).await?;
+            }
+            for _ in 0..CIPHERTEXT_CELLS {
+                ciphertext_cells.push(self.recv_cell().await?);
+            }
+        }
+        handshake.finish(&ciphertext_cells)
+    }
+
+    // Key confirmation: the gateway's first cell proves it derived the same
+    // session keys, then we send ours. Nothing else is sent before this.
+    pub async fn confirm_session(&mut self, session: &mut Session) -> anyhow::Result<()> {
+        let cell = self.recv_cell().await?;
+        session.check_confirmation(cell)?;
+        self.send_cell(session.confirmation_cell()?).await
+    }
+
+    // Ask the current last hop to connect to `next_hop` and relay our handshake.
+    // Extend and extended cells are handshake cells under the last hop's layer.
+    // The hop's identity key, when known, lets the relay share a link to it.
+    async fn extend_handshake(
+        &mut self,
+        next_hop: SocketAddr,
+        hello: &[u8; CLIENT_HELLO_LEN],
+        identity: Option<&PublicKey>,
+    ) -> anyhow::Result<[u8; HANDSHAKE_REPLY_LEN]> {
+        let payload = encode_extend(next_hop, hello, identity);
+        let header = CellHeader::new(CellType::Handshake, 0, 0, payload.len());
+        self.send_cell(Packet::encode(&header, &payload)?).await?;
+
+        let reply = self.recv_cell().await?;
+        let (header, payload) = Packet::decode(&reply)
+            .map_err(|_| anyhow::anyhow!("Circuit extension failed"))?;
+        if header.cell_type != CellType::Handshake || payload.len() != HANDSHAKE_REPLY_LEN {
+            return Err(anyhow::anyhow!("Circuit extension failed"));
+        }
+
+        let mut handshake = [0u8; HANDSHAKE_REPLY_LEN];
+        handshake.copy_from_slice(payload);
+        Ok(handshake)
+    }
+
+    // Wrap a cell in one layer per hop (innermost first) and send it to the entry relay
+    pub async fn send_cell(&mut self, mut cell: [u8; PACKET_SIZE]) -> anyhow::Result<()> {
+        for layer in self.layers.iter_mut().rev() {
+            layer.apply_forward(&mut cell);
+        }
+        self.stream.write_all(&cell).await?;
+        Ok(())
+    }
+
+    // Receive a cell from the entry relay and peel one layer per hop (outermost first)
+    pub async fn recv_cell(&mut self) -> anyhow::Result<[u8; PACKET_SIZE]> {
+        let mut cell = [0u8; PACKET_SIZE];
+        self.stream.read_exact(&mut cell).await?;
+        for layer in self.layers.iter_mut() {
+            layer.apply_backward(&mut cell);
+        }
+        Ok(cell)
+    }
+
+    // Split a built circuit so cells can be sent and received concurrently
+    pub fn into_split(self) -> (CircuitWriter, CircuitReader) {
+        let (read_half, write_half) = self.stream.into_split();
+        let (forward, backward) = self
+            .layers
+            .into_iter()
+            .map(|layer| (layer.forward, layer.backward))
+            .unzip();
+
+        (
+            CircuitWriter { stream: write_half, layers: forward },
+            CircuitReader { stream: read_half, layers: backward },
+        )
+    }
+}
+
+// Sending half of a circuit, holding the forward keystream of every hop
+pub struct CircuitWriter {
+    stream: OwnedWriteHalf,
+    layers: Vec<ChaCha20>,
+}
+
+impl CircuitWriter {
+    pub async fn send_cell(&mut self, mut cell: [u8; PACKET_SIZE]) -> anyhow::Result<()> {
+        for layer in self.layers.iter_mut().rev() {
+            layer.apply_keystream(&mut cell);
+        }
+        self.stream.write_all(&cell).await?;
+        Ok(())
+    }
+}
+
+// Receiving half of a circuit, holding the backward keystream of every hop
+pub struct CircuitReader {
+    stream: OwnedReadHalf,
+    layers: Vec<ChaCha20>,
+}
+
+impl CircuitReader {
+    pub async fn recv_cell(&mut self) -> anyhow::Result<[u8; PACKET_SIZE]> {
+        let mut cell = [0u8; PACKET_SIZE];
+        self.stream.read_exact(&mut cell).await?;
+        for layer in self.layers.iter_mut() {
+            layer.apply_keystream(&mut cell);
+        }
+        Ok(cell)
+    }
+}
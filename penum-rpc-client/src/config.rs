@@ -1,30 +1,205 @@
+use penum_protocol::handshake::{CAP_HYBRID_ML_KEM_768, MAX_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION};
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
 
+// Where the signed network directory comes from and who must have signed it
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct RelayConfig {
-    pub addr: SocketAddr,
+pub struct DirectoryConfig {
+    pub urls: Vec<String>,  // Mirrors serving the signed directory, tried in random order
+    pub authorities: Vec<String>,  // Hex-encoded Ed25519 keys of the trusted directory authorities
+    #[serde(default)]
+    pub threshold: Option<usize>,  // Authority signatures required before a directory is used; default a majority
+    #[serde(default = "default_directory_refresh_secs")]
+    pub refresh_secs: u64,
+    #[serde(default = "default_directory_cache_path")]
+    pub cache_path: String,  // Last accepted directory, used until it expires if no mirror answers
 }
 
+fn default_directory_refresh_secs() -> u64 {
+    3600
+}
+
+fn default_directory_cache_path() -> String {
+    "directory.json".to_string()
+}
+
+// Entry guards: the few relays every circuit enters through, kept across
+// restarts in a state file next to config.json
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct RpcClientConfig {
+pub struct GuardConfig {
+    #[serde(default = "default_guard_count")]
+    pub count: usize,  // Guards kept listed; circuits use the first one that is reachable
+    #[serde(default = "default_guard_lifetime_days")]
+    pub lifetime_days: u64,  // A guard is replaced after this long plus up to a third more
+    #[serde(default = "default_guard_state_path")]
+    pub state_path: String,
+}
+
+fn default_guard_count() -> usize {
+    2
+}
+
+fn default_guard_lifetime_days() -> u64 {
+    90
+}
+
+fn default_guard_state_path() -> String {
+    "state.json".to_string()
+}
+
+impl Default for GuardConfig {
+    fn default() -> Self {
+        Self {
+            count: default_guard_count(),
+            lifetime_days: default_guard_lifetime_days(),
+            state_path: default_guard_state_path(),
+        }
+    }
+}
+
+// Circuits built in the background before requests need them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PoolConfig {
+    #[serde(default = "default_pool_min_size")]
+    pub min_size: usize,  // Circuits kept ready even without recent demand
+    #[serde(default = "default_pool_max_size")]
+    pub max_size: usize,
+    #[serde(default = "default_pool_demand_window_secs")]
+    pub demand_window_secs: u64,  // The pool holds as many circuits as were needed during this window
+    #[serde(default = "default_pool_max_age_secs")]
+    pub max_age_secs: u64,  // Unused circuits are closed after this long
+}
+
+fn default_pool_min_size() -> usize {
+    1
+}
+
+fn default_pool_max_size() -> usize {
+    4
+}
+
+fn default_pool_demand_window_secs() -> u64 {
+    300
+}
+
+fn default_pool_max_age_secs() -> u64 {
+    300
+}
+
+// Mixnet mode: every request travels as one Sphinx packet through the mix
+// listeners of the relays and the gateway, and the answer comes back through
+// single-use reply blocks instead of a circuit
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MixnetConfig {
     pub entry_relay: SocketAddr,
+    pub entry_relay_key: String,  // Hex-encoded identity key of the entry relay
     pub middle_relay: SocketAddr,
-    pub gateway: SocketAddr,
+    pub middle_relay_key: String,  // Hex-encoded identity key of the middle relay
+    pub gateway: SocketAddr,  // The gateway's mix listener; its key is gateway_public_key
+    #[serde(default = "default_reply_blocks")]
+    pub reply_blocks: u8,  // Reply packets the gateway may send, which bounds the response size
+    #[serde(default = "default_reply_timeout_secs")]
+    pub reply_timeout_secs: u64,
+}
+
+fn default_reply_blocks() -> u8 {
+    8
+}
+
+fn default_reply_timeout_secs() -> u64 {
+    30
+}
+
+// Cover traffic on the tunnel: one cell per interval, padding when no real
+// cell is waiting, so the entry relay sees the same rate whether the wallet
+// is busy or idle
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+pub enum CoverTraffic {
+    #[default]
+    Off,
+    // Fixed interval between cells
+    Constant { interval_ms: u64 },
+    // Exponentially distributed intervals with the given mean
+    Randomized { mean_interval_ms: u64 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RpcClientConfig {
+    #[serde(default)]
+    pub entry_relay: Option<SocketAddr>,  // Static path, used when no directory is configured
+    #[serde(default)]
+    pub middle_relay: Option<SocketAddr>,
+    #[serde(default)]
+    pub gateway: Option<SocketAddr>,
+    #[serde(default)]
+    pub entry_relay_key: Option<String>,  // Hex-encoded identity keys of the static relays; hellos to them are masked under these
+    #[serde(default)]
+    pub middle_relay_key: Option<String>,
     pub rpc_port: u16,
     pub ui_port: u16,
-    pub protocol_version: u8,
+    pub protocol_version: u8,  // Highest protocol version offered to relays and the gateway
+    #[serde(default)]
+    pub gateway_public_key: Option<String>,  // Hex-encoded gateway identity key to pin
+    #[serde(default = "default_circuit_lifetime_secs")]
+    pub circuit_lifetime_secs: u64,  // How long a tunnel is reused before a new circuit is built
+    #[serde(default)]
+    pub circuit_max_requests: Option<u64>,  // Requests a tunnel carries before a new circuit is built
+    #[serde(default)]
+    pub rotate_on_account_switch: bool,  // New circuits for an origin when its requests move to another account
+    #[serde(default)]
+    pub strict_ephemeral: bool,  // Build a new circuit for every request instead of reusing the tunnel
+    #[serde(default = "default_post_quantum")]
+    pub post_quantum: bool,  // Offer the hybrid X25519 + ML-KEM-768 handshake to every hop
+    #[serde(default)]
+    pub mixnet: Option<MixnetConfig>,  // Send requests as Sphinx packets instead of over circuits
+    #[serde(default)]
+    pub cover_traffic: CoverTraffic,
+    #[serde(default)]
+    pub directory: Option<DirectoryConfig>,  // Choose every circuit's path from the signed directory
+    #[serde(default = "default_latency_candidates")]
+    pub latency_candidates: usize,  // Random paths compared by latency per circuit; 1 ignores latency
+    #[serde(default)]
+    pub circuit_pool: Option<PoolConfig>,  // Keep circuits ready so requests do not wait for handshakes
+    #[serde(default)]
+    pub guards: GuardConfig,  // Used with a directory; a static path has a fixed entry relay already
+}
+
+fn default_circuit_lifetime_secs() -> u64 {
+    600
+}
+
+fn default_post_quantum() -> bool {
+    true
+}
+
+fn default_latency_candidates() -> usize {
+    3
 }
 
 impl Default for RpcClientConfig {
     fn default() -> Self {
         Self {
-            entry_relay: "127.0.0.1:9001".parse().expect("Failed to parse default entry relay address"),
-            middle_relay: "127.0.0.1:9002".parse().expect("Failed to parse default middle relay address"),
-            gateway: "127.0.0.1:9003".parse().expect("Failed to parse default gateway address"),
+            entry_relay: Some("127.0.0.1:9001".parse().expect("Failed to parse default entry relay address")),
+            middle_relay: Some("127.0.0.1:9002".parse().expect("Failed to parse default middle relay address")),
+            gateway: Some("127.0.0.1:9003".parse().expect("Failed to parse default gateway address")),
+            entry_relay_key: None,
+            middle_relay_key: None,
             rpc_port: 8545,
             ui_port: 8546,
-            protocol_version: 1,
+            protocol_version: 3,
+            gateway_public_key: None,
+            circuit_lifetime_secs: default_circuit_lifetime_secs(),
+            circuit_max_requests: None,
+            rotate_on_account_switch: false,
+            strict_ephemeral: false,
+            post_quantum: default_post_quantum(),
+            mixnet: None,
+            cover_traffic: CoverTraffic::Off,
+            directory: None,
+            latency_candidates: default_latency_candidates(),
+            circuit_pool: None,
+            guards: GuardConfig::default(),
         }
     }
 }
@@ -34,4 +209,175 @@ impl RpcClientConfig {
         let config: RpcClientConfig = serde_json::from_str(json)?;
         Ok(config)
     }
+
+    pub fn pinned_gateway_key(&self) -> anyhow::Result<Option<[u8; 32]>> {
+        match &self.gateway_public_key {
+            Some(key_hex) => Ok(Some(parse_key("gateway_public_key", key_hex)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn validate_protocol_version(&self) -> anyhow::Result<()> {
+        if !(MIN_PROTOCOL_VERSION..=MAX_PROTOCOL_VERSION).contains(&self.protocol_version) {
+            return Err(anyhow::anyhow!(
+                "Unsupported protocol_version {}: this client supports {}-{}",
+                self.protocol_version,
+                MIN_PROTOCOL_VERSION,
+                MAX_PROTOCOL_VERSION
+            ));
+        }
+        Ok(())
+    }
+
+    // Without a directory, circuits use the configured hops
+    pub fn static_path(&self) -> anyhow::Result<Option<(SocketAddr, SocketAddr, SocketAddr)>> {
+        if self.directory.is_some() {
+            return Ok(None);
+        }
+        match (self.entry_relay, self.middle_relay, self.gateway) {
+            (Some(entry_relay), Some(middle_relay), Some(gateway)) => Ok(Some((entry_relay, middle_relay, gateway))),
+            _ => Err(anyhow::anyhow!(
+                "entry_relay, middle_relay and gateway are required when no directory is configured"
+            )),
+        }
+    }
+
+    pub fn validate_rotation(&self) -> anyhow::Result<()> {
+        if self.circuit_max_requests == Some(0) {
+            return Err(anyhow::anyhow!("circuit_max_requests must be at least 1"));
+        }
+        Ok(())
+    }
+
+    pub fn validate_cover_traffic(&self) -> anyhow::Result<()> {
+        match self.cover_traffic {
+            CoverTraffic::Constant { interval_ms: 0 } | CoverTraffic::Randomized { mean_interval_ms: 0 } => {
+                Err(anyhow::anyhow!("cover_traffic interval must be at least 1 ms"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Capability bits offered in every handshake. Hops that do not support
+    // the hybrid handshake fall back to plain X25519.
+    pub fn offered_capabilities(&self) -> u16 {
+        if self.post_quantum {
+            CAP_HYBRID_ML_KEM_768
+        } else {
+            0
+        }
+    }
+}
+
+impl MixnetConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.reply_blocks == 0 {
+            return Err(anyhow::anyhow!("mixnet.reply_blocks must be at least 1"));
+        }
+        Ok(())
+    }
+}
+
+impl DirectoryConfig {
+    pub fn threshold(&self) -> usize {
+        self.threshold.unwrap_or(self.authorities.len() / 2 + 1)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.urls.is_empty() {
+            return Err(anyhow::anyhow!("directory.urls must list at least one mirror"));
+        }
+        if self.authorities.is_empty() {
+            return Err(anyhow::anyhow!("directory.authorities must list at least one authority"));
+        }
+        let threshold = self.threshold();
+        if threshold == 0 || threshold > self.authorities.len() {
+            return Err(anyhow::anyhow!(
+                "directory.threshold must be between 1 and the number of authorities ({})",
+                self.authorities.len()
+            ));
+        }
+        // Otherwise any single authority, or whoever steals its key, could
+        // hand out a directory of its own choosing
+        if threshold == 1 && self.authorities.len() > 1 {
+            return Err(anyhow::anyhow!(
+                "directory.threshold of 1 lets one of the {} authorities decide alone; require at least 2",
+                self.authorities.len()
+            ));
+        }
+        if self.refresh_secs == 0 {
+            return Err(anyhow::anyhow!("directory.refresh_secs must be at least 1"));
+        }
+        Ok(())
+    }
+}
+
+impl PoolConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.max_size == 0 || self.min_size > self.max_size {
+            return Err(anyhow::anyhow!("circuit_pool needs 1 <= max_size and min_size <= max_size"));
+        }
+        if self.max_age_secs == 0 {
+            return Err(anyhow::anyhow!("circuit_pool.max_age_secs must be at least 1"));
+        }
+        Ok(())
+    }
+}
+
+impl GuardConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.count == 0 {
+            return Err(anyhow::anyhow!("guards.count must be at least 1"));
+        }
+        if self.lifetime_days == 0 {
+            return Err(anyhow::anyhow!("guards.lifetime_days must be at least 1"));
+        }
+        Ok(())
+    }
+}
+
+pub fn parse_key(name: &str, key_hex: &str) -> anyhow::Result<[u8; 32]> {
+    hex::decode(key_hex.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| anyhow::anyhow!("Invalid {}: expected 32 hex-encoded bytes", name))
+}
+
+pub fn parse_optional_key(name: &str, key_hex: &Option<String>) -> anyhow::Result<Option<[u8; 32]>> {
+    key_hex.as_deref().map(|key_hex| parse_key(name, key_hex)).transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn directory(authorities: usize, threshold: Option<usize>) -> DirectoryConfig {
+        DirectoryConfig {
+            urls: vec!["http://198.51.100.7:7000/directory".to_string()],
+            authorities: vec![String::new(); authorities],
+            threshold,
+            refresh_secs: 3600,
+            cache_path: default_directory_cache_path(),
+        }
+    }
+
+    #[test]
+    fn threshold_defaults_to_a_majority() {
+        for (authorities, majority) in [(1, 1), (2, 2), (3, 2), (4, 3), (5, 3)] {
+            let config = directory(authorities, None);
+            assert_eq!(config.threshold(), majority);
+            assert!(config.validate().is_ok());
+        }
+    }
+
+    #[test]
+    fn threshold_must_need_more_than_one_of_several_authorities() {
+        assert!(directory(1, Some(1)).validate().is_ok());
+        assert!(directory(3, Some(1)).validate().is_err());
+        assert!(directory(3, Some(2)).validate().is_ok());
+        assert!(directory(3, Some(3)).validate().is_ok());
+        assert!(directory(3, Some(4)).validate().is_err());
+        assert!(directory(3, Some(0)).validate().is_err());
+        assert!(directory(0, None).validate().is_err());
+    }
 }
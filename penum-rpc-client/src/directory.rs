@@ -0,0 +1,123 @@
+use crate::config::DirectoryConfig;
+use penum_protocol::cell::unix_time;
+use penum_protocol::directory::{parse_authority_key, Directory, SignedDirectory, VerifyingKey};
+use rand::seq::SliceRandom;
+use std::fs;
+use std::sync::RwLock;
+use std::time::Duration;
+
+// How long one directory download may take
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Retry interval after a failed refresh, shorter than the regular one
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+// Keeps a verified copy of the network directory. It is loaded from the cache
+// file at startup and written back whenever a newer directory is accepted.
+// Mirrors are only asked while there is no usable copy; after that the
+// client refreshes it through its own circuits (see keep_directory_fresh).
+pub struct DirectoryClient {
+    config: DirectoryConfig,
+    authorities: Vec<VerifyingKey>,
+    http: reqwest::Client,
+    current: RwLock<Option<Directory>>,
+}
+
+impl DirectoryClient {
+    pub fn new(config: DirectoryConfig) -> anyhow::Result<Self> {
+        config.validate()?;
+        let authorities = config
+            .authorities
+            .iter()
+            .map(|key| parse_authority_key(key).map_err(|e| e.context("Invalid directory authority key")))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let client = Self {
+            http: reqwest::Client::builder().timeout(FETCH_TIMEOUT).build()?,
+            current: RwLock::new(None),
+            authorities,
+            config,
+        };
+
+        // A cached directory is only used if it still verifies and has not expired
+        if let Ok(cached) = fs::read_to_string(&client.config.cache_path) {
+            match client.accept(&cached) {
+                Ok(directory) => println!("🌐 Loaded cached directory with {} nodes", directory.nodes.len()),
+                Err(e) => eprintln!("⚠️  Ignoring cached directory: {:#}", e),
+            }
+        }
+        Ok(client)
+    }
+
+    // The current directory, if one has been verified and has not expired since
+    pub fn current(&self) -> anyhow::Result<Directory> {
+        let current = self.current.read().expect("directory lock poisoned");
+        match current.as_ref() {
+            Some(directory) if directory.valid_until >= unix_time() => Ok(directory.clone()),
+            Some(_) => Err(anyhow::anyhow!("Directory expired and no newer one could be fetched")),
+            None => Err(anyhow::anyhow!("No verified directory available yet")),
+        }
+    }
+
+    // Try the mirrors in random order until one serves a directory that
+    // verifies and is not older than the one we have
+    pub async fn refresh(&self) -> anyhow::Result<()> {
+        let mut urls = self.config.urls.clone();
+        urls.shuffle(&mut rand::thread_rng());
+
+        let mut last_error = anyhow::anyhow!("No directory URLs configured");
+        for url in urls {
+            match self.fetch(&url).await {
+                Ok(document) => match self.store(&document) {
+                    Ok(()) => return Ok(()),
+                    Err(e) => last_error = e.context(format!("directory from {}", url)),
+                },
+                Err(e) => last_error = e.context(format!("fetching directory from {}", url)),
+            }
+        }
+        Err(last_error)
+    }
+
+    // Accept a directory, from a mirror or through a circuit, and cache it
+    pub fn store(&self, document: &str) -> anyhow::Result<()> {
+        self.accept(document)?;
+        // Failing to cache only costs a fetch at the next start
+        let _ = fs::write(&self.config.cache_path, document);
+        Ok(())
+    }
+
+    // How long to wait before the next refresh, sooner after a failure
+    pub fn next_refresh(&self, refreshed: bool) -> Duration {
+        let regular = Duration::from_secs(self.config.refresh_secs);
+        if refreshed {
+            regular
+        } else {
+            RETRY_INTERVAL.min(regular)
+        }
+    }
+
+    // Fetched directly, which is only done without a usable directory: no
+    // circuit can be built then. A mirror therefore learns our address and
+    // that we run the client, but nothing about our requests.
+    async fn fetch(&self, url: &str) -> anyhow::Result<String> {
+        let response = self.http.get(url).send().await?.error_for_status()?;
+        Ok(response.text().await?)
+    }
+
+    // Verify a signed directory and make it current. An older directory than
+    // the current one is refused, so a mirror cannot roll clients back to a
+    // network view the authorities have since replaced.
+    fn accept(&self, document: &str) -> anyhow::Result<Directory> {
+        let signed: SignedDirectory = serde_json::from_str(document)?;
+        let directory = signed.verify(&self.authorities, self.config.threshold(), unix_time())?;
+
+        let mut current = self.current.write().expect("directory lock poisoned");
+        if let Some(existing) = current.as_ref() {
+            if directory.published < existing.published {
+                return Err(anyhow::anyhow!("Directory is older than the current one"));
+            }
+        }
+        *current = Some(directory.clone());
+        Ok(directory)
+    }
+}
@@ -0,0 +1,296 @@
+use crate::config::GuardConfig;
+use crate::path;
+use penum_protocol::cell::unix_time;
+use penum_protocol::directory::{Directory, NodeDescriptor, NodeFlag, NodeRole};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// A guard the consensus stopped listing, for instance because it lost the
+// guard or running flag, is not used but keeps its place this long before a
+// replacement is sampled, so one bad consensus or a relay's maintenance does
+// not rotate guards. It is dropped after the longer removal period.
+const UNLISTED_GRACE_SECS: u64 = 7 * 24 * 3600;
+const UNLISTED_REMOVAL_SECS: u64 = 30 * 24 * 3600;
+
+// First retry delay after a guard failed to connect, doubled per failure
+const RETRY_BASE: Duration = Duration::from_secs(30);
+const RETRY_MAX: Duration = Duration::from_secs(3600);
+
+const DAY_SECS: u64 = 24 * 3600;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Guard {
+    identity_key: String,
+    added: u64,
+    expires: u64,
+    #[serde(default)]
+    unlisted_since: Option<u64>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct GuardState {
+    guards: Vec<Guard>,
+}
+
+// Connection failures seen by this client. Kept in memory only: after a
+// restart every guard gets another chance.
+struct Backoff {
+    failures: u32,
+    retry_at: Instant,
+}
+
+// Persistent entry guards. Every circuit enters the network through one of a
+// few relays chosen once and kept for months, so a malicious entry relay
+// either is a guard from the start or never sees this client at all.
+//
+// Guards are used in the order they were added, and replaced only when they
+// expire or the authorities have stopped listing them for a while. A guard this client cannot
+// reach is retried with backoff but never replaced: otherwise anyone who can
+// block connections could push the client through entry relays until it
+// lands on theirs.
+pub struct EntryGuards {
+    config: GuardConfig,
+    state: Mutex<GuardState>,
+    backoff: Mutex<HashMap<String, Backoff>>,
+}
+
+impl EntryGuards {
+    pub fn load(config: GuardConfig) -> anyhow::Result<Self> {
+        config.validate()?;
+        let state = match fs::read_to_string(&config.state_path) {
+            Ok(state) => serde_json::from_str(&state)
+                .map_err(|e| anyhow::anyhow!("Invalid guard state in {}: {}", config.state_path, e))?,
+            Err(_) => GuardState::default(),
+        };
+        Ok(Self {
+            config,
+            state: Mutex::new(state),
+            backoff: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // The entry relay for a new circuit: the first guard the directory lists
+    // that is not backing off
+    pub fn choose(&self, directory: &Directory) -> anyhow::Result<NodeDescriptor> {
+        let now = unix_time();
+        let mut state = self.state.lock().expect("guard state poisoned");
+        let changed = self.update(&mut state, directory, now);
+        if changed {
+            // Failing to save only means new guards after a restart
+            if let Err(e) = self.save(&state) {
+                eprintln!("⚠️  Could not save entry guards: {:#}", e);
+            }
+        }
+
+        let backoff = self.backoff.lock().expect("guard backoff poisoned");
+        let usable = |guard: &Guard| {
+            guard.unlisted_since.is_none()
+                && backoff
+                    .get(&guard.identity_key)
+                    .is_none_or(|backoff| backoff.retry_at <= Instant::now())
+        };
+        state
+            .guards
+            .iter()
+            .filter(|guard| usable(guard))
+            .find_map(|guard| listed_guard(directory, &guard.identity_key).cloned())
+            .ok_or_else(|| {
+                anyhow::anyhow!("All entry guards are unreachable or unlisted; not choosing new ones so an attacker cannot force guard churn")
+            })
+    }
+
+    pub fn record_failure(&self, identity: &[u8; 32]) {
+        let key = hex::encode(identity);
+        if !self.is_guard(&key) {
+            return;
+        }
+        let mut backoff = self.backoff.lock().expect("guard backoff poisoned");
+        let entry = backoff.entry(key).or_insert(Backoff {
+            failures: 0,
+            retry_at: Instant::now(),
+        });
+        entry.failures += 1;
+        let delay = RETRY_BASE
+            .saturating_mul(1 << entry.failures.min(16))
+            .min(RETRY_MAX);
+        entry.retry_at = Instant::now() + delay;
+    }
+
+    pub fn record_success(&self, identity: &[u8; 32]) {
+        self.backoff
+            .lock()
+            .expect("guard backoff poisoned")
+            .remove(&hex::encode(identity));
+    }
+
+    fn is_guard(&self, key: &str) -> bool {
+        self.state
+            .lock()
+            .expect("guard state poisoned")
+            .guards
+            .iter()
+            .any(|guard| guard.identity_key == key)
+    }
+
+    // Track which guards the directory lists, drop expired and long
+    // unlisted ones, and sample new guards until enough are listed or still
+    // within their grace period. If none is listed at all, one more is
+    // sampled so circuits can still be built. Returns whether the state changed.
+    fn update(&self, state: &mut GuardState, directory: &Directory, now: u64) -> bool {
+        let mut changed = false;
+        for guard in state.guards.iter_mut() {
+            let listed = listed_guard(directory, &guard.identity_key).is_some();
+            match (listed, guard.unlisted_since) {
+                (true, Some(_)) => guard.unlisted_since = None,
+                (false, None) => guard.unlisted_since = Some(now),
+                _ => continue,
+            }
+            changed = true;
+        }
+
+        let before = state.guards.len();
+        state.guards.retain(|guard| {
+            guard.expires > now && guard.unlisted_since.is_none_or(|since| since + UNLISTED_REMOVAL_SECS > now)
+        });
+        changed |= state.guards.len() != before;
+
+        let holds_place = |guard: &Guard| guard.unlisted_since.is_none_or(|since| since + UNLISTED_GRACE_SECS > now);
+        while state.guards.iter().filter(|guard| holds_place(guard)).count() < self.config.count
+            || state.guards.iter().all(|guard| guard.unlisted_since.is_some())
+        {
+            let Some(node) = sample_guard(directory, &state.guards) else {
+                break; // Not enough guard relays in the directory yet
+            };
+            let lifetime = self.config.lifetime_days * DAY_SECS;
+            state.guards.push(Guard {
+                identity_key: node.identity_key.to_lowercase(),
+                added: now,
+                // Spread expiry so guards chosen together are not replaced together
+                expires: now + rand::thread_rng().gen_range(lifetime..=lifetime + lifetime / 3),
+                unlisted_since: None,
+            });
+            changed = true;
+        }
+        changed
+    }
+
+    fn save(&self, state: &GuardState) -> anyhow::Result<()> {
+        let temporary = format!("{}.tmp", self.config.state_path);
+        fs::write(&temporary, serde_json::to_string_pretty(state)?)?;
+        fs::rename(&temporary, &self.config.state_path)?;
+        Ok(())
+    }
+}
+
+fn listed_guard<'a>(directory: &'a Directory, identity_key: &str) -> Option<&'a NodeDescriptor> {
+    directory.nodes.iter().find(|node| {
+        node.identity_key.eq_ignore_ascii_case(identity_key)
+            && node.role == NodeRole::Relay
+            && node.is_usable()
+            && node.has_flag(NodeFlag::Guard)
+    })
+}
+
+// A new guard, weighted by bandwidth, in a different network and family
+// than the guards we already have
+fn sample_guard<'a>(directory: &'a Directory, guards: &[Guard]) -> Option<&'a NodeDescriptor> {
+    let existing: Vec<&NodeDescriptor> = guards
+        .iter()
+        .filter_map(|guard| listed_guard(directory, &guard.identity_key))
+        .collect();
+    let candidates: Vec<&NodeDescriptor> = directory
+        .nodes
+        .iter()
+        .filter(|node| node.role == NodeRole::Relay && node.is_usable() && node.has_flag(NodeFlag::Guard))
+        .filter(|node| node.identity().is_ok())
+        .filter(|node| !guards.iter().any(|guard| node.identity_key.eq_ignore_ascii_case(&guard.identity_key)))
+        .collect();
+    path::draw(&candidates, &existing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use penum_protocol::{PublicKey, StaticSecret};
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn guard_relay(seed: u8) -> NodeDescriptor {
+        NodeDescriptor {
+            nickname: format!("node{}", seed),
+            role: NodeRole::Relay,
+            address: format!("{}.0.0.1:9001", seed).parse().unwrap(),
+            mix_address: None,
+            identity_key: hex::encode(PublicKey::from(&StaticSecret::from([seed; 32])).as_bytes()),
+            bandwidth: 100,
+            family: Vec::new(),
+            flags: vec![NodeFlag::Running, NodeFlag::Valid, NodeFlag::Guard],
+        }
+    }
+
+    fn directory(nodes: Vec<NodeDescriptor>) -> Directory {
+        Directory { published: 0, valid_until: u64::MAX, nodes }
+    }
+
+    fn entry_guards() -> EntryGuards {
+        EntryGuards {
+            config: GuardConfig::default(),
+            state: Mutex::new(GuardState::default()),
+            backoff: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn keys(state: &GuardState) -> Vec<String> {
+        state.guards.iter().map(|guard| guard.identity_key.clone()).collect()
+    }
+
+    #[test]
+    fn unlisted_guard_keeps_its_place_during_the_grace_period() {
+        let guards = entry_guards();
+        let mut state = GuardState::default();
+        let full = directory((1..=6).map(guard_relay).collect());
+        guards.update(&mut state, &full, NOW);
+        let sampled = keys(&state);
+        assert_eq!(sampled.len(), 2);
+
+        // The first guard loses its guard flag in one consensus
+        let mut flapped = full.clone();
+        let first = flapped.nodes.iter_mut().find(|node| node.identity_key == sampled[0]).unwrap();
+        first.flags.retain(|flag| *flag != NodeFlag::Guard);
+        guards.update(&mut state, &flapped, NOW + 600);
+        assert_eq!(keys(&state), sampled);
+        assert!(listed_guard(&flapped, &sampled[0]).is_none());
+
+        // It comes back before the grace period ends and is used again
+        guards.update(&mut state, &full, NOW + 3600);
+        assert_eq!(keys(&state), sampled);
+        assert!(state.guards.iter().all(|guard| guard.unlisted_since.is_none()));
+
+        // Only after the grace period is a replacement sampled, and the
+        // unlisted guard is still kept in case it returns
+        guards.update(&mut state, &flapped, NOW + 7200);
+        guards.update(&mut state, &flapped, NOW + 7200 + UNLISTED_GRACE_SECS);
+        assert_eq!(state.guards.len(), 3);
+        assert_eq!(keys(&state)[..2], sampled[..]);
+        guards.update(&mut state, &flapped, NOW + 7200 + UNLISTED_REMOVAL_SECS);
+        assert_eq!(state.guards.len(), 2);
+        assert!(!keys(&state).contains(&sampled[0]));
+    }
+
+    #[test]
+    fn a_listed_guard_is_sampled_when_none_is_listed() {
+        let guards = entry_guards();
+        let mut state = GuardState::default();
+        guards.update(&mut state, &directory((1..=6).map(guard_relay).collect()), NOW);
+        let sampled = keys(&state);
+
+        let remaining = directory((1..=6).map(guard_relay).filter(|node| !sampled.contains(&node.identity_key)).collect());
+        guards.update(&mut state, &remaining, NOW + 600);
+        assert_eq!(state.guards.len(), 3);
+        assert_eq!(state.guards.iter().filter(|guard| guard.unlisted_since.is_none()).count(), 1);
+    }
+}
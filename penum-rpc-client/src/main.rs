@@ -1,11 +1,19 @@
+mod accounts;
+mod circuit;
 mod config;
-mod crypto;
-mod packet;
+mod directory;
+mod guards;
+mod isolation;
+mod mixnet;
+mod path;
+mod path_bias;
 mod penum_client;
+mod pool;
 mod rpc_server;
+mod tunnel;
 mod ui;
 
-use config::RpcClientConfig;
+use config::{CoverTraffic, RpcClientConfig};
 use penum_client::PenumRpcClient;
 use std::sync::Arc;
 use std::fs;
@@ -22,13 +30,74 @@ async fn main() -> anyhow::Result<()> {
     };
 
     println!("🚀 Starting Penum RPC Client");
-    println!("   Entry Relay:  {}", config.entry_relay);
-    println!("   Middle Relay: {}", config.middle_relay);
-    println!("   Gateway:      {}", config.gateway);
+    match config.static_path()? {
+        Some((entry_relay, middle_relay, gateway)) => {
+            println!("   Entry Relay:  {}", entry_relay);
+            println!("   Middle Relay: {}", middle_relay);
+            println!("   Gateway:      {}", gateway);
+        }
+        None => println!("   Paths:        chosen per circuit from the directory"),
+    }
+    println!("   Protocol:     v{}", config.protocol_version);
+    if config.post_quantum {
+        println!("   Handshake:    hybrid X25519 + ML-KEM-768 where supported");
+    } else {
+        println!("   Handshake:    X25519");
+    }
+    match &config.gateway_public_key {
+        Some(key) => println!("   Gateway Key:  {} (pinned)", key),
+        None if config.directory.is_some() => println!("   Gateway Key:  taken from the directory"),
+        None => println!("⚠️  gateway_public_key not set, gateway identity is not pinned"),
+    }
+    if let Some(mixnet) = &config.mixnet {
+        println!("   Routing:      mixnet via {}, {} reply blocks per request", mixnet.entry_relay, mixnet.reply_blocks);
+    } else if config.strict_ephemeral {
+        println!("   Circuits:     new circuit per request (strict ephemeral mode)");
+    } else {
+        let mut rotation = format!("reused for {}s", config.circuit_lifetime_secs);
+        if let Some(max_requests) = config.circuit_max_requests {
+            rotation.push_str(&format!(" or {} requests", max_requests));
+        }
+        if config.rotate_on_account_switch {
+            rotation.push_str(", new ones on account switch");
+        }
+        println!("   Circuits:     {}", rotation);
+    }
+    if let (Some(pool), None) = (&config.circuit_pool, &config.mixnet) {
+        println!(
+            "   Pool:         {}-{} circuits ready by recent demand, closed after {}s unused",
+            pool.min_size, pool.max_size, pool.max_age_secs
+        );
+    }
+    match config.cover_traffic {
+        CoverTraffic::Off => {}
+        _ if config.mixnet.is_some() => println!("⚠️  cover_traffic applies to circuits only, not to mixnet mode"),
+        CoverTraffic::Constant { interval_ms } => println!("   Cover:        one cell every {} ms", interval_ms),
+        CoverTraffic::Randomized { mean_interval_ms } => {
+            println!("   Cover:        one cell every {} ms on average (randomized)", mean_interval_ms)
+        }
+    }
+    if let Some(directory) = &config.directory {
+        println!(
+            "   Directory:    {} mirrors, {} of {} authority signatures required",
+            directory.urls.len(),
+            directory.threshold(),
+            directory.authorities.len()
+        );
+        println!(
+            "   Guards:       {}, kept {}+ days in {}",
+            config.guards.count, config.guards.lifetime_days, config.guards.state_path
+        );
+    }
     println!();
 
     // Create Penum client
-    let penum_client = Arc::new(PenumRpcClient::new(config.clone()));
+    let penum_client = Arc::new(PenumRpcClient::new(config.clone())?);
+    tokio::spawn(penum_client.clone().keep_directory_fresh());
+    tokio::spawn(penum_client.clone().keep_pool());
+    if config.cover_traffic != CoverTraffic::Off && config.mixnet.is_none() {
+        tokio::spawn(penum_client.clone().keep_cover_tunnels());
+    }
 
     // Start RPC server and UI server concurrently
     let rpc_server = tokio::spawn(rpc_server::start_rpc_server(
@@ -36,7 +105,7 @@ async fn main() -> anyhow::Result<()> {
         penum_client.clone(),
     ));
 
-    let ui_server = tokio::spawn(ui::start_ui_server(config.ui_port, config.rpc_port));
+    let ui_server = tokio::spawn(ui::start_ui_server(config.ui_port, config.rpc_port, penum_client.clone()));
 
     // Wait for both servers
     tokio::select! {
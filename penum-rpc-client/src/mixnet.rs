@@ -0,0 +1,122 @@
+use crate::config::{parse_key, MixnetConfig};
+use penum_protocol::sphinx::{self, Command, SurbKeys, SPHINX_PACKET_SIZE};
+use penum_protocol::PublicKey;
+use rand::{thread_rng, RngCore};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+// Sends each request as one Sphinx packet: entry relay -> middle relay ->
+// gateway. No hop keeps state for the request, and the packets a relay sees
+// on its way in and out cannot be linked by their contents.
+pub struct MixnetClient {
+    config: MixnetConfig,
+    entry_relay_key: PublicKey,
+    middle_relay_key: PublicKey,
+    gateway_key: PublicKey,
+}
+
+impl MixnetClient {
+    pub fn new(config: MixnetConfig, gateway_key: Option<[u8; 32]>) -> anyhow::Result<Self> {
+        config.validate()?;
+        let gateway_key = gateway_key
+            .ok_or_else(|| anyhow::anyhow!("Mixnet mode needs gateway_public_key to address the gateway"))?;
+        Ok(Self {
+            entry_relay_key: PublicKey::from(parse_key("mixnet.entry_relay_key", &config.entry_relay_key)?),
+            middle_relay_key: PublicKey::from(parse_key("mixnet.middle_relay_key", &config.middle_relay_key)?),
+            gateway_key: PublicKey::from(gateway_key),
+            config,
+        })
+    }
+
+    pub async fn request(&self, message: &[u8]) -> anyhow::Result<Vec<u8>> {
+        // Replies travel gateway -> middle relay -> entry relay, which hands
+        // them to this connection through a fresh mailbox
+        let mut mailbox = [0u8; 16];
+        thread_rng().fill_bytes(&mut mailbox);
+
+        let mut surbs = Vec::with_capacity(self.config.reply_blocks as usize);
+        let mut surb_keys = Vec::with_capacity(self.config.reply_blocks as usize);
+        for index in 0..self.config.reply_blocks {
+            let reply_route = [
+                (
+                    self.middle_relay_key,
+                    Command::Forward {
+                        next_hop: self.config.entry_relay,
+                        reply_mailbox: None,
+                    },
+                ),
+                (self.entry_relay_key, Command::Deliver { mailbox, index }),
+            ];
+            let (surb, keys) = sphinx::create_surb(&reply_route, self.config.middle_relay)?;
+            surbs.push(surb);
+            surb_keys.push(keys);
+        }
+
+        let body = sphinx::encode_request(&surbs, message)?;
+        let route = [
+            (
+                self.entry_relay_key,
+                Command::Forward {
+                    next_hop: self.config.middle_relay,
+                    reply_mailbox: Some(mailbox),
+                },
+            ),
+            (
+                self.middle_relay_key,
+                Command::Forward {
+                    next_hop: self.config.gateway,
+                    reply_mailbox: None,
+                },
+            ),
+            (self.gateway_key, Command::Exit),
+        ];
+        let packet = sphinx::create_packet(&route, &body)?;
+
+        let mut stream = TcpStream::connect(self.config.entry_relay).await?;
+        stream.set_nodelay(true)?;
+        stream.write_all(&packet[..]).await?;
+
+        let timeout = Duration::from_secs(self.config.reply_timeout_secs);
+        tokio::time::timeout(timeout, receive_reply(&mut stream, &surb_keys))
+            .await
+            .map_err(|_| anyhow::anyhow!("No reply through the mixnet within {}s", self.config.reply_timeout_secs))?
+    }
+}
+
+// Collect reply packets until every part of the response has arrived. Mixing
+// relays may deliver them in any order.
+async fn receive_reply(stream: &mut TcpStream, surb_keys: &[SurbKeys]) -> anyhow::Result<Vec<u8>> {
+    let mut parts: Vec<Option<Vec<u8>>> = Vec::new();
+    loop {
+        let mut frame = Box::new([0u8; SPHINX_PACKET_SIZE]);
+        stream.read_exact(&mut frame[..]).await?;
+        let (index, payload) = sphinx::decode_delivery(&frame);
+
+        // Anything we cannot open was modified on the way
+        let Some(keys) = surb_keys.get(index as usize) else {
+            continue;
+        };
+        let Ok(body) = keys.open(payload) else {
+            continue;
+        };
+        let Some((part, count, data)) = sphinx::decode_reply_part(&body) else {
+            continue;
+        };
+
+        if count == 0 {
+            return Err(anyhow::anyhow!("Gateway could not process request"));
+        }
+        if parts.is_empty() {
+            parts = vec![None; count as usize];
+        }
+        if parts.len() != count as usize || part >= count {
+            continue;
+        }
+        parts[part as usize] = Some(data.to_vec());
+
+        if parts.iter().all(Option::is_some) {
+            return Ok(parts.into_iter().flatten().flatten().collect());
+        }
+    }
+}
@@ -0,0 +1,331 @@
+use penum_protocol::directory::{Directory, NodeDescriptor, NodeRole};
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::thread_rng;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Weight of the newest sample in a hop's latency estimate
+const LATENCY_SMOOTHING: f64 = 0.3;
+
+// Assumed for hops we have not built a circuit through yet
+const UNKNOWN_HOP_LATENCY: Duration = Duration::from_millis(200);
+
+// Attempts at drawing a path that satisfies the diversity rules
+const MAX_DRAWS: usize = 50;
+
+// One hop of a circuit, with the identity key it must answer with if known
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hop {
+    pub address: SocketAddr,
+    pub identity: Option<[u8; 32]>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Path {
+    pub entry_relay: Hop,
+    pub middle_relay: Hop,
+    pub gateway: Hop,
+}
+
+// Chooses a path per circuit from the directory. Nodes are drawn in
+// proportion to their bandwidth, and no two hops of a path may share a /16
+// (IPv4) or /32 (IPv6) network or a declared family, so one operator cannot
+// see both ends of a circuit.
+//
+// Latency only decides between a few paths that were each drawn this way,
+// which keeps the choice close to the bandwidth-weighted distribution: a
+// nearby node is picked at most `latency_candidates` times as often as its
+// weight alone would give.
+pub struct PathSelector {
+    latency_candidates: usize,
+    latencies: Mutex<HashMap<[u8; 32], Duration>>,
+}
+
+impl PathSelector {
+    pub fn new(latency_candidates: usize) -> Self {
+        Self {
+            latency_candidates: latency_candidates.max(1),
+            latencies: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // `entry` fixes the entry relay to a guard, `gateway_key` restricts the
+    // gateway to a pinned one
+    pub fn select(
+        &self,
+        directory: &Directory,
+        entry: Option<&NodeDescriptor>,
+        gateway_key: Option<[u8; 32]>,
+    ) -> anyhow::Result<Path> {
+        let gateways: Vec<&NodeDescriptor> = directory
+            .nodes
+            .iter()
+            .filter(|node| node.role == NodeRole::Gateway && node.is_usable() && identity_of(node).is_some())
+            .filter(|node| gateway_key.is_none_or(|key| identity_of(node) == Some(key)))
+            .collect();
+        let relays: Vec<&NodeDescriptor> = directory
+            .nodes
+            .iter()
+            .filter(|node| node.role == NodeRole::Relay && node.is_usable() && identity_of(node).is_some())
+            .collect();
+        if gateways.is_empty() {
+            return Err(anyhow::anyhow!("No usable gateway in the directory"));
+        }
+
+        let mut candidates = Vec::with_capacity(self.latency_candidates);
+        for _ in 0..self.latency_candidates {
+            if let Some(path) = draw_path(&gateways, &relays, entry) {
+                candidates.push(path);
+            }
+        }
+
+        let latencies = self.latencies.lock().expect("latency map poisoned");
+        let estimate = |path: &[&NodeDescriptor; 3]| -> Duration {
+            path.iter()
+                .map(|node| {
+                    identity_of(node)
+                        .and_then(|key| latencies.get(&key).copied())
+                        .unwrap_or(UNKNOWN_HOP_LATENCY)
+                })
+                .sum()
+        };
+        let [entry_relay, middle_relay, gateway] = candidates
+            .into_iter()
+            .min_by_key(|path| estimate(path))
+            .ok_or_else(|| {
+                anyhow::anyhow!("The directory has no three nodes in different networks and families to build a path from")
+            })?;
+
+        Ok(Path {
+            entry_relay: hop(entry_relay),
+            middle_relay: hop(middle_relay),
+            gateway: hop(gateway),
+        })
+    }
+
+    pub fn forget_latencies(&self) {
+        self.latencies.lock().expect("latency map poisoned").clear();
+    }
+
+    // Time it took to complete the handshake with a hop, measured from the
+    // client, so it includes the hops before it
+    pub fn record_latency(&self, identity: [u8; 32], elapsed: Duration) {
+        let mut latencies = self.latencies.lock().expect("latency map poisoned");
+        let estimate = latencies.entry(identity).or_insert(elapsed);
+        *estimate = estimate.mul_f64(1.0 - LATENCY_SMOOTHING) + elapsed.mul_f64(LATENCY_SMOOTHING);
+    }
+}
+
+// Gateway first, since there are fewest of them, then entry and middle relay
+fn draw_path<'a>(
+    gateways: &[&'a NodeDescriptor],
+    relays: &[&'a NodeDescriptor],
+    entry: Option<&'a NodeDescriptor>,
+) -> Option<[&'a NodeDescriptor; 3]> {
+    for _ in 0..MAX_DRAWS {
+        let gateway = draw(gateways, entry.as_slice())?;
+        let entry_relay = match entry {
+            Some(entry_relay) => entry_relay,
+            None => match draw(relays, &[gateway]) {
+                Some(entry_relay) => entry_relay,
+                None => continue,
+            },
+        };
+        let Some(middle_relay) = draw(relays, &[gateway, entry_relay]) else {
+            continue;
+        };
+        return Some([entry_relay, middle_relay, gateway]);
+    }
+    None
+}
+
+// Draw a node weighted by bandwidth among those compatible with `chosen`.
+// Nodes without a bandwidth still get a minimal weight.
+pub fn draw<'a>(nodes: &[&'a NodeDescriptor], chosen: &[&NodeDescriptor]) -> Option<&'a NodeDescriptor> {
+    let eligible: Vec<&NodeDescriptor> = nodes
+        .iter()
+        .copied()
+        .filter(|node| chosen.iter().all(|other| compatible(node, other)))
+        .collect();
+    let weights = WeightedIndex::new(eligible.iter().map(|node| node.bandwidth.max(1))).ok()?;
+    Some(eligible[weights.sample(&mut thread_rng())])
+}
+
+fn compatible(a: &NodeDescriptor, b: &NodeDescriptor) -> bool {
+    !a.identity_key.eq_ignore_ascii_case(&b.identity_key)
+        && network(a.address.ip()) != network(b.address.ip())
+        && !a.same_family(b)
+}
+
+// The network an operator most likely controls as a whole
+fn network(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            IpAddr::from([a, b, 0, 0])
+        }
+        IpAddr::V6(ip) => {
+            let mut octets = [0u8; 16];
+            octets[..4].copy_from_slice(&ip.octets()[..4]);
+            IpAddr::from(octets)
+        }
+    }
+}
+
+fn identity_of(node: &NodeDescriptor) -> Option<[u8; 32]> {
+    node.identity().ok().map(|key| *key.as_bytes())
+}
+
+fn hop(node: &NodeDescriptor) -> Hop {
+    Hop {
+        address: node.address,
+        identity: identity_of(node),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use penum_protocol::directory::NodeFlag;
+    use penum_protocol::{PublicKey, StaticSecret};
+
+    fn node(seed: u8, role: NodeRole, address: &str) -> NodeDescriptor {
+        NodeDescriptor {
+            nickname: format!("node{}", seed),
+            role,
+            address: address.parse().unwrap(),
+            mix_address: None,
+            identity_key: hex::encode(PublicKey::from(&StaticSecret::from([seed; 32])).as_bytes()),
+            bandwidth: 100,
+            family: Vec::new(),
+            flags: vec![NodeFlag::Running, NodeFlag::Valid],
+        }
+    }
+
+    fn relay(seed: u8, address: &str) -> NodeDescriptor {
+        node(seed, NodeRole::Relay, address)
+    }
+
+    fn gateway(seed: u8, address: &str) -> NodeDescriptor {
+        node(seed, NodeRole::Gateway, address)
+    }
+
+    fn directory(nodes: Vec<NodeDescriptor>) -> Directory {
+        Directory { published: 0, valid_until: u64::MAX, nodes }
+    }
+
+    fn addresses(path: &Path) -> [SocketAddr; 3] {
+        [path.entry_relay.address, path.middle_relay.address, path.gateway.address]
+    }
+
+    // Every path drawn from `directory`
+    fn paths(directory: &Directory, entry: Option<&NodeDescriptor>) -> Vec<[SocketAddr; 3]> {
+        let selector = PathSelector::new(3);
+        (0..200)
+            .map(|_| addresses(&selector.select(directory, entry, None).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn hops_are_in_different_networks() {
+        let directory = directory(vec![
+            gateway(1, "203.0.113.1:9003"),
+            relay(2, "198.51.1.1:9001"),
+            relay(3, "198.51.2.1:9001"),  // Same /16 as the relay above
+            relay(4, "192.0.2.1:9001"),
+            relay(5, "203.0.200.1:9001"),  // Same /16 as the gateway
+        ]);
+        let same_network: [SocketAddr; 2] = ["198.51.1.1:9001".parse().unwrap(), "198.51.2.1:9001".parse().unwrap()];
+        let gateway_network: SocketAddr = "203.0.200.1:9001".parse().unwrap();
+        for path in paths(&directory, None) {
+            assert!(!same_network.iter().all(|address| path.contains(address)), "{:?}", path);
+            assert!(!path.contains(&gateway_network), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn ipv6_hops_are_in_different_networks() {
+        let directory = directory(vec![
+            gateway(1, "[2001:db8:1::1]:9003"),
+            relay(2, "[2001:db8:2::1]:9001"),  // Same /32 as the gateway
+            relay(3, "[2606:4700::1]:9001"),
+            relay(4, "[2a00:1450::1]:9001"),
+        ]);
+        let excluded: SocketAddr = "[2001:db8:2::1]:9001".parse().unwrap();
+        for path in paths(&directory, None) {
+            assert!(!path.contains(&excluded), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn family_members_share_no_path() {
+        let mut a = relay(2, "198.51.100.1:9001");
+        let mut b = relay(3, "192.0.2.1:9001");
+        a.family = vec![b.identity_key.clone()];
+        b.family = vec![a.identity_key.clone()];
+        let directory = directory(vec![gateway(1, "203.0.113.1:9003"), a, b, relay(4, "100.1.0.1:9001")]);
+        for path in paths(&directory, None) {
+            assert!(!(path.contains(&"198.51.100.1:9001".parse().unwrap()) && path.contains(&"192.0.2.1:9001".parse().unwrap())));
+        }
+    }
+
+    #[test]
+    fn one_sided_family_does_not_count() {
+        let mut a = relay(2, "198.51.100.1:9001");
+        let b = relay(3, "192.0.2.1:9001");
+        a.family = vec![b.identity_key.clone()];
+        let directory = directory(vec![gateway(1, "203.0.113.1:9003"), a, b]);
+        assert!(PathSelector::new(1).select(&directory, None, None).is_ok());
+    }
+
+    #[test]
+    fn no_diverse_path_is_an_error() {
+        let directory = directory(vec![
+            gateway(1, "203.0.113.1:9003"),
+            relay(2, "198.51.1.1:9001"),
+            relay(3, "198.51.2.1:9001"),
+        ]);
+        assert!(PathSelector::new(3).select(&directory, None, None).is_err());
+    }
+
+    #[test]
+    fn guard_is_the_entry_and_constrains_the_rest() {
+        let guard = relay(2, "198.51.1.1:9001");
+        let directory = directory(vec![
+            gateway(1, "203.0.113.1:9003"),
+            gateway(6, "198.51.3.1:9003"),  // Same /16 as the guard
+            guard.clone(),
+            relay(3, "198.51.2.1:9001"),  // Same /16 as the guard
+            relay(4, "192.0.2.1:9001"),
+        ]);
+        for path in paths(&directory, Some(&guard)) {
+            assert_eq!(path[0], guard.address);
+            assert_eq!(path[1], "192.0.2.1:9001".parse::<SocketAddr>().unwrap());
+            assert_eq!(path[2], "203.0.113.1:9003".parse::<SocketAddr>().unwrap());
+        }
+    }
+
+    #[test]
+    fn pinned_gateway_and_unusable_nodes() {
+        let mut down = relay(5, "100.2.0.1:9001");
+        down.flags = vec![NodeFlag::Valid];
+        let directory = directory(vec![
+            gateway(1, "203.0.113.1:9003"),
+            gateway(6, "100.3.0.1:9003"),
+            relay(2, "198.51.100.1:9001"),
+            relay(3, "192.0.2.1:9001"),
+            down,
+        ]);
+        let pinned = identity_of(&directory.nodes[1]);
+        let selector = PathSelector::new(2);
+        for _ in 0..100 {
+            let path = selector.select(&directory, None, pinned).unwrap();
+            assert_eq!(path.gateway.identity, pinned);
+            assert_ne!(path.middle_relay.address, directory.nodes[4].address);
+            assert_ne!(path.entry_relay.address, directory.nodes[4].address);
+        }
+        assert!(selector.select(&directory, None, Some([9u8; 32])).is_err());
+    }
+}
@@ -0,0 +1,373 @@
+use crate::path::{Hop, Path};
+use penum_protocol::directory::Directory;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// Circuits through a relay before its failure rate is judged
+const MIN_CIRCUITS: u64 = 20;
+
+// Counts are halved at this many circuits, so old behavior fades and a relay
+// that turns bad later is still noticed
+const SCALE_AT: u64 = 300;
+
+// Standard deviations above the rest of the network for a warning, and for
+// leaving the relay out of new paths
+const WARN_SCORE: f64 = 3.0;
+const EXCLUDE_SCORE: f64 = 5.0;
+
+// Floor for the network's failure rate, so a few failures on an otherwise
+// perfect network are not infinitely surprising
+const MIN_BASE_RATE: f64 = 0.02;
+
+// A relay on (nearly) every path, like the first entry guard or a pinned
+// gateway, leaves too few other circuits to compare against. Like Tor's guard
+// path bias it is then held to fixed failure rates instead, high enough that
+// an unreliable network alone does not reach them.
+const WARN_FAILURE_RATE: f64 = 0.5;
+const EXCLUDE_FAILURE_RATE: f64 = 0.7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RelayStatus {
+    Ok,
+    Suspicious,
+    Excluded,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RelayHealth {
+    pub relay: String,  // Identity key if known, otherwise address
+    pub address: String,
+    pub circuits: u64,
+    pub failures: u64,
+    pub status: RelayStatus,
+    pub guard: bool,
+}
+
+struct RelayRecord {
+    address: String,
+    guard: bool,  // Was the entry of a path; with a directory that is an entry guard
+    circuits: u64,
+    failures: u64,
+    status: RelayStatus,
+}
+
+#[derive(Default)]
+struct State {
+    relays: HashMap<String, RelayRecord>,
+    circuits: u64,
+    failures: u64,
+}
+
+// Path-bias detection. A relay that cannot deanonymize a circuit can still
+// break it, pushing the client to build circuits until one runs through
+// relays that can. Every circuit that got past the entry relay counts for
+// each of its hops, as does whether it failed, while being built or while
+// carrying a request. Failures cannot be pinned on one hop, but paths are
+// drawn at random, so honest relays share the network's failure rate and a
+// relay that breaks circuits on purpose stands out against it.
+#[derive(Default)]
+pub struct PathBias {
+    state: Mutex<State>,
+}
+
+impl PathBias {
+    pub fn record_circuit(&self, path: &Path, failed: bool) {
+        let mut state = self.state.lock().expect("path bias poisoned");
+        state.circuits += 1;
+        state.failures += u64::from(failed);
+        for hop in hops(path) {
+            let record = state.relays.entry(relay_key(hop)).or_insert(RelayRecord {
+                address: hop.address.to_string(),
+                guard: false,
+                circuits: 0,
+                failures: 0,
+                status: RelayStatus::Ok,
+            });
+            record.guard |= hop == &path.entry_relay;
+            record.circuits += 1;
+            record.failures += u64::from(failed);
+        }
+        if state.circuits >= SCALE_AT {
+            state.scale();
+        }
+        state.judge();
+    }
+
+    // A circuit that was built but then failed a request. It was counted as
+    // a success when it was built.
+    pub fn record_use_failure(&self, path: &Path) {
+        let mut state = self.state.lock().expect("path bias poisoned");
+        state.failures = (state.failures + 1).min(state.circuits);
+        for hop in hops(path) {
+            if let Some(record) = state.relays.get_mut(&relay_key(hop)) {
+                record.failures = (record.failures + 1).min(record.circuits);
+            }
+        }
+        state.judge();
+    }
+
+    // Leave excluded relays out of the directory used for the next path,
+    // except `keep`: entry guards and a pinned gateway are only warned about,
+    // since dropping them on failures would let whoever causes the failures
+    // choose what replaces them
+    pub fn without_excluded(&self, mut directory: Directory, keep: &[[u8; 32]]) -> Directory {
+        let state = self.state.lock().expect("path bias poisoned");
+        directory.nodes.retain(|node| match node.identity() {
+            Ok(identity) => {
+                keep.contains(identity.as_bytes())
+                    || state
+                        .relays
+                        .get(&hex::encode(identity.as_bytes()))
+                        .is_none_or(|record| record.status != RelayStatus::Excluded)
+            }
+            Err(_) => true,  // Never selected anyway
+        });
+        directory
+    }
+
+    // Worst first
+    pub fn relay_health(&self) -> Vec<RelayHealth> {
+        let state = self.state.lock().expect("path bias poisoned");
+        let mut relays: Vec<RelayHealth> = state
+            .relays
+            .iter()
+            .map(|(relay, record)| RelayHealth {
+                relay: relay.clone(),
+                address: record.address.clone(),
+                circuits: record.circuits,
+                failures: record.failures,
+                status: record.status,
+                guard: record.guard,
+            })
+            .collect();
+        relays.sort_by(|a, b| {
+            let rate = |relay: &RelayHealth| relay.failures as f64 / relay.circuits.max(1) as f64;
+            rate(b).total_cmp(&rate(a))
+        });
+        relays
+    }
+}
+
+impl State {
+    fn scale(&mut self) {
+        self.circuits /= 2;
+        self.failures /= 2;
+        for record in self.relays.values_mut() {
+            record.circuits /= 2;
+            record.failures /= 2;
+        }
+        self.relays.retain(|_, record| record.circuits > 0);
+    }
+
+    // Every relay is judged against the latest baseline, including relays
+    // no longer on new paths: an excluded one gets another chance once its
+    // counts have decayed or the rest of the network fails as often. A
+    // change of status is reported.
+    fn judge(&mut self) {
+        let changes: Vec<(String, RelayStatus)> = self
+            .relays
+            .iter()
+            .map(|(key, record)| (key.clone(), self.status(record)))
+            .filter(|(key, status)| self.relays[key].status != *status)
+            .collect();
+        for (key, status) in changes {
+            let elsewhere = match self.baseline(&self.relays[&key]) {
+                Some(rate) => format!("{:.0}% elsewhere", 100.0 * rate),
+                None => "too few other circuits to compare".to_string(),
+            };
+            let Some(record) = self.relays.get_mut(&key) else {
+                continue;
+            };
+            let failed = 100.0 * record.failures as f64 / record.circuits.max(1) as f64;
+            match (status, record.guard) {
+                (RelayStatus::Excluded, false) => eprintln!(
+                    "🚨 Excluding relay {} from new paths: {:.0}% of its circuits failed, {}",
+                    record.address, failed, elsewhere
+                ),
+                (RelayStatus::Excluded, true) => eprintln!(
+                    "🚨 Entry guard {} fails {:.0}% of its circuits, {}; kept as a guard, left out as a middle relay",
+                    record.address, failed, elsewhere
+                ),
+                (RelayStatus::Suspicious, _) => eprintln!(
+                    "⚠️  Relay {} fails unusually many circuits: {:.0}%, {}",
+                    record.address, failed, elsewhere
+                ),
+                (RelayStatus::Ok, _) => {}
+            }
+            record.status = status;
+        }
+    }
+
+    // Failure rate of the circuits that did not run through the relay, if
+    // there are enough of them
+    fn baseline(&self, record: &RelayRecord) -> Option<f64> {
+        let other_circuits = self.circuits.saturating_sub(record.circuits);
+        let other_failures = self.failures.saturating_sub(record.failures);
+        (other_circuits >= MIN_CIRCUITS).then(|| other_failures as f64 / other_circuits as f64)
+    }
+
+    // How far the relay's failures are above what the failure rate of all
+    // other circuits predicts, in standard deviations of a binomial. Without
+    // enough other circuits, its failure rate against the fixed limits.
+    fn status(&self, record: &RelayRecord) -> RelayStatus {
+        if record.circuits < MIN_CIRCUITS {
+            return RelayStatus::Ok;
+        }
+        let Some(base_rate) = self.baseline(record) else {
+            let failure_rate = record.failures as f64 / record.circuits as f64;
+            return if failure_rate >= EXCLUDE_FAILURE_RATE {
+                RelayStatus::Excluded
+            } else if failure_rate >= WARN_FAILURE_RATE {
+                RelayStatus::Suspicious
+            } else {
+                RelayStatus::Ok
+            };
+        };
+        let base_rate = base_rate.clamp(MIN_BASE_RATE, 1.0 - MIN_BASE_RATE);
+        let circuits = record.circuits as f64;
+        let expected = circuits * base_rate;
+        let deviation = (circuits * base_rate * (1.0 - base_rate)).sqrt();
+        let score = (record.failures as f64 - expected) / deviation;
+
+        if score >= EXCLUDE_SCORE {
+            RelayStatus::Excluded
+        } else if score >= WARN_SCORE {
+            RelayStatus::Suspicious
+        } else {
+            RelayStatus::Ok
+        }
+    }
+}
+
+fn hops(path: &Path) -> [&Hop; 3] {
+    [&path.entry_relay, &path.middle_relay, &path.gateway]
+}
+
+fn relay_key(hop: &Hop) -> String {
+    match hop.identity {
+        Some(identity) => hex::encode(identity),
+        None => hop.address.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use penum_protocol::directory::{NodeDescriptor, NodeFlag, NodeRole};
+    use penum_protocol::{PublicKey, StaticSecret};
+
+    fn identity(seed: u8) -> [u8; 32] {
+        *PublicKey::from(&StaticSecret::from([seed; 32])).as_bytes()
+    }
+
+    fn hop(seed: u8) -> Hop {
+        Hop { address: format!("10.0.0.{}:9001", seed).parse().unwrap(), identity: Some(identity(seed)) }
+    }
+
+    fn path(entry: u8, middle: u8, gateway: u8) -> Path {
+        Path { entry_relay: hop(entry), middle_relay: hop(middle), gateway: hop(gateway) }
+    }
+
+    fn status(bias: &PathBias, seed: u8) -> RelayStatus {
+        let key = hex::encode(identity(seed));
+        bias.relay_health().into_iter().find(|relay| relay.relay == key).unwrap().status
+    }
+
+    // Entry 1 and gateway 9 on every path, middles 2..=5 in turn; every
+    // circuit through `bad` fails
+    fn run(bias: &PathBias, circuits: usize, bad: u8) {
+        for n in 0..circuits {
+            let middle = 2 + (n % 4) as u8;
+            bias.record_circuit(&path(1, middle, 9), middle == bad);
+        }
+    }
+
+    #[test]
+    fn relay_failing_far_above_the_rest_is_excluded() {
+        let bias = PathBias::default();
+        run(&bias, 200, 3);
+        assert_eq!(status(&bias, 3), RelayStatus::Excluded);
+        for honest in [2, 4, 5] {
+            assert_eq!(status(&bias, honest), RelayStatus::Ok);
+        }
+        // 25% failures on every path stay below the fixed limits
+        assert_eq!(status(&bias, 1), RelayStatus::Ok);
+        assert_eq!(status(&bias, 9), RelayStatus::Ok);
+    }
+
+    #[test]
+    fn relay_on_every_path_is_held_to_fixed_rates() {
+        let bias = PathBias::default();
+        for n in 0..40 {
+            bias.record_circuit(&path(1, 2 + (n % 4) as u8, 9), n % 5 != 0);
+        }
+        // 80% of the entry's circuits fail and no other circuits exist
+        assert_eq!(status(&bias, 1), RelayStatus::Excluded);
+        assert_eq!(status(&bias, 9), RelayStatus::Excluded);
+        let records = bias.relay_health();
+        assert!(records.iter().all(|relay| relay.guard == (relay.relay == hex::encode(identity(1)))));
+
+        let bias = PathBias::default();
+        for n in 0..40 {
+            bias.record_circuit(&path(1, 2 + (n % 4) as u8, 9), n % 5 < 3);
+        }
+        assert_eq!(status(&bias, 1), RelayStatus::Suspicious);
+    }
+
+    #[test]
+    fn use_failures_count_against_every_hop() {
+        let bias = PathBias::default();
+        run(&bias, 80, 0);
+        for _ in 0..20 {
+            bias.record_use_failure(&path(1, 4, 9));
+        }
+        let middle = bias.relay_health().into_iter().find(|relay| relay.relay == hex::encode(identity(4))).unwrap();
+        assert_eq!((middle.circuits, middle.failures), (20, 20));
+        assert_eq!(middle.status, RelayStatus::Excluded);
+        // A use failure never counts more failures than circuits
+        bias.record_use_failure(&path(1, 4, 9));
+        assert_eq!(bias.relay_health()[0].failures, 20);
+    }
+
+    #[test]
+    fn counts_are_halved_and_bad_relays_recover() {
+        let bias = PathBias::default();
+        run(&bias, 200, 3);
+        assert_eq!(status(&bias, 3), RelayStatus::Excluded);
+        // Reaching SCALE_AT halves everything: 300 circuits become 150
+        run(&bias, 100, 0);
+        let state = bias.state.lock().unwrap();
+        assert_eq!(state.circuits, 150);
+        assert_eq!(state.relays[&hex::encode(identity(1))].circuits, 150);
+        drop(state);
+        // Behaving well from then on, the relay's old failures fade
+        run(&bias, 1000, 0);
+        assert_eq!(status(&bias, 3), RelayStatus::Ok);
+    }
+
+    #[test]
+    fn excluded_relays_are_left_out_unless_kept() {
+        let bias = PathBias::default();
+        run(&bias, 200, 3);
+        let node = |seed: u8, role| NodeDescriptor {
+            nickname: format!("node{}", seed),
+            role,
+            address: hop(seed).address,
+            mix_address: None,
+            identity_key: hex::encode(identity(seed)),
+            bandwidth: 100,
+            family: Vec::new(),
+            flags: vec![NodeFlag::Running, NodeFlag::Valid],
+        };
+        let directory = Directory {
+            published: 0,
+            valid_until: u64::MAX,
+            nodes: vec![node(2, NodeRole::Relay), node(3, NodeRole::Relay), node(9, NodeRole::Gateway)],
+        };
+        let nicknames = |directory: Directory| directory.nodes.into_iter().map(|node| node.nickname).collect::<Vec<_>>();
+        assert_eq!(nicknames(bias.without_excluded(directory.clone(), &[])), ["node2", "node9"]);
+        assert_eq!(nicknames(bias.without_excluded(directory, &[identity(3)])), ["node2", "node3", "node9"]);
+    }
+}
@@ -1,107 +1,614 @@
-use crate::config::RpcClientConfig;
-use crate::crypto::{encrypt_in_place, decrypt_in_place, EphemeralKeys, derive_session_key};
-use crate::packet::{Packet, PACKET_SIZE, AEAD_TAG_LEN};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use x25519_dalek::PublicKey;
+use crate::circuit::Circuit;
+use crate::config::{parse_optional_key, RpcClientConfig};
+use crate::directory::DirectoryClient;
+use crate::guards::EntryGuards;
+use crate::accounts::{format_address, RequestAccounts};
+use crate::isolation::{AccountTracker, CircuitActivity, CircuitSummary, IsolationKey, LinkedQuery};
+use crate::mixnet::MixnetClient;
+use crate::path_bias::{PathBias, RelayHealth};
+use crate::pool::CircuitPool;
+use crate::tunnel::Tunnel;
+use penum_protocol::cell::MAX_MESSAGE_LEN;
+use penum_protocol::directory::DIRECTORY_METHOD;
+use crate::path::{Hop, Path, PathSelector};
+use penum_protocol::PublicKey;
 use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::sync::Mutex;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+// How often the cover traffic task checks that every key's tunnel is open
+const COVER_TUNNEL_CHECK: Duration = Duration::from_secs(1);
+
+// Longest wait for the entry relay's handshake, and then for the rest of the
+// circuit, so a hop that never answers fails the build instead of hanging it
+const CIRCUIT_BUILD_TIMEOUT: Duration = Duration::from_secs(15);
+
+// Isolation keys with an open circuit at once; the least recently used one
+// is closed to make room, so a local process inventing origins or accounts
+// cannot make the client hold circuits without bound
+const MAX_ISOLATED_CIRCUITS: usize = 32;
+
+// How often the pool is checked against demand, and how long to wait after
+// a circuit could not be built for it
+const POOL_CHECK: Duration = Duration::from_secs(1);
+const POOL_RETRY: Duration = Duration::from_secs(5);
+
+// Isolation keys remembered for the UI
+const MAX_TRACKED_KEYS: usize = 256;
+
+// Returned when a hop's identity key does not match the pinned key or the
+// key listed in the directory. The request is never sent in that case.
+#[derive(Debug)]
+pub struct IdentityMismatch {
+    pub hop: &'static str,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for IdentityMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} identity mismatch: expected {}, got {}",
+            self.hop, self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for IdentityMismatch {}
+
+// The tunnel an isolation key's requests currently share
+struct IsolatedTunnel {
+    tunnel: Arc<Tunnel>,
+    path: Path,
+    created_at: Instant,
+    last_used: Instant,
+    requests: u64,
+}
+
+impl IsolatedTunnel {
+    // Rotation policy: a tunnel is replaced when it fails, reaches
+    // `circuit_lifetime_secs` or has carried `circuit_max_requests`
+    fn is_current(&self, config: &RpcClientConfig) -> bool {
+        self.tunnel.is_usable(Duration::from_secs(config.circuit_lifetime_secs))
+            && config.circuit_max_requests.is_none_or(|max_requests| self.requests < max_requests)
+    }
+}
+
+// Resolves when a key's circuit build finishes, with the error if it failed
+type PendingBuild = watch::Receiver<Option<Result<(), String>>>;
+
+// Marks a key's circuit as being built, until the build is done or abandoned
+struct Building<'a> {
+    building: &'a Mutex<HashMap<IsolationKey, PendingBuild>>,
+    key: &'a IsolationKey,
+}
+
+impl Drop for Building<'_> {
+    fn drop(&mut self) {
+        self.building.lock().expect("build map poisoned").remove(self.key);
+    }
+}
+
+struct Activity {
+    requests: u64,
+    last_request: Instant,
+}
 
 pub struct PenumRpcClient {
     config: RpcClientConfig,
+    pinned_gateway_key: Option<[u8; 32]>,
+    tunnels: Mutex<HashMap<IsolationKey, IsolatedTunnel>>,
+    covered: Mutex<HashSet<IsolationKey>>,  // Keys the cover traffic task keeps a tunnel open for
+    building: Mutex<HashMap<IsolationKey, PendingBuild>>,
+    activity: Mutex<HashMap<IsolationKey, Activity>>,
+    accounts: Mutex<AccountTracker>,
+    mixnet: Option<MixnetClient>,
+    directory: Option<Arc<DirectoryClient>>,
+    static_path: Option<Path>,
+    path_selector: PathSelector,
+    guards: Option<EntryGuards>,
+    pool: Option<CircuitPool>,
+    path_bias: PathBias,
+    identity_epoch: AtomicU64,  // Changed by `new_identity` while holding `tunnels`
 }
 
 impl PenumRpcClient {
-    pub fn new(config: RpcClientConfig) -> Self {
-        Self { config }
+    pub fn new(config: RpcClientConfig) -> anyhow::Result<Self> {
+        let pinned_gateway_key = config.pinned_gateway_key()?;
+        config.validate_protocol_version()?;
+        config.validate_cover_traffic()?;
+        config.validate_rotation()?;
+        let mixnet = match &config.mixnet {
+            Some(mixnet) => Some(MixnetClient::new(mixnet.clone(), pinned_gateway_key)?),
+            None => None,
+        };
+        let directory = match &config.directory {
+            Some(directory) => Some(Arc::new(DirectoryClient::new(directory.clone())?)),
+            None => None,
+        };
+        let pool = match (&config.circuit_pool, &config.mixnet) {
+            (Some(pool), None) => Some(CircuitPool::new(pool.clone())?),
+            _ => None,
+        };
+        let guards = match &directory {
+            Some(_) => Some(EntryGuards::load(config.guards.clone())?),
+            None => None,
+        };
+        // Like the pinned gateway key, a static relay answering with another key fails
+        let entry_relay_key = parse_optional_key("entry_relay_key", &config.entry_relay_key)?;
+        let middle_relay_key = parse_optional_key("middle_relay_key", &config.middle_relay_key)?;
+        let static_path = config.static_path()?.map(|(entry_relay, middle_relay, gateway)| Path {
+            entry_relay: Hop { address: entry_relay, identity: entry_relay_key },
+            middle_relay: Hop { address: middle_relay, identity: middle_relay_key },
+            gateway: Hop { address: gateway, identity: pinned_gateway_key },
+        });
+        Ok(Self {
+            path_selector: PathSelector::new(config.latency_candidates),
+            config,
+            pinned_gateway_key,
+            tunnels: Mutex::new(HashMap::new()),
+            covered: Mutex::new(HashSet::new()),
+            building: Mutex::new(HashMap::new()),
+            activity: Mutex::new(HashMap::new()),
+            accounts: Mutex::new(AccountTracker::default()),
+            mixnet,
+            directory,
+            static_path,
+            guards,
+            pool,
+            path_bias: PathBias::default(),
+            identity_epoch: AtomicU64::new(0),
+        })
+    }
+
+    // Build a circuit: entry relay -> middle relay -> gateway, and start a
+    // tunnel over it. The entry relay only learns the middle relay's address,
+    // and only the middle relay learns the gateway's address.
+    async fn build_tunnel(&self) -> anyhow::Result<(Tunnel, Path)> {
+        let path = self.choose_path()?;
+
+        // Each hop is checked right after its handshake, so nothing is sent
+        // through a hop that is not the one we expected
+        let started = Instant::now();
+        let entry = tokio::time::timeout(
+            CIRCUIT_BUILD_TIMEOUT,
+            Circuit::connect(
+                path.entry_relay.address,
+                path.entry_relay.identity.map(PublicKey::from),
+                self.config.protocol_version,
+                self.config.offered_capabilities(),
+            ),
+        )
+        .await
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("Entry relay did not answer within {:?}", CIRCUIT_BUILD_TIMEOUT)))
+        .and_then(|(circuit, entry_identity)| {
+            self.check_hop("Entry relay", &path.entry_relay, &entry_identity, started)?;
+            Ok(circuit)
+        });
+        // Only failures to reach the guard itself count against it; later
+        // hops failing says nothing about the guard
+        if let (Some(guards), Some(identity)) = (&self.guards, &path.entry_relay.identity) {
+            match &entry {
+                Ok(_) => guards.record_success(identity),
+                Err(_) => guards.record_failure(identity),
+            }
+        }
+        let circuit = entry?;
+
+        // From here on a failure counts against every hop of the path.
+        // Failing to reach the entry relay is left out: it is a guard most of
+        // the time, and says nothing about the other hops.
+        let tunnel = tokio::time::timeout(CIRCUIT_BUILD_TIMEOUT, self.complete_circuit(circuit, &path))
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("Circuit not completed within {:?}", CIRCUIT_BUILD_TIMEOUT)));
+        self.path_bias.record_circuit(&path, tunnel.is_err());
+        Ok((tunnel?, path))
+    }
+
+    async fn complete_circuit(&self, mut circuit: Circuit, path: &Path) -> anyhow::Result<Tunnel> {
+        let started = Instant::now();
+        let middle_identity = circuit
+            .extend(path.middle_relay.address, path.middle_relay.identity.map(PublicKey::from))
+            .await?;
+        self.check_hop("Middle relay", &path.middle_relay, &middle_identity, started)?;
+
+        let started = Instant::now();
+        let (gateway_identity, mut session) = circuit
+            .open_gateway(path.gateway.address, path.gateway.identity.map(PublicKey::from))
+            .await?;
+        self.check_hop("Gateway", &path.gateway, &gateway_identity, started)?;
+
+        circuit.confirm_session(&mut session).await?;
+        Ok(Tunnel::start(circuit, session, self.config.cover_traffic))
+    }
+
+    // A new path from the directory for every circuit, entering through a
+    // guard and avoiding relays path-bias detection excluded, or the
+    // configured one
+    fn choose_path(&self) -> anyhow::Result<Path> {
+        match &self.directory {
+            Some(directory) => {
+                let directory = directory.current()?;
+                let guard = match &self.guards {
+                    Some(guards) => Some(guards.choose(&directory)?),
+                    None => None,
+                };
+                let keep: Vec<[u8; 32]> = guard
+                    .iter()
+                    .filter_map(|guard| guard.identity().ok().map(|key| *key.as_bytes()))
+                    .chain(self.pinned_gateway_key)
+                    .collect();
+                let directory = self.path_bias.without_excluded(directory, &keep);
+                self.path_selector
+                    .select(&directory, guard.as_ref(), self.pinned_gateway_key)
+            }
+            None => self
+                .static_path
+                .clone()
+                .ok_or_else(|| anyhow::anyhow!("No path configured")),
+        }
+    }
+
+    // Fail closed if a hop is not the one we expected. Latency is only
+    // learned from hops that passed.
+    fn check_hop(&self, name: &'static str, hop: &Hop, actual: &PublicKey, started: Instant) -> anyhow::Result<()> {
+        if let Some(expected) = hop.identity {
+            if actual.as_bytes() != &expected {
+                return Err(IdentityMismatch {
+                    hop: name,
+                    expected: hex::encode(expected),
+                    actual: hex::encode(actual.as_bytes()),
+                }
+                .into());
+            }
+        }
+        self.path_selector.record_latency(*actual.as_bytes(), started.elapsed());
+        Ok(())
+    }
+
+    // A circuit nobody has used yet: a ready one from the pool if there is
+    // one, otherwise built now
+    async fn new_tunnel(&self) -> anyhow::Result<(Tunnel, Path)> {
+        if let Some(pool) = &self.pool {
+            pool.record_demand();
+            if let Some(ready) = pool.take() {
+                return Ok(ready);
+            }
+        }
+        self.build_tunnel().await
+    }
+
+    // Refresh the directory right away, then periodically. With a usable
+    // directory the next one is asked from the gateway at the end of a fresh
+    // circuit, so the mirrors never see a client that already has one; they
+    // are only fetched from directly when there is no usable copy. Failures
+    // are retried sooner, and the current directory is kept until it expires.
+    pub async fn keep_directory_fresh(self: Arc<Self>) {
+        let Some(directory) = self.directory.clone() else {
+            return;
+        };
+        loop {
+            let refreshed = if directory.current().is_ok() {
+                match self.fetch_directory_over_circuit().await {
+                    Ok(document) => directory.store(&document),
+                    Err(e) => Err(e),
+                }
+            } else {
+                directory.refresh().await
+            };
+            if let Err(e) = &refreshed {
+                eprintln!("⚠️  Directory refresh failed: {:#}", e);
+            }
+            tokio::time::sleep(directory.next_refresh(refreshed.is_ok())).await;
+        }
+    }
+
+    // A circuit of its own, dropped afterwards, so the gateway cannot tie the
+    // fetch to any request
+    async fn fetch_directory_over_circuit(&self) -> anyhow::Result<String> {
+        let (tunnel, _) = self.build_tunnel().await?;
+        let request = serde_json::json!({ "jsonrpc": "2.0", "method": DIRECTORY_METHOD, "params": [], "id": 1 });
+        let response: Value = serde_json::from_slice(&tunnel.request(&serde_json::to_vec(&request)?).await?)?;
+        match response.get("result").and_then(Value::as_str) {
+            Some(document) => Ok(document.to_string()),
+            None => Err(anyhow::anyhow!("Gateway does not mirror the directory")),
+        }
+    }
+
+    // Keep the pool filled to recent demand. Circuits are built one at a
+    // time, without holding any lock requests wait on.
+    pub async fn keep_pool(self: Arc<Self>) {
+        let Some(pool) = &self.pool else {
+            return;
+        };
+        loop {
+            pool.prune();
+            if pool.wanted() == 0 {
+                tokio::time::sleep(POOL_CHECK).await;
+                continue;
+            }
+            let epoch = pool.epoch();
+            match self.build_tunnel().await {
+                Ok((tunnel, path)) => pool.add(tunnel, path, epoch),
+                Err(_) => tokio::time::sleep(POOL_RETRY).await, // Requests report their own failures
+            }
+        }
+    }
+
+    // Reuse the key's tunnel until it fails or expires, then build a new one.
+    // Tunnels are never shared between keys, so each origin has its own
+    // circuit and session keys.
+    // Only requests count towards `circuit_max_requests` and the least
+    // recently used order, not the cover traffic task keeping a tunnel open.
+    // The tunnel map is only locked to
+    // look up and insert; circuits are built without it, so a slow hop on
+    // one key's new circuit never holds up requests for other keys. Requests
+    // for a key whose circuit is being built wait for that build.
+    async fn current_tunnel(&self, key: &IsolationKey, for_request: bool) -> anyhow::Result<(Arc<Tunnel>, Path)> {
+        let mut last_used = Instant::now();
+        let done = loop {
+            let mut pending = {
+                let mut tunnels = self.tunnels.lock().expect("tunnel map poisoned");
+                if let Some(existing) = tunnels.get_mut(key) {
+                    if existing.is_current(&self.config) {
+                        if for_request {
+                            existing.last_used = Instant::now();
+                            existing.requests += 1;
+                        }
+                        return Ok((existing.tunnel.clone(), existing.path.clone()));
+                    }
+                    // It may also have failed while idle
+                    if existing.tunnel.take_failure() {
+                        self.path_bias.record_use_failure(&existing.path);
+                    }
+                    if !for_request {
+                        last_used = existing.last_used;
+                    }
+                    tunnels.remove(key);
+                }
+                let mut building = self.building.lock().expect("build map poisoned");
+                match building.get(key) {
+                    Some(pending) => pending.clone(),
+                    None => {
+                        let (done, pending) = watch::channel(None);
+                        building.insert(key.clone(), pending);
+                        break done;
+                    }
+                }
+            };
+            // A failed build fails its waiters too; if the request building
+            // was abandoned, look again
+            let failed = match pending.wait_for(Option::is_some).await {
+                Ok(outcome) => outcome.clone().and_then(Result::err),
+                Err(_) => None,
+            };
+            if let Some(error) = failed {
+                return Err(anyhow::anyhow!("{}", error));
+            }
+        };
+
+        let _building = Building { building: &self.building, key };
+        let epoch = self.identity_epoch.load(Ordering::SeqCst);
+        let (tunnel, path) = match self.new_tunnel().await {
+            Ok(built) => built,
+            Err(e) => {
+                done.send_replace(Some(Err(format!("{:#}", e))));
+                return Err(e);
+            }
+        };
+        let tunnel = Arc::new(tunnel);
+        let mut tunnels = self.tunnels.lock().expect("tunnel map poisoned");
+        if epoch != self.identity_epoch.load(Ordering::SeqCst) {
+            // A new identity was requested meanwhile: carry this request,
+            // but do not keep the circuit for the key's later ones
+            done.send_replace(Some(Ok(())));
+            return Ok((tunnel, path));
+        }
+        // Keys whose circuit is gone for good, failed to be replaced or was
+        // evicted, are no longer kept open by the cover traffic task
+        let mut covered = self.covered.lock().expect("cover set poisoned");
+        covered.retain(|covered| tunnels.contains_key(covered));
+        tunnels.retain(|_, existing| existing.is_current(&self.config));
+        if tunnels.len() >= MAX_ISOLATED_CIRCUITS {
+            let oldest = tunnels
+                .iter()
+                .min_by_key(|(_, existing)| existing.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                tunnels.remove(&oldest);
+                covered.remove(&oldest);
+            }
+        }
+        covered.insert(key.clone());
+        tunnels.insert(
+            key.clone(),
+            IsolatedTunnel {
+                tunnel: tunnel.clone(),
+                path: path.clone(),
+                created_at: Instant::now(),
+                last_used,
+                requests: u64::from(for_request),
+            },
+        );
+        done.send_replace(Some(Ok(())));
+        Ok((tunnel, path))
+    }
+
+    // Forget everything that ties future requests to past ones: every
+    // circuit, the origins and accounts seen and the measured latencies.
+    // Entry guards stay, since choosing new ones is what guards protect
+    // against. Returns how many circuits were closed.
+    pub fn new_identity(&self) -> usize {
+        let mut closed = {
+            let mut tunnels = self.tunnels.lock().expect("tunnel map poisoned");
+            self.identity_epoch.fetch_add(1, Ordering::SeqCst);
+            let closed = tunnels.len();
+            tunnels.clear();
+            self.covered.lock().expect("cover set poisoned").clear();
+            closed
+        };
+        if let Some(pool) = &self.pool {
+            closed += pool.clear();
+        }
+        self.activity.lock().expect("activity map poisoned").clear();
+        *self.accounts.lock().expect("account tracker poisoned") = AccountTracker::default();
+        self.path_selector.forget_latencies();
+        println!("🔀 New identity: closed {} circuits", closed);
+        closed
+    }
+
+    fn record_activity(&self, key: &IsolationKey) {
+        let mut activity = self.activity.lock().expect("activity map poisoned");
+        if !activity.contains_key(key) && activity.len() >= MAX_TRACKED_KEYS {
+            let oldest = activity
+                .iter()
+                .min_by_key(|(_, seen)| seen.last_request)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                activity.remove(&oldest);
+            }
+        }
+        let seen = activity.entry(key.clone()).or_insert(Activity {
+            requests: 0,
+            last_request: Instant::now(),
+        });
+        seen.requests += 1;
+        seen.last_request = Instant::now();
+    }
+
+    // The isolation key for a request from `origin` about `accounts`. With
+    // `rotate_on_account_switch`, an origin whose requests move to another
+    // account gets new circuits, so its account-less requests before and
+    // after the switch do not share one either.
+    pub fn isolate(&self, origin: IsolationKey, method: &str, accounts: &RequestAccounts) -> IsolationKey {
+        let (key, switched) = self
+            .accounts
+            .lock()
+            .expect("account tracker poisoned")
+            .isolate(origin, method, accounts);
+        if switched && self.config.rotate_on_account_switch {
+            self.tunnels
+                .lock()
+                .expect("tunnel map poisoned")
+                .retain(|existing, _| existing.origin != key.origin);
+            self.covered
+                .lock()
+                .expect("cover set poisoned")
+                .retain(|existing| existing.origin != key.origin);
+        }
+        key
+    }
+
+    pub fn relay_health(&self) -> Vec<RelayHealth> {
+        self.path_bias.relay_health()
+    }
+
+    pub fn linked_queries(&self) -> Vec<LinkedQuery> {
+        self.accounts.lock().expect("account tracker poisoned").linked_queries()
+    }
+
+    // Every isolation key seen, most recent first, with the circuit it is using
+    pub fn circuit_activity(&self) -> Vec<CircuitActivity> {
+        let tunnels = self.tunnels.lock().expect("tunnel map poisoned");
+        let activity = self.activity.lock().expect("activity map poisoned");
+        let mut seen: Vec<(&IsolationKey, &Activity)> = activity.iter().collect();
+        seen.sort_by_key(|(_, seen)| std::cmp::Reverse(seen.last_request));
+        seen.into_iter()
+            .map(|(key, seen)| CircuitActivity {
+                origin: key.label().to_string(),
+                account: key.account.as_ref().map(format_address),
+                requests: seen.requests,
+                last_request_secs: seen.last_request.elapsed().as_secs(),
+                circuit: tunnels
+                    .get(key)
+                    .filter(|existing| existing.is_current(&self.config))
+                    .map(|existing| CircuitSummary {
+                        entry_relay: existing.path.entry_relay.address.to_string(),
+                        middle_relay: existing.path.middle_relay.address.to_string(),
+                        gateway: existing.path.gateway.address.to_string(),
+                        age_secs: existing.created_at.elapsed().as_secs(),
+                    }),
+            })
+            .collect()
     }
 
-    pub async fn send_rpc_request(&self, json_rpc: &[u8]) -> anyhow::Result<Vec<u8>> {
+    // With cover traffic a tunnel must exist before the first request and
+    // through idle periods, or the first real cells would stand out again.
+    // That holds for every isolation key: one that had a circuit keeps one,
+    // sending on the schedule, until it is evicted, rotated away or a new
+    // identity is requested. The default key is covered from startup.
+    // Expired or failed tunnels are replaced like on a request.
+    pub async fn keep_cover_tunnels(self: Arc<Self>) {
+        loop {
+            let mut keys: Vec<IsolationKey> = self.covered.lock().expect("cover set poisoned").iter().cloned().collect();
+            if !keys.contains(&IsolationKey::default()) {
+                keys.push(IsolationKey::default());
+            }
+            // Checked concurrently, so one slow hop does not leave other keys
+            // without a tunnel
+            let mut checks = JoinSet::new();
+            for key in keys {
+                let client = self.clone();
+                checks.spawn(async move {
+                    if let Err(e) = client.current_tunnel(&key, false).await {
+                        eprintln!("⚠️  Cover traffic tunnel for {}: {:#}", key.label(), e);
+                    }
+                });
+            }
+            while checks.join_next().await.is_some() {}
+            tokio::time::sleep(COVER_TUNNEL_CHECK).await;
+        }
+    }
+
+    pub async fn send_rpc_request(&self, json_rpc: &[u8], key: &IsolationKey) -> anyhow::Result<Vec<u8>> {
         // Validate request size
-        if json_rpc.len() > 960 {
-            return Err(anyhow::anyhow!("Request too large: {} bytes (max 960)", json_rpc.len()));
+        if json_rpc.len() > MAX_MESSAGE_LEN {
+            return Err(anyhow::anyhow!("Request too large: {} bytes (max {})", json_rpc.len(), MAX_MESSAGE_LEN));
         }
 
-        // Create random packet for privacy (cryptographically secure random padding)
-        let mut buffer = Packet::new_random();
-
-        // Place JSON-RPC payload in packet
-        // We place it near the end to leave room for different packet layouts
-        let payload_len = json_rpc.len();
-        let payload_start = PACKET_SIZE - AEAD_TAG_LEN - payload_len - 32;
-        buffer[payload_start..payload_start + payload_len].copy_from_slice(json_rpc);
-
-
-        // Generate ephemeral keys for this request
-        let client_keys = EphemeralKeys::generate();
-        let client_pub = client_keys.public;
-
-        // Connect to the entry relay which will forward to middle relay and then to gateway
-        let mut stream = TcpStream::connect(self.config.entry_relay).await?;
-
-        // Penum handshake: send client public key (32 bytes)
-        stream.write_all(client_pub.as_bytes()).await?;
-
-        // Receive gateway public key (32 bytes)
-        let mut server_pub_bytes = [0u8; 32];
-        stream.read_exact(&mut server_pub_bytes).await?;
-        let server_pub = PublicKey::from(server_pub_bytes);
-
-        // Derive session key using HKDF with salt "penum-v1"
-        let shared_secret = client_keys.diffie_hellman(&server_pub);
-        let session_key = derive_session_key(shared_secret);
-
-        // Encrypt packet: header (32 bytes) + payload (976 bytes) + tag (16 bytes)
-        let mut encrypted_packet = buffer;
-        let (header, payload_and_tag) = encrypted_packet.split_at_mut(32);
-        let (payload, tag_space) = payload_and_tag.split_at_mut(PACKET_SIZE - 32 - AEAD_TAG_LEN);
-        
-        // Encrypt payload with header as AAD (this is a request)
-        let tag = encrypt_in_place(&session_key, header, payload, true)?;
-        tag_space.copy_from_slice(&tag);
-
-        // Send encrypted packet (exactly 1024 bytes)
-        assert_eq!(encrypted_packet.len(), PACKET_SIZE);
-        stream.write_all(&encrypted_packet).await?;
-
-        // Receive encrypted response (exactly 1024 bytes)
-        let mut response = [0u8; PACKET_SIZE];
-        stream.read_exact(&mut response).await?;
-
-        // Decrypt response using same session key (this is a response)
-        let (resp_header, resp_data_and_tag) = response.split_at(32);
-        let (resp_data, resp_tag) = resp_data_and_tag.split_at(PACKET_SIZE - 32 - AEAD_TAG_LEN);
-        let mut resp_data = resp_data.to_vec();
-        
-        let tag_array: [u8; 16] = resp_tag.try_into()
-            .map_err(|_| anyhow::anyhow!("Invalid tag length"))?;
-        decrypt_in_place(&session_key, resp_header, &mut resp_data, &tag_array, false)?;
-
-        // Extract JSON-RPC response from padding
-        // Find the JSON by looking for the first '{' and the last '}' in the response data
-        let json_start = resp_data.iter().position(|&b| b == b'{')
-            .ok_or_else(|| anyhow::anyhow!("No JSON response found"))?;
-        
-        // Find end of JSON (last '}' after the start)
-        let json_end = resp_data[json_start..].iter().rposition(|&b| b == b'}')
-            .ok_or_else(|| anyhow::anyhow!("Incomplete JSON response"))?;
-        
-        let json_response_candidate = &resp_data[json_start..json_start + json_end + 1];
-        
-        // Validate that the extracted data is valid UTF-8 before parsing
-        let json_str = std::str::from_utf8(json_response_candidate)
+        self.record_activity(key);
+        let resp_payload = match &self.mixnet {
+            // In mixnet mode the request is a single Sphinx packet and no circuit is built
+            Some(mixnet) => mixnet.request(json_rpc).await?,
+            None => {
+                // In strict ephemeral mode every request gets its own circuit and
+                // session key, so no two requests can be linked by the gateway
+                let (tunnel, path) = if self.config.strict_ephemeral {
+                    let (tunnel, path) = self.new_tunnel().await?;
+                    (Arc::new(tunnel), path)
+                } else {
+                    self.current_tunnel(key, true).await?
+                };
+
+                // The request is split into fixed-size cells on a new stream of the tunnel
+                let response = tunnel.request(json_rpc).await;
+                if response.is_err() && tunnel.take_failure() {
+                    self.path_bias.record_use_failure(&path);
+                }
+                response?
+            }
+        };
+
+        // Validate that the payload is valid UTF-8 before parsing
+        let json_str = std::str::from_utf8(&resp_payload)
             .map_err(|_| anyhow::anyhow!("Invalid UTF-8 in JSON response"))?;
-        
+
         // Validate that it's proper JSON and is a valid JSON-RPC response
         let parsed_json: Value = serde_json::from_str(json_str)
             .map_err(|_| anyhow::anyhow!("Invalid JSON format"))?;
-        
+
         // Verify it has the required JSON-RPC response fields
-        if parsed_json.get("jsonrpc").is_none() && !parsed_json.get("result").is_some() && !parsed_json.get("error").is_some() {
+        if parsed_json.get("jsonrpc").is_none() && parsed_json.get("result").is_none() && parsed_json.get("error").is_none() {
             return Err(anyhow::anyhow!("Invalid JSON-RPC response format"));
         }
-        
-        let json_response = json_response_candidate;
-        
-        Ok(json_response.to_vec())
+
+        Ok(resp_payload)
     }
-}
\ No newline at end of file
+}
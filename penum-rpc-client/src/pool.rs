@@ -0,0 +1,187 @@
+use crate::config::PoolConfig;
+use crate::path::Path;
+use crate::tunnel::Tunnel;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct PooledTunnel {
+    tunnel: Tunnel,
+    path: Path,
+    built_at: Instant,
+}
+
+// Circuits built ahead of time, so a request that needs a new circuit takes
+// one that is ready instead of waiting for three handshakes. A pooled
+// circuit has never carried a request and is handed out once, to a single
+// isolation key, so the pool does not weaken isolation.
+pub struct CircuitPool {
+    config: PoolConfig,
+    ready: Mutex<Vec<PooledTunnel>>,
+    demand: Mutex<VecDeque<Instant>>,  // When a new circuit was needed, within the demand window
+    epoch: AtomicU64,  // Changed by `clear` while holding `ready`
+}
+
+impl CircuitPool {
+    pub fn new(config: PoolConfig) -> anyhow::Result<Self> {
+        config.validate()?;
+        Ok(Self {
+            config,
+            ready: Mutex::new(Vec::new()),
+            demand: Mutex::new(VecDeque::new()),
+            epoch: AtomicU64::new(0),
+        })
+    }
+
+    // The oldest ready circuit that is still open
+    pub fn take(&self) -> Option<(Tunnel, Path)> {
+        self.prune();
+        let mut ready = self.ready.lock().expect("circuit pool poisoned");
+        if ready.is_empty() {
+            return None;
+        }
+        let pooled = ready.remove(0);
+        Some((pooled.tunnel, pooled.path))
+    }
+
+    // Pass to `add` the epoch read before building the circuit
+    pub fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::SeqCst)
+    }
+
+    // A circuit started before the pool was last cleared is closed instead:
+    // it belongs to the identity that was discarded
+    pub fn add(&self, tunnel: Tunnel, path: Path, epoch: u64) {
+        let mut ready = self.ready.lock().expect("circuit pool poisoned");
+        if epoch != self.epoch() {
+            return;
+        }
+        ready.push(PooledTunnel {
+            tunnel,
+            path,
+            built_at: Instant::now(),
+        });
+    }
+
+    pub fn record_demand(&self) {
+        let mut demand = self.demand.lock().expect("circuit pool poisoned");
+        demand.push_back(Instant::now());
+        if demand.len() > self.config.max_size {
+            demand.pop_front();  // More than that never changes the target
+        }
+    }
+
+    // As many circuits as were needed during the demand window, within the
+    // configured bounds
+    pub fn wanted(&self) -> usize {
+        let window = Duration::from_secs(self.config.demand_window_secs);
+        let mut demand = self.demand.lock().expect("circuit pool poisoned");
+        while demand.front().is_some_and(|needed| needed.elapsed() > window) {
+            demand.pop_front();
+        }
+        let target = demand.len().clamp(self.config.min_size, self.config.max_size);
+        target.saturating_sub(self.len())
+    }
+
+    // Close circuits that sat unused past max_age_secs or failed meanwhile
+    pub fn prune(&self) {
+        let max_age = Duration::from_secs(self.config.max_age_secs);
+        self.ready
+            .lock()
+            .expect("circuit pool poisoned")
+            .retain(|pooled| pooled.built_at.elapsed() < max_age && pooled.tunnel.is_usable(max_age));
+    }
+
+    pub fn clear(&self) -> usize {
+        let mut ready = self.ready.lock().expect("circuit pool poisoned");
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+        let closed = ready.len();
+        ready.clear();
+        closed
+    }
+
+    fn len(&self) -> usize {
+        self.ready.lock().expect("circuit pool poisoned").len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CoverTraffic;
+    use crate::path::Hop;
+    use crate::tunnel::tests::{one_hop_tunnel, Gateway};
+
+    fn pool(min_size: usize, max_size: usize) -> CircuitPool {
+        CircuitPool::new(PoolConfig { min_size, max_size, demand_window_secs: 300, max_age_secs: 300 }).unwrap()
+    }
+
+    fn path(port: u16) -> Path {
+        let hop = |port| Hop { address: ([127, 0, 0, 1], port).into(), identity: None };
+        Path { entry_relay: hop(port), middle_relay: hop(port + 1), gateway: hop(port + 2) }
+    }
+
+    // Add a new circuit started at `epoch`; it stays open while the returned
+    // far end is kept
+    async fn add(pool: &CircuitPool, port: u16, epoch: u64) -> Gateway {
+        let (tunnel, gateway) = one_hop_tunnel(CoverTraffic::Off).await;
+        pool.add(tunnel, path(port), epoch);
+        gateway
+    }
+
+    #[tokio::test]
+    async fn hands_out_the_oldest_circuit_once() {
+        let pool = pool(1, 4);
+        assert!(pool.take().is_none());
+        let _first = add(&pool, 1000, pool.epoch()).await;
+        let _second = add(&pool, 2000, pool.epoch()).await;
+        assert_eq!(pool.take().unwrap().1, path(1000));
+        assert_eq!(pool.take().unwrap().1, path(2000));
+        assert!(pool.take().is_none());
+    }
+
+    #[tokio::test]
+    async fn circuits_started_before_clear_are_closed() {
+        let pool = pool(1, 4);
+        let before = pool.epoch();
+        let _cleared = add(&pool, 1000, before).await;
+        assert_eq!(pool.clear(), 1);
+        assert_ne!(pool.epoch(), before);
+
+        // Built for the discarded identity while `clear` ran
+        let _stale = add(&pool, 2000, before).await;
+        assert!(pool.take().is_none());
+        let _current = add(&pool, 3000, pool.epoch()).await;
+        assert_eq!(pool.take().unwrap().1, path(3000));
+    }
+
+    #[tokio::test]
+    async fn wants_recent_demand_within_bounds() {
+        let pool = pool(1, 3);
+        assert_eq!(pool.wanted(), 1);
+        pool.record_demand();
+        pool.record_demand();
+        assert_eq!(pool.wanted(), 2);
+        for _ in 0..10 {
+            pool.record_demand();
+        }
+        assert_eq!(pool.wanted(), 3);
+        let _ready = add(&pool, 1000, pool.epoch()).await;
+        assert_eq!(pool.wanted(), 2);
+    }
+
+    #[tokio::test]
+    async fn prunes_failed_circuits() {
+        let pool = pool(0, 4);
+        let broken = add(&pool, 1000, pool.epoch()).await;
+        let _healthy = add(&pool, 2000, pool.epoch()).await;
+
+        // The far end closing fails the first circuit
+        drop(broken);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        pool.prune();
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.take().unwrap().1, path(2000));
+    }
+}
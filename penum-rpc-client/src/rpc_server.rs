@@ -1,4 +1,8 @@
-use crate::penum_client::PenumRpcClient;
+use crate::accounts::RequestAccounts;
+use crate::isolation::IsolationKey;
+use crate::penum_client::{IdentityMismatch, PenumRpcClient};
+use penum_protocol::cell::MAX_MESSAGE_LEN;
+use penum_protocol::handshake::ProtocolVersionRejected;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use warp::Filter;
@@ -35,7 +39,11 @@ pub async fn start_rpc_server(
 
     let rpc_route = warp::post()
         .and(warp::path::end())
+        // A request that is too large for one message is refused anyway, so
+        // never buffer or parse more than that
+        .and(warp::body::content_length_limit(MAX_MESSAGE_LEN as u64))
         .and(warp::body::json())
+        .and(isolation_key())
         .and(penum_client)
         .and_then(handle_rpc_request);
 
@@ -54,8 +62,21 @@ pub async fn start_rpc_server(
     Ok(())
 }
 
+// Requests are isolated by the web origin that sent them, then by account
+fn isolation_key() -> impl Filter<Extract = (IsolationKey,), Error = std::convert::Infallible> + Clone {
+    warp::header::optional::<String>("origin")
+        .and(warp::header::optional::<String>("referer"))
+        .map(|origin: Option<String>, referer: Option<String>| {
+            IsolationKey::from_headers(origin.as_deref(), referer.as_deref())
+        })
+        // Headers that are not valid text count as the opaque origin
+        .or(warp::any().map(|| IsolationKey::from_headers(Some("null"), None)))
+        .unify()
+}
+
 async fn handle_rpc_request(
     request: JsonRpcRequest,
+    origin: IsolationKey,
     penum_client: Arc<PenumRpcClient>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     // Validate supported methods
@@ -80,6 +101,9 @@ async fn handle_rpc_request(
         return Ok(warp::reply::json(&error_response));
     }
 
+    let accounts = RequestAccounts::of_request(&request.method, &request.params);
+    let isolation_key = penum_client.isolate(origin, &request.method, &accounts);
+
     // Serialize request to JSON
     let request_json = match serde_json::to_vec(&request) {
         Ok(json) => json,
@@ -98,7 +122,7 @@ async fn handle_rpc_request(
     };
 
     // Send through Penum
-    match penum_client.send_rpc_request(&request_json).await {
+    match penum_client.send_rpc_request(&request_json, &isolation_key).await {
         Ok(response_data) => {
             // Parse response - the gateway returns the raw JSON-RPC response from the provider
             // Convert the response bytes to string and parse as JSON
@@ -171,6 +195,36 @@ async fn handle_rpc_request(
                 }
             }
         }
+        Err(e) if e.downcast_ref::<IdentityMismatch>().is_some() => {
+            // A key mismatch means someone on the path may be impersonating a
+            // hop, so tell the user instead of failing silently
+            eprintln!("🚨 {}", e);
+            let error_response = JsonRpcResponse {
+                jsonrpc: "2.0".to_string(),
+                result: None,
+                error: Some(JsonRpcError {
+                    code: -32603,
+                    message: e.to_string(),
+                }),
+                id: request.id,
+            };
+            Ok(warp::reply::json(&error_response))
+        }
+        Err(e) if e.downcast_ref::<ProtocolVersionRejected>().is_some() => {
+            // Version rejections are a deployment problem (client and network on
+            // incompatible releases), so say so instead of failing silently
+            eprintln!("⚠️  {:#}", e);
+            let error_response = JsonRpcResponse {
+                jsonrpc: "2.0".to_string(),
+                result: None,
+                error: Some(JsonRpcError {
+                    code: -32603,
+                    message: format!("{:#}", e),
+                }),
+                id: request.id,
+            };
+            Ok(warp::reply::json(&error_response))
+        }
         Err(_e) => {
             // Fail silently - never log internal errors to prevent information leakage
             let error_response = JsonRpcResponse {
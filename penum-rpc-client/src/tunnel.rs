@@ -0,0 +1,353 @@
+use crate::circuit::{Circuit, CircuitReader, CircuitWriter};
+use crate::config::CoverTraffic;
+use penum_protocol::cell::{CellHeader, CellOpener, CellSealer, CellType, Packet, Reassembler, FLAG_END, SENDME_INCREMENT, STREAM_WINDOW};
+use penum_protocol::session::Session;
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::{mpsc, Semaphore};
+
+// Cells waiting for the writer task, which seals them in send order
+const OUTBOUND_QUEUE: usize = 256;
+
+// Per-stream state shared with the tunnel's reader task
+struct StreamHandle {
+    inbox: mpsc::UnboundedSender<(CellHeader, Vec<u8>)>,
+    send_window: Arc<Semaphore>,
+}
+
+type StreamMap = Arc<Mutex<HashMap<u16, StreamHandle>>>;
+
+// A long-lived encrypted tunnel to the gateway over one circuit. Each JSON-RPC
+// request runs on its own stream, so many requests can share the tunnel
+// concurrently without another TCP connection or handshake.
+pub struct Tunnel {
+    outbound: mpsc::Sender<(CellHeader, Vec<u8>)>,
+    streams: StreamMap,
+    next_stream_id: AtomicU32,
+    closed: Arc<AtomicBool>,
+    failure_counted: AtomicBool,
+    created_at: Instant,
+}
+
+impl Tunnel {
+    pub fn start(circuit: Circuit, session: Session, cover_traffic: CoverTraffic) -> Self {
+        let (writer, reader) = circuit.into_split();
+        let (sealer, opener) = session.into_parts();
+        let (outbound, outbound_rx) = mpsc::channel(OUTBOUND_QUEUE);
+        let streams: StreamMap = Arc::new(Mutex::new(HashMap::new()));
+        let closed = Arc::new(AtomicBool::new(false));
+
+        tokio::spawn(write_cells(writer, sealer, outbound_rx, cover_traffic, closed.clone()));
+        tokio::spawn(read_cells(reader, opener, streams.clone(), closed.clone()));
+
+        Self {
+            outbound,
+            streams,
+            next_stream_id: AtomicU32::new(1),
+            closed,
+            failure_counted: AtomicBool::new(false),
+            created_at: Instant::now(),
+        }
+    }
+
+    // A tunnel is reused until it fails or reaches the configured lifetime
+    pub fn is_usable(&self, lifetime: Duration) -> bool {
+        !self.closed.load(Ordering::Relaxed) && self.created_at.elapsed() < lifetime
+    }
+
+    // Whether the tunnel has failed and this is the first time anyone asked,
+    // so a failure that broke several requests is counted once
+    pub fn take_failure(&self) -> bool {
+        self.closed.load(Ordering::Relaxed) && !self.failure_counted.swap(true, Ordering::Relaxed)
+    }
+
+    pub async fn request(&self, message: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let stream_id = self.allocate_stream_id()?;
+        let (inbox, mut inbox_rx) = mpsc::unbounded_channel();
+        let send_window = Arc::new(Semaphore::new(STREAM_WINDOW));
+        self.streams.lock().expect("stream map poisoned").insert(
+            stream_id,
+            StreamHandle {
+                inbox,
+                send_window: send_window.clone(),
+            },
+        );
+        let _stream = StreamGuard {
+            streams: &self.streams,
+            stream_id,
+        };
+
+        // Send the request, waiting for a SENDME whenever the window is used up
+        for cell in Packet::split_message(CellType::Data, stream_id, message)? {
+            send_window
+                .acquire()
+                .await
+                .map_err(|_| anyhow::anyhow!("Tunnel closed"))?
+                .forget();
+            self.send(cell).await?;
+        }
+
+        // Reassemble the response, acknowledging every SENDME_INCREMENT cells
+        let mut reassembler = Reassembler::new(stream_id);
+        let mut received = 0;
+        let mut sendmes_sent = 0;
+        loop {
+            let (header, payload) = inbox_rx
+                .recv()
+                .await
+                .ok_or_else(|| anyhow::anyhow!("Tunnel closed"))?;
+
+            match header.cell_type {
+                CellType::Data => {
+                    if let Some(response) = reassembler.push(&header, &payload)? {
+                        return Ok(response);
+                    }
+                    received += 1;
+                    if received % SENDME_INCREMENT == 0 {
+                        let sendme = CellHeader::new(CellType::Sendme, stream_id, sendmes_sent, 0).with_flags(FLAG_END);
+                        self.send((sendme, Vec::new())).await?;
+                        sendmes_sent += 1;
+                    }
+                }
+                CellType::Error => return Err(anyhow::anyhow!("Gateway could not process request")),
+                _ => return Err(anyhow::anyhow!("Unexpected response cell")),
+            }
+        }
+    }
+
+    // Stream ids are never reused within a tunnel; once they run out the
+    // tunnel retires itself and the next request builds a new one
+    fn allocate_stream_id(&self) -> anyhow::Result<u16> {
+        let stream_id = self.next_stream_id.fetch_add(1, Ordering::Relaxed);
+        if stream_id > u16::MAX as u32 {
+            self.failure_counted.store(true, Ordering::Relaxed);  // Retired, not failed
+            self.closed.store(true, Ordering::Relaxed);
+            return Err(anyhow::anyhow!("Tunnel stream ids exhausted"));
+        }
+        Ok(stream_id as u16)
+    }
+
+    async fn send(&self, cell: (CellHeader, Vec<u8>)) -> anyhow::Result<()> {
+        self.outbound
+            .send(cell)
+            .await
+            .map_err(|_| anyhow::anyhow!("Tunnel closed"))
+    }
+}
+
+// Removes a stream from the tunnel when its request finishes or is abandoned
+struct StreamGuard<'a> {
+    streams: &'a StreamMap,
+    stream_id: u16,
+}
+
+impl Drop for StreamGuard<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut streams) = self.streams.lock() {
+            streams.remove(&self.stream_id);
+        }
+    }
+}
+
+// Time until the next cell under the cover traffic schedule
+fn cover_interval(cover_traffic: CoverTraffic) -> Option<Duration> {
+    match cover_traffic {
+        CoverTraffic::Off => None,
+        CoverTraffic::Constant { interval_ms } => Some(Duration::from_millis(interval_ms)),
+        CoverTraffic::Randomized { mean_interval_ms } => {
+            // Inverse transform sampling of an exponential distribution
+            let uniform: f64 = rand::thread_rng().gen();
+            let interval_ms = -(mean_interval_ms as f64) * (1.0 - uniform).ln();
+            Some(Duration::from_secs_f64(interval_ms / 1000.0))
+        }
+    }
+}
+
+async fn write_cells(
+    mut writer: CircuitWriter,
+    mut sealer: CellSealer,
+    mut outbound_rx: mpsc::Receiver<(CellHeader, Vec<u8>)>,
+    cover_traffic: CoverTraffic,
+    closed: Arc<AtomicBool>,
+) {
+    loop {
+        // With cover traffic a cell leaves on every tick of the schedule: the
+        // next real cell if one is waiting, otherwise a padding cell that the
+        // gateway discards. Real cells never leave between ticks.
+        // Padding is sealed end to end and carries no mark a relay could
+        // read: relays forward every cell anyway, so they have no use for
+        // one, and it would let a hostile relay strip the cover and see the
+        // real cells' timing. Carried to the gateway, padding covers every
+        // link of the circuit, not only the first.
+        let next = match cover_interval(cover_traffic) {
+            None => outbound_rx.recv().await,
+            Some(interval) => {
+                tokio::time::sleep(interval).await;
+                match outbound_rx.try_recv() {
+                    Ok(cell) => Some(cell),
+                    Err(TryRecvError::Empty) => Some((CellHeader::new(CellType::Padding, 0, 0, 0), Vec::new())),
+                    Err(TryRecvError::Disconnected) => None,
+                }
+            }
+        };
+        let Some((header, payload)) = next else {
+            break; // Tunnel dropped
+        };
+
+        let Ok(cell) = sealer.seal(&header, &payload) else {
+            break;
+        };
+        if writer.send_cell(cell).await.is_err() {
+            break;
+        }
+    }
+    closed.store(true, Ordering::Relaxed);
+}
+
+async fn read_cells(
+    mut reader: CircuitReader,
+    mut opener: CellOpener,
+    streams: StreamMap,
+    closed: Arc<AtomicBool>,
+) {
+    while let Ok(cell) = reader.recv_cell().await {
+        // Only the holder of the gateway identity key can produce a valid tag.
+        // Anything else means the circuit is broken or tampered with.
+        let Ok((header, payload)) = opener.open(cell) else {
+            break;
+        };
+
+        if header.cell_type == CellType::Padding {
+            continue;
+        }
+
+        let streams = streams.lock().expect("stream map poisoned");
+        let Some(stream) = streams.get(&header.stream_id) else {
+            continue; // Stream already finished or abandoned
+        };
+        match header.cell_type {
+            CellType::Sendme => stream.send_window.add_permits(SENDME_INCREMENT),
+            _ => {
+                let _ = stream.inbox.send((header, payload));
+            }
+        }
+    }
+
+    // Fail every open stream so pending requests return instead of hanging
+    closed.store(true, Ordering::Relaxed);
+    for (_, stream) in streams.lock().expect("stream map poisoned").drain() {
+        stream.send_window.close();
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::circuit::Circuit;
+    use penum_protocol::cell::PACKET_SIZE;
+    use penum_protocol::handshake::{respond, CLIENT_HELLO_LEN, MAX_PROTOCOL_VERSION};
+    use penum_protocol::kdf::OnionLayer;
+    use penum_protocol::StaticSecret;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    // The far end of a one-hop tunnel, standing in for a relay that is also
+    // the gateway
+    pub(crate) struct Gateway {
+        stream: TcpStream,
+        layer: OnionLayer,
+        opener: CellOpener,
+    }
+
+    impl Gateway {
+        pub(crate) async fn recv(&mut self) -> (CellHeader, Vec<u8>) {
+            let mut cell = [0u8; PACKET_SIZE];
+            self.stream.read_exact(&mut cell).await.unwrap();
+            self.layer.apply_forward(&mut cell);
+            self.opener.open(cell).unwrap()
+        }
+    }
+
+    // A tunnel over a one-hop circuit to a local node
+    pub(crate) async fn one_hop_tunnel(cover_traffic: CoverTraffic) -> (Tunnel, Gateway) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let node = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut hello_cell = [0u8; PACKET_SIZE];
+            stream.read_exact(&mut hello_cell).await.unwrap();
+            let response = respond(&StaticSecret::from([7u8; 32]), hello_cell[..CLIENT_HELLO_LEN].try_into().unwrap());
+            stream.write_all(&response.reply_cell()).await.unwrap();
+            let (_, secrets) = response.handshake.unwrap().finish(&[]).unwrap();
+            (stream, secrets)
+        });
+        let (circuit, _) = Circuit::connect(address, None, MAX_PROTOCOL_VERSION, 0).await.unwrap();
+        let (stream, secrets) = node.await.unwrap();
+        // Both ends derived the same secrets
+        let tunnel = Tunnel::start(circuit, secrets.client_session(), cover_traffic);
+        let (_, opener) = secrets.gateway_session().into_parts();
+        (tunnel, Gateway { stream, layer: secrets.onion_layer(), opener })
+    }
+
+    #[test]
+    fn cover_intervals_follow_the_schedule() {
+        assert_eq!(cover_interval(CoverTraffic::Off), None);
+        assert_eq!(cover_interval(CoverTraffic::Constant { interval_ms: 40 }), Some(Duration::from_millis(40)));
+
+        let samples = 20_000;
+        let total: Duration = (0..samples)
+            .map(|_| cover_interval(CoverTraffic::Randomized { mean_interval_ms: 100 }).unwrap())
+            .sum();
+        let mean_ms = total.as_secs_f64() * 1000.0 / samples as f64;
+        assert!((95.0..105.0).contains(&mean_ms), "mean {} ms", mean_ms);
+    }
+
+    #[tokio::test]
+    async fn idle_tunnel_sends_padding_on_every_tick() {
+        let (_tunnel, mut gateway) = one_hop_tunnel(CoverTraffic::Constant { interval_ms: 20 }).await;
+        let started = Instant::now();
+        for _ in 0..5 {
+            let (header, _) = gateway.recv().await;
+            assert_eq!(header.cell_type, CellType::Padding);
+        }
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn real_cells_take_the_next_tick() {
+        let (tunnel, mut gateway) = one_hop_tunnel(CoverTraffic::Constant { interval_ms: 50 }).await;
+        let tunnel = Arc::new(tunnel);
+        let request = tokio::spawn({
+            let tunnel = tunnel.clone();
+            async move { tunnel.request(b"{}").await }
+        });
+        let started = Instant::now();
+        let (header, payload) = gateway.recv().await;
+        assert_eq!(header.cell_type, CellType::Data);
+        assert_eq!(payload, b"{}");
+        assert!(started.elapsed() >= Duration::from_millis(40));
+        // Then padding again, a full interval later
+        let sent = Instant::now();
+        assert_eq!(gateway.recv().await.0.cell_type, CellType::Padding);
+        assert!(sent.elapsed() >= Duration::from_millis(40));
+        request.abort();
+    }
+
+    #[tokio::test]
+    async fn without_cover_cells_leave_at_once() {
+        let (tunnel, mut gateway) = one_hop_tunnel(CoverTraffic::Off).await;
+        let tunnel = Arc::new(tunnel);
+        let request = tokio::spawn({
+            let tunnel = tunnel.clone();
+            async move { tunnel.request(b"{}").await }
+        });
+        let started = Instant::now();
+        assert_eq!(gateway.recv().await.0.cell_type, CellType::Data);
+        assert!(started.elapsed() < Duration::from_millis(500));
+        request.abort();
+    }
+}
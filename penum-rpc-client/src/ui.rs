@@ -1,8 +1,59 @@
+use crate::penum_client::PenumRpcClient;
+use std::sync::Arc;
 use warp::Filter;
 
-pub async fn start_ui_server(port: u16, rpc_port: u16) -> anyhow::Result<()> {
+pub async fn start_ui_server(port: u16, rpc_port: u16, penum_client: Arc<PenumRpcClient>) -> anyhow::Result<()> {
+    // Every route answers only requests addressed to the UI itself. A website
+    // that rebinds its own hostname to 127.0.0.1 sends its hostname in Host,
+    // so it cannot read which origins and accounts have circuits.
+    let own_hosts = [format!("127.0.0.1:{}", port), format!("localhost:{}", port)];
+    let local_host = warp::header::optional::<String>("host")
+        .and_then(move |host: Option<String>| {
+            let allowed = host.is_some_and(|host| own_hosts.contains(&host.to_ascii_lowercase()));
+            async move {
+                if allowed {
+                    Ok(())
+                } else {
+                    Err(warp::reject::not_found())
+                }
+            }
+        })
+        .untuple_one();
+
+    // Which circuit each origin and account is using, which requests linked
+    // accounts and how often each relay's circuits fail, polled by the page
+    let linked_client = penum_client.clone();
+    let relays_client = penum_client.clone();
+    let control_client = penum_client.clone();
+    let circuits = warp::path("circuits")
+        .and(warp::path::end())
+        .map(move || warp::reply::json(&penum_client.circuit_activity()));
+    let linked = warp::path("linked")
+        .and(warp::path::end())
+        .map(move || warp::reply::json(&linked_client.linked_queries()));
+    let relays = warp::path("relays")
+        .and(warp::path::end())
+        .map(move || warp::reply::json(&relays_client.relay_health()));
+
+    // Control endpoint, also used by the page's button. Browsers send Origin
+    // with every cross-site POST, so other websites cannot trigger it.
+    let new_identity = warp::post()
+        .and(warp::path!("control" / "new-identity"))
+        .and(warp::header::optional::<String>("origin"))
+        .and_then(move |origin: Option<String>| {
+            let penum_client = control_client.clone();
+            async move {
+                let own_origins = [format!("http://127.0.0.1:{}", port), format!("http://localhost:{}", port)];
+                if origin.is_some_and(|origin| !own_origins.contains(&origin)) {
+                    return Err(warp::reject::not_found());
+                }
+                let closed = penum_client.new_identity();
+                Ok(warp::reply::json(&serde_json::json!({ "closed_circuits": closed })))
+            }
+        });
+
     let html = move || {
-        warp::any().map(move || {
+        warp::path::end().map(move || {
             warp::reply::html(format!(
                 r#"<!DOCTYPE html>
 <html lang="en">
@@ -123,6 +174,40 @@ pub async fn start_ui_server(port: u16, rpc_port: u16) -> anyhow::Result<()> {
             color: #78350f;
             font-size: 14px;
         }}
+        .circuit-table {{
+            width: 100%;
+            border-collapse: collapse;
+            font-size: 13px;
+        }}
+        .circuit-table th {{
+            text-align: left;
+            color: #6b7280;
+            font-weight: 600;
+            padding: 4px 8px 4px 0;
+        }}
+        .circuit-table td {{
+            color: #1f2937;
+            font-family: 'Monaco', monospace;
+            padding: 4px 8px 4px 0;
+            vertical-align: top;
+            word-break: break-all;
+        }}
+        .identity-button {{
+            margin-top: 12px;
+            padding: 8px 16px;
+            border: none;
+            border-radius: 8px;
+            background: #667eea;
+            color: white;
+            font-weight: 600;
+            cursor: pointer;
+        }}
+        .circuit-empty {{
+            color: #6b7280;
+            font-size: 14px;
+        }}
+        .relay-suspicious {{ color: #b45309 !important; }}
+        .relay-excluded {{ color: #dc2626 !important; }}
     </style>
 </head>
 <body>
@@ -145,6 +230,36 @@ pub async fn start_ui_server(port: u16, rpc_port: u16) -> anyhow::Result<()> {
             <div class="info-value" style="color: #10b981;">Connected</div>
         </div>
         
+        <div class="info-card">
+            <div class="info-label">Circuits by Origin and Account</div>
+            <table class="circuit-table">
+                <thead><tr><th>Origin</th><th>Account</th><th>Requests</th><th>Circuit</th></tr></thead>
+                <tbody id="circuits"></tbody>
+            </table>
+            <div class="circuit-empty" id="circuits-empty">No requests yet</div>
+            <button class="identity-button" onclick="newIdentity()">New Identity</button>
+            <span class="circuit-empty" id="identity-result"></span>
+        </div>
+        
+        <div class="warning" id="linked-card" hidden>
+            <div class="warning-title">⚠️ Linkable Queries</div>
+            <div class="warning-text">
+                These requests involved more than one of your accounts, so whoever sees them can link those accounts.
+            </div>
+            <table class="circuit-table">
+                <thead><tr><th>Origin</th><th>Method</th><th>Accounts</th><th>When</th></tr></thead>
+                <tbody id="linked"></tbody>
+            </table>
+        </div>
+        
+        <div class="info-card" id="relays-card" hidden>
+            <div class="info-label">Relay Health</div>
+            <table class="circuit-table">
+                <thead><tr><th>Relay</th><th>Circuits</th><th>Failed</th><th>Status</th></tr></thead>
+                <tbody id="relays"></tbody>
+            </table>
+        </div>
+        
         <div class="features">
             <div class="feature">
                 <div class="feature-icon">🛡️</div>
@@ -179,6 +294,67 @@ pub async fn start_ui_server(port: u16, rpc_port: u16) -> anyhow::Result<()> {
             </div>
         </div>
     </div>
+    <script>
+        // Each origin and account gets its own circuit; show which one it is using
+        async function refreshCircuits() {{
+            try {{
+                const keys = await (await fetch('/circuits')).json();
+                const body = document.getElementById('circuits');
+                body.replaceChildren();
+                for (const key of keys) {{
+                    const row = body.insertRow();
+                    row.insertCell().textContent = key.origin;
+                    row.insertCell().textContent = key.account ?? '—';
+                    row.insertCell().textContent = key.requests;
+                    const circuit = key.circuit;
+                    row.insertCell().textContent = circuit
+                        ? `${{circuit.entry_relay}} → ${{circuit.middle_relay}} → ${{circuit.gateway}} (${{circuit.age_secs}}s old)`
+                        : 'no circuit open';
+                }}
+                document.getElementById('circuits-empty').hidden = keys.length > 0;
+
+                const queries = await (await fetch('/linked')).json();
+                const linked = document.getElementById('linked');
+                linked.replaceChildren();
+                for (const query of queries) {{
+                    const row = linked.insertRow();
+                    row.insertCell().textContent = query.origin;
+                    row.insertCell().textContent = query.method;
+                    row.insertCell().textContent = query.accounts.join(', ');
+                    row.insertCell().textContent = `${{query.secs_ago}}s ago`;
+                }}
+                document.getElementById('linked-card').hidden = queries.length === 0;
+
+                const relays = await (await fetch('/relays')).json();
+                const health = document.getElementById('relays');
+                health.replaceChildren();
+                for (const relay of relays) {{
+                    const row = health.insertRow();
+                    // Guards are only ever warned about, never dropped
+                    row.insertCell().textContent = relay.guard ? `${{relay.address}} (guard)` : relay.address;
+                    row.insertCell().textContent = relay.circuits;
+                    row.insertCell().textContent = `${{Math.round(100 * relay.failures / relay.circuits)}}%`;
+                    const status = row.insertCell();
+                    status.textContent = relay.status;
+                    status.className = `relay-${{relay.status}}`;
+                }}
+                document.getElementById('relays-card').hidden = relays.length === 0;
+            }} catch (e) {{}}
+        }}
+        // Close every circuit and forget the origins and accounts seen
+        async function newIdentity() {{
+            const result = document.getElementById('identity-result');
+            try {{
+                const reply = await (await fetch('/control/new-identity', {{ method: 'POST' }})).json();
+                result.textContent = ` Closed ${{reply.closed_circuits}} circuits`;
+            }} catch (e) {{
+                result.textContent = ' Failed';
+            }}
+            refreshCircuits();
+        }}
+        refreshCircuits();
+        setInterval(refreshCircuits, 3000);
+    </script>
 </body>
 </html>"#,
                 rpc_port, rpc_port
@@ -188,7 +364,9 @@ pub async fn start_ui_server(port: u16, rpc_port: u16) -> anyhow::Result<()> {
 
     println!("🎨 Penum UI available at http://127.0.0.1:{}", port);
 
-    warp::serve(html()).run(([127, 0, 0, 1], port)).await;
+    warp::serve(local_host.and(html().or(circuits).or(linked).or(relays).or(new_identity)))
+        .run(([127, 0, 0, 1], port))
+        .await;
 
     Ok(())
 }
@@ -0,0 +1,53 @@
+use crate::identity::IdentityKeys;
+use penum_protocol::directory::{Descriptor, NodeDescriptor, SignedDescriptor};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// How long one upload to an authority may take
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Publish this node's descriptor to every directory authority and republish
+// it every `interval`. Each upload is freshly signed and doubles as a
+// heartbeat: authorities drop nodes they stop hearing from.
+pub async fn publish_descriptors(authorities: Vec<String>, node: NodeDescriptor, identity: IdentityKeys, interval: Duration) {
+    let Ok(http) = reqwest::Client::builder().timeout(UPLOAD_TIMEOUT).build() else {
+        return;
+    };
+    // Report each authority's failures once, not at every heartbeat
+    let mut failing = vec![false; authorities.len()];
+
+    loop {
+        let descriptor = Descriptor {
+            published: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or(0),
+            node: node.clone(),
+        };
+        match SignedDescriptor::sign(&descriptor, &identity.secret) {
+            Ok(signed) => {
+                for (authority, failing) in authorities.iter().zip(failing.iter_mut()) {
+                    let url = format!("{}/descriptor", authority.trim_end_matches('/'));
+                    let result = match http.post(&url).json(&signed).send().await {
+                        Ok(response) if response.status().is_success() => Ok(()),
+                        Ok(response) => Err(response.text().await.unwrap_or_default()),
+                        Err(e) => Err(e.to_string()),
+                    };
+                    match result {
+                        Ok(()) if *failing => {
+                            println!("🏛️  Descriptor accepted by {} again", authority);
+                            *failing = false;
+                        }
+                        Ok(()) => {}
+                        Err(e) if !*failing => {
+                            eprintln!("⚠️  Could not publish descriptor to {}: {}", authority, e);
+                            *failing = true;
+                        }
+                        Err(_) => {}
+                    }
+                }
+            }
+            Err(e) => eprintln!("🚨 Could not sign descriptor: {:#}", e),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
@@ -1,22 +1,118 @@
+use crate::gateway::ResponseTiming;
+use crate::identity::IdentityKeys;
+use penum_protocol::directory::{NodeDescriptor, NodeRole as DirectoryRole};
 use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeRole {
+    #[default]
+    Gateway,
+    Relay,
+}
+
+// Optional delay relays add to every cell, trading latency for resistance to
+// end-to-end timing correlation
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+pub enum MixingMode {
+    #[default]
+    Off,
+    // Random per-cell delay with the given mean, never above the latency budget
+    Exponential { mean_delay_ms: u64, max_delay_ms: u64 },
+    // Hold cells and release all of them on a fixed clock shared by every circuit
+    Pool { flush_interval_ms: u64 },
+}
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct GatewayConfig {
+    #[serde(default)]
+    pub role: NodeRole,  // Relays learn their next hop per circuit, not from config
     pub listen_addr: String,
     pub listen_port: u16,
     pub rpc_provider_url: String,
     pub allow_public_mempool: bool,  // Privacy guard setting
     pub mev_blocker_url: Option<String>, // MEV safety hook
+    #[serde(default = "default_identity_key_path")]
+    pub identity_key_path: String,  // Long-term identity key, created on first start
+    #[serde(default = "default_replay_window_secs")]
+    pub replay_window_secs: u64,  // Allowed clock difference; handshakes are remembered this long
+    #[serde(default)]
+    pub relay_batch_window_ms: u64,  // Relays: wait this long to coalesce a circuit's cells into one write
+    #[serde(default)]
+    pub relay_mixing: MixingMode,
+    #[serde(default)]
+    pub mix_listen_port: Option<u16>,  // Also accept Sphinx packets (mixnet mode) on this port
+    #[serde(default)]
+    pub response_buckets_ms: Vec<u64>,  // Release responses only at these latencies; empty = immediately
+    #[serde(default)]
+    pub max_response_delay_ms: Option<u64>,  // Answer with an error if the provider takes longer; release cap for buckets
+    #[serde(default)]
+    pub directory_authorities: Vec<String>,  // Base URLs of the authorities to publish our descriptor to
+    #[serde(default = "default_nickname")]
+    pub nickname: String,
+    #[serde(default)]
+    pub public_addr: Option<String>,  // IP clients reach us on, if not listen_addr
+    #[serde(default = "default_heartbeat_interval_secs")]
+    pub heartbeat_interval_secs: u64,
+    #[serde(default)]
+    pub bandwidth_kbps: u64,  // Capacity offered to the network; clients weight path selection by it
+    #[serde(default)]
+    pub family: Vec<String>,  // Identity keys of the other nodes this operator runs
+    #[serde(default)]
+    pub allow_private_next_hops: bool,  // Let clients extend to loopback and private addresses; test networks only
+    #[serde(default)]
+    pub require_keyed_hello: bool,  // Ignore hellos from clients that do not know our identity key
+    #[serde(default = "default_answer_legacy_hellos")]
+    pub answer_legacy_hellos: bool,  // Keep answering version 2 clients; turn off once they have upgraded
+}
+
+fn default_nickname() -> String {
+    "unnamed".to_string()
+}
+
+fn default_heartbeat_interval_secs() -> u64 {
+    60
+}
+
+fn default_identity_key_path() -> String {
+    "identity.key".to_string()
+}
+
+fn default_replay_window_secs() -> u64 {
+    300
+}
+
+fn default_answer_legacy_hellos() -> bool {
+    true
 }
 
 impl Default for GatewayConfig {
     fn default() -> Self {
         Self {
+            role: NodeRole::Gateway,
             listen_addr: "127.0.0.1".to_string(),
             listen_port: 9003,
             rpc_provider_url: "https://cloudflare-eth.com".to_string(),
             allow_public_mempool: false,  // Default to privacy-safe
             mev_blocker_url: None,
+            identity_key_path: default_identity_key_path(),
+            replay_window_secs: default_replay_window_secs(),
+            relay_batch_window_ms: 0,
+            relay_mixing: MixingMode::Off,
+            mix_listen_port: None,
+            response_buckets_ms: Vec::new(),
+            max_response_delay_ms: None,
+            directory_authorities: Vec::new(),
+            nickname: default_nickname(),
+            public_addr: None,
+            heartbeat_interval_secs: default_heartbeat_interval_secs(),
+            bandwidth_kbps: 0,
+            family: Vec::new(),
+            allow_private_next_hops: false,
+            require_keyed_hello: false,
+            answer_legacy_hellos: default_answer_legacy_hellos(),
         }
     }
 }
@@ -25,5 +121,44 @@ impl GatewayConfig {
     pub fn from_json(json_str: &str) -> Result<Self, serde_json::Error> {
         serde_json::from_str(json_str)
     }
+
+    pub fn response_timing(&self) -> ResponseTiming {
+        ResponseTiming::new(&self.response_buckets_ms, self.max_response_delay_ms)
+    }
+
+    // Addresses of this node, which clients may not use as a next hop
+    pub fn own_ips(&self) -> Vec<IpAddr> {
+        [Some(&self.listen_addr), self.public_addr.as_ref()]
+            .into_iter()
+            .flatten()
+            .filter_map(|addr| addr.parse().ok())
+            .collect()
+    }
+
+    // How this node appears in the directory. Authorities assign the flags.
+    pub fn node_descriptor(&self, identity: &IdentityKeys) -> anyhow::Result<NodeDescriptor> {
+        let ip: IpAddr = self
+            .public_addr
+            .as_deref()
+            .unwrap_or(&self.listen_addr)
+            .parse()
+            .map_err(|_| anyhow::anyhow!("public_addr (or listen_addr) must be an IP address to publish a descriptor"))?;
+        if ip.is_unspecified() {
+            return Err(anyhow::anyhow!("Set public_addr: {} is not an address clients can reach", ip));
+        }
+        Ok(NodeDescriptor {
+            nickname: self.nickname.clone(),
+            role: match self.role {
+                NodeRole::Gateway => DirectoryRole::Gateway,
+                NodeRole::Relay => DirectoryRole::Relay,
+            },
+            address: SocketAddr::new(ip, self.listen_port),
+            mix_address: self.mix_listen_port.map(|port| SocketAddr::new(ip, port)),
+            identity_key: identity.public_hex(),
+            bandwidth: self.bandwidth_kbps,
+            family: self.family.clone(),
+            flags: Vec::new(),
+        })
+    }
 }
 
@@ -1,16 +1,33 @@
-use crate::crypto::{decrypt_in_place, encrypt_in_place, derive_session_key, EphemeralKeys};
+use crate::identity::IdentityKeys;
+use crate::link::{self, CircuitHandler, CircuitStream};
+use crate::mirror::DirectoryMirror;
+use crate::replay::ReplayCache;
 use crate::rpc_forwarder::RpcForwarder;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use chacha20::cipher::StreamCipher;
+use chacha20::ChaCha20;
+use penum_protocol::cell::{CellHeader, CellSealer, CellType, Packet, Reassembler, FLAG_END, PACKET_SIZE, SENDME_INCREMENT, STREAM_WINDOW};
+use penum_protocol::directory::DIRECTORY_METHOD;
+use penum_protocol::handshake::{HandshakeSecrets, CAP_LINK, MIN_PROTOCOL_VERSION};
+use penum_protocol::kdf::OnionLayer;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use penum_protocol::PublicKey;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
 use tokio::net::{TcpListener, TcpStream};
-use x25519_dalek::PublicKey;
-use rand::RngCore;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time::{sleep_until, timeout_at, Instant};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-const PACKET_SIZE: usize = 1024;
-const HEADER_SIZE: usize = 32;
-const TAG_SIZE: usize = 16;
-const PAYLOAD_SIZE: usize = PACKET_SIZE - HEADER_SIZE - TAG_SIZE;
+// Cells waiting for the tunnel's writer task
+const OUTBOUND_QUEUE: usize = 256;
+
+// Requests that may be partially received on one tunnel at the same time
+const MAX_PENDING_STREAMS: usize = 64;
+
+// Send windows of responses in flight, keyed by stream id
+type WindowMap = Arc<Mutex<HashMap<u16, Arc<Semaphore>>>>;
 
 #[derive(Deserialize, Serialize, Debug)]
 struct JsonRpcRequest {
@@ -21,108 +38,356 @@ struct JsonRpcRequest {
     id: Value,
 }
 
+// When responses are released: at the smallest bucket that has not passed
+// since the request arrived, past the largest one at its next multiple, and
+// never later than the maximum delay. A request the provider has not answered
+// by the maximum is answered with an error then, so slow calls do not leak
+// their latency in coarse steps either.
+#[derive(Clone, Debug)]
+pub struct ResponseTiming {
+    buckets: Arc<[Duration]>,
+    max_delay: Option<Duration>,
+}
+
+impl ResponseTiming {
+    pub fn new(buckets_ms: &[u64], max_delay_ms: Option<u64>) -> Self {
+        let mut buckets: Vec<u64> = buckets_ms.iter().copied().filter(|ms| *ms > 0).collect();
+        buckets.sort_unstable();
+        buckets.dedup();
+        Self {
+            buckets: buckets.into_iter().map(Duration::from_millis).collect(),
+            max_delay: max_delay_ms.map(Duration::from_millis),
+        }
+    }
+
+    pub fn describe(&self) -> Option<String> {
+        let buckets: Vec<u128> = self.buckets.iter().map(Duration::as_millis).collect();
+        match (buckets.is_empty(), self.max_delay) {
+            (true, None) => None,
+            (true, Some(max)) => Some(format!("errors after {} ms", max.as_millis())),
+            (false, None) => Some(format!("released at {:?} ms", buckets)),
+            (false, Some(max)) => Some(format!("released at {:?} ms, errors after {} ms", buckets, max.as_millis())),
+        }
+    }
+
+    // How long after the request arrived a response ready after `elapsed` is released
+    fn release_delay(&self, elapsed: Duration) -> Duration {
+        let Some(&largest) = self.buckets.last() else {
+            return elapsed; // Buckets disabled: right away
+        };
+        let bucket = match self.buckets.iter().find(|bucket| **bucket >= elapsed) {
+            Some(bucket) => *bucket,
+            None => largest * (elapsed.as_nanos() / largest.as_nanos() + 1) as u32,
+        };
+        match self.max_delay {
+            Some(max) => bucket.min(max),
+            None => bucket,
+        }
+    }
+}
+
 pub struct Gateway {
     rpc_forwarder: RpcForwarder,
+    identity: IdentityKeys,
+    replay_cache: Arc<Mutex<ReplayCache>>,
+    timing: ResponseTiming,
+    directory: DirectoryMirror,
 }
 
 impl Clone for Gateway {
     fn clone(&self) -> Self {
         Gateway {
             rpc_forwarder: self.rpc_forwarder.clone(),
+            identity: self.identity.clone(),
+            replay_cache: self.replay_cache.clone(),
+            timing: self.timing.clone(),
+            directory: self.directory.clone(),
         }
     }
 }
 
 impl Gateway {
-    pub fn new(rpc_forwarder: RpcForwarder) -> Self {
-        Self { rpc_forwarder }
+    pub fn new(
+        rpc_forwarder: RpcForwarder,
+        identity: IdentityKeys,
+        replay_window_secs: u64,
+        timing: ResponseTiming,
+        directory: DirectoryMirror,
+    ) -> Self {
+        Self {
+            rpc_forwarder,
+            identity,
+            replay_cache: Arc::new(Mutex::new(ReplayCache::new(replay_window_secs))),
+            timing,
+            directory,
+        }
+    }
+
+    // Forward a request that arrived at `received` and hold the response
+    // until its release time, so its timing does not reveal the method or
+    // whether the provider had it cached. None is answered with an error.
+    pub async fn timed_response(&self, request: &[u8], received: Instant) -> Option<Vec<u8>> {
+        let response = match self.timing.max_delay {
+            Some(max) => timeout_at(received + max, self.process_request(request)).await.ok().flatten(),
+            None => self.process_request(request).await,
+        };
+        sleep_until(received + self.timing.release_delay(received.elapsed())).await;
+        response
     }
 
     pub async fn handle_connection(&self, mut stream: TcpStream) -> anyhow::Result<()> {
-        // Generate ephemeral keys for this connection
-        let server_keys = EphemeralKeys::generate();
-        let server_pub = server_keys.public;
+        // Receive the client hello cell (offered protocol versions, capabilities
+        // and public key) and send the selected version, ephemeral public key and
+        // identity public key, followed by the ML-KEM exchange for a hybrid
+        // handshake. The session key mixes in DH with our identity key, so only
+        // the real gateway can decrypt the request or produce a valid response.
+        let Ok(Some((client_key, secrets))) = self.identity.accept_handshake(&mut stream).await else {
+            return Ok(()); // Fail silently (or rejected: no common protocol version)
+        };
+        if secrets.capabilities & CAP_LINK == 0 {
+            return self.serve_tunnel(stream, client_key, secrets).await;
+        }
+
+        // A middle relay carrying many circuits to us on one link
+        let gateway = self.clone();
+        let accept: CircuitHandler = Arc::new(move |circuit| {
+            let gateway = gateway.clone();
+            Box::pin(async move {
+                let _ = gateway.handle_link_circuit(circuit).await;
+            })
+        });
+        link::serve(stream, &secrets, Duration::ZERO, accept).await;
+        Ok(())
+    }
 
-        // Receive client public key (32 bytes)
-        let mut client_pub_bytes = [0u8; 32];
-        if let Err(_) = stream.read_exact(&mut client_pub_bytes).await {
+    // A circuit a relay created on its link to us. It cannot be a link.
+    async fn handle_link_circuit(&self, mut stream: DuplexStream) -> anyhow::Result<()> {
+        let Ok(Some((client_key, secrets))) = self.identity.accept_handshake(&mut stream).await else {
+            return Ok(()); // Fail silently (or rejected: no common protocol version)
+        };
+        if secrets.capabilities & CAP_LINK != 0 {
             return Ok(()); // Fail silently
         }
-        let client_pub = PublicKey::from(client_pub_bytes);
+        self.serve_tunnel(stream, client_key, secrets).await
+    }
 
-        // Send server public key (32 bytes)
-        if let Err(_) = stream.write_all(server_pub.as_bytes()).await {
+    // Answer the requests of one client tunnel whose handshake is done
+    async fn serve_tunnel<S: CircuitStream + 'static>(
+        &self,
+        mut stream: S,
+        client_key: PublicKey,
+        secrets: HandshakeSecrets,
+    ) -> anyhow::Result<()> {
+
+        // Derive directional session keys using HKDF with salt "penum-v1",
+        // bound to the handshake transcript. Like a relay we also add an onion
+        // layer, so cell headers never cross the last link in the clear.
+        // Version 2 clients do not expect one.
+        let mut session = secrets.gateway_session();
+        let (mut forward, mut backward) = (secrets.version >= MIN_PROTOCOL_VERSION)
+            .then(|| {
+                let OnionLayer { forward, backward } = secrets.onion_layer();
+                (forward, backward)
+            })
+            .unzip();
+
+        // Key confirmation: send ours first, then the client's first cell must carry theirs
+        let Ok(mut confirmation) = session.confirmation_cell() else {
+            return Ok(()); // Fail silently
+        };
+        if let Some(backward) = &mut backward {
+            backward.apply_keystream(&mut confirmation);
+        }
+        if stream.write_all(&confirmation).await.is_err() {
+            return Ok(()); // Fail silently
+        }
+        let mut first_cell = [0u8; PACKET_SIZE];
+        if stream.read_exact(&mut first_cell).await.is_err() {
             return Ok(()); // Fail silently
         }
+        if let Some(forward) = &mut forward {
+            forward.apply_keystream(&mut first_cell);
+        }
+        let Ok(confirmation) = session.check_confirmation(first_cell) else {
+            return Ok(()); // Fail silently
+        };
 
-        // Derive session key using HKDF with salt "penum-v1"
-        let shared_secret = server_keys.diffie_hellman(&client_pub);
-        let session_key = derive_session_key(shared_secret);
+        // The client's cells carry a timestamp. Each client handshake is
+        // accepted once within the replay window, so a recorded handshake and
+        // request replayed later never reach the provider.
+        let client_key = client_key.to_bytes();
+        if !self.replay_cache.lock().expect("replay cache poisoned").accept(client_key, confirmation.timestamp) {
+            return Ok(()); // Fail silently: stale or replayed handshake
+        }
+        let (sealer, mut opener) = session.into_parts();
 
-        // Receive encrypted packet (exactly 1024 bytes)
-        let mut encrypted_packet = [0u8; PACKET_SIZE];
-        if let Err(_) = stream.read_exact(&mut encrypted_packet).await {
-            return Ok(()); // Fail silently
+        // The tunnel stays open for many requests. Responses from concurrent
+        // streams are queued to a single writer, which seals them in send order
+        // so the nonce counters on both sides stay in step.
+        let (mut reader, writer) = tokio::io::split(stream);
+        let (outbound, outbound_rx) = mpsc::channel(OUTBOUND_QUEUE);
+        tokio::spawn(write_cells(writer, sealer, backward, outbound_rx));
+
+        let windows: WindowMap = Arc::new(Mutex::new(HashMap::new()));
+        let mut requests: HashMap<u16, (Reassembler, usize)> = HashMap::new();
+        let mut used_stream_ids: HashSet<u16> = HashSet::new();
+
+        // Receive encrypted packets (exactly 1024 bytes each) until the tunnel closes
+        loop {
+            let mut encrypted_packet = [0u8; PACKET_SIZE];
+            if reader.read_exact(&mut encrypted_packet).await.is_err() {
+                break; // Fail silently
+            }
+            if let Some(forward) = &mut forward {
+                forward.apply_keystream(&mut encrypted_packet);
+            }
+
+            // Decrypt packet (this is a request)
+            let (header, payload) = match opener.open(encrypted_packet) {
+                Ok(cell) => cell,
+                Err(_) => break, // Fail silently
+            };
+            let stream_id = header.stream_id;
+            match header.cell_type {
+                CellType::Data => {}
+                CellType::Padding => continue, // Cover traffic: dropped, never reaches the provider
+                CellType::Sendme => {
+                    // The client has consumed part of a response; let more cells through
+                    if let Some(window) = windows.lock().expect("window map poisoned").get(&stream_id) {
+                        window.add_permits(SENDME_INCREMENT);
+                    }
+                    continue;
+                }
+                _ => break, // Fail silently
+            }
+
+            // Stream ids are never reused, so a replayed or recycled stream is dropped.
+            // The number of streams being received at once is bounded.
+            if !requests.contains_key(&stream_id) {
+                if used_stream_ids.contains(&stream_id) || requests.len() >= MAX_PENDING_STREAMS {
+                    continue; // Fail silently
+                }
+                used_stream_ids.insert(stream_id);
+
+                // A request held back on the path for longer than the window is dropped
+                if !self.replay_cache.lock().expect("replay cache poisoned").is_fresh(header.timestamp) {
+                    let _ = outbound.send(error_cell(stream_id)).await;
+                    continue; // Fail silently
+                }
+                requests.insert(stream_id, (Reassembler::new(stream_id), 0));
+            }
+
+            let (reassembler, received) = requests.get_mut(&stream_id).expect("stream registered above");
+            let message = match reassembler.push(&header, &payload) {
+                Ok(message) => message,
+                Err(_) => {
+                    requests.remove(&stream_id);
+                    let _ = outbound.send(error_cell(stream_id)).await;
+                    continue; // Fail silently
+                }
+            };
+
+            let Some(message) = message else {
+                // Acknowledge every SENDME_INCREMENT request cells so the client can keep sending
+                *received += 1;
+                if *received % SENDME_INCREMENT == 0 {
+                    let seq = (*received / SENDME_INCREMENT - 1) as u32;
+                    let sendme = CellHeader::new(CellType::Sendme, stream_id, seq, 0).with_flags(FLAG_END);
+                    let _ = outbound.send((sendme, Vec::new())).await;
+                }
+                continue;
+            };
+            requests.remove(&stream_id);
+            let received = Instant::now();
+
+            // Answer each request on its own task so a slow provider call does
+            // not hold up other streams in the tunnel
+            let window = Arc::new(Semaphore::new(STREAM_WINDOW));
+            windows.lock().expect("window map poisoned").insert(stream_id, window.clone());
+
+            let gateway = self.clone();
+            let outbound = outbound.clone();
+            let windows = windows.clone();
+            tokio::spawn(async move {
+                gateway.respond(stream_id, &message, received, &window, &outbound).await;
+                windows.lock().expect("window map poisoned").remove(&stream_id);
+            });
         }
 
-        // Decrypt packet (this is a request)
-        let (header, payload_and_tag) = encrypted_packet.split_at(HEADER_SIZE);
-        let (payload, tag) = payload_and_tag.split_at(PAYLOAD_SIZE);
-        let mut payload = payload.to_vec();
-        
-        let tag_array: [u8; TAG_SIZE] = match tag.try_into() {
-            Ok(tag) => tag,
-            Err(_) => return Ok(()), // Fail silently
-        };
-        if let Err(_) = decrypt_in_place(&session_key, header, &mut payload, &tag_array, true) {
-            return Ok(()); // Fail silently
+        // Stop any responses still waiting for a SENDME
+        for (_, window) in windows.lock().expect("window map poisoned").drain() {
+            window.close();
         }
 
-        // Extract JSON-RPC request from padding
-        // The client places the JSON at the end of the payload with random padding at the start
-        
-        // Find the JSON by looking for the first '{' and the last '}' in the payload
-        // But also validate that the extracted content is valid JSON to avoid false matches
-        let json_start = match payload.iter().position(|&b| b == b'{') {
-            Some(pos) => pos,
-            None => return Ok(()), // Fail silently
+        Ok(())
+    }
+
+    // Answer one request on its stream. Anything that cannot be answered gets
+    // a single error cell without details.
+    async fn respond(
+        &self,
+        stream_id: u16,
+        request: &[u8],
+        received: Instant,
+        window: &Semaphore,
+        outbound: &mpsc::Sender<(CellHeader, Vec<u8>)>,
+    ) {
+        // Split the response across as many cells as needed (this is a response).
+        // Responses over the message size limit become an error rather than being truncated.
+        let response_packets = match self.timed_response(request, received).await {
+            Some(response) => Packet::split_message(CellType::Data, stream_id, &response).ok(),
+            None => None,
         };
-        
-        // Find end of JSON (last '}' after the start)
-        let json_end = match payload[json_start..].iter().rposition(|&b| b == b'}') {
-            Some(pos) => pos,
-            None => return Ok(()), // Fail silently
+        let Some(response_packets) = response_packets else {
+            let _ = outbound.send(error_cell(stream_id)).await;
+            return; // Fail silently
         };
-        
-        let json_rpc_candidate = &payload[json_start..json_start + json_end + 1];
-        
+
+        // Send encrypted response (exactly 1024 bytes per cell), waiting for a
+        // SENDME whenever the stream window is used up
+        for response_packet in response_packets {
+            match window.acquire().await {
+                Ok(permit) => permit.forget(),
+                Err(_) => return, // Tunnel closed
+            }
+            if outbound.send(response_packet).await.is_err() {
+                return; // Fail silently
+            }
+        }
+    }
+
+    // Validate a reassembled request and forward it to the RPC provider
+    pub async fn process_request(&self, payload: &[u8]) -> Option<Vec<u8>> {
+        // The header carries the exact payload length, so no scanning is needed
+        let json_rpc_candidate = payload;
+
         // Validate that the extracted data is valid UTF-8 before parsing
         let json_str = match std::str::from_utf8(json_rpc_candidate) {
             Ok(s) => s,
-            Err(_) => return Ok(()), // Fail silently
+            Err(_) => return None, // Fail silently
         };
         
         // Validate that it's proper JSON and is a valid JSON-RPC request
         // This adds extra validation to make the parsing more robust
         let parsed_json: Value = match serde_json::from_str(json_str) {
             Ok(v) => v,
-            Err(_) => return Ok(()), // Fail silently
+            Err(_) => return None, // Fail silently
         };
         
         // Verify it has the required JSON-RPC fields
         if parsed_json.get("jsonrpc").and_then(|v| v.as_str()) != Some("2.0") {
-            return Ok(()); // Fail silently
+            return None; // Fail silently
         }
         
-        if parsed_json.get("method").and_then(|v| v.as_str()).is_none() {
-            return Ok(()); // Fail silently
+        if !parsed_json.get("method").is_some_and(|v| v.is_string()) {
+            return None; // Fail silently
         }
         
         
         // Now convert to our specific request struct
         let request: JsonRpcRequest = match serde_json::from_value(parsed_json) {
             Ok(req) => req,
-            Err(_) => return Ok(()), // Fail silently
+            Err(_) => return None, // Fail silently
         };
         
         let json_rpc = json_rpc_candidate;
@@ -133,34 +398,41 @@ impl Gateway {
         
         // Validate method name format
         if request.method.is_empty() {
-            return Ok(()); // Fail silently
+            return None; // Fail silently
         }
         
         // Validate that method name is a valid string (no control characters, reasonable length)
         if request.method.len() > 100 {
-            return Ok(()); // Fail silently
+            return None; // Fail silently
         }
         
         // Check for potentially dangerous methods
         if request.method.starts_with("_") {
-            return Ok(()); // Fail silently
+            return None; // Fail silently
+        }
+
+        // Clients that have a directory fetch the next one through a circuit
+        // rather than from a mirror. Never forwarded to the provider.
+        if request.method == DIRECTORY_METHOD {
+            let document = self.directory.document()?;
+            return serde_json::to_vec(&serde_json::json!({ "jsonrpc": "2.0", "result": document, "id": request.id })).ok();
         }
         
         // MEV safety check: validate transaction privacy parameters
         if request.method == "eth_sendRawTransaction" {
             // Check for MEV protection parameters in the transaction
             if let Some(params) = request.params.as_array() {
-                if let Some(tx_data) = params.get(0) {
+                if let Some(tx_data) = params.first() {
                     if let Some(tx_str) = tx_data.as_str() {
                         // Validate transaction format
                         if !tx_str.starts_with("0x") {
-                            return Ok(()); // Fail silently
+                            return None; // Fail silently
                         }
                         
                         // Check for privacy-enhancing transaction metadata
                         // This is a hook for future privacy features
                         if tx_str.len() < 10 { // Minimum transaction length check
-                            return Ok(()); // Fail silently
+                            return None; // Fail silently
                         }
                     }
                 }
@@ -168,39 +440,31 @@ impl Gateway {
         }
         
         // Forward to RPC provider
-        let response = match self.rpc_forwarder.forward_request(json_rpc).await {
-            Ok(resp) => resp,
-            Err(_) => return Ok(()), // Fail silently
-        };
-
-        // Create response packet with random padding
-        let mut response_packet = [0u8; PACKET_SIZE];
-        rand::thread_rng().fill_bytes(&mut response_packet);
+        self.rpc_forwarder.forward_request(json_rpc).await.ok()
+    }
+}
 
-        // Place response in packet (near end, leaving random padding at start)
-        // If response is too large, truncate it to fit in the payload size
-        let response_len = std::cmp::min(response.len(), PAYLOAD_SIZE);
-        let response_start = PAYLOAD_SIZE - response_len;
-        response_packet[HEADER_SIZE + response_start..HEADER_SIZE + response_start + response_len]
-            .copy_from_slice(&response[..response_len]);
+// Tell the client a request failed, without any details
+fn error_cell(stream_id: u16) -> (CellHeader, Vec<u8>) {
+    (CellHeader::new(CellType::Error, stream_id, 0, 0).with_flags(FLAG_END), Vec::new())
+}
 
-        // Encrypt response with same session key (this is a response)
-        let (resp_header, resp_payload_and_tag) = response_packet.split_at_mut(HEADER_SIZE);
-        let (resp_payload, resp_tag_space) = resp_payload_and_tag.split_at_mut(PAYLOAD_SIZE);
-        
-        let tag = match encrypt_in_place(&session_key, resp_header, resp_payload, false) {
-            Ok(t) => t,
-            Err(_) => return Ok(()), // Fail silently
+async fn write_cells<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    mut sealer: CellSealer,
+    mut layer: Option<ChaCha20>,
+    mut outbound_rx: mpsc::Receiver<(CellHeader, Vec<u8>)>,
+) {
+    while let Some((header, payload)) = outbound_rx.recv().await {
+        let Ok(mut cell) = sealer.seal(&header, &payload) else {
+            return; // Fail silently
         };
-        resp_tag_space.copy_from_slice(&tag);
-
-        // Send encrypted response (exactly 1024 bytes)
-        assert_eq!(response_packet.len(), PACKET_SIZE);
-        if let Err(_) = stream.write_all(&response_packet).await {
-            return Ok(()); // Fail silently
+        if let Some(layer) = &mut layer {
+            layer.apply_keystream(&mut cell);
+        }
+        if writer.write_all(&cell).await.is_err() {
+            return; // Fail silently
         }
-
-        Ok(())
     }
 }
 
@@ -208,13 +472,19 @@ pub async fn start_gateway(
     listen_addr: &str,
     listen_port: u16,
     rpc_forwarder: RpcForwarder,
-    _allow_public_mempool: bool,
+    identity: IdentityKeys,
+    replay_window_secs: u64,
+    timing: ResponseTiming,
+    directory: DirectoryMirror,
 ) -> anyhow::Result<()> {
-    let gateway = Gateway::new(rpc_forwarder);
+    let gateway = Gateway::new(rpc_forwarder, identity, replay_window_secs, timing, directory);
     let listener = TcpListener::bind(format!("{}:{}", listen_addr, listen_port)).await?;
 
     println!("🌐 Penum Gateway listening on {}:{}", listen_addr, listen_port);
     println!("   Privacy mode: ON (no logging of request contents)");
+    if let Some(timing) = gateway.timing.describe() {
+        println!("   Response timing: {}", timing);
+    }
 
     loop {
         match listener.accept().await {
@@ -234,4 +504,44 @@ pub async fn start_gateway(
             }
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn release_at_the_smallest_bucket_not_yet_passed() {
+        let timing = ResponseTiming::new(&[500, 0, 100, 100, 250], None);
+        assert_eq!(timing.release_delay(ms(0)), ms(100));
+        assert_eq!(timing.release_delay(ms(100)), ms(100));
+        assert_eq!(timing.release_delay(ms(101)), ms(250));
+        assert_eq!(timing.release_delay(ms(400)), ms(500));
+    }
+
+    #[test]
+    fn release_past_the_largest_bucket_at_its_multiples() {
+        let timing = ResponseTiming::new(&[100, 500], None);
+        assert_eq!(timing.release_delay(ms(501)), ms(1000));
+        assert_eq!(timing.release_delay(ms(1700)), ms(2000));
+    }
+
+    #[test]
+    fn release_never_later_than_the_maximum() {
+        let timing = ResponseTiming::new(&[100, 500], Some(1200));
+        assert_eq!(timing.release_delay(ms(300)), ms(500));
+        assert_eq!(timing.release_delay(ms(800)), ms(1000));
+        assert_eq!(timing.release_delay(ms(1100)), ms(1200));
+        assert_eq!(timing.release_delay(ms(1200)), ms(1200));
+    }
+
+    #[test]
+    fn release_immediately_without_buckets() {
+        let timing = ResponseTiming::new(&[0], Some(1000));
+        assert_eq!(timing.release_delay(ms(42)), ms(42));
+        assert!(ResponseTiming::new(&[], None).describe().is_none());
+    }
+}
@@ -0,0 +1,157 @@
+use penum_protocol::cell::PACKET_SIZE;
+use penum_protocol::handshake::{
+    self, client_hello_public_key, is_legacy_hello, ClientHello, HandshakeResponse, HandshakeSecrets, CLIENT_HELLO_LEN,
+    LEGACY_CLIENT_HELLO_LEN, LEGACY_PROTOCOL_VERSION, MAX_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION,
+};
+use penum_protocol::{PublicKey, StaticSecret};
+use rand::thread_rng;
+use std::fs;
+use std::path::Path;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// Long-term identity of a relay or gateway. Clients pin the public half
+// (e.g. `gateway_public_key` in the client config) to detect MITM relays.
+#[derive(Clone)]
+pub struct IdentityKeys {
+    pub secret: StaticSecret,
+    pub public: PublicKey,
+    pub require_keyed_hello: bool,  // Leave clients that do not know our key without an answer
+    pub answer_legacy_hellos: bool,  // Still answer version 2 clients in the old, unpadded framing
+}
+
+impl IdentityKeys {
+    // Load the identity key from `path`, creating a new one on first start
+    pub fn load_or_generate(path: &str) -> anyhow::Result<Self> {
+        if Path::new(path).exists() {
+            let key_hex = fs::read_to_string(path)?;
+            let key_bytes: [u8; 32] = hex::decode(key_hex.trim())
+                .ok()
+                .and_then(|bytes| bytes.try_into().ok())
+                .ok_or_else(|| anyhow::anyhow!("Invalid identity key file: {}", path))?;
+            return Ok(Self::from_secret(StaticSecret::from(key_bytes)));
+        }
+
+        let identity = Self::from_secret(StaticSecret::random_from_rng(thread_rng()));
+        write_secret_file(path, &hex::encode(identity.secret.to_bytes()))?;
+        Ok(identity)
+    }
+
+    pub fn from_secret(secret: StaticSecret) -> Self {
+        let public = PublicKey::from(&secret);
+        Self {
+            secret,
+            public,
+            require_keyed_hello: false,
+            answer_legacy_hellos: true,
+        }
+    }
+
+    pub fn public_hex(&self) -> String {
+        hex::encode(self.public.as_bytes())
+    }
+
+    // Node side of the handshake: pick a protocol version, reply with a fresh
+    // ephemeral key plus our identity key, and compute both DH outputs for the
+    // session keys
+    pub fn respond(&self, client_hello: &[u8; CLIENT_HELLO_LEN]) -> HandshakeResponse {
+        let response = handshake::respond(&self.secret, client_hello);
+        if response.handshake.is_none() {
+            let identity_secret = self.secret.diffie_hellman(&client_hello_public_key(client_hello));
+            let (hello, _) = ClientHello::decode(client_hello, &identity_secret);
+            self.log_rejection(hello.min_version, hello.max_version);
+        }
+        response
+    }
+
+    // The same for a version 1 or 2 hello, answered in the old framing
+    pub fn respond_legacy(&self, client_hello: &[u8; LEGACY_CLIENT_HELLO_LEN]) -> HandshakeResponse {
+        let response = handshake::respond_legacy(&self.secret, client_hello);
+        if response.handshake.is_none() {
+            self.log_rejection(client_hello[0], client_hello[1]);
+        }
+        response
+    }
+
+    // Run the whole handshake on a fresh connection, or a circuit new on a
+    // link from another relay: hello cells first, then the
+    // ML-KEM exchange if the client asked for a hybrid handshake.
+    // Returns the client's ephemeral key with the secrets, or `None` if the
+    // client was rejected.
+    // With `require_keyed_hello`, a hello not masked under our identity key is
+    // rejected without a reply, so a prober that does not know the key cannot
+    // tell us from a server that just reads. Legacy hellos are never keyed.
+    pub async fn accept_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+    ) -> anyhow::Result<Option<(PublicKey, HandshakeSecrets)>> {
+        // A version 2 client sends a bare hello and waits for the reply, so
+        // look at the first bytes before waiting for the rest of a cell
+        let mut hello_cell = [0u8; PACKET_SIZE];
+        stream.read_exact(&mut hello_cell[..LEGACY_CLIENT_HELLO_LEN]).await?;
+        let mut legacy_hello = [0u8; LEGACY_CLIENT_HELLO_LEN];
+        legacy_hello.copy_from_slice(&hello_cell[..LEGACY_CLIENT_HELLO_LEN]);
+
+        let (client_key, response) = if self.answers_legacy_hellos() && is_legacy_hello(&legacy_hello) {
+            let response = self.respond_legacy(&legacy_hello);
+            stream.write_all(&response.reply).await?;
+            let client_key: [u8; 32] = legacy_hello[4..].try_into()?;
+            (PublicKey::from(client_key), response)
+        } else {
+            stream.read_exact(&mut hello_cell[LEGACY_CLIENT_HELLO_LEN..]).await?;
+            let mut client_hello = [0u8; CLIENT_HELLO_LEN];
+            client_hello.copy_from_slice(&hello_cell[..CLIENT_HELLO_LEN]);
+
+            let response = self.respond(&client_hello);
+            if self.require_keyed_hello && !response.keyed {
+                return Ok(None);
+            }
+            stream.write_all(&response.reply_cell()).await?;
+            (client_hello_public_key(&client_hello), response)
+        };
+        let Some(handshake) = response.handshake else {
+            return Ok(None); // Rejected: no common protocol version
+        };
+
+        let mut encapsulation_key_cells = vec![[0u8; PACKET_SIZE]; handshake.expected_cells()];
+        for cell in encapsulation_key_cells.iter_mut() {
+            stream.read_exact(cell).await?;
+        }
+        let (ciphertext_cells, secrets) = handshake.finish(&encapsulation_key_cells)?;
+        for cell in &ciphertext_cells {
+            stream.write_all(cell).await?;
+        }
+        Ok(Some((client_key, secrets)))
+    }
+
+    fn answers_legacy_hellos(&self) -> bool {
+        self.answer_legacy_hellos && !self.require_keyed_hello
+    }
+
+    fn log_rejection(&self, min_version: u8, max_version: u8) {
+        let lowest = if self.answers_legacy_hellos() { LEGACY_PROTOCOL_VERSION } else { MIN_PROTOCOL_VERSION };
+        eprintln!(
+            "⚠️  Rejected handshake: client offered protocol versions {}-{}, this node supports {}-{}",
+            min_version, max_version, lowest, MAX_PROTOCOL_VERSION
+        );
+    }
+}
+
+#[cfg(unix)]
+fn write_secret_file(path: &str, contents: &str) -> anyhow::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn write_secret_file(path: &str, contents: &str) -> anyhow::Result<()> {
+    fs::write(path, contents)?;
+    Ok(())
+}
@@ -0,0 +1,558 @@
+use crate::next_hop::NextHopPolicy;
+use chacha20::cipher::StreamCipher;
+use chacha20::ChaCha20;
+use penum_protocol::cell::{Packet, PACKET_SIZE};
+use penum_protocol::handshake::{ClientHandshake, HandshakeSecrets, CAP_LINK, HANDSHAKE_REPLY_LEN, MAX_PROTOCOL_VERSION};
+use penum_protocol::kdf::OnionLayer;
+use penum_protocol::PublicKey;
+use rand::Rng;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::time::{timeout, timeout_at, Instant};
+
+// Links between relays.
+//
+// Every circuit a relay extends to the same next hop shares one connection
+// to it, a link, instead of getting a connection of its own. The relay opens
+// the link with a handshake offering CAP_LINK, keyed on the identity key the
+// client named for the next hop, and encrypts both directions with the onion
+// layer keys of that handshake. Each frame carries one cell of one circuit:
+//
+//   circuit ID (4) | command (1) | cell (1024)
+//
+// CREATE carries the first cell of a new circuit, which the far end serves
+// like a circuit on a connection of its own, CELL the ones after it, and
+// DESTROY closes a circuit. Only the relay that opened a link creates
+// circuits on it. One writer per link sends the frames of all its circuits
+// in the order the relay releases them, coalescing them into shared writes,
+// so an observer of the link sees one stream of frames rather than where
+// each circuit starts, ends and pauses.
+//
+// The link handshake is classic X25519. The cells it carries are still
+// under the onion layers the client negotiated with each hop, so the link
+// keys only hide the framing. Frames are 1029 bytes rather than 1024, so a
+// link between relays can be told from a client connection by its length.
+
+const FRAME_HEADER_LEN: usize = 5;
+const FRAME_LEN: usize = FRAME_HEADER_LEN + PACKET_SIZE;
+
+const CREATE: u8 = 1;
+const CELL: u8 = 2;
+const DESTROY: u8 = 3;
+
+// Frames waiting for the writer of one link
+const LINK_QUEUE: usize = 256;
+
+// Most frames coalesced into a single write
+const MAX_BATCH_FRAMES: usize = 32;
+
+// Cells waiting for one circuit on a link. The stream windows keep a client's
+// circuit well below this, so a circuit that falls this far behind is closed
+// rather than holding up every other circuit on the link.
+const CIRCUIT_QUEUE: usize = 4096;
+
+// Bytes buffered between a link and the task serving one of its circuits
+const CIRCUIT_BUFFER: usize = 16 * PACKET_SIZE;
+
+// Circuits the far end may create on one link
+const MAX_CIRCUITS_PER_LINK: usize = 4096;
+
+// Longest wait for the next hop to answer the link handshake
+const LINK_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// A link without circuits is closed after sending nothing for this long
+const LINK_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// A link that receives no frame for this long is closed with its circuits,
+// like a circuit on its own connection
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+// How long a next hop that does not accept links gets connections per circuit
+// before we try again
+const DIRECT_RETRY: Duration = Duration::from_secs(10 * 60);
+
+type Frame = [u8; FRAME_LEN];
+type CellSender = mpsc::Sender<[u8; PACKET_SIZE]>;
+
+// Either end of a circuit: its own connection or its share of a link
+pub trait CircuitStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> CircuitStream for T {}
+
+// Serves a circuit the far end created on a link
+pub type CircuitHandler = Arc<dyn Fn(DuplexStream) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+// One end of a link: the queue to its writer and the circuits it carries
+#[derive(Clone)]
+struct Link {
+    frames: mpsc::Sender<Frame>,
+    circuits: Arc<Mutex<HashMap<u32, CellSender>>>,
+}
+
+impl Link {
+    fn new() -> (Self, mpsc::Receiver<Frame>) {
+        let (frames, frames_rx) = mpsc::channel(LINK_QUEUE);
+        let link = Self {
+            frames,
+            circuits: Arc::new(Mutex::new(HashMap::new())),
+        };
+        (link, frames_rx)
+    }
+
+    // Start a circuit from this end under a random unused ID
+    fn open_circuit(&self) -> DuplexStream {
+        let (cells, cells_rx) = mpsc::channel(CIRCUIT_QUEUE);
+        let circuit_id = {
+            let mut circuits = self.circuits.lock().expect("circuit map poisoned");
+            let mut rng = rand::thread_rng();
+            let circuit_id = loop {
+                let circuit_id = rng.gen_range(1..=u32::MAX);
+                if !circuits.contains_key(&circuit_id) {
+                    break circuit_id;
+                }
+            };
+            circuits.insert(circuit_id, cells);
+            circuit_id
+        };
+        self.attach(circuit_id, cells_rx, CREATE)
+    }
+
+    // A circuit the far end created: serve it with `accept`. None if the ID
+    // is taken or the link carries as many circuits as it may.
+    fn accept_circuit(&self, circuit_id: u32, accept: &CircuitHandler) -> Option<CellSender> {
+        let (cells, cells_rx) = mpsc::channel(CIRCUIT_QUEUE);
+        {
+            let mut circuits = self.circuits.lock().expect("circuit map poisoned");
+            if circuits.contains_key(&circuit_id) || circuits.len() >= MAX_CIRCUITS_PER_LINK {
+                return None;
+            }
+            circuits.insert(circuit_id, cells.clone());
+        }
+        tokio::spawn(accept(self.attach(circuit_id, cells_rx, CELL)));
+        Some(cells)
+    }
+
+    // Connect a registered circuit to a stream: cells from the far end come
+    // out of it, and whole cells written to it go to the far end. The first
+    // one goes as `first_command`.
+    fn attach(&self, circuit_id: u32, cells: mpsc::Receiver<[u8; PACKET_SIZE]>, first_command: u8) -> DuplexStream {
+        let (local, circuit) = tokio::io::duplex(CIRCUIT_BUFFER);
+        let (reader, writer) = tokio::io::split(local);
+        tokio::spawn(deliver_cells(writer, cells));
+        tokio::spawn(self.clone().send_cells(circuit_id, reader, first_command));
+        circuit
+    }
+
+    async fn send_cells(self, circuit_id: u32, mut reader: ReadHalf<DuplexStream>, mut command: u8) {
+        // A partial cell at the end of the stream is dropped, never forwarded
+        let mut cell = [0u8; PACKET_SIZE];
+        while reader.read_exact(&mut cell).await.is_ok() {
+            if self.frames.send(frame(circuit_id, command, &cell)).await.is_err() {
+                return; // Link gone
+            }
+            command = CELL;
+        }
+        self.close_circuit(circuit_id).await;
+    }
+
+    // Forget a circuit, which closes its stream, and tell the far end unless
+    // it closed the circuit first
+    async fn close_circuit(&self, circuit_id: u32) {
+        let open = self.circuits.lock().expect("circuit map poisoned").remove(&circuit_id).is_some();
+        if open {
+            let _ = self.frames.send(frame_destroy(circuit_id)).await;
+        }
+    }
+
+    // Read frames and hand each cell to its circuit. `accept` serves the
+    // circuits the far end creates; it is None on the end that opened the link.
+    async fn read_frames<R: AsyncRead + Unpin>(&self, mut reader: R, mut cipher: ChaCha20, accept: Option<&CircuitHandler>) {
+        let mut frame = [0u8; FRAME_LEN];
+        loop {
+            match timeout(IDLE_TIMEOUT, reader.read_exact(&mut frame)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(_)) => break, // Link closed or read failed
+                Err(_) => break, // Idle too long
+            }
+            cipher.apply_keystream(&mut frame);
+            let circuit_id = u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]);
+            let mut cell = [0u8; PACKET_SIZE];
+            cell.copy_from_slice(&frame[FRAME_HEADER_LEN..]);
+
+            let circuit = match (frame[4], accept) {
+                (CELL, _) => self.circuits.lock().expect("circuit map poisoned").get(&circuit_id).cloned(),
+                (CREATE, Some(accept)) => match self.accept_circuit(circuit_id, accept) {
+                    Some(circuit) => Some(circuit),
+                    None => {
+                        let _ = self.frames.send(frame_destroy(circuit_id)).await;
+                        continue;
+                    }
+                },
+                (DESTROY, _) => {
+                    self.circuits.lock().expect("circuit map poisoned").remove(&circuit_id);
+                    continue;
+                }
+                _ => break, // Nothing a relay sends: drop the link
+            };
+            let Some(circuit) = circuit else {
+                continue; // Closed on this end, the far end will hear of it
+            };
+            if circuit.try_send(cell).is_err() {
+                self.close_circuit(circuit_id).await; // Not keeping up
+            }
+        }
+    }
+
+    // Write frames as they are released, coalescing those already queued, and
+    // those arriving within the batch window, into one write. Frames of every
+    // circuit on the link share writes. A link without circuits is closed
+    // once it has sent nothing for LINK_IDLE_TIMEOUT.
+    async fn write_frames<W: AsyncWrite + Unpin>(
+        &self,
+        mut writer: W,
+        mut cipher: ChaCha20,
+        mut frames: mpsc::Receiver<Frame>,
+        batch_window: Duration,
+    ) {
+        let mut batch = Vec::with_capacity(MAX_BATCH_FRAMES * FRAME_LEN);
+        loop {
+            let first = match timeout(LINK_IDLE_TIMEOUT, frames.recv()).await {
+                Ok(Some(first)) => first,
+                Ok(None) => break,
+                Err(_) if self.circuits.lock().expect("circuit map poisoned").is_empty() => break,
+                Err(_) => continue,
+            };
+            batch.extend_from_slice(&first);
+            let deadline = Instant::now() + batch_window;
+
+            while batch.len() < MAX_BATCH_FRAMES * FRAME_LEN {
+                let next = match frames.try_recv() {
+                    Ok(next) => next,
+                    Err(TryRecvError::Disconnected) => break,
+                    Err(TryRecvError::Empty) if batch_window.is_zero() => break,
+                    Err(TryRecvError::Empty) => match timeout_at(deadline, frames.recv()).await {
+                        Ok(Some(next)) => next,
+                        _ => break, // Window elapsed
+                    },
+                };
+                batch.extend_from_slice(&next);
+            }
+
+            cipher.apply_keystream(&mut batch);
+            if writer.write_all(&batch).await.is_err() || writer.flush().await.is_err() {
+                break; // Link failed
+            }
+            batch.clear();
+        }
+    }
+
+    // Carry frames both ways until the link fails, closes or idles out, then
+    // close every circuit on it
+    async fn run<S: CircuitStream>(
+        self,
+        stream: S,
+        frames: mpsc::Receiver<Frame>,
+        layer: OnionLayer,
+        batch_window: Duration,
+        accept: Option<CircuitHandler>,
+    ) {
+        let (reader, writer) = tokio::io::split(stream);
+        let OnionLayer { forward, backward } = layer;
+        let (write_cipher, read_cipher) = match accept {
+            None => (forward, backward),
+            Some(_) => (backward, forward),
+        };
+        tokio::select! {
+            _ = self.write_frames(writer, write_cipher, frames, batch_window) => {},
+            _ = self.read_frames(reader, read_cipher, accept.as_ref()) => {},
+        }
+        self.circuits.lock().expect("circuit map poisoned").clear();
+    }
+}
+
+fn frame(circuit_id: u32, command: u8, cell: &[u8; PACKET_SIZE]) -> Frame {
+    let mut frame = [0u8; FRAME_LEN];
+    frame[..4].copy_from_slice(&circuit_id.to_be_bytes());
+    frame[4] = command;
+    frame[FRAME_HEADER_LEN..].copy_from_slice(cell);
+    frame
+}
+
+fn frame_destroy(circuit_id: u32) -> Frame {
+    frame(circuit_id, DESTROY, &[0u8; PACKET_SIZE])
+}
+
+// Write the cells the far end sent on a circuit into its stream. The stream
+// is closed once the circuit is forgotten.
+async fn deliver_cells(mut writer: WriteHalf<DuplexStream>, mut cells: mpsc::Receiver<[u8; PACKET_SIZE]>) {
+    while let Some(cell) = cells.recv().await {
+        if writer.write_all(&cell).await.is_err() {
+            return; // Circuit gone
+        }
+    }
+    let _ = writer.shutdown().await;
+}
+
+// Serve a link another relay opened to us, once its handshake selected
+// CAP_LINK. Each circuit it creates is handed to `accept`.
+pub async fn serve<S: CircuitStream>(stream: S, secrets: &HandshakeSecrets, batch_window: Duration, accept: CircuitHandler) {
+    let (link, frames) = Link::new();
+    link.run(stream, frames, secrets.onion_layer(), batch_window, Some(accept)).await;
+}
+
+enum NextHopLink {
+    Open { link: Link, identity: PublicKey },
+    Direct(Instant), // Does not accept links, as of then
+}
+
+// The links a relay opened, one per next hop
+#[derive(Clone)]
+pub struct Links {
+    links: Arc<Mutex<HashMap<SocketAddr, NextHopLink>>>,
+    next_hops: NextHopPolicy,
+    batch_window: Duration,
+}
+
+impl Links {
+    pub fn new(next_hops: NextHopPolicy, batch_window: Duration) -> Self {
+        Self {
+            links: Arc::new(Mutex::new(HashMap::new())),
+            next_hops,
+            batch_window,
+        }
+    }
+
+    // A new circuit to `next_hop` on the link to it, opening the link if there
+    // is none. None when the circuit needs a connection of its own: the client
+    // did not name the next hop's identity key, the next hop does not accept
+    // links, or it holds a different key than the one named.
+    // Circuits extended at the same moment may each open a link. The last one
+    // stays in the map; the others close once their circuits are gone.
+    pub async fn open_circuit(&self, next_hop: SocketAddr, identity: Option<PublicKey>) -> anyhow::Result<Option<DuplexStream>> {
+        let Some(identity) = identity else {
+            return Ok(None);
+        };
+        match self.links.lock().expect("link map poisoned").get(&next_hop) {
+            Some(NextHopLink::Open { link, identity: link_identity }) if !link.frames.is_closed() => {
+                return Ok((*link_identity == identity).then(|| link.open_circuit()));
+            }
+            Some(NextHopLink::Direct(since)) if since.elapsed() < DIRECT_RETRY => return Ok(None),
+            _ => {}
+        }
+
+        let mut stream = self.next_hops.connect(next_hop).await?;
+        stream.set_nodelay(true)?;
+        let secrets = match timeout(LINK_HANDSHAKE_TIMEOUT, link_handshake(&mut stream, &identity)).await {
+            Ok(Ok(Some((node_identity, secrets)))) if node_identity == identity => secrets,
+            Ok(Ok(Some(_))) => return Ok(None), // Not the key the client named: its own handshake will tell
+            _ => {
+                let mut links = self.links.lock().expect("link map poisoned");
+                links.retain(|_, link| !matches!(link, NextHopLink::Direct(since) if since.elapsed() >= DIRECT_RETRY));
+                links.insert(next_hop, NextHopLink::Direct(Instant::now()));
+                return Ok(None);
+            }
+        };
+
+        let (link, frames) = Link::new();
+        let circuit = link.open_circuit();
+        self.links
+            .lock()
+            .expect("link map poisoned")
+            .insert(next_hop, NextHopLink::Open { link: link.clone(), identity });
+
+        // Remove the link from the map once it closes, so the next circuit
+        // opens a new one
+        let links = self.links.clone();
+        let batch_window = self.batch_window;
+        tokio::spawn(async move {
+            let this_link = link.frames.clone();
+            link.run(stream, frames, secrets.onion_layer(), batch_window, None).await;
+            let mut links = links.lock().expect("link map poisoned");
+            if let Some(NextHopLink::Open { link, .. }) = links.get(&next_hop) {
+                if link.frames.same_channel(&this_link) {
+                    links.remove(&next_hop);
+                }
+            }
+        });
+        Ok(Some(circuit))
+    }
+}
+
+// Handshake offering CAP_LINK, keyed on the identity key the client named.
+// Returns the next hop's identity key and the secrets, or None if it does
+// not accept links.
+async fn link_handshake(stream: &mut TcpStream, identity: &PublicKey) -> anyhow::Result<Option<(PublicKey, HandshakeSecrets)>> {
+    let mut handshake = ClientHandshake::new(MAX_PROTOCOL_VERSION, CAP_LINK, Some(identity));
+    stream.write_all(&Packet::padded(handshake.hello())).await?;
+
+    let mut reply_cell = [0u8; PACKET_SIZE];
+    stream.read_exact(&mut reply_cell).await?;
+    let mut reply = [0u8; HANDSHAKE_REPLY_LEN];
+    reply.copy_from_slice(&reply_cell[..HANDSHAKE_REPLY_LEN]);
+    handshake.read_reply(&reply)?; // No hybrid offered, so no ML-KEM cells follow
+    let (node_identity, secrets) = handshake.finish(&[])?;
+    Ok((secrets.capabilities & CAP_LINK != 0).then_some((node_identity, secrets)))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use chacha20::cipher::KeyIvInit;
+    use penum_protocol::handshake;
+    use penum_protocol::StaticSecret;
+    use std::io;
+    use std::task::{Context, Poll};
+
+    // Records each write it is given, to see how cells were coalesced
+    #[derive(Clone, Default)]
+    pub(crate) struct RecordingWriter {
+        pub(crate) writes: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl AsyncWrite for RecordingWriter {
+        fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.writes.lock().unwrap().push(buf.to_vec());
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn cipher() -> ChaCha20 {
+        ChaCha20::new(&[7u8; 32].into(), &[0u8; 12].into())
+    }
+
+    // Both ends of a link handshake
+    fn link_secrets() -> (HandshakeSecrets, HandshakeSecrets) {
+        let identity = StaticSecret::from([0x22u8; 32]);
+        let mut client = ClientHandshake::new(MAX_PROTOCOL_VERSION, CAP_LINK, Some(&PublicKey::from(&identity)));
+        let response = handshake::respond(&identity, client.hello());
+        client.read_reply(&response.reply).unwrap();
+        let (_, node) = response.handshake.unwrap().finish(&[]).unwrap();
+        let (_, client) = client.finish(&[]).unwrap();
+        assert_ne!(client.capabilities & CAP_LINK, 0);
+        (client, node)
+    }
+
+    #[tokio::test]
+    async fn cells_of_different_circuits_share_writes() {
+        let (link, frames) = Link::new();
+        let mut first = link.open_circuit();
+        let mut second = link.open_circuit();
+        first.write_all(&[1u8; PACKET_SIZE]).await.unwrap();
+        second.write_all(&[2u8; PACKET_SIZE]).await.unwrap();
+
+        let writer = RecordingWriter::default();
+        let writes = writer.writes.clone();
+        let writing = link.clone();
+        let task = tokio::spawn(async move {
+            writing.write_frames(writer, cipher(), frames, Duration::from_millis(50)).await;
+        });
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        task.abort();
+
+        // One write with a CREATE frame for each circuit, under distinct IDs
+        let mut writes = writes.lock().unwrap().clone();
+        assert_eq!(writes.len(), 1);
+        let batch = &mut writes[0];
+        assert_eq!(batch.len(), 2 * FRAME_LEN);
+        cipher().apply_keystream(batch);
+        let mut cells: Vec<(u32, u8, u8)> = batch
+            .chunks(FRAME_LEN)
+            .map(|frame| (u32::from_be_bytes(frame[..4].try_into().unwrap()), frame[4], frame[FRAME_HEADER_LEN]))
+            .collect();
+        assert_ne!(cells[0].0, cells[1].0);
+        cells.sort_by_key(|cell| cell.2);
+        assert_eq!(cells.iter().map(|cell| (cell.1, cell.2)).collect::<Vec<_>>(), [(CREATE, 1), (CREATE, 2)]);
+        let circuits = link.circuits.lock().unwrap();
+        assert!(cells.iter().all(|cell| circuits.contains_key(&cell.0)));
+    }
+
+    #[tokio::test]
+    async fn circuits_are_created_carried_and_destroyed() {
+        let (client_secrets, node_secrets) = link_secrets();
+        let (near, far) = tokio::io::duplex(64 * FRAME_LEN);
+
+        // The far end echoes every circuit and reports when one closes
+        let (closed, mut closed_rx) = mpsc::channel(8);
+        let accept: CircuitHandler = Arc::new(move |circuit| {
+            let closed = closed.clone();
+            Box::pin(async move {
+                let (mut reader, mut writer) = tokio::io::split(circuit);
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                let _ = closed.send(()).await;
+            })
+        });
+        tokio::spawn(async move { serve(far, &node_secrets, Duration::ZERO, accept).await });
+
+        let (link, frames) = Link::new();
+        tokio::spawn(link.clone().run(near, frames, client_secrets.onion_layer(), Duration::ZERO, None));
+        let mut first = link.open_circuit();
+        let mut second = link.open_circuit();
+        for round in 0..3u8 {
+            first.write_all(&[round; PACKET_SIZE]).await.unwrap();
+            second.write_all(&[round + 100; PACKET_SIZE]).await.unwrap();
+        }
+        let mut cell = [0u8; PACKET_SIZE];
+        for round in 0..3u8 {
+            first.read_exact(&mut cell).await.unwrap();
+            assert_eq!(cell, [round; PACKET_SIZE]);
+            second.read_exact(&mut cell).await.unwrap();
+            assert_eq!(cell, [round + 100; PACKET_SIZE]);
+        }
+
+        // Closing one circuit closes it on the far end and leaves the other open
+        drop(first);
+        tokio::time::timeout(Duration::from_secs(1), closed_rx.recv()).await.unwrap();
+        assert_eq!(link.circuits.lock().unwrap().len(), 1);
+        second.write_all(&[9u8; PACKET_SIZE]).await.unwrap();
+        second.read_exact(&mut cell).await.unwrap();
+        assert_eq!(cell, [9u8; PACKET_SIZE]);
+
+        // Circuits opened later share the same link
+        let mut third = link.open_circuit();
+        third.write_all(&[5u8; PACKET_SIZE]).await.unwrap();
+        third.read_exact(&mut cell).await.unwrap();
+        assert_eq!(cell, [5u8; PACKET_SIZE]);
+        assert_eq!(link.circuits.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn the_far_end_only_accepts_new_circuits_by_create() {
+        let (link, _frames) = Link::new();
+        let (accepted, mut accepted_rx) = mpsc::channel(8);
+        let accept: CircuitHandler = Arc::new(move |_circuit| {
+            let accepted = accepted.clone();
+            Box::pin(async move {
+                let _ = accepted.send(()).await;
+            })
+        });
+
+        // A CELL for an unknown circuit is dropped, a CREATE opens one
+        let mut stream = Vec::new();
+        let mut write_cipher = cipher();
+        for (circuit_id, command) in [(5, CELL), (6, CREATE)] {
+            let mut frame = frame(circuit_id, command, &[0u8; PACKET_SIZE]);
+            write_cipher.apply_keystream(&mut frame);
+            stream.extend_from_slice(&frame);
+        }
+        link.read_frames(&stream[..], cipher(), Some(&accept)).await;
+        tokio::time::timeout(Duration::from_secs(1), accepted_rx.recv()).await.unwrap();
+        assert!(accepted_rx.try_recv().is_err());
+        assert!(!link.circuits.lock().unwrap().contains_key(&5));
+    }
+}
@@ -1,12 +1,25 @@
+mod announce;
 mod config;
-mod crypto;
 mod gateway;
+mod identity;
+mod link;
+mod mirror;
+mod mixing;
+mod mixnet;
+mod next_hop;
 mod relay;
+mod replay;
 mod rpc_forwarder;
 
-use config::GatewayConfig;
+use config::{GatewayConfig, NodeRole};
+use gateway::Gateway;
+use identity::IdentityKeys;
+use mirror::DirectoryMirror;
+use mixnet::MixNode;
+use next_hop::NextHopPolicy;
 use rpc_forwarder::RpcForwarder;
 use std::fs;
+use std::time::Duration;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -22,18 +35,93 @@ async fn main() -> anyhow::Result<()> {
     println!("🚀 Starting Penum RPC Gateway");
     println!("   Listen:       {}:{}", config.listen_addr, config.listen_port);
     println!("   RPC Provider: <configured>"); // Don't log actual provider URL for privacy
+
+    let mut identity = IdentityKeys::load_or_generate(&config.identity_key_path)?;
+    identity.require_keyed_hello = config.require_keyed_hello;
+    identity.answer_legacy_hellos = config.answer_legacy_hellos;
+    println!("   Identity Key: {}", identity.public_hex());
+    if config.require_keyed_hello {
+        println!("   Hellos:       only answered when masked under our identity key");
+    } else if config.answer_legacy_hellos {
+        println!("   Hellos:       also answering version 2 clients");
+    }
+    if config.allow_private_next_hops {
+        println!("   Next Hops:    private addresses allowed (test network)");
+    }
+    let next_hops = NextHopPolicy::new(config.allow_private_next_hops, config.own_ips());
+    if !config.directory_authorities.is_empty() {
+        println!("   Directory:    publishing to {} authorities", config.directory_authorities.len());
+    }
     println!();
 
-    // Check if we're running as a relay or as a gateway
-    if let Some(ref next_hop_str) = std::env::var("PENUM_NEXT_HOP").ok() {
-        // Running as a relay - forward to next hop
-        let next_hop: std::net::SocketAddr = next_hop_str.parse()
-            .map_err(|_| anyhow::anyhow!("Invalid next hop address: {}", next_hop_str))?;
-        relay::start_relay(&config.listen_addr, config.listen_port, next_hop).await?;
-    } else {
-        // Running as a gateway - process RPC requests
-        let rpc_forwarder = RpcForwarder::new(config.rpc_provider_url, config.allow_public_mempool, config.mev_blocker_url);
-        gateway::start_gateway(&config.listen_addr, config.listen_port, rpc_forwarder, config.allow_public_mempool).await?;
+    // Relays and gateways alike describe themselves to the directory
+    // authorities, so clients can find them
+    if !config.directory_authorities.is_empty() {
+        let node = config.node_descriptor(&identity)?;
+        tokio::spawn(announce::publish_descriptors(
+            config.directory_authorities.clone(),
+            node,
+            identity.clone(),
+            Duration::from_secs(config.heartbeat_interval_secs.max(1)),
+        ));
+    }
+
+    // Gateways mirror the directory for the clients whose circuits end here
+    let directory = DirectoryMirror::default();
+    if config.role == NodeRole::Gateway && !config.directory_authorities.is_empty() {
+        tokio::spawn(directory.clone().keep_fresh(config.directory_authorities.clone()));
+    }
+
+    // Mixnet mode runs next to circuits on its own port. A gateway answers the
+    // requests that reach it; a relay only forwards and delivers packets.
+    if let Some(mix_listen_port) = config.mix_listen_port {
+        let exit = match config.role {
+            NodeRole::Gateway => Some(Gateway::new(
+                RpcForwarder::new(config.rpc_provider_url.clone(), config.allow_public_mempool, config.mev_blocker_url.clone()),
+                identity.clone(),
+                config.replay_window_secs,
+                config.response_timing(),
+                directory.clone(),
+            )),
+            NodeRole::Relay => None,
+        };
+        let node = MixNode::new(identity.clone(), config.relay_mixing, config.replay_window_secs, next_hops.clone(), exit);
+        let listen_addr = config.listen_addr.clone();
+        tokio::spawn(async move {
+            if let Err(e) = mixnet::start_mix_node(&listen_addr, mix_listen_port, node).await {
+                eprintln!("🚨 Mixnet listener failed: {}", e);
+            }
+        });
+    }
+
+    match config.role {
+        NodeRole::Relay => {
+            // Running as a relay - the next hop is chosen by the client for each circuit
+            relay::start_relay(
+                &config.listen_addr,
+                config.listen_port,
+                identity,
+                Duration::from_millis(config.relay_batch_window_ms),
+                config.relay_mixing,
+                next_hops,
+            )
+            .await?;
+        }
+        NodeRole::Gateway => {
+            // Running as a gateway - process RPC requests
+            let timing = config.response_timing();
+            let rpc_forwarder = RpcForwarder::new(config.rpc_provider_url, config.allow_public_mempool, config.mev_blocker_url);
+            gateway::start_gateway(
+                &config.listen_addr,
+                config.listen_port,
+                rpc_forwarder,
+                identity,
+                config.replay_window_secs,
+                timing,
+                directory,
+            )
+            .await?;
+        }
     }
 
     Ok(())
@@ -0,0 +1,93 @@
+use penum_protocol::directory::{Directory, SignedDirectory};
+use rand::seq::SliceRandom;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+// How long one download from an authority may take
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How often a gateway fetches the consensus from the authorities, well
+// within the default consensus interval so clients rarely get an older one
+// than a mirror would serve
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// Retry interval while no authority could be reached, shorter than the regular one
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+// The consensus as a gateway last fetched it, served to clients through their
+// circuits so they do not have to reach a mirror directly once they have a
+// directory. It is not verified here: clients check the signatures and refuse
+// a document older than theirs, so a gateway can withhold updates but not
+// forge or roll back a directory.
+#[derive(Clone, Default)]
+pub struct DirectoryMirror {
+    document: Arc<RwLock<Option<(u64, String)>>>, // Published time and the document
+}
+
+impl DirectoryMirror {
+    pub fn document(&self) -> Option<String> {
+        let document = self.document.read().expect("mirror poisoned");
+        document.as_ref().map(|(_, document)| document.clone())
+    }
+
+    // Fetch the consensus from a random authority now and every REFRESH_INTERVAL,
+    // keeping the newest copy. Failures are retried sooner.
+    pub async fn keep_fresh(self, authorities: Vec<String>) {
+        let Ok(http) = reqwest::Client::builder().timeout(FETCH_TIMEOUT).build() else {
+            return;
+        };
+        let mut failing = false;
+        loop {
+            let mut authorities = authorities.clone();
+            authorities.shuffle(&mut rand::thread_rng());
+            let mut fetched = false;
+            for authority in &authorities {
+                if let Some((published, document)) = fetch(&http, authority).await {
+                    self.store(published, document);
+                    fetched = true;
+                    break;
+                }
+            }
+            match (fetched, failing) {
+                (true, true) => println!("🌐 Mirroring the directory again"),
+                (false, false) => eprintln!("⚠️  Could not fetch the directory from any authority"),
+                _ => {}
+            }
+            failing = !fetched;
+            tokio::time::sleep(if fetched { REFRESH_INTERVAL } else { RETRY_INTERVAL }).await;
+        }
+    }
+
+    fn store(&self, published: u64, document: String) {
+        let mut current = self.document.write().expect("mirror poisoned");
+        if current.as_ref().is_none_or(|(current, _)| published >= *current) {
+            *current = Some((published, document));
+        }
+    }
+}
+
+// The consensus an authority serves, if it is a well-formed signed directory
+async fn fetch(http: &reqwest::Client, authority: &str) -> Option<(u64, String)> {
+    let url = format!("{}/directory", authority.trim_end_matches('/'));
+    let response = http.get(&url).send().await.ok()?.error_for_status().ok()?;
+    let document = response.text().await.ok()?;
+    let signed: SignedDirectory = serde_json::from_str(&document).ok()?;
+    let directory: Directory = serde_json::from_str(&signed.directory).ok()?;
+    Some((directory.published, document))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_newest_document() {
+        let mirror = DirectoryMirror::default();
+        assert!(mirror.document().is_none());
+        mirror.store(200, "second".to_string());
+        mirror.store(100, "first".to_string());
+        assert_eq!(mirror.document().as_deref(), Some("second"));
+        mirror.store(300, "third".to_string());
+        assert_eq!(mirror.document().as_deref(), Some("third"));
+    }
+}
@@ -0,0 +1,110 @@
+use crate::config::MixingMode;
+use rand::Rng;
+use std::time::Duration;
+use tokio::time::Instant;
+
+// Decides when a relayed cell may leave the relay. Cells of one circuit
+// direction never overtake each other, because the next hop's keystream
+// depends on their order. Delays of different circuits are independent, so
+// circuits sharing a link leave in a different order than they arrived. A
+// circuit on a connection of its own only gets the delay: there is nothing
+// on that connection for its cells to swap places with.
+#[derive(Clone, Copy)]
+pub struct Mixer {
+    mode: MixingMode,
+    epoch: Instant,
+}
+
+impl Mixer {
+    pub fn new(mode: MixingMode) -> Self {
+        Self {
+            mode,
+            epoch: Instant::now(),
+        }
+    }
+
+    // Release time of a cell that arrived at `arrival`, given the release time
+    // of the previous cell in the same direction
+    pub fn release_time(&self, arrival: Instant, previous: Instant) -> Instant {
+        let release = match self.mode {
+            MixingMode::Off => arrival,
+            MixingMode::Exponential { mean_delay_ms, max_delay_ms } => {
+                // Inverse transform sampling, capped at the latency budget
+                let uniform: f64 = rand::thread_rng().gen();
+                let delay_ms = -(mean_delay_ms as f64) * (1.0 - uniform).ln();
+                arrival + Duration::from_secs_f64(delay_ms.min(max_delay_ms as f64) / 1000.0)
+            }
+            MixingMode::Pool { flush_interval_ms } => {
+                // Next tick of the relay-wide clock: everything that arrived
+                // within one interval, on any circuit, leaves together
+                let interval = flush_interval_ms.max(1) as u128 * 1_000_000;
+                let elapsed = arrival.duration_since(self.epoch).as_nanos();
+                let tick = (elapsed / interval + 1) * interval;
+                self.epoch + Duration::from_nanos(tick as u64)
+            }
+        };
+        release.max(previous)
+    }
+
+    pub fn describe(&self) -> String {
+        match self.mode {
+            MixingMode::Off => "off".to_string(),
+            MixingMode::Exponential { mean_delay_ms, max_delay_ms } => format!(
+                "exponential, mean {} ms, budget {} ms",
+                mean_delay_ms, max_delay_ms
+            ),
+            MixingMode::Pool { flush_interval_ms } => {
+                format!("pool, flushed every {} ms", flush_interval_ms)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn cells_leave_on_arrival_without_mixing() {
+        let mixer = Mixer::new(MixingMode::Off);
+        let arrival = Instant::now() + ms(5);
+        assert_eq!(mixer.release_time(arrival, arrival - ms(1)), arrival);
+    }
+
+    #[test]
+    fn cells_never_leave_before_the_previous_one() {
+        let arrival = Instant::now();
+        for mode in [
+            MixingMode::Off,
+            MixingMode::Exponential { mean_delay_ms: 1, max_delay_ms: 2 },
+            MixingMode::Pool { flush_interval_ms: 10 },
+        ] {
+            let previous = arrival + ms(500);
+            assert_eq!(Mixer::new(mode).release_time(arrival, previous), previous);
+        }
+    }
+
+    #[test]
+    fn exponential_delays_stay_within_the_budget() {
+        let mixer = Mixer::new(MixingMode::Exponential { mean_delay_ms: 30, max_delay_ms: 50 });
+        let arrival = Instant::now();
+        let delays: Vec<Duration> = (0..1000).map(|_| mixer.release_time(arrival, arrival) - arrival).collect();
+        assert!(delays.iter().all(|delay| *delay <= ms(50)));
+        assert!(delays.iter().any(|delay| *delay == ms(50)));
+        assert!(delays.iter().any(|delay| *delay < ms(30)));
+    }
+
+    #[test]
+    fn pool_releases_at_the_next_tick() {
+        let mixer = Mixer::new(MixingMode::Pool { flush_interval_ms: 50 });
+        let epoch = mixer.epoch;
+        assert_eq!(mixer.release_time(epoch + ms(1), epoch), epoch + ms(50));
+        assert_eq!(mixer.release_time(epoch + ms(49), epoch), epoch + ms(50));
+        assert_eq!(mixer.release_time(epoch + ms(50), epoch), epoch + ms(100));
+        assert_eq!(mixer.release_time(epoch + ms(120), epoch), epoch + ms(150));
+    }
+}
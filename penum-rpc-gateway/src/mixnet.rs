@@ -0,0 +1,354 @@
+use crate::config::MixingMode;
+use crate::gateway::Gateway;
+use crate::identity::IdentityKeys;
+use crate::mixing::Mixer;
+use crate::next_hop::NextHopPolicy;
+use crate::replay::ReplayCache;
+use penum_protocol::sphinx::{self, Action, Mailbox, SphinxPacket, SPHINX_PACKET_SIZE};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::time::{sleep_until, Instant};
+
+// Packets waiting for one outgoing connection
+const LINK_QUEUE: usize = 256;
+
+// Most packets coalesced into a single write
+const MAX_BATCH_PACKETS: usize = 8;
+
+// Mailboxes one client connection may register
+const MAX_MAILBOXES_PER_CONNECTION: usize = 1024;
+
+// An incoming connection that sends no packet for this long is closed.
+// Links between nodes are reopened by the next packet.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+type PacketSender = mpsc::Sender<Box<SphinxPacket>>;
+
+// Mixnet mode: every request is a self-contained Sphinx packet, accepted on
+// its own listener. Packets to the same next hop share one connection,
+// whichever client they came from.
+#[derive(Clone)]
+pub struct MixNode {
+    identity: IdentityKeys,
+    mixer: Mixer,
+    replay_cache: Arc<Mutex<ReplayCache>>,
+    links: Arc<Mutex<HashMap<SocketAddr, PacketSender>>>,
+    next_hops: NextHopPolicy,
+    mailboxes: Arc<Mutex<HashMap<Mailbox, PacketSender>>>,
+    exit: Option<Gateway>,
+}
+
+impl MixNode {
+    // `exit` is set on a gateway, which answers the requests addressed to it
+    pub fn new(
+        identity: IdentityKeys,
+        mixing: MixingMode,
+        replay_window_secs: u64,
+        next_hops: NextHopPolicy,
+        exit: Option<Gateway>,
+    ) -> Self {
+        Self {
+            identity,
+            mixer: Mixer::new(mixing),
+            replay_cache: Arc::new(Mutex::new(ReplayCache::new(replay_window_secs))),
+            links: Arc::new(Mutex::new(HashMap::new())),
+            next_hops,
+            mailboxes: Arc::new(Mutex::new(HashMap::new())),
+            exit,
+        }
+    }
+
+    pub async fn handle_connection(&self, stream: TcpStream) {
+        let _ = stream.set_nodelay(true);
+        let (mut reader, writer) = stream.into_split();
+        let (replies, replies_rx) = mpsc::channel(LINK_QUEUE);
+        tokio::spawn(write_packets(writer, replies_rx));
+
+        let mut registered = Vec::new();
+        loop {
+            let mut packet = Box::new([0u8; SPHINX_PACKET_SIZE]);
+            match tokio::time::timeout(IDLE_TIMEOUT, reader.read_exact(&mut packet[..])).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(_)) => break, // Connection closed
+                Err(_) => break, // Idle too long
+            }
+            let arrival = Instant::now();
+
+            let Ok(processed) = sphinx::process_packet(&self.identity.secret, &packet) else {
+                continue; // Fail silently
+            };
+            // Each packet is accepted once, and only until it expires
+            if !self
+                .replay_cache
+                .lock()
+                .expect("replay cache poisoned")
+                .accept_until(processed.replay_tag, processed.expiry)
+            {
+                continue; // Fail silently: stale or replayed packet
+            }
+
+            // Unlike cells on a circuit, packets are independent, so each one
+            // gets its own delay and may overtake others
+            let release = self.mixer.release_time(arrival, arrival);
+            match processed.action {
+                Action::Forward { next_hop, reply_mailbox, packet } => {
+                    if let Some(mailbox) = reply_mailbox {
+                        if registered.len() < MAX_MAILBOXES_PER_CONNECTION {
+                            self.mailboxes.lock().expect("mailbox map poisoned").insert(mailbox, replies.clone());
+                            registered.push(mailbox);
+                        }
+                    }
+                    let node = self.clone();
+                    tokio::spawn(async move {
+                        sleep_until(release).await;
+                        node.send_to(next_hop, packet).await;
+                    });
+                }
+                Action::Deliver { mailbox, index, payload } => {
+                    let Some(client) = self.mailboxes.lock().expect("mailbox map poisoned").get(&mailbox).cloned() else {
+                        continue; // Fail silently: client already gone
+                    };
+                    tokio::spawn(async move {
+                        sleep_until(release).await;
+                        let _ = client.send(sphinx::encode_delivery(index, &payload)).await;
+                    });
+                }
+                Action::Exit { body } => {
+                    if self.exit.is_none() {
+                        continue; // Fail silently: only gateways answer requests
+                    }
+                    let node = self.clone();
+                    tokio::spawn(async move { node.answer(&body, arrival).await });
+                }
+            }
+        }
+
+        // Replies to mailboxes of this connection can no longer be delivered
+        let mut mailboxes = self.mailboxes.lock().expect("mailbox map poisoned");
+        for mailbox in registered {
+            mailboxes.remove(&mailbox);
+        }
+    }
+
+    // Forward a request to the RPC provider and send the response back through
+    // the reply blocks the client supplied, one reply packet per block
+    async fn answer(&self, body: &[u8], received: Instant) {
+        let Some(gateway) = &self.exit else {
+            return;
+        };
+        let Some((surbs, request)) = sphinx::decode_request(body) else {
+            return; // Fail silently
+        };
+        let Some(first) = surbs.first() else {
+            return; // Fail silently: no way to reply
+        };
+
+        // A response that needs more reply blocks than we were given becomes
+        // an error rather than being truncated
+        let parts = match gateway.timed_response(request, received).await {
+            Some(response) => sphinx::encode_reply_parts(&response, surbs.len()),
+            None => None,
+        };
+        let replies = match parts {
+            Some(parts) => surbs.iter().zip(parts).collect::<Vec<_>>(),
+            None => vec![(first, sphinx::error_reply())],
+        };
+
+        for (surb, part) in replies {
+            if let Ok(packet) = surb.reply_packet(&part) {
+                self.send_to(surb.first_hop, packet).await;
+            }
+        }
+    }
+
+    // Queue a packet on the connection to `next_hop`, opening it if needed.
+    // Both forwarded packets and reply blocks name their next hop, so both
+    // go through the next hop policy.
+    async fn send_to(&self, next_hop: SocketAddr, packet: Box<SphinxPacket>) {
+        if self.next_hops.check(next_hop).is_err() {
+            return; // Fail silently
+        }
+        let link = self
+            .links
+            .lock()
+            .expect("link map poisoned")
+            .entry(next_hop)
+            .or_insert_with(|| open_link(next_hop, self.links.clone(), self.next_hops.clone()))
+            .clone();
+        let _ = link.send(packet).await; // Fail silently: the link is gone
+    }
+}
+
+// Start the writer for a new outgoing connection. It removes itself from the
+// link map when the connection fails, so the next packet opens a new one.
+fn open_link(
+    next_hop: SocketAddr,
+    links: Arc<Mutex<HashMap<SocketAddr, PacketSender>>>,
+    next_hops: NextHopPolicy,
+) -> PacketSender {
+    let (link, link_rx) = mpsc::channel(LINK_QUEUE);
+    let this_link = link.downgrade();
+    tokio::spawn(async move {
+        if let Ok(stream) = next_hops.connect(next_hop).await {
+            let _ = stream.set_nodelay(true);
+            write_packets(stream, link_rx).await;
+        }
+        let mut links = links.lock().expect("link map poisoned");
+        let current = links.get(&next_hop);
+        if current.is_some_and(|current| this_link.upgrade().is_some_and(|link| link.same_channel(current))) {
+            links.remove(&next_hop);
+        }
+    });
+    link
+}
+
+// Write packets as they are released, coalescing those already queued into one write
+async fn write_packets<W: AsyncWrite + Unpin>(mut writer: W, mut packets: mpsc::Receiver<Box<SphinxPacket>>) {
+    let mut batch = Vec::with_capacity(MAX_BATCH_PACKETS * SPHINX_PACKET_SIZE);
+    while let Some(packet) = packets.recv().await {
+        batch.extend_from_slice(&packet[..]);
+        while batch.len() < MAX_BATCH_PACKETS * SPHINX_PACKET_SIZE {
+            match packets.try_recv() {
+                Ok(packet) => batch.extend_from_slice(&packet[..]),
+                Err(TryRecvError::Empty | TryRecvError::Disconnected) => break,
+            }
+        }
+
+        if writer.write_all(&batch).await.is_err() || writer.flush().await.is_err() {
+            return; // Fail silently
+        }
+        batch.clear();
+    }
+}
+
+pub async fn start_mix_node(listen_addr: &str, listen_port: u16, node: MixNode) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(format!("{}:{}", listen_addr, listen_port)).await?;
+
+    println!("🕸️  Mixnet listener on {}:{} (Sphinx packets)", listen_addr, listen_port);
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _addr)) => {
+                let node = node.clone();
+                tokio::spawn(async move { node.handle_connection(stream).await });
+            }
+            Err(_) => {
+                continue; // Continue accepting connections on error
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use penum_protocol::sphinx::{Command, SurbKeys};
+    use penum_protocol::StaticSecret;
+
+    const MAILBOX: Mailbox = [9; 16];
+
+    fn identity(seed: u8) -> IdentityKeys {
+        IdentityKeys::from_secret(StaticSecret::from([seed; 32]))
+    }
+
+    // An entry relay on a local listener, and a listener standing in for the
+    // middle relay it forwards to
+    async fn entry_relay() -> (MixNode, SocketAddr, TcpListener) {
+        let node = MixNode::new(identity(1), MixingMode::Off, 300, NextHopPolicy::new(true, Vec::new()), None);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, node.clone()));
+        let middle = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        (node, address, middle)
+    }
+
+    async fn serve(listener: TcpListener, node: MixNode) {
+        while let Ok((stream, _)) = listener.accept().await {
+            let node = node.clone();
+            tokio::spawn(async move { node.handle_connection(stream).await });
+        }
+    }
+
+    // A client's request through the entry relay, registering `MAILBOX`
+    async fn send_request(entry: SocketAddr, middle: SocketAddr) -> TcpStream {
+        let route = [
+            (identity(1).public, Command::Forward { next_hop: middle, reply_mailbox: Some(MAILBOX) }),
+            (identity(2).public, Command::Exit),
+        ];
+        let mut client = TcpStream::connect(entry).await.unwrap();
+        client.write_all(&sphinx::create_packet(&route, b"request").unwrap()[..]).await.unwrap();
+        client
+    }
+
+    // A reply packet the entry relay should deliver to `mailbox`, as the
+    // middle relay would pass it on
+    fn reply(mailbox: Mailbox, index: u8) -> (Box<SphinxPacket>, SurbKeys) {
+        let route = [(identity(1).public, Command::Deliver { mailbox, index })];
+        let (surb, keys) = sphinx::create_surb(&route, "127.0.0.1:1".parse().unwrap()).unwrap();
+        (surb.reply_packet(b"response").unwrap(), keys)
+    }
+
+    async fn read_packet(stream: &mut TcpStream) -> Option<Box<SphinxPacket>> {
+        let mut packet = Box::new([0u8; SPHINX_PACKET_SIZE]);
+        match tokio::time::timeout(Duration::from_millis(300), stream.read_exact(&mut packet[..])).await {
+            Ok(Ok(_)) => Some(packet),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn replies_reach_the_connection_that_registered_the_mailbox() {
+        let (_node, entry, middle) = entry_relay().await;
+        let mut client = send_request(entry, middle.local_addr().unwrap()).await;
+        let (mut link, _) = middle.accept().await.unwrap();
+        assert!(read_packet(&mut link).await.is_some());
+
+        let (packet, keys) = reply(MAILBOX, 3);
+        let mut from_middle = TcpStream::connect(entry).await.unwrap();
+        from_middle.write_all(&packet[..]).await.unwrap();
+        let delivered = read_packet(&mut client).await.unwrap();
+        let (index, payload) = sphinx::decode_delivery(&delivered);
+        assert_eq!(index, 3);
+        assert_eq!(keys.open(payload).unwrap(), b"response");
+
+        // Nothing for a mailbox nobody registered
+        from_middle.write_all(&reply([8; 16], 0).0[..]).await.unwrap();
+        assert!(read_packet(&mut client).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn mailboxes_are_dropped_with_their_connection() {
+        let (node, entry, middle) = entry_relay().await;
+        let client = send_request(entry, middle.local_addr().unwrap()).await;
+        let (mut link, _) = middle.accept().await.unwrap();
+        assert!(read_packet(&mut link).await.is_some());
+        assert!(node.mailboxes.lock().unwrap().contains_key(&MAILBOX));
+
+        drop(client);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(node.mailboxes.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn replayed_packets_are_forwarded_once() {
+        let (_node, entry, middle) = entry_relay().await;
+        let route = [
+            (identity(1).public, Command::Forward { next_hop: middle.local_addr().unwrap(), reply_mailbox: None }),
+            (identity(2).public, Command::Exit),
+        ];
+        let packet = sphinx::create_packet(&route, b"request").unwrap();
+        let mut client = TcpStream::connect(entry).await.unwrap();
+        client.write_all(&packet[..]).await.unwrap();
+        client.write_all(&packet[..]).await.unwrap();
+
+        let (mut link, _) = middle.accept().await.unwrap();
+        assert!(read_packet(&mut link).await.is_some());
+        assert!(read_packet(&mut link).await.is_none());
+    }
+}
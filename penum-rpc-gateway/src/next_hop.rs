@@ -0,0 +1,102 @@
+use penum_protocol::directory::is_public_address;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+// Longest wait for a TCP connection to a next hop
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Where a node may connect on a client's behalf. Clients name the next hop
+// of their circuits and reply blocks, so without this every relay would be
+// an open proxy into the networks it sits in and to services on its own host.
+#[derive(Clone)]
+pub struct NextHopPolicy {
+    allow_private: bool,  // Test networks on one host or a LAN
+    own_ips: Vec<IpAddr>,
+}
+
+impl NextHopPolicy {
+    pub fn new(allow_private: bool, own_ips: Vec<IpAddr>) -> Self {
+        Self { allow_private, own_ips }
+    }
+
+    pub fn check(&self, next_hop: SocketAddr) -> anyhow::Result<()> {
+        if next_hop.port() == 0 {
+            return Err(anyhow::anyhow!("Next hop {} has no port", next_hop));
+        }
+        if self.allow_private {
+            return Ok(());
+        }
+        if !is_public_address(next_hop.ip()) {
+            return Err(anyhow::anyhow!("Next hop {} is not a public address", next_hop));
+        }
+        if self.own_ips.contains(&next_hop.ip()) {
+            return Err(anyhow::anyhow!("Next hop {} is this node", next_hop));
+        }
+        Ok(())
+    }
+
+    pub async fn connect(&self, next_hop: SocketAddr) -> anyhow::Result<TcpStream> {
+        self.check(next_hop)?;
+        tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(next_hop))
+            .await
+            .map_err(|_| anyhow::anyhow!("Next hop {} did not accept within {:?}", next_hop, CONNECT_TIMEOUT))?
+            .map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> NextHopPolicy {
+        NextHopPolicy::new(false, vec!["203.0.113.7".parse().unwrap(), "8.8.4.4".parse().unwrap()])
+    }
+
+    #[test]
+    fn allows_public_addresses() {
+        for next_hop in ["1.1.1.1:9002", "8.8.8.8:443", "[2606:4700::1111]:9003"] {
+            assert!(policy().check(next_hop.parse().unwrap()).is_ok(), "{}", next_hop);
+        }
+    }
+
+    #[test]
+    fn refuses_internal_and_special_addresses() {
+        for next_hop in [
+            "127.0.0.1:22",
+            "10.1.2.3:80",
+            "172.16.0.1:80",
+            "192.168.1.1:80",
+            "169.254.169.254:80",
+            "100.64.0.1:80",
+            "0.0.0.0:80",
+            "255.255.255.255:80",
+            "224.0.0.1:80",
+            "240.0.0.1:80",
+            "[::1]:22",
+            "[::]:22",
+            "[fd00::1]:80",
+            "[fe80::1]:80",
+            "[ff02::1]:80",
+            "[::ffff:127.0.0.1]:22",
+            "[::ffff:10.0.0.1]:80",
+            "[64:ff9b::a00:1]:80",
+            "1.1.1.1:0",
+        ] {
+            assert!(policy().check(next_hop.parse().unwrap()).is_err(), "{}", next_hop);
+        }
+    }
+
+    #[test]
+    fn refuses_this_node() {
+        assert!(policy().check("8.8.4.4:22".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_networks_may_allow_private_addresses() {
+        let policy = NextHopPolicy::new(true, vec!["127.0.0.1".parse().unwrap()]);
+        assert!(policy.check("127.0.0.1:9002".parse().unwrap()).is_ok());
+        assert!(policy.check("10.0.0.2:9002".parse().unwrap()).is_ok());
+        assert!(policy.check("127.0.0.1:0".parse().unwrap()).is_err());
+    }
+}
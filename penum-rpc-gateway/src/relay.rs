@@ -1,82 +1,399 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use crate::config::MixingMode;
+use crate::identity::IdentityKeys;
+use crate::link::{self, CircuitHandler, CircuitStream, Links};
+use crate::mixing::Mixer;
+use crate::next_hop::NextHopPolicy;
+use chacha20::cipher::StreamCipher;
+use chacha20::ChaCha20;
+use penum_protocol::cell::{CellHeader, CellType, Packet, PACKET_SIZE};
+use penum_protocol::extend::{parse_extend, parse_legacy_extend};
+use penum_protocol::handshake::{HandshakeSecrets, CAP_LINK, HANDSHAKE_REPLY_LEN, MIN_PROTOCOL_VERSION};
+use penum_protocol::kdf::OnionLayer;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
 use tokio::net::{TcpListener, TcpStream};
-use std::net::SocketAddr;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::time::{sleep_until, timeout_at, Instant};
 use anyhow::Result;
 
+// Most cells of one circuit direction coalesced into a single write on a
+// connection of its own
+const MAX_BATCH_CELLS: usize = 32;
+
+// Cells buffered between the reading and writing side of one direction
+const QUEUE_CELLS: usize = 64;
+
+// How often the relay prints its stats
+const STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+// Longest a circuit may take from the first handshake byte until it is
+// extended, so connections that stall during setup do not pile up
+const SETUP_TIMEOUT: Duration = Duration::from_secs(20);
+
+// A circuit whose previous or next hop sends no cell for this long is closed.
+// Clients replace their circuits well before that (10 minutes by default).
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+// Counters for the periodic stats line
+#[derive(Default)]
+pub struct RelayStats {
+    open_circuits: AtomicU64,
+    circuits: AtomicU64,
+    cells: AtomicU64,
+    delay_micros: AtomicU64,
+}
+
+impl RelayStats {
+    fn report(&self, mixer: &Mixer) {
+        let cells = self.cells.load(Ordering::Relaxed);
+        let delay_micros = self.delay_micros.load(Ordering::Relaxed);
+        let mean_delay_ms = if cells == 0 {
+            0.0
+        } else {
+            delay_micros as f64 / cells as f64 / 1000.0
+        };
+        println!(
+            "📊 Relay stats: {} open circuits ({} total), {} cells forwarded, mean delay {:.1} ms (mixing: {})",
+            self.open_circuits.load(Ordering::Relaxed),
+            self.circuits.load(Ordering::Relaxed),
+            cells,
+            mean_delay_ms,
+            mixer.describe()
+        );
+    }
+}
+
+// A cell waiting at the relay, with the time it arrived and the time the
+// mixer lets it leave
+struct QueuedCell {
+    arrival: Instant,
+    release: Instant,
+    cell: [u8; PACKET_SIZE],
+}
+
+// Settings and counters shared by every forwarding task
+#[derive(Clone)]
+struct Forwarding {
+    batch_window: Duration,
+    mixer: Mixer,
+    stats: Arc<RelayStats>,
+}
+
+#[derive(Clone)]
 pub struct Relay {
-    next_hop: SocketAddr,
+    identity: IdentityKeys,
+    next_hops: NextHopPolicy,
+    links: Links,
+    forwarding: Forwarding,
 }
 
 impl Relay {
-    pub fn new(next_hop: SocketAddr) -> Self {
-        Self { next_hop }
+    pub fn new(identity: IdentityKeys, batch_window: Duration, mixing: MixingMode, next_hops: NextHopPolicy) -> Self {
+        Self {
+            identity,
+            links: Links::new(next_hops.clone(), batch_window),
+            next_hops,
+            forwarding: Forwarding {
+                batch_window,
+                mixer: Mixer::new(mixing),
+                stats: Arc::new(RelayStats::default()),
+            },
+        }
+    }
+
+    // A circuit on a connection of its own, or a link from another relay if
+    // the handshake selects CAP_LINK
+    pub async fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
+        stream.set_nodelay(true)?;
+        let deadline = Instant::now() + SETUP_TIMEOUT;
+        let Some(secrets) = self.accept_handshake(&mut stream, deadline).await? else {
+            return Ok(());
+        };
+        if secrets.capabilities & CAP_LINK == 0 {
+            return self.relay_circuit(stream, secrets, false, deadline).await;
+        }
+
+        let relay = self.clone();
+        let accept: CircuitHandler = Arc::new(move |circuit| {
+            let relay = relay.clone();
+            Box::pin(async move {
+                let _ = relay.handle_link_circuit(circuit).await;
+            })
+        });
+        link::serve(stream, &secrets, self.forwarding.batch_window, accept).await;
+        Ok(())
     }
 
-    pub async fn handle_connection(&self, stream: TcpStream) -> Result<()> {
-        // Connect to the next hop in the relay chain
-        let next_stream = TcpStream::connect(self.next_hop).await?;
-        
-        // Forward data bidirectionally between current connection and next hop
+    // A circuit another relay created on its link to us. It cannot be a link.
+    async fn handle_link_circuit(&self, mut stream: DuplexStream) -> Result<()> {
+        let deadline = Instant::now() + SETUP_TIMEOUT;
+        let Some(secrets) = self.accept_handshake(&mut stream, deadline).await? else {
+            return Ok(());
+        };
+        if secrets.capabilities & CAP_LINK != 0 {
+            return Ok(());
+        }
+        self.relay_circuit(stream, secrets, true, deadline).await
+    }
+
+    // Handshake with the previous hop: receive the client hello, send ours.
+    // None if the client was rejected.
+    async fn accept_handshake<S: CircuitStream>(&self, stream: &mut S, deadline: Instant) -> Result<Option<HandshakeSecrets>> {
+        let accepted = timeout_at(deadline, self.identity.accept_handshake(stream))
+            .await
+            .map_err(|_| anyhow::anyhow!("Circuit setup timed out"))??;
+        Ok(accepted.map(|(_, secrets)| secrets))
+    }
+
+    // Extend a circuit whose handshake is done and forward its cells until
+    // either side closes it. `on_link` if the previous hop is a link.
+    async fn relay_circuit<S: CircuitStream + 'static>(
+        &self,
+        mut stream: S,
+        secrets: HandshakeSecrets,
+        on_link: bool,
+        deadline: Instant,
+    ) -> Result<()> {
+        let (layer, next_stream, next_on_link) = timeout_at(deadline, self.extend_circuit(&mut stream, &secrets))
+            .await
+            .map_err(|_| anyhow::anyhow!("Circuit setup timed out"))??;
+
+        // Forward whole cells in both directions, peeling our layer on the way
+        // in and adding it on the way out. Output is re-framed on cell
+        // boundaries, so however the previous hop's TCP stack segmented its
+        // bytes, nothing of that shape reaches the next link. Cells going onto
+        // a link are batched by the link's writer with those of other circuits.
         let (stream_reader, stream_writer) = tokio::io::split(stream);
         let (next_reader, next_writer) = tokio::io::split(next_stream);
-        
-        // Forward data in both directions
-        let forward_task = tokio::spawn(async move {
-            let mut reader = stream_reader;
-            let mut writer = next_writer;
-            let mut buffer = [0u8; 1024]; // Fixed packet size
-            loop {
-                match reader.read(&mut buffer).await {
-                    Ok(0) => break, // Connection closed
-                    Ok(n) => {
-                        if let Err(_) = writer.write_all(&buffer[..n]).await {
-                            break; // Forward failed
-                        }
-                        if let Err(_) = writer.flush().await {
-                            break; // Flush failed
-                        }
-                    }
-                    Err(_) => break, // Read failed
-                }
-            }
-        });
-        
-        let backward_task = tokio::spawn(async move {
-            let mut reader = next_reader;
-            let mut writer = stream_writer;
-            let mut buffer = [0u8; 1024]; // Fixed packet size
-            loop {
-                match reader.read(&mut buffer).await {
-                    Ok(0) => break, // Connection closed
-                    Ok(n) => {
-                        if let Err(_) = writer.write_all(&buffer[..n]).await {
-                            break; // Forward failed
-                        }
-                        if let Err(_) = writer.flush().await {
-                            break; // Flush failed
-                        }
-                    }
-                    Err(_) => break, // Read failed
-                }
-            }
-        });
-        
-        // Wait for either task to complete
+
+        let stats = &self.forwarding.stats;
+        stats.circuits.fetch_add(1, Ordering::Relaxed);
+        stats.open_circuits.fetch_add(1, Ordering::Relaxed);
+
+        let forward_task = tokio::spawn(self.forwarding.clone().relay_cells(
+            stream_reader,
+            next_writer,
+            layer.forward,
+            self.forwarding.window_for(next_on_link),
+        ));
+        let backward_task = tokio::spawn(self.forwarding.clone().relay_cells(
+            next_reader,
+            stream_writer,
+            layer.backward,
+            self.forwarding.window_for(on_link),
+        ));
+
+        // Wait for either task to complete, then tear down the other direction
+        // so the close propagates along the circuit instead of leaving it half-open
+        let forward_abort = forward_task.abort_handle();
+        let backward_abort = backward_task.abort_handle();
         tokio::select! {
             _ = forward_task => {},
             _ = backward_task => {},
         };
-        
+        forward_abort.abort();
+        backward_abort.abort();
+        stats.open_circuits.fetch_sub(1, Ordering::Relaxed);
+
         Ok(())
     }
+
+    // Extend the circuit to the next hop named in its first cell, once the
+    // handshake with the previous hop is done. Returns our layer and the
+    // stream to the next hop, and whether that stream is on a link.
+    async fn extend_circuit<S: CircuitStream>(
+        &self,
+        stream: &mut S,
+        secrets: &HandshakeSecrets,
+    ) -> Result<(OnionLayer, Box<dyn CircuitStream>, bool)> {
+        let mut layer = secrets.onion_layer();
+
+        // The first cell on a circuit tells us where to extend it.
+        // This is the only point where the relay learns the next hop.
+        let mut cell = [0u8; PACKET_SIZE];
+        stream.read_exact(&mut cell).await?;
+        layer.apply_forward(&mut cell);
+        let (header, payload) = Packet::decode(&cell)?;
+        if header.cell_type != CellType::Handshake {
+            return Err(anyhow::anyhow!("Invalid extend cell"));
+        }
+
+        // Connect to the next hop and relay the client's hello to it, padded to
+        // a full cell. A rejection from the next hop is passed back like any
+        // other reply. The ML-KEM cells of a hybrid handshake follow through the
+        // forwarding pipe. A version 2 client's hellos go as they are, on a
+        // connection of their own.
+        let legacy = secrets.version < MIN_PROTOCOL_VERSION;
+        let (next_hop, hello, identity) = if legacy {
+            parse_legacy_extend(payload).map(|(next_hop, hello)| (next_hop, hello.to_vec(), None))
+        } else {
+            parse_extend(payload).map(|(next_hop, hello, identity)| (next_hop, Packet::padded(&hello).to_vec(), identity))
+        }
+        .ok_or_else(|| anyhow::anyhow!("Invalid extend cell"))?;
+        let (mut next_stream, next_on_link): (Box<dyn CircuitStream>, bool) =
+            match self.links.open_circuit(next_hop, identity).await? {
+                Some(circuit) => (Box::new(circuit), true),
+                None => {
+                    let next_stream = self.next_hops.connect(next_hop).await?;
+                    next_stream.set_nodelay(true)?;
+                    (Box::new(next_stream), false)
+                }
+            };
+        next_stream.write_all(&hello).await?;
+        let mut reply_cell = [0u8; PACKET_SIZE];
+        let reply_len = if legacy { HANDSHAKE_REPLY_LEN } else { PACKET_SIZE };
+        next_stream.read_exact(&mut reply_cell[..reply_len]).await?;
+        let next_reply = &reply_cell[..HANDSHAKE_REPLY_LEN];
+
+        let header = CellHeader::new(CellType::Handshake, 0, 0, next_reply.len());
+        let mut extended = Packet::encode(&header, next_reply)?;
+        layer.apply_backward(&mut extended);
+        stream.write_all(&extended).await?;
+        Ok((layer, next_stream, next_on_link))
+    }
 }
 
-pub async fn start_relay(listen_addr: &str, listen_port: u16, next_hop: SocketAddr) -> Result<()> {
-    let relay = Relay::new(next_hop);
+impl Forwarding {
+    // Batch window for cells written to a connection of their own. The
+    // writer of a link batches the cells of all its circuits instead.
+    fn window_for(&self, on_link: bool) -> Duration {
+        if on_link {
+            Duration::ZERO
+        } else {
+            self.batch_window
+        }
+    }
+
+    // One direction of a circuit: read whole cells, apply our layer and hand
+    // them to the writer
+    async fn relay_cells<R, W>(self, reader: R, writer: W, cipher: ChaCha20, batch_window: Duration)
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let (tx, rx) = mpsc::channel(QUEUE_CELLS);
+        tokio::join!(self.read_cells(reader, cipher, tx), self.write_cells(writer, rx, batch_window));
+    }
+
+    async fn read_cells<R: AsyncRead + Unpin>(
+        &self,
+        mut reader: R,
+        mut cipher: ChaCha20,
+        tx: mpsc::Sender<QueuedCell>,
+    ) {
+        let mut previous = Instant::now();
+        loop {
+            // A partial cell at the end of the stream is dropped, never forwarded
+            let mut cell = [0u8; PACKET_SIZE];
+            match tokio::time::timeout(IDLE_TIMEOUT, reader.read_exact(&mut cell)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(_)) => break, // Connection closed or read failed
+                Err(_) => break, // Idle too long
+            }
+            cipher.apply_keystream(&mut cell);
+
+            let arrival = Instant::now();
+            let release = self.mixer.release_time(arrival, previous);
+            previous = release;
+            if tx.send(QueuedCell { arrival, release, cell }).await.is_err() {
+                break; // Writer gone
+            }
+        }
+    }
+
+    // Write cells in batches once the mixer releases them. Everything already
+    // due goes out in one write; with a batch window we also wait that long for
+    // more cells to join it. Only cells of this circuit direction share such a
+    // write; on a link, the link's writer then coalesces them with the cells
+    // of its other circuits.
+    async fn write_cells<W: AsyncWrite + Unpin>(
+        &self,
+        mut writer: W,
+        mut rx: mpsc::Receiver<QueuedCell>,
+        batch_window: Duration,
+    ) {
+        let mut batch = Vec::with_capacity(MAX_BATCH_CELLS * PACKET_SIZE);
+        let mut arrivals = Vec::with_capacity(MAX_BATCH_CELLS);
+        let mut pending = None;
+
+        loop {
+            let first = match pending.take() {
+                Some(first) => first,
+                None => match rx.recv().await {
+                    Some(first) => first,
+                    None => break, // Reader gone and queue drained
+                },
+            };
+            sleep_until(first.release).await;
+            batch.extend_from_slice(&first.cell);
+            arrivals.push(first.arrival);
+            let deadline = Instant::now() + batch_window;
+
+            while arrivals.len() < MAX_BATCH_CELLS {
+                let next = match rx.try_recv() {
+                    Ok(next) => next,
+                    Err(TryRecvError::Disconnected) => break,
+                    Err(TryRecvError::Empty) if batch_window.is_zero() => break,
+                    Err(TryRecvError::Empty) => match timeout_at(deadline, rx.recv()).await {
+                        Ok(Some(next)) => next,
+                        _ => break, // Window elapsed or reader gone
+                    },
+                };
+                if next.release > Instant::now() {
+                    pending = Some(next); // Not due yet: starts the next batch
+                    break;
+                }
+                batch.extend_from_slice(&next.cell);
+                arrivals.push(next.arrival);
+            }
+
+            if writer.write_all(&batch).await.is_err() {
+                break; // Forward failed
+            }
+            if writer.flush().await.is_err() {
+                break; // Flush failed
+            }
+
+            let now = Instant::now();
+            let delay_micros: u64 = arrivals
+                .iter()
+                .map(|arrival| now.duration_since(*arrival).as_micros() as u64)
+                .sum();
+            self.stats.cells.fetch_add(arrivals.len() as u64, Ordering::Relaxed);
+            self.stats.delay_micros.fetch_add(delay_micros, Ordering::Relaxed);
+            batch.clear();
+            arrivals.clear();
+        }
+    }
+}
+
+pub async fn start_relay(
+    listen_addr: &str,
+    listen_port: u16,
+    identity: IdentityKeys,
+    batch_window: Duration,
+    mixing: MixingMode,
+    next_hops: NextHopPolicy,
+) -> Result<()> {
+    let relay = Relay::new(identity, batch_window, mixing, next_hops);
     let listener = TcpListener::bind(format!("{}:{}", listen_addr, listen_port)).await?;
-    
-    println!("🔗 Relay listening on {}:{} forwarding to {}", listen_addr, listen_port, next_hop);
-    
+
+    println!("🔗 Relay listening on {}:{} (next hop chosen per circuit)", listen_addr, listen_port);
+    println!("🔀 Mixing: {}", relay.forwarding.mixer.describe());
+
+    let forwarding = relay.forwarding.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(STATS_INTERVAL);
+        interval.tick().await; // The first tick completes immediately
+        loop {
+            interval.tick().await;
+            forwarding.stats.report(&forwarding.mixer);
+        }
+    });
+
     loop {
         match listener.accept().await {
             Ok((stream, _addr)) => {
@@ -92,11 +409,93 @@ pub async fn start_relay(listen_addr: &str, listen_port: u16, next_hop: SocketAd
     }
 }
 
-// Clone implementation for Relay
-impl Clone for Relay {
-    fn clone(&self) -> Self {
-        Self {
-            next_hop: self.next_hop,
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::link::tests::RecordingWriter;
+    use chacha20::cipher::KeyIvInit;
+
+    fn forwarding() -> Forwarding {
+        Forwarding {
+            batch_window: Duration::ZERO,
+            mixer: Mixer::new(MixingMode::Off),
+            stats: Arc::new(RelayStats::default()),
+        }
+    }
+
+    fn cipher() -> ChaCha20 {
+        ChaCha20::new(&[3u8; 32].into(), &[0u8; 12].into())
+    }
+
+    // Cells as the next hop should see them: our layer applied
+    fn expected(cells: &[u8]) -> Vec<u8> {
+        let mut cells = cells.to_vec();
+        cipher().apply_keystream(&mut cells);
+        cells
+    }
+
+    #[tokio::test]
+    async fn cells_are_reframed_whatever_the_segmentation() {
+        let input: Vec<u8> = (0..3 * PACKET_SIZE).map(|i| i as u8).collect();
+        let (mut previous_hop, reader) = tokio::io::duplex(4 * PACKET_SIZE);
+        let writer = RecordingWriter::default();
+        let writes = writer.writes.clone();
+        let relaying = tokio::spawn(forwarding().relay_cells(reader, writer, cipher(), Duration::ZERO));
+
+        // One and a half cells, a byte, the rest of the second cell and part
+        // of a third that never completes
+        for chunk in [&input[..1536], &input[1536..1537], &input[1537..2048], &input[2048..2100]] {
+            previous_hop.write_all(chunk).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        drop(previous_hop);
+        relaying.await.unwrap();
+
+        let writes = writes.lock().unwrap();
+        assert!(writes.iter().all(|write| !write.is_empty() && write.len() % PACKET_SIZE == 0));
+        assert_eq!(writes.concat(), expected(&input[..2 * PACKET_SIZE]));
+    }
+
+    #[tokio::test]
+    async fn cells_within_the_batch_window_share_a_write() {
+        let input: Vec<u8> = (0..40 * PACKET_SIZE).map(|i| (i / PACKET_SIZE) as u8).collect();
+        let (mut previous_hop, reader) = tokio::io::duplex(64 * PACKET_SIZE);
+        let writer = RecordingWriter::default();
+        let writes = writer.writes.clone();
+        let relaying = tokio::spawn(forwarding().relay_cells(reader, writer, cipher(), Duration::from_millis(100)));
+
+        // Three cells a few milliseconds apart go out together
+        for cell in input.chunks(PACKET_SIZE).take(3) {
+            previous_hop.write_all(cell).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
         }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(writes.lock().unwrap().len(), 1);
+
+        // Many at once are split at the batch limit
+        previous_hop.write_all(&input[3 * PACKET_SIZE..]).await.unwrap();
+        drop(previous_hop);
+        relaying.await.unwrap();
+        let writes = writes.lock().unwrap();
+        let sizes: Vec<usize> = writes.iter().map(|write| write.len() / PACKET_SIZE).collect();
+        assert_eq!(sizes, [3, MAX_BATCH_CELLS, 37 - MAX_BATCH_CELLS]);
+        assert_eq!(writes.concat(), expected(&input));
+    }
+
+    #[tokio::test]
+    async fn writes_to_a_link_are_not_held_back() {
+        let forwarding = Forwarding {
+            batch_window: Duration::from_secs(10),
+            ..forwarding()
+        };
+        let (mut previous_hop, reader) = tokio::io::duplex(4 * PACKET_SIZE);
+        let writer = RecordingWriter::default();
+        let writes = writer.writes.clone();
+        let window = forwarding.window_for(true);
+        tokio::spawn(forwarding.relay_cells(reader, writer, cipher(), window));
+
+        previous_hop.write_all(&[1u8; PACKET_SIZE]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(writes.lock().unwrap().len(), 1);
     }
-}
\ No newline at end of file
+}
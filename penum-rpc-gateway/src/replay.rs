@@ -0,0 +1,179 @@
+use penum_protocol::cell::unix_time;
+use penum_protocol::sphinx::MAX_LIFETIME_SECS;
+use std::collections::{BTreeSet, HashSet};
+
+// Upper bound on remembered handshakes and packets
+const MAX_ENTRIES: usize = 100_000;
+
+// Client handshakes and Sphinx packets accepted within the acceptance window.
+// Each is remembered until it would be rejected as stale anyway, and accepted
+// only once until then.
+//
+// A replayed circuit handshake already fails on its own: the node answers
+// every hello with a fresh ephemeral key, so the recorded client cells do not
+// open under the new session keys. For circuits the cache is a second line,
+// should a node ever reuse an ephemeral key. Sphinx packets have no such step: a node
+// processes a recorded packet exactly like the original, so this cache is
+// the only thing stopping a replay, and with it a replay-and-watch attack on
+// where the packet goes next.
+//
+// When the cache is full of entries that are all still valid, the entry that
+// expires first is forgotten, and from then on anything that expires no later
+// than it is refused. Under a flood the window shrinks from the old end
+// instead of every new handshake being refused.
+pub struct ReplayCache {
+    window_secs: u64,
+    max_entries: usize,
+    seen: HashSet<[u8; 32]>,
+    by_expiry: BTreeSet<(u64, [u8; 32])>,
+    forgotten_until: u64,  // Entries expiring up to this time may have been forgotten
+}
+
+impl ReplayCache {
+    pub fn new(window_secs: u64) -> Self {
+        Self {
+            window_secs,
+            max_entries: MAX_ENTRIES,
+            seen: HashSet::new(),
+            by_expiry: BTreeSet::new(),
+            forgotten_until: 0,
+        }
+    }
+
+    // Whether a timestamp is within the window of our clock, in either direction
+    pub fn is_fresh(&self, timestamp: u64) -> bool {
+        unix_time().abs_diff(timestamp) <= self.window_secs
+    }
+
+    // Accept a client handshake, identified by the client's ephemeral key, if it
+    // is fresh and has not been seen before
+    pub fn accept(&mut self, client_key: [u8; 32], timestamp: u64) -> bool {
+        if !self.is_fresh(timestamp) {
+            return false;
+        }
+        self.remember(client_key, timestamp.saturating_add(self.window_secs))
+    }
+
+    // Accept a Sphinx packet, identified by its replay tag, if it has not
+    // expired and has not been seen before. Expiry times further ahead than
+    // any client sets are refused. The window allows for clock differences.
+    pub fn accept_until(&mut self, replay_tag: [u8; 32], expiry: u64) -> bool {
+        let now = unix_time();
+        let latest = now + MAX_LIFETIME_SECS + self.window_secs;
+        if expiry.saturating_add(self.window_secs) < now || expiry > latest {
+            return false;
+        }
+        self.remember(replay_tag, expiry + self.window_secs)
+    }
+
+    fn remember(&mut self, key: [u8; 32], forget_at: u64) -> bool {
+        // Entries past their time would be rejected as stale anyway
+        let now = unix_time();
+        while let Some(&(oldest, old_key)) = self.by_expiry.first() {
+            if oldest >= now {
+                break;
+            }
+            self.by_expiry.remove(&(oldest, old_key));
+            self.seen.remove(&old_key);
+        }
+
+        if forget_at <= self.forgotten_until || self.seen.contains(&key) {
+            return false;
+        }
+        if self.seen.len() >= self.max_entries {
+            match self.by_expiry.first() {
+                // It would be the first to go, so keep what we have
+                Some(&(oldest, _)) if forget_at <= oldest => return false,
+                Some(&(oldest, old_key)) => {
+                    self.by_expiry.remove(&(oldest, old_key));
+                    self.seen.remove(&old_key);
+                    self.forgotten_until = oldest;
+                }
+                None => {}
+            }
+        }
+        self.seen.insert(key);
+        self.by_expiry.insert((forget_at, key));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(n: u8) -> [u8; 32] {
+        [n; 32]
+    }
+
+    #[test]
+    fn handshakes_are_accepted_once_within_the_window() {
+        let mut cache = ReplayCache::new(300);
+        let now = unix_time();
+        assert!(cache.accept(key(1), now));
+        assert!(!cache.accept(key(1), now));
+        assert!(cache.accept(key(2), now - 299));
+        assert!(cache.accept(key(3), now + 299));
+        assert!(!cache.accept(key(4), now - 301));
+        assert!(!cache.accept(key(5), now + 301));
+    }
+
+    #[test]
+    fn packets_are_accepted_once_until_they_expire() {
+        let mut cache = ReplayCache::new(300);
+        let now = unix_time();
+        assert!(cache.accept_until(key(1), now + 120));
+        assert!(!cache.accept_until(key(1), now + 120));
+        assert!(cache.accept_until(key(2), now - 299));
+        assert!(!cache.accept_until(key(3), now - 301));
+        assert!(cache.accept_until(key(4), now + MAX_LIFETIME_SECS + 299));
+        assert!(!cache.accept_until(key(5), now + MAX_LIFETIME_SECS + 301));
+    }
+
+    #[test]
+    fn expired_entries_are_evicted() {
+        let mut cache = ReplayCache::new(300);
+        let now = unix_time();
+        cache.by_expiry.insert((now - 1, key(1)));
+        cache.seen.insert(key(1));
+        assert!(cache.accept(key(2), now));
+        assert!(!cache.seen.contains(&key(1)));
+        assert_eq!(cache.seen.len(), cache.by_expiry.len());
+    }
+
+    #[test]
+    fn full_cache_shrinks_the_window_instead_of_refusing_everything() {
+        let mut cache = ReplayCache::new(300);
+        cache.max_entries = 3;
+        let now = unix_time();
+        for n in 0..3 {
+            assert!(cache.accept(key(n), now - 100 + n as u64));
+        }
+
+        // A fresh handshake still gets in, pushing out the oldest entry
+        assert!(cache.accept(key(10), now));
+        assert_eq!(cache.seen.len(), 3);
+        assert!(!cache.seen.contains(&key(0)));
+
+        // The forgotten entry, and anything as old, is refused rather than
+        // accepted a second time
+        assert!(!cache.accept(key(0), now - 100));
+        assert!(!cache.accept(key(11), now - 100));
+        // Entries still remembered are refused as replays
+        assert!(!cache.accept(key(1), now - 99));
+        assert!(!cache.accept(key(10), now));
+        // Newer than everything remembered is fine
+        assert!(cache.accept(key(12), now + 1));
+    }
+
+    #[test]
+    fn full_cache_keeps_entries_newer_than_the_offer() {
+        let mut cache = ReplayCache::new(300);
+        cache.max_entries = 2;
+        let now = unix_time();
+        assert!(cache.accept(key(1), now));
+        assert!(cache.accept(key(2), now));
+        assert!(!cache.accept(key(3), now - 10));
+        assert!(cache.seen.contains(&key(1)) && cache.seen.contains(&key(2)));
+    }
+}